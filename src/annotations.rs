@@ -0,0 +1,210 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+
+use crate::assembler::{is_register_name, number};
+
+/// How the bytes of a data range are shown
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataKind {
+    Byte,
+    Word,
+    /// Text, with the bytes that aren't printable shown as numbers
+    String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataRange {
+    pub kind: DataKind,
+    /// In bytes
+    pub length: usize,
+}
+
+/// Names, comments and data ranges for the addresses of a binary, so that a disassembly can be
+/// repeated with what has been worked out about it so far. The text has an entry per line:
+///
+/// ```text
+/// 0x0000 symbol start
+/// 0x0003 comment print the greeting
+/// 0x0020 string 14     ; 14 characters
+/// 0x002e word 4        ; 4 words
+/// 0x0036 byte 16
+/// ```
+///
+/// Addresses and counts are numbers as the assembler reads them. Everything after a `;` is ignored,
+/// except in the text of a comment.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Annotations {
+    pub symbols: BTreeMap<usize, String>,
+    pub comments: BTreeMap<usize, String>,
+    /// Bytes that are never decoded, by start address
+    pub data: BTreeMap<usize, DataRange>,
+}
+
+/// Why a line of an annotations file could not be read; `line` counts from 1
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnnotationError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for AnnotationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AnnotationError {}
+
+impl Annotations {
+    pub fn parse(text: &str) -> Result<Annotations, AnnotationError> {
+        let mut annotations = Annotations::default();
+        for (i, line) in text.lines().enumerate() {
+            annotations.entry(line).map_err(|message| AnnotationError { line: i + 1, message })?;
+        }
+        Ok(annotations)
+    }
+
+    fn entry(&mut self, line: &str) -> Result<(), String> {
+        let (address, rest) = split_word(line);
+        if address.is_empty() || address.starts_with(';') {
+            return Ok(());
+        }
+        let address = parse_number(address)?;
+        let (kind, rest) = split_word(rest);
+        if kind == "comment" {
+            if rest.is_empty() {
+                return Err("missing comment text".to_owned());
+            }
+            let comment = self.comments.entry(address).or_default();
+            if !comment.is_empty() {
+                comment.push_str("; ");
+            }
+            comment.push_str(rest);
+            return Ok(());
+        }
+
+        let (value, rest) = split_word(strip_comment(rest));
+        if !rest.is_empty() {
+            return Err(format!("unexpected {rest}"));
+        }
+        match kind {
+            "symbol" => self.symbol(address, value),
+            "byte" => self.data(address, DataKind::Byte, value, 1),
+            "word" => self.data(address, DataKind::Word, value, 2),
+            "string" => self.data(address, DataKind::String, value, 1),
+            "" => Err("missing symbol, comment, byte, word or string".to_owned()),
+            kind => Err(format!("unknown annotation {kind}")),
+        }
+    }
+
+    fn symbol(&mut self, address: usize, name: &str) -> Result<(), String> {
+        let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            && !is_register_name(name);
+        if !valid {
+            return Err(format!("invalid symbol name {name:?}"));
+        }
+        if self.symbols.values().any(|symbol| symbol == name) {
+            return Err(format!("symbol {name} is defined more than once"));
+        }
+        if let Some(symbol) = self.symbols.get(&address) {
+            return Err(format!("{address:#06x} is already called {symbol}"));
+        }
+        self.symbols.insert(address, name.to_owned());
+        Ok(())
+    }
+
+    fn data(&mut self, address: usize, kind: DataKind, count: &str, unit: usize) -> Result<(), String> {
+        let length = parse_number(count)? * unit;
+        if length == 0 {
+            return Err("a data range needs at least one byte".to_owned());
+        }
+        if self.overlaps_data(address, length) {
+            return Err(format!("data at {address:#06x} overlaps another range"));
+        }
+        self.data.insert(address, DataRange { kind, length });
+        Ok(())
+    }
+
+    /// Whether any of the `length` bytes from `address` are in a data range
+    pub fn overlaps_data(&self, address: usize, length: usize) -> bool {
+        self.data.range(..address + length).next_back().is_some_and(|(start, range)| start + range.length > address)
+    }
+
+    /// How the data at `address` is shown, as bytes unless a data range starts there
+    pub fn data_kind(&self, address: usize) -> DataKind {
+        self.data.get(&address).map_or(DataKind::Byte, |range| range.kind)
+    }
+
+    /// The comments for any of the `length` bytes from `address`
+    pub fn comment(&self, address: usize, length: usize) -> Option<String> {
+        let comments: Vec<&str> = self.comments.range(address..address + length).map(|(_, comment)| comment.as_str()).collect();
+        (!comments.is_empty()).then(|| comments.join("; "))
+    }
+
+    /// The start and range of the data range `address` is in
+    pub fn data_at(&self, address: usize) -> Option<(usize, DataRange)> {
+        let (start, range) = self.data.range(..=address).next_back()?;
+        (address < start + range.length).then_some((*start, *range))
+    }
+}
+
+fn parse_number(text: &str) -> Result<usize, String> {
+    match number(text) {
+        Some(value) if value >= 0 => Ok(value as usize),
+        _ if text.is_empty() => Err("missing number".to_owned()),
+        _ => Err(format!("invalid number {text}")),
+    }
+}
+
+fn split_word(text: &str) -> (&str, &str) {
+    let text = text.trim_start();
+    let end = text.find(char::is_whitespace).unwrap_or(text.len());
+    (&text[..end], text[end..].trim())
+}
+
+fn strip_comment(text: &str) -> &str {
+    text.split(';').next().unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::annotations::{AnnotationError, Annotations, DataKind, DataRange};
+
+    #[test]
+    fn parses_entries() {
+        let annotations = Annotations::parse(
+            "
+            ; the entry point
+            0x0000 symbol start
+            0 comment print the greeting ; then exit
+            0x0000 comment  twice
+            20h string 14
+            0x2e word 4     ; 8 bytes
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(annotations.symbols[&0], "start");
+        assert_eq!(annotations.comments[&0], "print the greeting ; then exit; twice");
+        assert_eq!(annotations.data[&0x20], DataRange { kind: DataKind::String, length: 14 });
+        assert_eq!(annotations.data_at(0x35), Some((0x2e, DataRange { kind: DataKind::Word, length: 8 })));
+        assert_eq!(annotations.data_at(0x36), None);
+        assert_eq!(annotations.data_at(0x1f), None);
+    }
+
+    #[test]
+    fn reports_errors_with_line_numbers() {
+        let error = |text| Annotations::parse(text).unwrap_err();
+        assert_eq!(error("\n0x10 label start"), AnnotationError { line: 2, message: "unknown annotation label".to_owned() });
+        assert_eq!(error("0x10 symbol 2nd").message, "invalid symbol name \"2nd\"");
+        assert_eq!(error("0x10 symbol DS").message, "invalid symbol name \"DS\"");
+        assert_eq!(error("1 symbol a\n2 symbol a").message, "symbol a is defined more than once");
+        assert_eq!(error("1 symbol a\n1 symbol b").message, "0x0001 is already called a");
+        assert_eq!(error("0x10 byte 4\n0x12 word 1").message, "data at 0x0012 overlaps another range");
+        assert_eq!(error("0x10 byte 4\n0x0c word 3").message, "data at 0x000c overlaps another range");
+        assert_eq!(error("0x10 word").message, "missing number");
+        assert_eq!(error("0x10 byte 2 3").message, "unexpected 3");
+        assert_eq!(error("start symbol main").message, "invalid number start");
+    }
+}
@@ -0,0 +1,1343 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+
+use crate::ops::*;
+
+/// Why a source line could not be assembled; `line` counts from 1
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssembleError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for AssembleError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+/// What a statement assembled to: an instruction, or bytes from `db`/`dw`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Item {
+    Instruction(Instruction),
+    Data(Vec<u8>),
+}
+
+impl Item {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Item::Instruction(instruction) => instruction.to_bytes(),
+            Item::Data(bytes) => bytes.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Statement {
+    pub address: usize,
+    pub line: usize,
+    pub item: Item,
+}
+
+#[derive(Debug, Clone)]
+pub struct Program {
+    /// The address set by `org`, where the first statement is placed
+    pub origin: usize,
+    pub statements: Vec<Statement>,
+}
+
+impl Program {
+    /// The flat binary, starting at `origin`
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.statements.iter().flat_map(|statement| statement.item.to_bytes()).collect()
+    }
+
+    pub fn instructions(&self) -> impl Iterator<Item = &Instruction> {
+        self.statements.iter().filter_map(|statement| match &statement.item {
+            Item::Instruction(instruction) => Some(instruction),
+            Item::Data(_) => None,
+        })
+    }
+}
+
+/// Assembles `bits 16` NASM syntax, as `printer` emits it, into a flat binary.
+///
+/// Supports labels (`.name` is local to the previous label), `byte`/`word`/`strict` size specifiers,
+/// `short`/`near`/`far` jumps, `[es:bx + si + 4]` style addressing and the `org`, `db`, `dw` and
+/// `times` directives, with `$` and `$$` in expressions. `bits` must be 16 and `cpu` is accepted
+/// but not checked. Where an instruction has several encodings the one NASM picks is used, except
+/// that a `jmp` to a later label is always near.
+pub fn assemble(source: &str) -> Result<Program, AssembleError> {
+    let lines: Vec<&str> = source.lines().collect();
+
+    // the first pass only finds the label addresses, the second has them all to encode with
+    let mut first = Pass::new(HashMap::new(), false);
+    first.run(&lines)?;
+    let mut second = Pass::new(first.labels, true);
+    second.run(&lines)?;
+
+    Ok(Program { origin: second.origin, statements: second.statements })
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Label {
+    address: usize,
+    /// How many labels were defined before this one, to tell backward references from forward ones
+    order: usize,
+}
+
+/// The value of an expression. `forward` is set when it refers to a label defined further on, which
+/// the first pass doesn't know yet; both passes then pick the encoding that fits any value.
+#[derive(Debug, Clone, Copy)]
+struct Value {
+    value: i64,
+    forward: bool,
+}
+
+impl Value {
+    fn known(value: i64) -> Value {
+        Value { value, forward: false }
+    }
+
+    fn combine(self, other: Value, op: fn(i64, i64) -> i64) -> Value {
+        Value { value: op(self.value, other.value), forward: self.forward || other.forward }
+    }
+
+    /// Whether the value can be encoded as a sign-extended byte, treating 0x8000..=0xFFFF as negative words
+    fn fits_sign_extended(&self) -> bool {
+        let value = if (0x8000..=0xFFFF).contains(&self.value) { self.value - 0x10000 } else { self.value };
+        !self.forward && (-128..=127).contains(&value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Distance {
+    Short,
+    Near,
+    Far,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Operand {
+    size: Option<OpWidth>,
+    strict: bool,
+    distance: Option<Distance>,
+    kind: OperandKind,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum OperandKind {
+    Reg(RegisterAccess),
+    SegReg(SegmentRegister),
+    Mem(EffectiveAddress),
+    Imm(Value),
+    Far { segment: Value, offset: Value },
+}
+
+impl Operand {
+    fn reg_or_mem(&self) -> Option<RegOrMem> {
+        match self.kind {
+            OperandKind::Reg(reg) => Some(RegOrMem::Reg(reg)),
+            OperandKind::Mem(mem) => Some(RegOrMem::Mem(mem)),
+            _ => None,
+        }
+    }
+
+    fn is_register(&self, reg: Register, width: OpWidth) -> bool {
+        matches!(self.kind, OperandKind::Reg(r) if r.reg == reg && r.width == width && r.offset == 0)
+    }
+}
+
+#[derive(Default)]
+struct Prefixes {
+    lock: bool,
+    repeat: Option<RepeatPrefix>,
+    segment: Option<SegmentRegister>,
+}
+
+struct Pass {
+    labels: HashMap<String, Label>,
+    final_pass: bool,
+    origin: usize,
+    address: usize,
+    line: usize,
+    labels_defined: usize,
+    /// The last label not starting with a dot, which local labels belong to
+    scope: String,
+    statements: Vec<Statement>,
+}
+
+impl Pass {
+    fn new(labels: HashMap<String, Label>, final_pass: bool) -> Pass {
+        Pass { labels, final_pass, origin: 0, address: 0, line: 0, labels_defined: 0, scope: String::new(), statements: vec![] }
+    }
+
+    fn run(&mut self, lines: &[&str]) -> Result<(), AssembleError> {
+        for (i, text) in lines.iter().enumerate() {
+            self.line = i + 1;
+            self.source_line(text).map_err(|message| AssembleError { line: self.line, message })?;
+        }
+        Ok(())
+    }
+
+    fn source_line(&mut self, text: &str) -> Result<(), String> {
+        let mut text = strip_comment(text).trim();
+
+        let name_length = text.find(|c: char| !is_identifier_char(c)).unwrap_or(text.len());
+        let (name, rest) = text.split_at(name_length);
+        if !name.is_empty() && rest.starts_with(':') && register(name).is_none() && segment_register(name).is_none() {
+            self.define_label(name, self.address)?;
+            text = rest[1..].trim();
+        }
+
+        let (word, value) = split_word(rest.trim_start());
+        if !name.is_empty() && word.eq_ignore_ascii_case("equ") {
+            let value = self.eval(value)?;
+            if value.forward || value.value < 0 {
+                return Err("equ needs a known value that isn't negative".to_owned());
+            }
+            return self.define_label(name, value.value as usize);
+        }
+
+        if text.is_empty() {
+            return Ok(());
+        }
+        self.statement(text)
+    }
+
+    fn define_label(&mut self, name: &str, address: usize) -> Result<(), String> {
+        if !name.starts_with(|c: char| c.is_alphabetic() || c == '_' || c == '.' || c == '?' || c == '@') {
+            return Err(format!("invalid label name {name}"));
+        }
+        let name = if name.starts_with('.') {
+            format!("{}{name}", self.scope)
+        } else {
+            self.scope = name.to_owned();
+            name.to_owned()
+        };
+
+        let label = Label { address, order: self.labels_defined };
+        self.labels_defined += 1;
+        if self.final_pass {
+            return Ok(());
+        }
+        if self.labels.insert(name.clone(), label).is_some() {
+            return Err(format!("label {name} is defined more than once"));
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, text: &str) -> Result<(), String> {
+        let (word, rest) = split_word(text);
+        match word.to_lowercase().as_str() {
+            "bits" => match self.eval(rest)?.value {
+                16 => Ok(()),
+                bits => Err(format!("only bits 16 is supported, not {bits}")),
+            },
+            "cpu" => Ok(()),
+            "org" => {
+                if !self.statements.is_empty() {
+                    return Err("org must come before any code or data".to_owned());
+                }
+                let origin = self.eval(rest)?;
+                if origin.forward || origin.value < 0 {
+                    return Err("org needs a known, positive address".to_owned());
+                }
+                self.origin = origin.value as usize;
+                self.address = self.origin;
+                Ok(())
+            }
+            "times" => {
+                let mut cursor = Cursor::new(rest);
+                let count = self.expression(&mut cursor)?;
+                if count.forward || count.value < 0 {
+                    return Err("times needs a known count that isn't negative".to_owned());
+                }
+                let repeated = cursor.rest().trim();
+                for _ in 0..count.value {
+                    self.statement(repeated)?;
+                }
+                Ok(())
+            }
+            "db" => {
+                let bytes = self.data(rest, OpWidth::Byte)?;
+                self.emit(Item::Data(bytes));
+                Ok(())
+            }
+            "dw" => {
+                let bytes = self.data(rest, OpWidth::Word)?;
+                self.emit(Item::Data(bytes));
+                Ok(())
+            }
+            _ => {
+                let instruction = self.instruction(text)?;
+                self.emit(Item::Instruction(instruction));
+                Ok(())
+            }
+        }
+    }
+
+    fn emit(&mut self, item: Item) {
+        let length = item.to_bytes().len();
+        self.statements.push(Statement { address: self.address, line: self.line, item });
+        self.address += length;
+    }
+
+    fn data(&self, text: &str, width: OpWidth) -> Result<Vec<u8>, String> {
+        let mut bytes = vec![];
+        for value in split_operands(text) {
+            if let Some(string) = quoted(value) {
+                bytes.extend(string.bytes());
+                // strings in dw are padded to a whole number of words
+                if width == OpWidth::Word && string.len() % 2 == 1 {
+                    bytes.push(0);
+                }
+                continue;
+            }
+            match width {
+                OpWidth::Byte => bytes.push(self.byte(self.eval(value)?)?),
+                OpWidth::Word => bytes.extend(self.word(self.eval(value)?)?.to_le_bytes()),
+            }
+        }
+        Ok(bytes)
+    }
+
+    fn instruction(&self, text: &str) -> Result<Instruction, String> {
+        let mut prefixes = Prefixes::default();
+        let (mut mnemonic, mut rest) = split_word(text);
+        loop {
+            match mnemonic.to_lowercase().as_str() {
+                "lock" => prefixes.lock = true,
+                "rep" | "repe" | "repz" => prefixes.repeat = Some(RepeatPrefix::Rep),
+                "repne" | "repnz" => prefixes.repeat = Some(RepeatPrefix::Repne),
+                word => match segment_register(word) {
+                    Some(segment) => prefixes.segment = Some(segment),
+                    None => break,
+                },
+            }
+            (mnemonic, rest) = split_word(rest);
+        }
+        if mnemonic.is_empty() {
+            return Err("prefix without an instruction".to_owned());
+        }
+
+        let operands = split_operands(rest).into_iter().map(|operand| self.operand(operand)).collect::<Result<Vec<_>, _>>()?;
+        let address = self.address + prefixes.lock as usize;
+        let mut instruction = self.build(&mnemonic.to_lowercase(), &operands, address)?;
+
+        if let Some(segment) = prefixes.segment {
+            if !instruction.set_segment_override(segment) {
+                return Err(format!("{segment} prefix on an instruction without a memory operand"));
+            }
+        }
+        if let Some(prefix) = prefixes.repeat {
+            match &mut instruction {
+                Instruction::StringManipulation { repeat, .. } => *repeat = Some(prefix),
+                _ => return Err("repeat prefix on an instruction that isn't a string instruction".to_owned()),
+            }
+        }
+        if prefixes.lock {
+            instruction = Instruction::Lock(Box::new(instruction));
+        }
+        Ok(instruction)
+    }
+
+    /// Builds the instruction for a mnemonic, `address` being where its opcode goes
+    fn build(&self, mnemonic: &str, operands: &[Operand], address: usize) -> Result<Instruction, String> {
+        use OperandKind::*;
+
+        if let Some(instruction) = no_operands(mnemonic) {
+            return match operands {
+                [] => Ok(instruction),
+                _ => Err(format!("{mnemonic} takes no operands")),
+            };
+        }
+        if let Some(jump) = short_jump(mnemonic) {
+            return match operands {
+                [target] => Ok(jump(self.short_displacement(target, address + 2)?)),
+                _ => Err(format!("{mnemonic} takes a jump target")),
+            };
+        }
+
+        let invalid = || format!("invalid operands for {mnemonic}");
+        let instruction = match (mnemonic, operands) {
+            ("mov", [a, b]) => self.mov(a, b)?,
+            ("add" | "or" | "adc" | "sbb" | "and" | "sub" | "xor" | "cmp", [a, b]) => self.arithmetic(arithmetic_op(mnemonic), a, b)?,
+            ("test", [a, b]) => {
+                let width = width(&[a, b])?;
+                match (a.kind, b.kind) {
+                    (Reg(a), Reg(b)) => Instruction::TestRegMemWithReg { reg: b, reg_or_mem: RegOrMem::Reg(a) },
+                    (Mem(mem), Reg(reg)) | (Reg(reg), Mem(mem)) => Instruction::TestRegMemWithReg { reg, reg_or_mem: RegOrMem::Mem(mem) },
+                    (Reg(reg), Imm(data)) if is_accumulator(reg) => {
+                        Instruction::TestImmediateWithAccumulator { width, data: self.immediate(data, width)? }
+                    }
+                    (Reg(_) | Mem(_), Imm(data)) => Instruction::TestImmediateWithRegMem {
+                        width,
+                        data: self.immediate(data, width)?,
+                        reg_or_mem: a.reg_or_mem().unwrap(),
+                    },
+                    _ => return Err(invalid()),
+                }
+            }
+            ("xchg", [a, b]) => {
+                width(&[a, b])?;
+                match (a.kind, b.kind) {
+                    (Reg(reg), Reg(_)) if b.is_register(Register::A, OpWidth::Word) => Instruction::ExchangeRegWithAccumulator { reg },
+                    (Reg(_), Reg(reg)) if a.is_register(Register::A, OpWidth::Word) => Instruction::ExchangeRegWithAccumulator { reg },
+                    (Reg(reg), Reg(_) | Mem(_)) => Instruction::ExchangeRegMemWithReg { reg, reg_or_mem: b.reg_or_mem().unwrap() },
+                    (Mem(mem), Reg(reg)) => Instruction::ExchangeRegMemWithReg { reg, reg_or_mem: RegOrMem::Mem(mem) },
+                    _ => return Err(invalid()),
+                }
+            }
+            ("inc" | "dec", [a]) => {
+                let increment = mnemonic == "inc";
+                match a.kind {
+                    Reg(reg) if reg.width == OpWidth::Word && increment => Instruction::IncrementReg { reg },
+                    Reg(reg) if reg.width == OpWidth::Word => Instruction::DecrementReg { reg },
+                    Reg(_) | Mem(_) => {
+                        let (width, reg_or_mem) = (width(&[a])?, a.reg_or_mem().unwrap());
+                        if increment {
+                            Instruction::IncrementRegMem { width, reg_or_mem }
+                        } else {
+                            Instruction::DecrementRegMem { width, reg_or_mem }
+                        }
+                    }
+                    _ => return Err(invalid()),
+                }
+            }
+            ("push", [a]) => match a.kind {
+                Reg(reg) if reg.width == OpWidth::Word => Instruction::PushReg { reg },
+                SegReg(seg_reg) => Instruction::PushSegmentRegister { seg_reg },
+                Mem(_) if width(&[a])? == OpWidth::Word => Instruction::PushRegMem { reg_or_mem: a.reg_or_mem().unwrap() },
+                Imm(data) => Instruction::PushImmediate { data: self.word_or_sign_extended(a, data)? },
+                _ => return Err(invalid()),
+            },
+            ("pop", [a]) => match a.kind {
+                Reg(reg) if reg.width == OpWidth::Word => Instruction::PopReg { reg },
+                SegReg(seg_reg) => Instruction::PopSegmentRegister { seg_reg },
+                Mem(_) if width(&[a])? == OpWidth::Word => Instruction::PopRegMem { reg_or_mem: a.reg_or_mem().unwrap() },
+                _ => return Err(invalid()),
+            },
+            ("not" | "neg" | "mul" | "imul" | "div" | "idiv", [a]) => {
+                let (width, reg_or_mem) = (width(&[a])?, a.reg_or_mem().ok_or_else(invalid)?);
+                match mnemonic {
+                    "not" => Instruction::Not { width, reg_or_mem },
+                    "neg" => Instruction::Negate { width, reg_or_mem },
+                    "mul" => Instruction::MultiplyDivide { op: MultiplyDivideOp::Mul, width, reg_or_mem },
+                    "imul" => Instruction::MultiplyDivide { op: MultiplyDivideOp::Imul, width, reg_or_mem },
+                    "div" => Instruction::MultiplyDivide { op: MultiplyDivideOp::Div, width, reg_or_mem },
+                    _ => Instruction::MultiplyDivide { op: MultiplyDivideOp::Idiv, width, reg_or_mem },
+                }
+            }
+            ("imul", [a, b]) | ("imul", [a, _, b]) => {
+                let reg_or_mem = operands[operands.len() - 2].reg_or_mem().ok_or_else(invalid)?;
+                match (a.kind, b.kind) {
+                    (Reg(reg), Imm(data)) if reg.width == OpWidth::Word => {
+                        Instruction::MultiplyImmediate { reg, reg_or_mem, data: self.word_or_sign_extended(b, data)? }
+                    }
+                    _ => return Err(invalid()),
+                }
+            }
+            ("rol" | "ror" | "rcl" | "rcr" | "shl" | "sal" | "shr" | "sar", [a, count]) => {
+                let count = match count.kind {
+                    Imm(Value { value: 1, forward: false }) if !count.strict => ShiftCount::One,
+                    Reg(reg) if reg.reg == Register::C && reg.width == OpWidth::Byte && reg.offset == 0 => ShiftCount::Cl,
+                    Imm(count) => ShiftCount::Immediate(self.byte(count)?),
+                    _ => return Err(invalid()),
+                };
+                Instruction::Shift { op: shift_op(mnemonic), width: width(&[a])?, count, reg_or_mem: a.reg_or_mem().ok_or_else(invalid)? }
+            }
+            ("lea" | "lds" | "les" | "bound", [a, b]) => match (a.kind, b.kind) {
+                (Reg(reg), Mem(mem)) if reg.width == OpWidth::Word => match mnemonic {
+                    "lea" => Instruction::LoadEffectiveAddress { reg, mem },
+                    "lds" => Instruction::LoadPointerToDs { reg, mem },
+                    "les" => Instruction::LoadPointerToEs { reg, mem },
+                    _ => Instruction::Bound { reg, mem },
+                },
+                _ => return Err(invalid()),
+            },
+            ("in", [a, b]) => match (a.kind, b.kind) {
+                (Reg(reg), Imm(port)) if is_accumulator(reg) => Instruction::InFixedPort { width: reg.width, port: self.byte(port)? },
+                (Reg(reg), Reg(_)) if is_accumulator(reg) && b.is_register(Register::D, OpWidth::Word) => {
+                    Instruction::InVariablePort { width: reg.width }
+                }
+                _ => return Err(invalid()),
+            },
+            ("out", [a, b]) => match (a.kind, b.kind) {
+                (Imm(port), Reg(reg)) if is_accumulator(reg) => Instruction::OutFixedPort { width: reg.width, port: self.byte(port)? },
+                (Reg(_), Reg(reg)) if is_accumulator(reg) && a.is_register(Register::D, OpWidth::Word) => {
+                    Instruction::OutVariablePort { width: reg.width }
+                }
+                _ => return Err(invalid()),
+            },
+            ("jmp", [target]) => match (target.kind, target.distance) {
+                (Imm(_), Some(Distance::Short)) => Instruction::JumpShort(self.short_displacement(target, address + 2)?),
+                (Imm(_), Some(Distance::Near)) => Instruction::JumpDirect(self.near_displacement(target, address + 3)?),
+                // a backward jump is known in both passes, so it can be short without changing size between them
+                (Imm(value), None) if !value.forward && (-128..=127).contains(&(value.value - address as i64 - 2)) => {
+                    Instruction::JumpShort(self.short_displacement(target, address + 2)?)
+                }
+                (Imm(_), None) => Instruction::JumpDirect(self.near_displacement(target, address + 3)?),
+                (Far { segment, offset }, _) => Instruction::JumpFarDirect { offset: self.word(offset)?, segment: self.word(segment)? },
+                (Mem(mem), Some(Distance::Far)) => Instruction::JumpFarIndirect { mem },
+                (Reg(_) | Mem(_), None | Some(Distance::Near)) if width(&[target])? == OpWidth::Word => {
+                    Instruction::JumpIndirect { reg_or_mem: target.reg_or_mem().unwrap() }
+                }
+                _ => return Err(invalid()),
+            },
+            ("call", [target]) => match (target.kind, target.distance) {
+                (Imm(_), None | Some(Distance::Near)) => Instruction::CallDirect(self.near_displacement(target, address + 3)?),
+                (Far { segment, offset }, _) => Instruction::CallFarDirect { offset: self.word(offset)?, segment: self.word(segment)? },
+                (Mem(mem), Some(Distance::Far)) => Instruction::CallFarIndirect { mem },
+                (Reg(_) | Mem(_), None | Some(Distance::Near)) if width(&[target])? == OpWidth::Word => {
+                    Instruction::CallIndirect { reg_or_mem: target.reg_or_mem().unwrap() }
+                }
+                _ => return Err(invalid()),
+            },
+            ("ret", []) => Instruction::Return { pop: None },
+            ("ret", [Operand { kind: Imm(pop), .. }]) => Instruction::Return { pop: Some(self.word(*pop)?) },
+            ("retf", []) => Instruction::ReturnFar { pop: None },
+            ("retf", [Operand { kind: Imm(pop), .. }]) => Instruction::ReturnFar { pop: Some(self.word(*pop)?) },
+            ("int", [Operand { kind: Imm(vector), .. }]) => Instruction::Interrupt(self.byte(*vector)?),
+            ("aam", []) => Instruction::AsciiAdjustMultiply { base: 10 },
+            ("aam", [Operand { kind: Imm(base), .. }]) => Instruction::AsciiAdjustMultiply { base: self.byte(*base)? },
+            ("aad", []) => Instruction::AsciiAdjustDivide { base: 10 },
+            ("aad", [Operand { kind: Imm(base), .. }]) => Instruction::AsciiAdjustDivide { base: self.byte(*base)? },
+            ("enter", [Operand { kind: Imm(size), .. }, Operand { kind: Imm(level), .. }]) => {
+                Instruction::Enter { size: Imm16(self.word(*size)?), level: Imm8(self.byte(*level)?) }
+            }
+            ("esc", [Operand { kind: Imm(opcode), .. }, operand]) => match (opcode.value, operand.reg_or_mem()) {
+                (0..=63, Some(reg_or_mem)) => Instruction::Escape { opcode: opcode.value as u8, reg_or_mem },
+                _ => return Err(invalid()),
+            },
+            _ => match string_op(mnemonic) {
+                Some((op, width)) if operands.is_empty() => Instruction::StringManipulation { op, width, repeat: None, segment: None },
+                _ if is_mnemonic(mnemonic) => return Err(invalid()),
+                _ => return Err(format!("unknown instruction {mnemonic}")),
+            },
+        };
+        Ok(instruction)
+    }
+
+    fn mov(&self, a: &Operand, b: &Operand) -> Result<Instruction, String> {
+        use OperandKind::*;
+
+        Ok(match (a.kind, b.kind) {
+            (SegReg(seg_reg), Reg(_) | Mem(_)) if width(&[b]).unwrap_or(OpWidth::Word) == OpWidth::Word => {
+                Instruction::SegmentRegisterMove { dir: Direction::ToRegister, seg_reg, reg_or_mem: b.reg_or_mem().unwrap() }
+            }
+            (Reg(_) | Mem(_), SegReg(seg_reg)) if width(&[a]).unwrap_or(OpWidth::Word) == OpWidth::Word => {
+                Instruction::SegmentRegisterMove { dir: Direction::FromRegister, seg_reg, reg_or_mem: a.reg_or_mem().unwrap() }
+            }
+            (Reg(reg), Mem(mem)) if is_accumulator(reg) && is_direct(&mem) => {
+                width(&[a, b])?;
+                Instruction::AccumulatorMove { dir: Direction::ToRegister, width: reg.width, addr: direct_address(&mem), segment: mem.segment }
+            }
+            (Mem(mem), Reg(reg)) if is_accumulator(reg) && is_direct(&mem) => {
+                width(&[a, b])?;
+                Instruction::AccumulatorMove { dir: Direction::FromRegister, width: reg.width, addr: direct_address(&mem), segment: mem.segment }
+            }
+            (Reg(_) | Mem(_), Reg(reg)) => {
+                width(&[a, b])?;
+                Instruction::MovToFromRegMem { dir: Direction::FromRegister, reg, reg_or_mem: a.reg_or_mem().unwrap() }
+            }
+            (Reg(reg), Mem(mem)) => {
+                width(&[a, b])?;
+                Instruction::MovToFromRegMem { dir: Direction::ToRegister, reg, reg_or_mem: RegOrMem::Mem(mem) }
+            }
+            (Reg(reg), Imm(data)) => {
+                let width = width(&[a, b])?;
+                Instruction::ImmediateMovReg { reg, data: self.immediate(data, width)? }
+            }
+            (Mem(mem), Imm(data)) => {
+                let width = width(&[a, b])?;
+                Instruction::ImmediateMovRegMem { width, reg_or_mem: RegOrMem::Mem(mem), data: self.immediate(data, width)? }
+            }
+            _ => return Err("invalid operands for mov".to_owned()),
+        })
+    }
+
+    fn arithmetic(&self, op: ArithmeticOp, a: &Operand, b: &Operand) -> Result<Instruction, String> {
+        use OperandKind::*;
+
+        let width = width(&[a, b])?;
+        Ok(match (a.kind, b.kind) {
+            (Reg(_) | Mem(_), Reg(reg)) => {
+                Instruction::ArithmeticFromToRegMem { op, dir: Direction::FromRegister, width, reg, reg_or_mem: a.reg_or_mem().unwrap() }
+            }
+            (Reg(reg), Mem(mem)) => Instruction::ArithmeticFromToRegMem { op, dir: Direction::ToRegister, width, reg, reg_or_mem: RegOrMem::Mem(mem) },
+            // the accumulator has a shorter encoding, unless the immediate fits in a sign-extended byte
+            (Reg(reg), Imm(data)) if is_accumulator(reg) && (width == OpWidth::Byte || b.strict || !data.fits_sign_extended()) => {
+                Instruction::ArithmeticImmediateToAccumulator { op, width, data: self.immediate(data, width)? }
+            }
+            (Reg(_) | Mem(_), Imm(data)) => {
+                let data = match width {
+                    OpWidth::Byte => self.immediate(data, width)?,
+                    OpWidth::Word => self.word_or_sign_extended(b, data)?,
+                };
+                Instruction::ArithmeticImmediateToRegMem { op, width, data, reg_or_mem: a.reg_or_mem().unwrap() }
+            }
+            _ => return Err(format!("invalid operands for {op}")),
+        })
+    }
+
+    fn operand(&self, text: &str) -> Result<Operand, String> {
+        let mut operand = Operand { size: None, strict: false, distance: None, kind: OperandKind::Imm(Value::known(0)) };
+
+        let mut text = text.trim();
+        loop {
+            let (word, rest) = split_word(text);
+            match word.to_lowercase().as_str() {
+                "byte" => operand.size = Some(OpWidth::Byte),
+                "word" => operand.size = Some(OpWidth::Word),
+                "strict" => operand.strict = true,
+                "short" => operand.distance = Some(Distance::Short),
+                "near" => operand.distance = Some(Distance::Near),
+                "far" => operand.distance = Some(Distance::Far),
+                _ => break,
+            }
+            text = rest;
+        }
+
+        let lower = text.to_lowercase();
+        operand.kind = if let Some(reg) = register(&lower) {
+            OperandKind::Reg(reg)
+        } else if let Some(seg_reg) = segment_register(&lower) {
+            OperandKind::SegReg(seg_reg)
+        } else if let Some(open) = text.find('[') {
+            // the segment can be written before the brackets, as printer does, or inside them
+            let segment = match text[..open].trim().strip_suffix(':') {
+                Some(segment) => Some(segment_register(&segment.trim().to_lowercase()).ok_or_else(|| format!("{segment} is not a segment register"))?),
+                None if text[..open].trim().is_empty() => None,
+                None => return Err(format!("unexpected {} before memory operand", &text[..open])),
+            };
+            let inner = text[open + 1..].strip_suffix(']').ok_or_else(|| format!("missing ] in {text}"))?;
+            OperandKind::Mem(self.memory(inner, segment)?)
+        } else if let Some((segment, offset)) = split_top_level(text, ':') {
+            OperandKind::Far { segment: self.eval(segment)?, offset: self.eval(offset)? }
+        } else {
+            OperandKind::Imm(self.eval(text)?)
+        };
+        Ok(operand)
+    }
+
+    fn memory(&self, inner: &str, mut segment: Option<SegmentRegister>) -> Result<EffectiveAddress, String> {
+        let (word, rest) = split_word(inner.trim());
+        let (forced, mut inner) = match word.to_lowercase().as_str() {
+            "byte" => (Some(OpWidth::Byte), rest),
+            "word" => (Some(OpWidth::Word), rest),
+            _ => (None, inner.trim()),
+        };
+        if let Some((prefix, rest)) = inner.split_once(':') {
+            if let Some(seg_reg) = segment_register(&prefix.trim().to_lowercase()) {
+                segment = Some(seg_reg);
+                inner = rest;
+            }
+        }
+
+        let mut registers = vec![];
+        let mut displacement: Option<Value> = None;
+        for (negative, term) in split_terms(inner) {
+            match register(&term.to_lowercase()) {
+                Some(reg) if !negative && reg.width == OpWidth::Word => registers.push(reg.reg),
+                Some(_) => return Err(format!("{term} can't be used in an address")),
+                None => {
+                    let value = self.eval(term)?;
+                    let value = if negative { Value { value: -value.value, ..value } } else { value };
+                    displacement = Some(match displacement {
+                        Some(sum) => sum.combine(value, |a, b| a + b),
+                        None => value,
+                    });
+                }
+            }
+        }
+
+        registers.sort_by_key(|reg| *reg as u8);
+        let base = match registers[..] {
+            [] => EffectiveAddressBase::Direct,
+            [Register::B, Register::Si] => EffectiveAddressBase::BxPlusSi,
+            [Register::B, Register::Di] => EffectiveAddressBase::BxPlusDi,
+            [Register::Bp, Register::Si] => EffectiveAddressBase::BpPlusSi,
+            [Register::Bp, Register::Di] => EffectiveAddressBase::BpPlusDi,
+            [Register::Si] => EffectiveAddressBase::Si,
+            [Register::Di] => EffectiveAddressBase::Di,
+            [Register::Bp] => EffectiveAddressBase::Bp,
+            [Register::B] => EffectiveAddressBase::Bx,
+            _ => return Err(format!("invalid address [{inner}]")),
+        };
+
+        let displacement = match (base, displacement, forced) {
+            (EffectiveAddressBase::Direct, None, _) => return Err("empty address".to_owned()),
+            (EffectiveAddressBase::Direct, Some(address), _) => Displacement::Disp16(self.word(address)? as i16),
+            (_, Some(value), Some(OpWidth::Byte)) => Displacement::Disp8(self.signed_byte(value)?),
+            (_, Some(value), Some(OpWidth::Word)) => Displacement::Disp16(self.word(value)? as i16),
+            (EffectiveAddressBase::Bp, None, _) => Displacement::Disp8(0),
+            (_, None, _) => Displacement::None,
+            (base, Some(value), None) if !value.forward && value.value == 0 && base != EffectiveAddressBase::Bp => Displacement::None,
+            (_, Some(value), None) if value.fits_sign_extended() && (-128..=127).contains(&value.value) => Displacement::Disp8(value.value as i8),
+            (_, Some(value), None) => Displacement::Disp16(self.word(value)? as i16),
+        };
+
+        Ok(EffectiveAddress { base, displacement, segment })
+    }
+
+    fn immediate(&self, value: Value, width: OpWidth) -> Result<Immediate, String> {
+        Ok(match width {
+            OpWidth::Byte => Immediate::Byte(Imm8(self.byte(value)?)),
+            OpWidth::Word => Immediate::Word(Imm16(self.word(value)?)),
+        })
+    }
+
+    /// A word immediate, encoded as a sign-extended byte where NASM would, unless it's `strict`
+    fn word_or_sign_extended(&self, operand: &Operand, value: Value) -> Result<Immediate, String> {
+        if operand.size == Some(OpWidth::Byte) {
+            return Err("the immediate needs to be a word".to_owned());
+        }
+        if !operand.strict && value.fits_sign_extended() {
+            Ok(Immediate::SignExtended(Imm8(value.value as u8)))
+        } else {
+            Ok(Immediate::Word(Imm16(self.word(value)?)))
+        }
+    }
+
+    fn byte(&self, value: Value) -> Result<u8, String> {
+        match value.value {
+            -128..=255 => Ok(value.value as u8),
+            _ => Err(format!("{} doesn't fit in a byte", value.value)),
+        }
+    }
+
+    fn signed_byte(&self, value: Value) -> Result<i8, String> {
+        i8::try_from(value.value).map_err(|_| format!("{} doesn't fit in a signed byte", value.value))
+    }
+
+    fn word(&self, value: Value) -> Result<u16, String> {
+        match value.value {
+            -32768..=65535 => Ok(value.value as u16),
+            _ => Err(format!("{} doesn't fit in a word", value.value)),
+        }
+    }
+
+    fn jump_target(&self, target: &Operand) -> Result<Value, String> {
+        match target.kind {
+            OperandKind::Imm(value) => Ok(value),
+            _ => Err("expected a jump target".to_owned()),
+        }
+    }
+
+    fn short_displacement(&self, target: &Operand, next: usize) -> Result<i8, String> {
+        let target = self.jump_target(target)?;
+        if !self.final_pass {
+            return Ok(0);
+        }
+        let displacement = target.value - next as i64;
+        i8::try_from(displacement).map_err(|_| {
+            let by = if displacement > 0 { displacement - 127 } else { -128 - displacement };
+            format!("short jump to {:#x} is out of range by {by} bytes", target.value)
+        })
+    }
+
+    /// Near jumps wrap around within the 64K code segment
+    fn near_displacement(&self, target: &Operand, next: usize) -> Result<i16, String> {
+        let target = self.jump_target(target)?;
+        Ok((target.value - next as i64) as u16 as i16)
+    }
+
+    fn eval(&self, text: &str) -> Result<Value, String> {
+        let mut cursor = Cursor::new(text);
+        let value = self.expression(&mut cursor)?;
+        if !cursor.rest().trim().is_empty() {
+            return Err(format!("unexpected {} in expression", cursor.rest().trim()));
+        }
+        Ok(value)
+    }
+
+    fn expression(&self, cursor: &mut Cursor) -> Result<Value, String> {
+        let mut value = self.term(cursor)?;
+        loop {
+            match cursor.peek() {
+                Some('+') => {
+                    cursor.bump();
+                    value = value.combine(self.term(cursor)?, |a, b| a + b);
+                }
+                Some('-') => {
+                    cursor.bump();
+                    value = value.combine(self.term(cursor)?, |a, b| a - b);
+                }
+                _ => return Ok(value),
+            }
+        }
+    }
+
+    fn term(&self, cursor: &mut Cursor) -> Result<Value, String> {
+        let mut value = self.unary(cursor)?;
+        loop {
+            let op = match cursor.peek() {
+                Some(op @ ('*' | '/' | '%')) => op,
+                _ => return Ok(value),
+            };
+            cursor.bump();
+            let other = self.unary(cursor)?;
+            if op != '*' && other.value == 0 && !other.forward {
+                return Err("division by zero".to_owned());
+            }
+            value = match op {
+                '*' => value.combine(other, |a, b| a * b),
+                '/' => value.combine(other, |a, b| a.checked_div(b).unwrap_or(0)),
+                _ => value.combine(other, |a, b| a.checked_rem(b).unwrap_or(0)),
+            };
+        }
+    }
+
+    fn unary(&self, cursor: &mut Cursor) -> Result<Value, String> {
+        match cursor.peek() {
+            Some('-') => {
+                cursor.bump();
+                let value = self.unary(cursor)?;
+                Ok(Value { value: -value.value, ..value })
+            }
+            Some('+') => {
+                cursor.bump();
+                self.unary(cursor)
+            }
+            Some('~') => {
+                cursor.bump();
+                let value = self.unary(cursor)?;
+                Ok(Value { value: !value.value, ..value })
+            }
+            _ => self.primary(cursor),
+        }
+    }
+
+    fn primary(&self, cursor: &mut Cursor) -> Result<Value, String> {
+        match cursor.peek() {
+            Some('(') => {
+                cursor.bump();
+                let value = self.expression(cursor)?;
+                match cursor.peek() {
+                    Some(')') => {
+                        cursor.bump();
+                        Ok(value)
+                    }
+                    _ => Err("missing )".to_owned()),
+                }
+            }
+            Some('$') => {
+                cursor.bump();
+                if cursor.rest().starts_with('$') {
+                    cursor.bump();
+                    return Ok(Value::known(self.origin as i64));
+                }
+                Ok(Value::known(self.address as i64))
+            }
+            Some(quote @ ('\'' | '"')) => {
+                cursor.bump();
+                let end = cursor.rest().find(quote).ok_or("unterminated character constant")?;
+                let chars = &cursor.rest()[..end];
+                if chars.is_empty() || chars.len() > 8 {
+                    return Err(format!("invalid character constant {quote}{chars}{quote}"));
+                }
+                let value = chars.bytes().rev().fold(0i64, |value, byte| value << 8 | byte as i64);
+                cursor.advance(end + 1);
+                Ok(Value::known(value))
+            }
+            Some(c) if c.is_ascii_digit() => {
+                let literal = cursor.take_while(|c| c.is_ascii_alphanumeric() || c == '_');
+                number(literal).map(Value::known).ok_or_else(|| format!("invalid number {literal}"))
+            }
+            Some(c) if is_identifier_char(c) => {
+                let name = cursor.take_while(is_identifier_char);
+                self.label(name)
+            }
+            Some(c) => Err(format!("unexpected {c} in expression")),
+            None => Err("missing value".to_owned()),
+        }
+    }
+
+    fn label(&self, name: &str) -> Result<Value, String> {
+        if register(&name.to_lowercase()).is_some() || segment_register(&name.to_lowercase()).is_some() {
+            return Err(format!("{name} can't be used here"));
+        }
+        let full_name = if name.starts_with('.') { format!("{}{name}", self.scope) } else { name.to_owned() };
+
+        match self.labels.get(&full_name) {
+            // in the first pass only the labels seen so far are in the table
+            Some(label) => Ok(Value { value: label.address as i64, forward: label.order >= self.labels_defined }),
+            None if !self.final_pass => Ok(Value { value: 0, forward: true }),
+            None => Err(format!("undefined label {name}")),
+        }
+    }
+}
+
+struct Cursor<'a> {
+    text: &'a str,
+    position: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(text: &'a str) -> Cursor<'a> {
+        Cursor { text, position: 0 }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.text[self.position..]
+    }
+
+    /// The next character that isn't whitespace, without consuming it
+    fn peek(&mut self) -> Option<char> {
+        let rest = self.rest();
+        self.position += rest.len() - rest.trim_start().len();
+        self.rest().chars().next()
+    }
+
+    fn bump(&mut self) {
+        self.advance(self.rest().chars().next().map_or(0, char::len_utf8));
+    }
+
+    fn advance(&mut self, bytes: usize) {
+        self.position += bytes;
+    }
+
+    fn take_while(&mut self, predicate: impl Fn(char) -> bool) -> &'a str {
+        let rest = self.rest();
+        let end = rest.find(|c| !predicate(c)).unwrap_or(rest.len());
+        self.position += end;
+        &rest[..end]
+    }
+}
+
+fn no_operands(mnemonic: &str) -> Option<Instruction> {
+    Some(match mnemonic {
+        "xlat" | "xlatb" => Instruction::TranslateByte { segment: None },
+        "lahf" => Instruction::LoadAhFromFlags,
+        "sahf" => Instruction::StoreAhIntoFlags,
+        "pushf" => Instruction::PushFlags,
+        "popf" => Instruction::PopFlags,
+        "int3" => Instruction::Interrupt3,
+        "into" => Instruction::InterruptOnOverflow,
+        "iret" => Instruction::InterruptReturn,
+        "clc" => Instruction::ClearCarry,
+        "cmc" => Instruction::ComplementCarry,
+        "stc" => Instruction::SetCarry,
+        "cld" => Instruction::ClearDirection,
+        "std" => Instruction::SetDirection,
+        "cli" => Instruction::ClearInterrupt,
+        "sti" => Instruction::SetInterrupt,
+        "hlt" => Instruction::Halt,
+        "wait" | "fwait" => Instruction::Wait,
+        "nop" => Instruction::Nop,
+        "daa" => Instruction::Adjust(AdjustOp::Daa),
+        "das" => Instruction::Adjust(AdjustOp::Das),
+        "aaa" => Instruction::Adjust(AdjustOp::Aaa),
+        "aas" => Instruction::Adjust(AdjustOp::Aas),
+        "cbw" => Instruction::ConvertByteToWord,
+        "cwd" => Instruction::ConvertWordToDouble,
+        "salc" => Instruction::SetAlFromCarry,
+        "pusha" => Instruction::PushAll,
+        "popa" => Instruction::PopAll,
+        "leave" => Instruction::Leave,
+        _ => return None,
+    })
+}
+
+fn short_jump(mnemonic: &str) -> Option<fn(i8) -> Instruction> {
+    Some(match mnemonic {
+        "jo" => Instruction::JumpOnOverflow,
+        "jno" => Instruction::JumpOnNoOverflow,
+        "jb" | "jc" | "jnae" => Instruction::JumpOnBelow,
+        "jnb" | "jnc" | "jae" => Instruction::JumpOnNotBelow,
+        "je" | "jz" => Instruction::JumpOnEqual,
+        "jne" | "jnz" => Instruction::JumpOnNotEqual,
+        "jbe" | "jna" => Instruction::JumpOnNotAbove,
+        "ja" | "jnbe" => Instruction::JumpOnAbove,
+        "js" => Instruction::JumpOnSign,
+        "jns" => Instruction::JumpOnNotSign,
+        "jp" | "jpe" => Instruction::JumpOnParity,
+        "jnp" | "jpo" => Instruction::JumpOnNoParity,
+        "jl" | "jnge" => Instruction::JumpOnLess,
+        "jnl" | "jge" => Instruction::JumpOnNotLess,
+        "jle" | "jng" => Instruction::JumpOnNotGreater,
+        "jg" | "jnle" => Instruction::JumpOnGreater,
+        "loop" => Instruction::Loop,
+        "loope" | "loopz" => Instruction::LoopWhileEqual,
+        "loopne" | "loopnz" => Instruction::LoopWhileNotEqual,
+        "jcxz" => Instruction::JumpOnCxZero,
+        _ => return None,
+    })
+}
+
+fn string_op(mnemonic: &str) -> Option<(StringOp, OpWidth)> {
+    let (op, width) = mnemonic.split_at(mnemonic.len().checked_sub(1)?);
+    let width = match width {
+        "b" => OpWidth::Byte,
+        "w" => OpWidth::Word,
+        _ => return None,
+    };
+    let op = match op {
+        "movs" => StringOp::Movs,
+        "cmps" => StringOp::Cmps,
+        "scas" => StringOp::Scas,
+        "lods" => StringOp::Lods,
+        "stos" => StringOp::Stos,
+        "ins" => StringOp::Ins,
+        "outs" => StringOp::Outs,
+        _ => return None,
+    };
+    Some((op, width))
+}
+
+/// Mnemonics handled in `Pass::build` that take operands, so a bad operand isn't reported as an unknown instruction
+fn is_mnemonic(mnemonic: &str) -> bool {
+    const MNEMONICS: [&str; 44] = [
+        "mov", "add", "or", "adc", "sbb", "and", "sub", "xor", "cmp", "test", "xchg", "inc", "dec", "push", "pop", "not", "neg", "mul",
+        "imul", "div", "idiv", "rol", "ror", "rcl", "rcr", "shl", "sal", "shr", "sar", "lea", "lds", "les", "bound", "in", "out", "jmp",
+        "call", "ret", "retf", "int", "aam", "aad", "enter", "esc",
+    ];
+    MNEMONICS.contains(&mnemonic) || string_op(mnemonic).is_some()
+}
+
+fn arithmetic_op(mnemonic: &str) -> ArithmeticOp {
+    match mnemonic {
+        "add" => ArithmeticOp::Add,
+        "or" => ArithmeticOp::Or,
+        "adc" => ArithmeticOp::Adc,
+        "sbb" => ArithmeticOp::Sbb,
+        "and" => ArithmeticOp::And,
+        "sub" => ArithmeticOp::Sub,
+        "xor" => ArithmeticOp::Xor,
+        _ => ArithmeticOp::Cmp,
+    }
+}
+
+fn shift_op(mnemonic: &str) -> ShiftOp {
+    match mnemonic {
+        "rol" => ShiftOp::Rol,
+        "ror" => ShiftOp::Ror,
+        "rcl" => ShiftOp::Rcl,
+        "rcr" => ShiftOp::Rcr,
+        "shl" | "sal" => ShiftOp::Shl,
+        "shr" => ShiftOp::Shr,
+        _ => ShiftOp::Sar,
+    }
+}
+
+fn register(name: &str) -> Option<RegisterAccess> {
+    let (reg, width, offset) = match name {
+        "al" => (Register::A, OpWidth::Byte, 0),
+        "cl" => (Register::C, OpWidth::Byte, 0),
+        "dl" => (Register::D, OpWidth::Byte, 0),
+        "bl" => (Register::B, OpWidth::Byte, 0),
+        "ah" => (Register::A, OpWidth::Byte, 1),
+        "ch" => (Register::C, OpWidth::Byte, 1),
+        "dh" => (Register::D, OpWidth::Byte, 1),
+        "bh" => (Register::B, OpWidth::Byte, 1),
+        "ax" => (Register::A, OpWidth::Word, 0),
+        "cx" => (Register::C, OpWidth::Word, 0),
+        "dx" => (Register::D, OpWidth::Word, 0),
+        "bx" => (Register::B, OpWidth::Word, 0),
+        "sp" => (Register::Sp, OpWidth::Word, 0),
+        "bp" => (Register::Bp, OpWidth::Word, 0),
+        "si" => (Register::Si, OpWidth::Word, 0),
+        "di" => (Register::Di, OpWidth::Word, 0),
+        _ => return None,
+    };
+    Some(RegisterAccess::new(reg, width, offset))
+}
+
+fn segment_register(name: &str) -> Option<SegmentRegister> {
+    match name {
+        "es" => Some(SegmentRegister::Es),
+        "cs" => Some(SegmentRegister::Cs),
+        "ss" => Some(SegmentRegister::Ss),
+        "ds" => Some(SegmentRegister::Ds),
+        _ => None,
+    }
+}
+
+/// The operand size, from a register or a size specifier; all operands that have one must agree
+fn width(operands: &[&Operand]) -> Result<OpWidth, String> {
+    let mut width = None;
+    for operand in operands {
+        let operand_width = match operand.kind {
+            OperandKind::Reg(reg) => Some(reg.width),
+            OperandKind::SegReg(_) => Some(OpWidth::Word),
+            _ => operand.size,
+        };
+        match (width, operand_width) {
+            (Some(width), Some(operand_width)) if width != operand_width => return Err("mismatch in operand sizes".to_owned()),
+            (None, operand_width) => width = operand_width,
+            _ => {}
+        }
+    }
+    width.ok_or_else(|| "operation size not specified".to_owned())
+}
+
+fn is_accumulator(reg: RegisterAccess) -> bool {
+    reg.reg == Register::A && reg.offset == 0
+}
+
+fn is_direct(mem: &EffectiveAddress) -> bool {
+    mem.base == EffectiveAddressBase::Direct
+}
+
+fn direct_address(mem: &EffectiveAddress) -> Addr16 {
+    Addr16(mem.displacement.value() as u16)
+}
+
+/// Whether `name` can't be a label because it names a register
+pub(crate) fn is_register_name(name: &str) -> bool {
+    let name = name.to_lowercase();
+    register(&name).is_some() || segment_register(&name).is_some()
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '.' | '?' | '@' | '$' | '#' | '~')
+}
+
+pub(crate) fn number(literal: &str) -> Option<i64> {
+    let literal = literal.replace('_', "").to_lowercase();
+    if let Some(hex) = literal.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(hex) = literal.strip_suffix('h') {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = literal.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()
+    } else {
+        literal.parse().ok()
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (None, ';') => return &line[..i],
+            (None, '\'' | '"') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            _ => {}
+        }
+    }
+    line
+}
+
+fn split_word(text: &str) -> (&str, &str) {
+    let text = text.trim_start();
+    let end = text.find(char::is_whitespace).unwrap_or(text.len());
+    (&text[..end], text[end..].trim_start())
+}
+
+/// The contents of a quoted string, if `text` is one
+fn quoted(text: &str) -> Option<&str> {
+    let text = text.trim();
+    let quote = text.chars().next().filter(|c| *c == '\'' || *c == '"')?;
+    text[1..].strip_suffix(quote).filter(|inner| !inner.contains(quote))
+}
+
+/// Splits at commas that aren't inside brackets, parentheses or quotes
+fn split_operands(text: &str) -> Vec<&str> {
+    let text = text.trim();
+    if text.is_empty() {
+        return vec![];
+    }
+    let mut operands = vec![];
+    let mut start = 0;
+    let mut depth = 0;
+    let mut quote = None;
+    for (i, c) in text.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if q == c => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '[' | '(') => depth += 1,
+            (None, ']' | ')') => depth -= 1,
+            (None, ',') if depth == 0 => {
+                operands.push(text[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    operands.push(text[start..].trim());
+    operands
+}
+
+fn split_top_level(text: &str, separator: char) -> Option<(&str, &str)> {
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            c if c == separator && depth == 0 => return Some((&text[..i], &text[i + 1..])),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Splits the inside of an address into the terms that are added or subtracted, with whether each is subtracted
+fn split_terms(text: &str) -> Vec<(bool, &str)> {
+    let mut terms = vec![];
+    let mut negative = false;
+    let mut start = 0;
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            '+' | '-' if depth == 0 && !text[start..i].trim().is_empty() => {
+                terms.push((negative, text[start..i].trim()));
+                negative = c == '-';
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    terms.push((negative, text[start..].trim()));
+    terms
+}
+
+#[cfg(test)]
+mod test {
+    use crate::assembler::{assemble, AssembleError};
+    use crate::decoder::{CpuModel, Decoder};
+
+    fn bytes(source: &str) -> Vec<u8> {
+        assemble(source).unwrap().to_bytes()
+    }
+
+    fn error(source: &str) -> AssembleError {
+        assemble(source).unwrap_err()
+    }
+
+    #[test]
+    fn picks_the_encodings_nasm_does() {
+        assert_eq!(bytes("mov ax, bx"), [0x89, 0xd8]);
+        assert_eq!(bytes("mov cl, [bx + si + 4]"), [0x8a, 0x48, 0x04]);
+        assert_eq!(bytes("mov [bp - 2], dx"), [0x89, 0x56, 0xfe]);
+        assert_eq!(bytes("mov al, [0x1234]"), [0xa0, 0x34, 0x12]);
+        assert_eq!(bytes("mov word [bx], 7"), [0xc7, 0x07, 0x07, 0x00]);
+        assert_eq!(bytes("mov [bp], byte 7"), [0xc6, 0x46, 0x00, 0x07]);
+        assert_eq!(bytes("add ax, 5"), [0x83, 0xc0, 0x05]);
+        assert_eq!(bytes("add ax, strict word 5"), [0x05, 0x05, 0x00]);
+        assert_eq!(bytes("add ax, 1000"), [0x05, 0xe8, 0x03]);
+        assert_eq!(bytes("sub bx, 0xffff"), [0x83, 0xeb, 0xff]);
+        assert_eq!(bytes("cmp byte [di], 200"), [0x80, 0x3d, 0xc8]);
+        assert_eq!(bytes("add word [bx + 300], strict word 3"), [0x81, 0x87, 0x2c, 0x01, 0x03, 0x00]);
+        assert_eq!(bytes("test al, 1"), [0xa8, 0x01]);
+        assert_eq!(bytes("xchg cx, ax"), [0x91]);
+        assert_eq!(bytes("inc word [bx]"), [0xff, 0x07]);
+        assert_eq!(bytes("shl ax, 1"), [0xd1, 0xe0]);
+        assert_eq!(bytes("sar byte [bx], cl"), [0xd2, 0x3f]);
+        assert_eq!(bytes("mov ax, [byte bx + 0]"), [0x8b, 0x47, 0x00]);
+        assert_eq!(bytes("mov ax, [word bx + 1]"), [0x8b, 0x87, 0x01, 0x00]);
+        assert_eq!(bytes("mov ax, es:[bx]"), [0x26, 0x8b, 0x07]);
+        assert_eq!(bytes("mov ax, [cs:bp + di]"), [0x2e, 0x8b, 0x03]);
+        assert_eq!(bytes("lock xchg ax, [bx]"), [0xf0, 0x87, 0x07]);
+        assert_eq!(bytes("es rep movsw"), [0xf3, 0x26, 0xa5]);
+        assert_eq!(bytes("jmp 0x1234:0x10"), [0xea, 0x10, 0x00, 0x34, 0x12]);
+        assert_eq!(bytes("call far [bx]"), [0xff, 0x1f]);
+        assert_eq!(bytes("push strict word 5"), [0x68, 0x05, 0x00]);
+        assert_eq!(bytes("push word -1"), [0x6a, 0xff]);
+    }
+
+    #[test]
+    fn resolves_labels_in_two_passes() {
+        let source = "
+            bits 16
+            start:
+                mov cx, 3
+            .again:
+                dec cx
+                jnz .again      ; backward
+                jmp short done
+                jmp start       ; backward and close, so short
+                call done
+            done: ret
+        ";
+        assert_eq!(bytes(source), [0xb9, 0x03, 0x00, 0x49, 0x75, 0xfd, 0xeb, 0x05, 0xeb, 0xf6, 0xe8, 0x00, 0x00, 0xc3]);
+
+        // forward jumps without a size are near, as the first pass can't know the distance yet
+        assert_eq!(bytes("jmp next\nnext: nop"), [0xe9, 0x00, 0x00, 0x90]);
+    }
+
+    #[test]
+    fn directives() {
+        let program = assemble(
+            "
+            org 0x100
+            mov si, message
+            mov ax, end - message
+            message: db 'hi', 13, 10, \"$\"
+            dw 0x1234, 'abc'
+            times 3 db 0
+            times 2 nop
+            end: dw $$, $
+        ",
+        )
+        .unwrap();
+
+        assert_eq!(program.origin, 0x100);
+        assert_eq!(program.instructions().count(), 4);
+        assert_eq!(
+            program.to_bytes(),
+            [
+                0xbe, 0x06, 0x01, 0xb8, 0x10, 0x00, b'h', b'i', 13, 10, b'$', 0x34, 0x12, b'a', b'b', b'c', 0, 0, 0, 0, 0x90, 0x90, 0x00,
+                0x01, 0x16, 0x01
+            ]
+        );
+        assert_eq!(program.statements[2].address, 0x106);
+        assert_eq!(program.statements[2].line, 5);
+
+        let program = assemble("counter equ 0x1234\nmov ax, [counter + 2]").unwrap();
+        assert_eq!(program.to_bytes(), [0xa1, 0x36, 0x12]);
+        let error = assemble("bad equ later\nlater:").unwrap_err();
+        assert_eq!(error, AssembleError { line: 1, message: "equ needs a known value that isn't negative".to_owned() });
+    }
+
+    #[test]
+    fn reassembles_disassembled_code() {
+        let original: &[u8] = &[
+            0x89, 0xd8, 0x8b, 0x47, 0x02, 0xc7, 0x06, 0x10, 0x00, 0x34, 0x12, 0xb8, 0x01, 0x00, 0x03, 0x46, 0xfe, 0x83, 0xc3, 0x04, 0x81,
+            0xbe, 0x00, 0x01, 0x00, 0x20, 0x74, 0xfe, 0x75, 0xfc, 0xe8, 0x00, 0x00, 0x50, 0x5b, 0xff, 0x36, 0x00, 0x01, 0xf3, 0xa4, 0x2e,
+            0x8a, 0x00, 0xd1, 0xe0, 0xf7, 0xe3, 0xfe, 0xc0, 0x86, 0x60, 0x21, 0xe4, 0xc8, 0xa3, 0x34, 0x12, 0x8c, 0xd8, 0x8e, 0x46, 0x08,
+            0xc4, 0x1e, 0x00, 0x02, 0xe2, 0xd0, 0xeb, 0x00, 0xe9, 0x00, 0x00, 0xcd, 0x21, 0xd4, 0x10, 0xc2, 0x04, 0x00, 0xc1, 0xe8, 0x04,
+            0x6b, 0xc3, 0x05, 0xc8, 0x10, 0x00, 0x01, 0xd9, 0x07, 0xd7, 0x26, 0xd7,
+        ];
+
+        let decoder = Decoder::new(CpuModel::I80186);
+        let mut source = String::new();
+        let mut address = 0;
+        while let Some(decoded) = decoder.decode_at(original, address) {
+            let decoded = decoded.unwrap();
+            let next = decoded.next_address() as i64;
+            source += &decoded.instruction.encode(|disp| format!("{}", next + disp as i64));
+            source += "\n";
+            address = decoded.next_address();
+        }
+
+        assert_eq!(bytes(&source), original, "{source}");
+    }
+
+    #[test]
+    fn reports_errors_with_line_numbers() {
+        assert_eq!(error("nop\njmp nowhere"), AssembleError { line: 2, message: "undefined label nowhere".to_owned() });
+        assert_eq!(error("inc [bx]").message, "operation size not specified");
+        assert_eq!(error("mov ax, bl").message, "mismatch in operand sizes");
+        assert_eq!(error("frob ax").message, "unknown instruction frob");
+        assert_eq!(error("mov ax").message, "invalid operands for mov");
+        assert_eq!(error("a: nop\na: nop").message, "label a is defined more than once");
+        assert_eq!(error("je far_away\ntimes 200 nop\nfar_away:").message, "short jump to 0xca is out of range by 73 bytes");
+        assert_eq!(error("mov al, 300").message, "300 doesn't fit in a byte");
+        assert_eq!(error("mov ax, [bx + bp]").message, "invalid address [bx + bp]");
+    }
+
+    #[test]
+    fn short_jumps_reach_127_ahead_and_128_back() {
+        assert_eq!(bytes("jmp short ahead\ntimes 127 nop\nahead:")[..2], [0xeb, 0x7f]);
+        assert_eq!(error("jmp short ahead\ntimes 128 nop\nahead:").message, "short jump to 0x82 is out of range by 1 bytes");
+        assert_eq!(bytes("back:\ntimes 126 nop\njmp short back")[126..], [0xeb, 0x80]);
+        assert_eq!(error("back:\ntimes 127 nop\njmp short back").message, "short jump to 0x0 is out of range by 1 bytes");
+    }
+}
@@ -0,0 +1,34 @@
+use std::path::Path;
+use std::{env, fs};
+
+use sim8086::assembler::assemble;
+
+/// Assembles `<source.asm>` into a flat binary at `[output]`, which defaults to the source path
+/// without its extension, as nasm does
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 {
+        println!("usage: assembler <source.asm> [output]");
+        std::process::exit(1);
+    }
+
+    let output = match args.get(2) {
+        Some(output) => output.clone(),
+        None => Path::new(&args[1]).with_extension("").to_string_lossy().into_owned(),
+    };
+
+    let exit_code = match assemble_file(&args[1], &output) {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("Error assembling {}: {e}", args[1]);
+            1
+        }
+    };
+    std::process::exit(exit_code);
+}
+
+fn assemble_file(source: &str, output: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let program = assemble(&fs::read_to_string(source)?)?;
+    fs::write(output, program.to_bytes())?;
+    Ok(())
+}
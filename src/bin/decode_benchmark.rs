@@ -0,0 +1,79 @@
+use std::time::{Duration, Instant};
+use std::{env, fs};
+
+use sim8086::decoder::{CpuModel, DecodeError, Decoder};
+use sim8086::ops::Instruction;
+
+/// A mix of register, memory, immediate, prefixed and group instructions, repeated to fill the sample
+const SAMPLE: [u8; 56] = [
+    0x89, 0xd8, 0x8b, 0x47, 0x02, 0xc7, 0x06, 0x10, 0x00, 0x34, 0x12, 0xb8, 0x01, 0x00, 0x03, 0x46, 0xfe, 0x83, 0xc3, 0x04, 0x81, 0xbe,
+    0x00, 0x01, 0x00, 0x20, 0x74, 0xfe, 0x75, 0xfc, 0xe8, 0x00, 0x00, 0x50, 0x5b, 0xff, 0x36, 0x00, 0x01, 0xf3, 0xa4, 0x2e, 0x8a, 0x00,
+    0xd1, 0xe0, 0xf7, 0xe3, 0xfe, 0xc0, 0x86, 0x60, 0x21, 0xe4, 0xc8, 0xc3,
+];
+const SAMPLE_SIZE: usize = 4 * 1024 * 1024;
+const ROUNDS: usize = 5;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    let bytes = match args.get(1) {
+        Some(path) => fs::read(path).unwrap_or_else(|e| panic!("could not read {path}: {e}")),
+        None => SAMPLE.iter().copied().cycle().take(SAMPLE_SIZE).collect(),
+    };
+
+    let decoder = Decoder::new(CpuModel::I8086);
+    let hash_map = decoder.hash_map_lookup();
+    println!("decoding {} bytes, best of {ROUNDS} rounds", bytes.len());
+    report("HashMap and iterator", best_of(|| decode_iterator(&bytes, |iter| decoder.decode_next_with(&hash_map, iter))));
+    report("slice (decode_at)", best_of(|| decode_slice(&decoder, &bytes)));
+    report("iterator (decode_next)", best_of(|| decode_iterator(&bytes, |iter| decoder.decode_next(iter))));
+}
+
+fn best_of<F: Fn() -> usize>(decode: F) -> (usize, Duration) {
+    (0..ROUNDS)
+        .map(|_| {
+            let start = Instant::now();
+            let count = decode();
+            (count, start.elapsed())
+        })
+        .min_by_key(|(_, elapsed)| *elapsed)
+        .unwrap()
+}
+
+fn report(name: &str, (count, elapsed): (usize, Duration)) {
+    let per_second = count as f64 / elapsed.as_secs_f64();
+    println!("{name:<24} {count} instructions in {:>8.2} ms, {:>6.2} M instructions/s", elapsed.as_secs_f64() * 1000.0, per_second / 1e6);
+}
+
+/// Undecodable bytes are skipped one at a time, the way the printer emits them as `db`
+fn decode_slice(decoder: &Decoder, bytes: &[u8]) -> usize {
+    let mut count = 0;
+    let mut address = 0;
+    while let Some(decoded) = decoder.decode_at(bytes, address) {
+        address = match decoded {
+            Ok(decoded) => decoded.next_address(),
+            Err(_) => address + 1,
+        };
+        count += 1;
+    }
+    count
+}
+
+/// Counts like `decode_slice`: after an error the iterator starts again one byte further on
+fn decode_iterator<D>(bytes: &[u8], decode_next: D) -> usize
+where
+    D: Fn(&mut dyn Iterator<Item = &u8>) -> Option<Result<Instruction, DecodeError>>,
+{
+    let mut count = 0;
+    let mut iter = bytes.iter();
+    loop {
+        let address = bytes.len() - iter.as_slice().len();
+        match decode_next(&mut iter) {
+            Some(Ok(_)) => {}
+            Some(Err(_)) => iter = bytes[address + 1..].iter(),
+            None => break,
+        }
+        count += 1;
+    }
+    count
+}
@@ -7,7 +7,7 @@ use std::io::{BufReader, Read};
 use sim8086::haversine;
 use sim8086::json::JsonValue;
 
-const USAGE: &'static str = r#"Usage: haversine_calculator path_to_json (path_to_reference)"#;
+const USAGE: &str = r#"Usage: haversine_calculator path_to_json (path_to_reference)"#;
 
 fn main() {
     match run() {
@@ -50,8 +50,8 @@ fn run() -> Result<(), Box<dyn Error>> {
                                     x0, y0, x1, y1, haversine::EARTH_RADIUS,
                                 );
 
-                                if (reference_reader.is_some()) {
-                                    reference_reader.as_mut().unwrap().read_exact(&mut buf)?;
+                                if let Some(reference_reader) = reference_reader.as_mut() {
+                                    reference_reader.read_exact(&mut buf)?;
                                     let reference_dist = f64::from_le_bytes(buf);
 
                                     if (dist - reference_dist).abs() > f64::EPSILON {
@@ -76,8 +76,8 @@ fn run() -> Result<(), Box<dyn Error>> {
 
     println!("Processed {count} pairs:");
     println!("  Average: {average}");
-    if reference_reader.is_some() {
-        reference_reader.as_mut().unwrap().read_exact(&mut buf);
+    if let Some(reference_reader) = reference_reader.as_mut() {
+        reference_reader.read_exact(&mut buf)?;
         let reference_average = f64::from_le_bytes(buf);
         let diff = (average - reference_average).abs();
         println!("  Reference: {reference_average}");
@@ -91,13 +91,13 @@ fn run() -> Result<(), Box<dyn Error>> {
 fn extract_number(object: &HashMap<String, JsonValue>, key: &str) -> Result<f64, Box<dyn Error>> {
     let value = object.get(key).ok_or(format!("value for {key} not found"))?;
     match value {
-        JsonValue::Number(number) => { Ok(number.clone()) }
+        JsonValue::Number(number) => { Ok(*number) }
         _ => Err("not a number".into()),
     }
 }
 
 fn read_args() -> Result<Args, Box<dyn Error>> {
-    let mut args: Vec<String> = env::args().collect();
+    let args: Vec<String> = env::args().collect();
     if args.len() < 2 {
         return Err(USAGE.into());
     }
@@ -8,7 +8,7 @@ use rand::prelude::*;
 use sim8086::haversine;
 use sim8086::haversine::EARTH_RADIUS;
 
-const USAGE: &'static str = "Usage: haversine_generator uniform|clustered seed point_count";
+const USAGE: &str = "Usage: haversine_generator uniform|clustered seed point_count";
 const CLUSTER_COUNT: usize = 64;
 
 #[derive(Debug)]
@@ -32,7 +32,7 @@ fn main() {
     let mut json = BufWriter::new(File::create(json).unwrap());
     let mut reference_answers = BufWriter::new(File::create(reference_answers).unwrap());
 
-    write!(&mut json, "{{\"pairs\": [\n");
+    writeln!(&mut json, "{{\"pairs\": [").unwrap();
     let mut rng = StdRng::seed_from_u64(config.seed);
 
     let mut points_left = match config.mode {
@@ -70,16 +70,16 @@ fn main() {
         assert!(y1 >= -90.0);
         assert!(y1 <= 90.0);
 
-        write!(&mut json, "  {{\"x0\": {x0:.24}, \"y0\": {y0:.24}, \"x1\": {x1:.24}, \"y1\": {y1:.24} }}");
-        if i < config.count - 1 { write!(&mut json, ","); }
-        write!(&mut json, "\n");
+        write!(&mut json, "  {{\"x0\": {x0:.24}, \"y0\": {y0:.24}, \"x1\": {x1:.24}, \"y1\": {y1:.24} }}").unwrap();
+        if i < config.count - 1 { write!(&mut json, ",").unwrap(); }
+        writeln!(&mut json).unwrap();
 
         let reference = haversine::reference_haversine(x0, y0, x1, y1, EARTH_RADIUS);
         sum += reference * coeff;
 
         reference_answers.write_all(reference.to_le_bytes().as_slice()).unwrap();
     }
-    write!(&mut json, "]}}");
+    write!(&mut json, "]}}").unwrap();
 
     println!("Sum: {sum}");
     reference_answers.write_all(sum.to_le_bytes().as_slice()).unwrap();
@@ -88,7 +88,7 @@ fn main() {
 fn parse_args() -> Config {
     let args: Vec<String> = env::args().collect();
 
-    if (args.len() < 4) {
+    if args.len() < 4 {
         usage();
         unreachable!("usage() should terminate the program")
     }
@@ -107,7 +107,7 @@ fn parse_args() -> Config {
     let seed = args[2].as_str();
     let seed = match seed.parse::<u64>() {
         Ok(seed) => { seed }
-        Err(err) => {
+        Err(_) => {
             println!("'{seed}' is not a valid seed. Seed must be a positive integer");
             usage();
             unreachable!()
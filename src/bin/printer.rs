@@ -1,20 +1,44 @@
-use std::collections::{BTreeSet, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
 use std::fs::File;
 use std::path::Path;
 use std::{env, fs};
 
-use sim8086::decoder::Decoder;
+use sim8086::annotations::{Annotations, DataKind};
+use sim8086::clocks::Clocks;
+use sim8086::control_flow::ControlFlowGraph;
+use sim8086::decoder::{CpuModel, DecodeMode, Decoder, OpCodeCoverage};
+use sim8086::disassembly::{self, jump_target, Chunk, Disassembly};
+use sim8086::format::{Att, Case, InstructionFormatter, Masm, MemorySize, Names, Nasm, Operand};
+use sim8086::image::{Format, Image};
+use sim8086::json::JsonValue;
 use sim8086::memory::Memory;
-use sim8086::ops::Instruction;
+use sim8086::ops::{EffectiveAddressBase, OpWidth};
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    let options = take_cpu_model(&mut args).and_then(|model| Ok((model, take_syntax(&mut args)?, take_annotations(&mut args)?)));
+    let (model, syntax, annotations) = match options {
+        Ok(options) => options,
+        Err(e) => {
+            println!("{e}");
+            std::process::exit(1);
+        }
+    };
+    let mode = take_decode_mode(&mut args);
+    let output = take_output(&mut args);
+    let traversal = take_traversal(&mut args);
 
     if args.len() < 2 {
         std::process::exit(1);
     }
 
-    let exit_code = match encode_to_assembler(&args[1]) {
+    if args[1] == "--coverage" {
+        print_coverage(model, mode);
+        return;
+    }
+
+    let exit_code = match encode_to_assembler(&args[1], model, mode, traversal, output, syntax, &annotations) {
         Ok(_) => 0,
         Err(e) => {
             println!("Error converting to assembler: {e}");
@@ -24,107 +48,527 @@ fn main() {
     std::process::exit(exit_code);
 }
 
-fn encode_to_assembler<P: AsRef<Path>>(path: P) -> std::io::Result<()> {
-    let decoder = Decoder::new();
-    let mut memory = Memory::new();
+/// Removes `--cpu <model>` from the arguments, defaulting to the 8086
+fn take_cpu_model(args: &mut Vec<String>) -> Result<CpuModel, String> {
+    let Some(i) = args.iter().position(|arg| arg == "--cpu") else {
+        return Ok(CpuModel::default());
+    };
+    let model = args.get(i + 1).ok_or("--cpu needs a model")?.parse()?;
+    args.drain(i..i + 2);
+    Ok(model)
+}
 
-    let bytes = fs::read(path)?;
-    memory.copy_from_slice(&bytes, 0);
-    memory.dump(&mut File::create("scratch/dump.data")?)?;
+/// Removes `--permissive` from the arguments, which makes undocumented opcodes decode
+fn take_decode_mode(args: &mut Vec<String>) -> DecodeMode {
+    match args.iter().position(|arg| arg == "--permissive") {
+        Some(i) => {
+            args.remove(i);
+            DecodeMode::Permissive
+        }
+        None => DecodeMode::Strict,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Syntax {
+    Nasm(Nasm),
+    Masm(Masm),
+    Att(Att),
+}
 
-    let mut iter = memory.iter(0, bytes.len()).enumerate().peekable();
-    println!("bits 16");
+impl Syntax {
+    fn formatter(&self) -> &dyn InstructionFormatter {
+        match self {
+            Syntax::Nasm(nasm) => nasm,
+            Syntax::Masm(masm) => masm,
+            Syntax::Att(att) => att,
+        }
+    }
+
+    fn case(&self) -> Case {
+        match self {
+            Syntax::Nasm(nasm) => nasm.case,
+            Syntax::Masm(masm) => masm.case,
+            Syntax::Att(att) => att.case,
+        }
+    }
 
-    let mut decoded_instructions: Vec<(usize, usize, Instruction)> = vec![];
-    loop {
-        let position_before = match iter.peek() {
-            Some((i, _byte)) => *i,
-            None => bytes.len(),
+    /// A number in hex with at least `digits` digits, MASM wanting it to start with a digit
+    fn hex(&self, value: u16, digits: usize) -> String {
+        let hex = match self {
+            Syntax::Nasm(_) | Syntax::Att(_) => format!("{value:#0width$x}", width = digits + 2),
+            Syntax::Masm(_) => format!("0{value:0digits$x}h"),
         };
+        self.case().apply(&hex)
+    }
 
-        let instruction = decoder.decode_next(&mut iter.by_ref().map(|(_i, byte)| byte));
-        if instruction.is_none() {
-            break;
+    /// What the current address is called in an expression
+    fn here(&self) -> &'static str {
+        match self {
+            Syntax::Nasm(_) | Syntax::Masm(_) => "$",
+            Syntax::Att(_) => ".",
         }
+    }
+}
 
-        let instruction = instruction.unwrap();
+/// Removes `--syntax <nasm|masm|att>` and `--uppercase` from the arguments, defaulting to lowercase NASM.
+/// Only NASM output is meant to reassemble to the same bytes.
+fn take_syntax(args: &mut Vec<String>) -> Result<Syntax, String> {
+    let case = match args.iter().position(|arg| arg == "--uppercase") {
+        Some(i) => {
+            args.remove(i);
+            Case::Upper
+        }
+        None => Case::Lower,
+    };
+    let Some(i) = args.iter().position(|arg| arg == "--syntax") else {
+        return Ok(Syntax::Nasm(Nasm { case }));
+    };
+    let syntax = match args.get(i + 1).map(String::as_str) {
+        Some("nasm") => Syntax::Nasm(Nasm { case }),
+        Some("masm" | "tasm") => Syntax::Masm(Masm { case }),
+        Some("att") => Syntax::Att(Att { case }),
+        Some(syntax) => return Err(format!("unknown syntax {syntax}, expected nasm, masm or att")),
+        None => return Err("--syntax needs nasm, masm or att".to_owned()),
+    };
+    args.drain(i..i + 2);
+    Ok(syntax)
+}
 
-        let position_after = match iter.peek() {
-            Some((i, _byte)) => *i,
-            None => bytes.len(),
-        };
+/// Removes `--annotations <file>` from the arguments and reads the symbols, comments and data ranges in it
+fn take_annotations(args: &mut Vec<String>) -> Result<Annotations, String> {
+    let Some(i) = args.iter().position(|arg| arg == "--annotations") else {
+        return Ok(Annotations::default());
+    };
+    let path = args.get(i + 1).ok_or("--annotations needs a file")?.clone();
+    args.drain(i..i + 2);
+    let text = fs::read_to_string(&path).map_err(|e| format!("{path}: {e}"))?;
+    Annotations::parse(&text).map_err(|e| format!("{path}: {e}"))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Output {
+    /// Source that assembles back to the input
+    Source,
+    /// Address, bytes and instruction per line, like ndisasm, optionally with the estimated clocks
+    Listing { clocks: bool },
+    /// The control flow graph in Graphviz DOT
+    Dot,
+    /// An array of JSON objects, one per line for each instruction or run of data
+    Json,
+}
 
-        decoded_instructions.push((position_before, position_after, instruction));
+/// Removes `--listing`, `--clocks`, `--dot` and `--json` from the arguments; `--clocks` implies `--listing`
+fn take_output(args: &mut Vec<String>) -> Output {
+    let mut take = |flag: &str| match args.iter().position(|arg| arg == flag) {
+        Some(i) => {
+            args.remove(i);
+            true
+        }
+        None => false,
+    };
+    let listing = take("--listing");
+    let clocks = take("--clocks");
+    let json = take("--json");
+    if take("--dot") {
+        Output::Dot
+    } else if json {
+        Output::Json
+    } else if listing || clocks {
+        Output::Listing { clocks }
+    } else {
+        Output::Source
     }
+}
 
-    //collect jump targets
-    let mut jump_targets: BTreeSet<usize> = BTreeSet::new();
-    for (_position_before, position_after, instruction) in decoded_instructions.iter() {
-        if let Some(jump) = relative_jump(instruction) {
-            let target = to_absolute(jump, *position_after);
-            jump_targets.insert(target);
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Traversal {
+    /// Decode everything from the start, as if the whole file were code
+    Linear,
+    /// Follow the code from the entry point, leaving what it never reaches as data
+    Recursive,
+}
+
+/// Removes `--recursive` from the arguments
+fn take_traversal(args: &mut Vec<String>) -> Traversal {
+    match args.iter().position(|arg| arg == "--recursive") {
+        Some(i) => {
+            args.remove(i);
+            Traversal::Recursive
         }
+        None => Traversal::Linear,
     }
+}
+
+fn encode_to_assembler<P: AsRef<Path>>(
+    path: P,
+    model: CpuModel,
+    mode: DecodeMode,
+    traversal: Traversal,
+    output: Output,
+    syntax: Syntax,
+    annotations: &Annotations,
+) -> Result<(), Box<dyn Error>> {
+    let decoder = Decoder::with_mode(model, mode);
+    let mut memory = Memory::new();
+
+    let path = path.as_ref();
+    let bytes = fs::read(path)?;
+    let image = Image::load(Format::detect(path, &bytes), &bytes, &mut memory)?;
+    memory.dump(&mut File::create("scratch/dump.data")?)?;
 
-    let mut jump_table = HashMap::new();
-    for (i, target) in jump_targets.iter().enumerate() {
-        jump_table.insert(*target, format!("label_{}", i + 1));
+    // addresses are offsets into the code segment, where the entry point is
+    let (code, origin) = image.code(&memory);
+    let disassembly = match traversal {
+        Traversal::Linear => disassembly::linear(&decoder, code, origin, annotations),
+        Traversal::Recursive => disassembly::recursive(&decoder, code, origin, &[image.ip as usize], annotations),
+    };
+    for e in &disassembly.errors {
+        eprintln!("{e}");
+    }
+
+    match output {
+        Output::Source => print_source(code, origin, model, &disassembly, syntax, annotations),
+        Output::Listing { clocks } => print_listing(code, &disassembly, clocks, syntax, annotations),
+        Output::Dot => print!("{}", ControlFlowGraph::build(&disassembly).to_dot(syntax.formatter())),
+        Output::Json => print_json(code, &disassembly, syntax, annotations),
+    }
+    Ok(())
+}
+
+/// `origin` is where the code starts in its segment, such as 0x100 for a .COM file
+fn print_source(bytes: &[u8], origin: usize, model: CpuModel, disassembly: &Disassembly, syntax: Syntax, annotations: &Annotations) {
+    let formatter = syntax.formatter();
+    let header = match (syntax, model) {
+        (Syntax::Nasm(_), CpuModel::I8086) => "bits 16",
+        (Syntax::Nasm(_), CpuModel::I80186) => "bits 16\ncpu 186",
+        (Syntax::Nasm(_), CpuModel::I80286) => "bits 16\ncpu 286",
+        (Syntax::Masm(_), CpuModel::I8086) => ".8086",
+        (Syntax::Masm(_), CpuModel::I80186) => ".186",
+        (Syntax::Masm(_), CpuModel::I80286) => ".286",
+        (Syntax::Att(_), CpuModel::I8086) => ".code16\n.arch i8086",
+        (Syntax::Att(_), CpuModel::I80186) => ".code16\n.arch i186",
+        (Syntax::Att(_), CpuModel::I80286) => ".code16\n.arch i286",
+    };
+    println!("{}", formatter.mnemonic(header));
+    match syntax {
+        _ if origin == 0 => {}
+        // as leaves where the code runs to the linker
+        Syntax::Att(_) => println!("{}", formatter.comment(&format!("org {}", syntax.hex(origin as u16, 4)))),
+        _ => println!("{} {}", formatter.mnemonic("org"), syntax.hex(origin as u16, 4)),
+    }
+
+    // only targets where a line starts can have a label, the others are written relative to $;
+    // symbols that don't start a line are defined with equ instead
+    let starts: BTreeSet<usize> = disassembly.chunks.iter().map(Chunk::address).collect();
+    let mut jump_table: HashMap<usize, String> = disassembly
+        .jump_targets()
+        .into_iter()
+        .filter(|target| starts.contains(target) && !annotations.symbols.contains_key(target))
+        .enumerate()
+        .map(|(i, target)| (target, format!("label_{}", i + 1)))
+        .collect();
+    for (address, symbol) in &annotations.symbols {
+        if !starts.contains(address) {
+            match syntax {
+                Syntax::Att(_) => println!("{} {symbol}, {}", formatter.mnemonic(".set"), syntax.hex(*address as u16, 4)),
+                _ => println!("{symbol} {}", formatter.mnemonic(&format!("equ {}", syntax.hex(*address as u16, 4)))),
+            }
+        }
+        jump_table.insert(*address, symbol.clone());
     }
-    let jump_table = jump_table;
 
-    for (position_before, position_after, instruction) in decoded_instructions {
-        if let Some(label) = jump_table.get(&position_before) {
+    for chunk in &disassembly.chunks {
+        if let Some(label) = jump_table.get(&chunk.address()) {
             println!("{}:", label);
         }
-        println!("{}", instruction.encode(|disp| { to_label(disp, position_after, &jump_table) }));
+        match chunk {
+            Chunk::Code { address, length, instruction } => {
+                let names = SourceNames { chunk, jump_table: &jump_table, symbols: &annotations.symbols, here: syntax.here() };
+                print_source_line(&instruction.format(formatter, &names), annotations.comment(*address, *length), formatter);
+            }
+            Chunk::Data { address, length } => {
+                for (offset, length, text) in data_lines(&bytes[*address..address + length], annotations.data_kind(*address), 16, syntax) {
+                    print_source_line(&text, annotations.comment(address + offset, length), formatter);
+                }
+            }
+        }
     }
+}
 
-    Ok(())
+fn print_source_line(text: &str, comment: Option<String>, formatter: &dyn InstructionFormatter) {
+    match comment {
+        Some(comment) => println!("{text} {}", formatter.comment(&comment)),
+        None => println!("{text}"),
+    }
+}
+
+/// Jump targets without a symbol are shown as absolute addresses, as there are no labels to refer to
+fn print_listing(bytes: &[u8], disassembly: &Disassembly, clocks: bool, syntax: Syntax, annotations: &Annotations) {
+    for chunk in &disassembly.chunks {
+        let Chunk::Code { address, length, instruction } = chunk else {
+            let data = &bytes[chunk.address()..chunk.next_address()];
+            for (offset, length, text) in data_lines(data, annotations.data_kind(chunk.address()), 8, syntax) {
+                let address = chunk.address() + offset;
+                print_listing_line(address, &data[offset..offset + length], &text, None, annotations.comment(address, length));
+            }
+            continue;
+        };
+        let names = ListingNames { next_address: chunk.next_address(), symbols: &annotations.symbols };
+        let text = instruction.format(syntax.formatter(), &names);
+        let clock_count = if clocks { Some(Clocks::of(instruction)) } else { None };
+        print_listing_line(*address, &bytes[*address..chunk.next_address()], &text, clock_count, annotations.comment(*address, *length));
+    }
 }
 
-fn to_label(disp: i8, current_i: usize, jump_table: &HashMap<usize, String>) -> String {
-    let target = if disp < 0 {
-        let disp = -disp as usize;
-        current_i.checked_sub(disp).unwrap()
+fn print_listing_line(address: usize, bytes: &[u8], text: &str, clocks: Option<Clocks>, comment: Option<String>) {
+    let hex: String = bytes.iter().map(|byte| format!("{byte:02X}")).collect();
+    let notes: Vec<String> = clocks.map(|clocks| format!("{clocks} clocks")).into_iter().chain(comment).collect();
+    if notes.is_empty() {
+        println!("{address:08X}  {hex:<16}  {text}");
     } else {
-        let disp = disp as usize;
-        current_i + disp
+        println!("{address:08X}  {hex:<16}  {text:<32} ; {}", notes.join("; "));
+    }
+}
+
+/// Each chunk as an object with its `address`, `bytes` and `kind`, either `code` or `data`, along
+/// with any annotated `symbol` and `comment`. Code also has the `mnemonic` with any prefixes, the
+/// `operands`, the `text` in the chosen syntax and, for relative jumps and calls, the `target`.
+fn print_json(bytes: &[u8], disassembly: &Disassembly, syntax: Syntax, annotations: &Annotations) {
+    println!("[");
+    for (i, chunk) in disassembly.chunks.iter().enumerate() {
+        let address = chunk.address();
+        let mut fields = vec![
+            ("address", number(address)),
+            ("bytes", JsonValue::Array(bytes[address..chunk.next_address()].iter().map(|byte| number(*byte)).collect())),
+        ];
+        if let Some(symbol) = annotations.symbols.get(&address) {
+            fields.push(("symbol", JsonValue::String(symbol.clone())));
+        }
+        if let Some(comment) = annotations.comment(address, chunk.length()) {
+            fields.push(("comment", JsonValue::String(comment)));
+        }
+        match chunk {
+            Chunk::Code { instruction, .. } => {
+                let (mnemonic, operands) = instruction.operands();
+                let names = ListingNames { next_address: chunk.next_address(), symbols: &annotations.symbols };
+                fields.extend([
+                    ("kind", JsonValue::String("code".to_owned())),
+                    ("mnemonic", JsonValue::String(mnemonic)),
+                    ("operands", JsonValue::Array(operands.iter().map(|operand| operand_json(operand, chunk.next_address())).collect())),
+                    ("text", JsonValue::String(instruction.format(syntax.formatter(), &names))),
+                ]);
+                if let Some(disp) = instruction.relative_jump() {
+                    fields.push(("target", number(jump_target(chunk.next_address(), disp))));
+                }
+            }
+            Chunk::Data { .. } => fields.push(("kind", JsonValue::String("data".to_owned()))),
+        }
+        let separator = if i + 1 < disassembly.chunks.len() { "," } else { "" };
+        println!("{}{separator}", JsonValue::object(fields));
+    }
+    println!("]");
+}
+
+/// An operand as an object with its `kind`: `register` or `segment_register` with the `register`,
+/// `memory` with the `segment` override, `base` registers, `displacement` and `size`, `immediate`
+/// with the `value` and `size`, `jump` with the `target` and `displacement`, or `far_pointer` with
+/// the `segment` and `offset`. A direct memory address has no base, and its displacement is the
+/// address. Sizes that the other operands imply are null.
+fn operand_json(operand: &Operand, next_address: usize) -> JsonValue {
+    let string = |text: &str| JsonValue::String(text.to_owned());
+    let fields = match *operand {
+        Operand::Register(reg) => vec![("kind", string("register")), ("register", string(&reg.to_string()))],
+        Operand::SegmentRegister(seg_reg) => vec![("kind", string("segment_register")), ("register", string(&seg_reg.to_string()))],
+        Operand::Memory { mem, size } => {
+            let (base, displacement) = match mem.base {
+                EffectiveAddressBase::Direct => (JsonValue::Null, number(mem.displacement.value() as u16)),
+                base => (string(&base.to_string()), number(mem.displacement.value())),
+            };
+            let size = match size {
+                None => JsonValue::Null,
+                Some(MemorySize::Byte) => string("byte"),
+                Some(MemorySize::Word) => string("word"),
+                Some(MemorySize::Far) => string("far"),
+            };
+            let segment = mem.segment.map_or(JsonValue::Null, |segment| string(&segment.to_string()));
+            vec![("kind", string("memory")), ("segment", segment), ("base", base), ("displacement", displacement), ("size", size)]
+        }
+        Operand::Immediate { value, size, .. } => {
+            let size = match size {
+                None => JsonValue::Null,
+                Some(OpWidth::Byte) => string("byte"),
+                Some(OpWidth::Word) => string("word"),
+            };
+            vec![("kind", string("immediate")), ("value", number(value)), ("size", size)]
+        }
+        Operand::Number(value) => vec![("kind", string("immediate")), ("value", number(value)), ("size", JsonValue::Null)],
+        Operand::Jump { disp, .. } => {
+            vec![("kind", string("jump")), ("target", number(jump_target(next_address, disp))), ("displacement", number(disp))]
+        }
+        Operand::FarPointer { segment, offset } => vec![("kind", string("far_pointer")), ("segment", number(segment)), ("offset", number(offset))],
     };
-    jump_table.get(&target).unwrap().clone()
+    JsonValue::object(fields)
 }
 
-fn to_absolute(disp: i8, current_i: usize) -> usize {
-    if disp < 0 {
-        let disp = -disp as usize;
-        current_i.checked_sub(disp).unwrap()
-    } else {
-        let disp = disp as usize;
-        current_i + disp
-    }
-}
-
-fn relative_jump(instruction: &Instruction) -> Option<i8> {
-    match *instruction {
-        Instruction::JumpOnEqual(disp) => Some(disp),
-        Instruction::JumpOnLess(disp) => Some(disp),
-        Instruction::JumpOnNotGreater(disp) => Some(disp),
-        Instruction::JumpOnBelow(disp) => Some(disp),
-        Instruction::JumpOnNotAbove(disp) => Some(disp),
-        Instruction::JumpOnParity(disp) => Some(disp),
-        Instruction::JumpOnOverflow(disp) => Some(disp),
-        Instruction::JumpOnSign(disp) => Some(disp),
-        Instruction::JumpOnNotEqual(disp) => Some(disp),
-        Instruction::JumpOnNotLess(disp) => Some(disp),
-        Instruction::JumpOnGreater(disp) => Some(disp),
-        Instruction::JumpOnNotBelow(disp) => Some(disp),
-        Instruction::JumpOnAbove(disp) => Some(disp),
-        Instruction::JumpOnNoParity(disp) => Some(disp),
-        Instruction::JumpOnNoOverflow(disp) => Some(disp),
-        Instruction::JumpOnNotSign(disp) => Some(disp),
-        Instruction::Loop(disp) => Some(disp),
-        Instruction::LoopWhileEqual(disp) => Some(disp),
-        Instruction::LoopWhileNotEqual(disp) => Some(disp),
-        Instruction::JumpOnCxZero(disp) => Some(disp),
-        _ => None,
+fn number(value: impl TryInto<i64>) -> JsonValue {
+    JsonValue::Number(value.try_into().unwrap_or_default() as f64)
+}
+
+/// Data as `db` or `dw` lines of up to `row` bytes, each with its offset into `bytes` and length. A
+/// word range with an odd length ends in a `db`.
+fn data_lines(bytes: &[u8], kind: DataKind, row: usize, syntax: Syntax) -> Vec<(usize, usize, String)> {
+    let directive = |directive: &str, values: Vec<String>| format!("{} {}", syntax.formatter().mnemonic(directive), values.join(", "));
+    let mut lines = vec![];
+    for (i, row_bytes) in bytes.chunks(row).enumerate() {
+        let offset = i * row;
+        match kind {
+            DataKind::Byte => {
+                lines.push((offset, row_bytes.len(), directive("db", row_bytes.iter().map(|byte| syntax.hex(*byte as u16, 2)).collect())));
+            }
+            DataKind::Word => {
+                let words = row_bytes.chunks_exact(2);
+                let odd = words.remainder();
+                if row_bytes.len() > 1 {
+                    let values = words.map(|word| syntax.hex(u16::from_le_bytes([word[0], word[1]]), 4)).collect();
+                    lines.push((offset, row_bytes.len() - odd.len(), directive("dw", values)));
+                }
+                if let [byte] = odd {
+                    lines.push((offset + row_bytes.len() - 1, 1, directive("db", vec![syntax.hex(*byte as u16, 2)])));
+                }
+            }
+            // as has no mix of text and numbers on one line
+            DataKind::String if matches!(syntax, Syntax::Att(_)) => {
+                for (start, length, text) in ascii_runs(row_bytes) {
+                    let run = &row_bytes[start..start + length];
+                    let text = if text {
+                        format!("{} \"{}\"", syntax.formatter().mnemonic(".ascii"), String::from_utf8_lossy(run))
+                    } else {
+                        directive("db", run.iter().map(|byte| syntax.hex(*byte as u16, 2)).collect())
+                    };
+                    lines.push((offset + start, length, text));
+                }
+            }
+            DataKind::String => lines.push((offset, row_bytes.len(), directive("db", string_values(row_bytes, syntax)))),
+        }
+    }
+    lines
+}
+
+/// The start, length and whether it is text of each run of `bytes` that can go in an `.ascii` string
+fn ascii_runs(bytes: &[u8]) -> Vec<(usize, usize, bool)> {
+    let printable = |byte: &u8| (0x20..0x7f).contains(byte) && *byte != b'"' && *byte != b'\\';
+    let mut runs: Vec<(usize, usize, bool)> = vec![];
+    for (i, byte) in bytes.iter().enumerate() {
+        match runs.last_mut() {
+            Some((_, length, text)) if *text == printable(byte) => *length += 1,
+            _ => runs.push((i, 1, printable(byte))),
+        }
+    }
+    runs
+}
+
+/// Runs of printable characters are quoted, and the other bytes are numbers
+fn string_values(bytes: &[u8], syntax: Syntax) -> Vec<String> {
+    let mut values = vec![];
+    let mut text = String::new();
+    for byte in bytes {
+        if (0x20..0x7f).contains(byte) && *byte != b'\'' {
+            text.push(*byte as char);
+            continue;
+        }
+        if !text.is_empty() {
+            values.push(format!("'{}'", std::mem::take(&mut text)));
+        }
+        values.push(syntax.hex(*byte as u16, 2));
+    }
+    if !text.is_empty() {
+        values.push(format!("'{text}'"));
+    }
+    values
+}
+
+/// Prints an opcode map: `##` decodes, `pf` is a prefix, `g` + count is a group with that many of its
+/// eight reg field values mapped, and `..` is unmapped
+fn print_coverage(model: CpuModel, mode: DecodeMode) {
+    let coverage = Decoder::with_mode(model, mode).coverage();
+
+    println!("    {}", (0..16).map(|lo| format!("_{lo:x}")).collect::<Vec<_>>().join(" "));
+    for hi in 0..16 {
+        let row: Vec<&str> = (0..16).map(|lo| coverage_marker(coverage[hi * 16 + lo])).collect();
+        println!("{hi:x}_  {}", row.join(" "));
+    }
+
+    let unmapped: Vec<String> = (0..256).filter(|b| coverage[*b] == OpCodeCoverage::Unmapped).map(|b| format!("{b:#04x}")).collect();
+    let partial: Vec<String> = (0..256)
+        .filter_map(|b| match coverage[b] {
+            OpCodeCoverage::Group { reg_fields } if reg_fields != 0xff => {
+                let missing: Vec<String> = (0..8).filter(|reg| reg_fields & 1 << reg == 0).map(|reg| format!("/{reg}")).collect();
+                Some(format!("{b:#04x} {}", missing.join(" ")))
+            }
+            _ => None,
+        })
+        .collect();
+
+    println!();
+    println!("mapped: {}/256", 256 - unmapped.len());
+    println!("unmapped: {}", unmapped.join(", "));
+    println!("groups missing reg fields: {}", partial.join(", "));
+}
+
+fn coverage_marker(coverage: OpCodeCoverage) -> &'static str {
+    const GROUP_MARKERS: [&str; 9] = ["g0", "g1", "g2", "g3", "g4", "g5", "g6", "g7", "##"];
+    match coverage {
+        OpCodeCoverage::Unmapped => "..",
+        OpCodeCoverage::Prefix => "pf",
+        OpCodeCoverage::Mapped => "##",
+        OpCodeCoverage::Group { reg_fields } => GROUP_MARKERS[reg_fields.count_ones() as usize],
+    }
+}
+
+/// Labels and symbols in the source
+struct SourceNames<'a> {
+    chunk: &'a Chunk,
+    jump_table: &'a HashMap<usize, String>,
+    symbols: &'a BTreeMap<usize, String>,
+    here: &'static str,
+}
+
+impl Names for SourceNames<'_> {
+    fn jump(&self, disp: i16) -> String {
+        to_label(disp, self.chunk, self.jump_table, self.here)
+    }
+
+    fn address(&self, address: u16) -> Option<String> {
+        self.symbols.get(&(address as usize)).cloned()
+    }
+}
+
+/// Symbols in a listing, where other jump targets are absolute addresses
+struct ListingNames<'a> {
+    next_address: usize,
+    symbols: &'a BTreeMap<usize, String>,
+}
+
+impl Names for ListingNames<'_> {
+    fn jump(&self, disp: i16) -> String {
+        let target = jump_target(self.next_address, disp);
+        self.symbols.get(&target).cloned().unwrap_or_else(|| format!("{target:#06x}"))
+    }
+
+    fn address(&self, address: u16) -> Option<String> {
+        self.symbols.get(&(address as usize)).cloned()
+    }
+}
+
+/// Targets without a label, in the middle of an instruction or outside the file, are written as an
+/// offset from the start of the jump, `here`, which reassembles to the same displacement
+fn to_label(disp: i16, chunk: &Chunk, jump_table: &HashMap<usize, String>, here: &str) -> String {
+    if let Some(label) = jump_table.get(&jump_target(chunk.next_address(), disp)) {
+        return label.clone();
+    }
+    match chunk.length() as i64 + disp as i64 {
+        offset if offset < 0 => format!("{here} - {}", -offset),
+        offset => format!("{here} + {offset}"),
     }
 }
@@ -2,35 +2,68 @@ use std::{env, error::Error, fmt::Debug, fs, path::Path};
 use std::fs::File;
 
 use sim8086::{
-    decoder::Decoder,
+    decoder::{CpuModel, DecodeError, DecodeMode, Decoder},
     flag_registers::Flags,
-    ops::{ArithmeticOp, Instruction, OpWidth, Register, RegisterAccess, SegmentRegister, RegOrMem},
+    ops::{AdjustOp, ArithmeticOp, Instruction, OpWidth, Register, RegisterAccess, SegmentRegister, RegOrMem, ShiftCount, ShiftOp, MultiplyDivideOp, RepeatPrefix, StringOp},
 };
+use sim8086::clocks::Clocks;
+use sim8086::image::{Format, Image};
 use sim8086::memory::Memory;
-use sim8086::ops::{Direction, EffectiveAddress, EffectiveAddressBase};
+use sim8086::ops::{Direction, Displacement, EffectiveAddress, EffectiveAddressBase};
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    let model = match take_cpu_model(&mut args) {
+        Ok(model) => model,
+        Err(e) => {
+            println!("{e}");
+            std::process::exit(1);
+        }
+    };
+    let mode = take_decode_mode(&mut args);
 
     if args.len() < 2 {
         std::process::exit(1);
     }
 
     let assembly_file = &args[1];
-    match simulate(assembly_file) {
+    match simulate(assembly_file, model, mode) {
         Ok(_) => println!("OK"),
-        Err(_) => println!("Err"),
+        Err(e) => println!("Err: {e}"),
+    };
+}
+
+/// Removes `--cpu <model>` from the arguments, defaulting to the 8086
+fn take_cpu_model(args: &mut Vec<String>) -> Result<CpuModel, String> {
+    let Some(i) = args.iter().position(|arg| arg == "--cpu") else {
+        return Ok(CpuModel::default());
     };
+    let model = args.get(i + 1).ok_or("--cpu needs a model")?.parse()?;
+    args.drain(i..i + 2);
+    Ok(model)
+}
+
+/// Removes `--permissive` from the arguments, which makes undocumented opcodes decode
+fn take_decode_mode(args: &mut Vec<String>) -> DecodeMode {
+    match args.iter().position(|arg| arg == "--permissive") {
+        Some(i) => {
+            args.remove(i);
+            DecodeMode::Permissive
+        }
+        None => DecodeMode::Strict,
+    }
 }
 
 #[derive(Debug)]
 struct CpuState {
     registers: Registers,
+    halted: bool,
+    model: CpuModel,
     // memory: Memory
 }
 
 impl CpuState {
-        fn new() -> CpuState {
+        fn new(model: CpuModel) -> CpuState {
         CpuState {
             registers: Registers {
                 regs: [0i16; 8],
@@ -38,6 +71,8 @@ impl CpuState {
                 ip: 0,
                 flags: Flags::empty(),
             },
+            halted: false,
+            model,
         }
     }
 }
@@ -51,7 +86,7 @@ struct Registers {
 }
 
 fn print_reg(name: &str, old: i16, new: i16) {
-    print!("{}:0x{:x}->0x{:x}", name, old, new);
+    print!("{}:0x{:x}->0x{:x} ", name, old, new);
 }
 
 impl Registers {
@@ -68,6 +103,18 @@ impl Registers {
         self.read_reg(RegisterAccess { reg: Register::C, width: OpWidth::Word, offset: 0 })
     }
 
+    fn set_sp(&mut self, value: i16) {
+        self.write_reg(value, RegisterAccess { reg: Register::Sp, width: OpWidth::Word, offset: 0 });
+    }
+
+    fn set_si(&mut self, value: i16) {
+        self.write_reg(value, RegisterAccess { reg: Register::Si, width: OpWidth::Word, offset: 0 });
+    }
+
+    fn set_di(&mut self, value: i16) {
+        self.write_reg(value, RegisterAccess { reg: Register::Di, width: OpWidth::Word, offset: 0 });
+    }
+
     fn set_cx(&mut self, value: i16) {
         self.write_reg(value, RegisterAccess { reg: Register::C, width: OpWidth::Word, offset: 0 });
     }
@@ -165,8 +212,7 @@ impl Registers {
                     _ => panic!("impossible"),
                 },
                 OpWidth::Byte => {
-                    assert!(value <= i8::MAX as i16);
-                    assert!(value >= i8::MIN as i16);
+                    let value = value & 0xFF;
 
                     let original: i16 = match reg.reg {
                         A => self.regs[0],
@@ -237,39 +283,62 @@ impl Registers {
     }
 }
 
-fn simulate<P: AsRef<Path>>(path: P) -> Result<(), Box<dyn Error>> {
-    let decoder = Decoder::new();
+fn simulate<P: AsRef<Path>>(path: P, model: CpuModel, mode: DecodeMode) -> Result<(), Box<dyn Error>> {
+    let decoder = Decoder::with_mode(model, mode);
 
     let path = path.as_ref();
     let bytes = fs::read(path)?;
-    let program_length = bytes.len();
 
-    let mut state = CpuState::new();
+    let mut state = CpuState::new(model);
     let mut memory = Memory::new();
-    memory.copy_from_slice(&bytes, 0);
-
-    let mut total_clocks = 0;
+    let image = Image::load(Format::detect(path, &bytes), &bytes, &mut memory)?;
+    state.registers.seg_regs = [image.es as i16, image.cs as i16, image.ss as i16, image.ds as i16];
+    state.registers.regs[4] = image.sp as i16;
+    state.registers.ip = image.ip as usize;
+
+    // running off the program, or back into the PSP as returning from a .COM file does, ends it
+    // a multiply or divide takes a range of clocks, so the total is a range too
+    let mut total_clocks = (0, 0);
     loop {
-        let ip_before = state.registers.ip;
-        let instruction = {
-            let mut iter = memory.iter(state.registers.ip, program_length).enumerate().peekable();
-            let instruction = decoder.decode_next(&mut iter.by_ref().map(|(_i, byte)| byte));
-            let instruction_len = iter.peek().map(|(i, _u)| *i).unwrap_or(program_length - ip_before);
-            state.registers.ip += instruction_len;
+        let fetch_address = physical_address(state.registers.read_seg_reg(SegmentRegister::Cs), state.registers.ip as i16);
+        if !(image.start..image.end).contains(&fetch_address) {
+            break;
+        }
 
-            if instruction.is_none() {
-                break;
+        let instruction = match decoder.decode_at(memory.slice(0, image.end), fetch_address) {
+            Some(Ok(decoded)) => decoded,
+            // the 8086 has no invalid opcode exception, later models trap to interrupt 6 with IP at the opcode
+            Some(Err(DecodeError::UnknownOpcode { .. } | DecodeError::UnsupportedModRm { .. })) if model >= CpuModel::I80186 => {
+                print!("invalid opcode ; ");
+                interrupt(&mut state, &mut memory, 6);
+                println!();
+                continue;
             }
-
-            instruction.unwrap()
+            Some(Err(e)) => return Err(e.into()),
+            None => break,
         };
+        state.registers.ip += instruction.length;
+        let (instruction, length) = (instruction.instruction, instruction.length);
 
         print!("{:<20} ; ", instruction.encode(|disp| format!("{disp}")));
-        let current_clocks = estimate_clocks(&instruction);
-        total_clocks += current_clocks;
-        print!(" Clocks {:+} = {} | ", current_clocks, total_clocks);
-        simulate_instruction(&mut state, &mut memory, instruction);
-        println!();
+        let clocks = Clocks::of(&instruction);
+        let next_ip = state.registers.ip;
+        let cl = state.registers.read_reg(RegisterAccess::new(Register::C, OpWidth::Byte, 0));
+        let cx = state.registers.cx();
+        simulate_instruction(&mut state, &mut memory, instruction, length);
+        let repetitions = cx.wrapping_sub(state.registers.cx()) as u16 as usize;
+        let current_clocks = clocks.run(state.registers.ip != next_ip, cl as u8 as usize, repetitions);
+        total_clocks = (total_clocks.0 + current_clocks.0, total_clocks.1 + current_clocks.1);
+        // printed after the changes, as jumps taken and repetitions are only known once it has run
+        if current_clocks.0 == current_clocks.1 && total_clocks.0 == total_clocks.1 {
+            println!(" | Clocks {:+} = {}", current_clocks.0, total_clocks.0);
+        } else {
+            println!(" | Clocks +{}-{} = {}-{}", current_clocks.0, current_clocks.1, total_clocks.0, total_clocks.1);
+        }
+
+        if state.halted {
+            break;
+        }
     }
     println!();
     println!("Final registers:");
@@ -299,104 +368,6 @@ fn simulate<P: AsRef<Path>>(path: P) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-fn estimate_ea(effective_address: &EffectiveAddress, transfers: usize) -> usize {
-    let displacement = effective_address.displacement != 0;
-    if !displacement {
-        match effective_address.base {
-            EffectiveAddressBase::Direct => {6} //should never happen, but let's keep it
-            EffectiveAddressBase::BxPlusSi => {7}
-            EffectiveAddressBase::BxPlusDi => {8}
-            EffectiveAddressBase::BpPlusSi => {8}
-            EffectiveAddressBase::BpPlusDi => {7}
-            EffectiveAddressBase::Si => {5}
-            EffectiveAddressBase::Di => {5}
-            EffectiveAddressBase::Bp => {5}
-            EffectiveAddressBase::Bx => {5}
-        }
-    } else {
-        let odd = effective_address.displacement % 2 == 1;
-        let penalty = if odd { 4 * transfers } else  {0};
-        let ea = match effective_address.base {
-            EffectiveAddressBase::Direct => {6}
-            EffectiveAddressBase::BxPlusSi => {11}
-            EffectiveAddressBase::BxPlusDi => {12}
-            EffectiveAddressBase::BpPlusSi => {12}
-            EffectiveAddressBase::BpPlusDi => {11}
-            EffectiveAddressBase::Si => {9}
-            EffectiveAddressBase::Di => {9}
-            EffectiveAddressBase::Bp => {9}
-            EffectiveAddressBase::Bx => {9}
-        };
-        ea + penalty
-    }
-}
-
-fn estimate_clocks(instruction: &Instruction) -> usize {
-    match instruction {
-        Instruction::MovToFromRegMem { dir, reg_or_mem, .. } => {
-            match reg_or_mem {
-                RegOrMem::Reg(_) => {2}
-                RegOrMem::Mem(ea) => {
-                    match dir {
-                        Direction::ToRegister => {8 + estimate_ea(ea, 1) }
-                        Direction::FromRegister => { 9 + estimate_ea(ea, 1)}
-                    }
-                }
-            }
-        }
-        Instruction::ImmediateMovRegMem { reg_or_mem, .. } => {
-            match reg_or_mem {
-                RegOrMem::Reg(_) => {4}
-                RegOrMem::Mem(ea) => {
-                    10 + estimate_ea(ea, 1)
-                }
-            }
-        }
-        Instruction::ImmediateMovReg { .. } => {4}
-        Instruction::AccumulatorMove { .. } => {10}
-        Instruction::SegmentRegisterMove { .. } => {todo!()}
-        Instruction::ArithmeticFromToRegMem { dir, reg_or_mem, .. } => {
-            match reg_or_mem {
-                RegOrMem::Reg(_) => {3}
-                RegOrMem::Mem(ea) => {
-                    match dir {
-                        Direction::ToRegister => {9 + estimate_ea(ea, 1)}
-                        Direction::FromRegister => {16 + estimate_ea(ea, 2)}
-                    }
-                }
-            }
-        }
-        Instruction::ArithmeticImmediateToRegMem { reg_or_mem, .. } => {
-            //TODO adjust for op
-            match reg_or_mem {
-                RegOrMem::Reg(_) => {4}
-                RegOrMem::Mem(ea) => {17 + estimate_ea(ea, 2)}
-            }
-        }
-        Instruction::ArithmeticImmediateToAccumulator { .. } => {4}
-        Instruction::JumpOnEqual(_) => {16}
-        Instruction::JumpOnLess(_) => {16}
-        Instruction::JumpOnNotGreater(_) => {16}
-        Instruction::JumpOnBelow(_) => {16}
-        Instruction::JumpOnNotAbove(_) => {16}
-        Instruction::JumpOnParity(_) => {16}
-        Instruction::JumpOnOverflow(_) => {16}
-        Instruction::JumpOnSign(_) => {16}
-        Instruction::JumpOnNotEqual(_) => {16}
-        Instruction::JumpOnNotLess(_) => {16}
-        Instruction::JumpOnGreater(_) => {16}
-        Instruction::JumpOnNotBelow(_) => {16}
-        Instruction::JumpOnAbove(_) => {16}
-        Instruction::JumpOnNoParity(_) => {16}
-        Instruction::JumpOnNoOverflow(_) => {16}
-        Instruction::JumpOnNotSign(_) => {16}
-        Instruction::Loop(_) => {16}
-        Instruction::LoopWhileEqual(_) => {16}
-        Instruction::LoopWhileNotEqual(_) => {16}
-        Instruction::JumpOnCxZero(_) => {16}
-    }
-}
-
 fn print_register(name: &str, value: i16) {
     if value == 0 { return; }
     let value = value as u16;
@@ -405,43 +376,67 @@ fn print_register(name: &str, value: i16) {
 
 fn calculate_address(ea: EffectiveAddress, state: &CpuState) -> i16 {
     if matches!(ea.base, EffectiveAddressBase::Direct) {
-        return ea.displacement;
+        return ea.displacement.value();
     }
 
     let base = match ea.base {
         EffectiveAddressBase::Direct => { panic!() }
-        EffectiveAddressBase::BxPlusSi => {state.registers.bx() + state.registers.si()}
-        EffectiveAddressBase::BxPlusDi => {state.registers.bx() + state.registers.di()}
-        EffectiveAddressBase::BpPlusSi => {state.registers.bp() + state.registers.si()}
-        EffectiveAddressBase::BpPlusDi => {state.registers.bp() + state.registers.di()}
+        EffectiveAddressBase::BxPlusSi => {state.registers.bx().wrapping_add(state.registers.si())}
+        EffectiveAddressBase::BxPlusDi => {state.registers.bx().wrapping_add(state.registers.di())}
+        EffectiveAddressBase::BpPlusSi => {state.registers.bp().wrapping_add(state.registers.si())}
+        EffectiveAddressBase::BpPlusDi => {state.registers.bp().wrapping_add(state.registers.di())}
         EffectiveAddressBase::Si => {state.registers.si()}
         EffectiveAddressBase::Di => {state.registers.di()}
         EffectiveAddressBase::Bp => {state.registers.bp()}
         EffectiveAddressBase::Bx => {state.registers.bx()}
     };
 
-    base + ea.displacement
+    base.wrapping_add(ea.displacement.value())
+}
+
+/// Addresses based on BP live on the stack, everything else in the data segment, unless overridden
+fn segment_for(ea: EffectiveAddress) -> SegmentRegister {
+    if let Some(segment) = ea.segment {
+        return segment;
+    }
+
+    match ea.base {
+        EffectiveAddressBase::BpPlusSi | EffectiveAddressBase::BpPlusDi | EffectiveAddressBase::Bp => SegmentRegister::Ss,
+        _ => SegmentRegister::Ds,
+    }
+}
+
+fn physical_address(segment: i16, offset: i16) -> usize {
+    (((segment as u16 as usize) << 4) + offset as u16 as usize) & 0xF_FFFF
+}
+
+fn effective_physical_address(ea: EffectiveAddress, state: &CpuState) -> usize {
+    let segment = state.registers.read_seg_reg(segment_for(ea));
+    physical_address(segment, calculate_address(ea, state))
 }
 
 fn read_mem(mem: &Memory, state: &CpuState, effective_address: EffectiveAddress, width: OpWidth) -> i16 {
-    let address = calculate_address(effective_address, state);
-    if address < 0 { panic!(); }
+    let address = effective_physical_address(effective_address, state);
+    read_mem_at(mem, address, width)
+}
 
+fn read_mem_at(mem: &Memory, address: usize, width: OpWidth) -> i16 {
     match width {
-        OpWidth::Byte => {*mem.get(address as usize).unwrap() as i16}
+        OpWidth::Byte => {*mem.get(address).unwrap() as i16}
         OpWidth::Word => {
-            let lo = *mem.get(address as usize).unwrap();
-            let hi = *mem.get((address as usize) + 1).unwrap();
+            let lo = *mem.get(address).unwrap();
+            let hi = *mem.get((address + 1) & 0xF_FFFF).unwrap();
             i16::from_le_bytes([lo, hi])
         }
     }
 }
 
 fn write_mem(value: i16, mem: &mut Memory, state: &CpuState, effective_address: EffectiveAddress, width: OpWidth) {
-    let address = calculate_address(effective_address, state);
-    if address < 0 { panic!(); }
-    let address = address as usize;
+    let address = effective_physical_address(effective_address, state);
+    write_mem_at(value, mem, address, width)
+}
 
+fn write_mem_at(value: i16, mem: &mut Memory, address: usize, width: OpWidth) {
     match width {
         OpWidth::Byte => {
             let value = value as u8;
@@ -450,15 +445,62 @@ fn write_mem(value: i16, mem: &mut Memory, state: &CpuState, effective_address:
         OpWidth::Word => {
             let le_bytes = value.to_le_bytes();
             mem.set(le_bytes[0], address);
-            mem.set(le_bytes[1], address + 1);
+            mem.set(le_bytes[1], (address + 1) & 0xF_FFFF);
         }
     }
 }
 
-fn simulate_instruction(state: &mut CpuState, memory: &mut Memory, instruction: Instruction) {
+fn read_reg_or_mem(memory: &Memory, state: &CpuState, reg_or_mem: RegOrMem, width: OpWidth) -> i16 {
+    match reg_or_mem {
+        RegOrMem::Mem(ea) => read_mem(memory, state, ea, width),
+        RegOrMem::Reg(reg_access) => state.registers.read_reg(reg_access),
+    }
+}
+
+fn write_reg_or_mem(value: i16, memory: &mut Memory, state: &mut CpuState, reg_or_mem: RegOrMem, width: OpWidth) {
+    match reg_or_mem {
+        RegOrMem::Mem(ea) => write_mem(value, memory, state, ea, width),
+        RegOrMem::Reg(reg_access) => state.registers.write_reg(value, reg_access),
+    }
+}
+
+fn push(value: i16, state: &mut CpuState, memory: &mut Memory) {
+    let sp = state.registers.sp().wrapping_sub(2);
+    state.registers.set_sp(sp);
+    let ss = state.registers.read_seg_reg(SegmentRegister::Ss);
+    write_mem_at(value, memory, physical_address(ss, sp), OpWidth::Word);
+}
+
+fn pop(state: &mut CpuState, memory: &Memory) -> i16 {
+    let sp = state.registers.sp();
+    let ss = state.registers.read_seg_reg(SegmentRegister::Ss);
+    let value = read_mem_at(memory, physical_address(ss, sp), OpWidth::Word);
+    state.registers.set_sp(sp.wrapping_add(2));
+    value
+}
+
+// no devices are attached, so reads see a floating bus and writes go nowhere
+fn read_port(_port: u16, _width: OpWidth) -> i16 {
+    -1
+}
+
+fn write_port(_port: u16, _value: i16, _width: OpWidth) {}
+
+fn set_flags(state: &mut CpuState, flags: Flags) {
+    print!(" flags:{}->{}", state.registers.flags, flags);
+    state.registers.flags = flags;
+}
+
+/// Flags that LAHF and SAHF transfer through AH
+fn low_flags() -> Flags {
+    Flags::Sign | Flags::Zero | Flags::AuxiliaryCarry | Flags::Parity | Flags::Carry
+}
+
+/// IP has already been moved past the instruction, which is `length` bytes long
+fn simulate_instruction(state: &mut CpuState, memory: &mut Memory, instruction: Instruction, length: usize) {
     match instruction {
         Instruction::ImmediateMovReg { reg, data } => {
-            state.registers.write_reg(data, reg);
+            state.registers.write_reg(data.value(), reg);
         }
         Instruction::ImmediateMovRegMem {
             width,
@@ -467,10 +509,21 @@ fn simulate_instruction(state: &mut CpuState, memory: &mut Memory, instruction:
         } => {
             match reg_or_mem {
                 RegOrMem::Mem(ea) => {
-                    write_mem(data, memory, state, ea, width)
+                    write_mem(data.value(), memory, state, ea, width)
                 },
                 RegOrMem::Reg(access) => {
-                    state.registers.write_reg(data, access);
+                    state.registers.write_reg(data.value(), access);
+                }
+            }
+        }
+        Instruction::AccumulatorMove { dir, width, addr, segment } => {
+            let ea = EffectiveAddress { segment, ..EffectiveAddress::direct(addr) };
+            let accumulator = RegisterAccess::new(Register::A, width, 0);
+            match dir {
+                Direction::FromRegister => write_mem(state.registers.read_reg(accumulator), memory, state, ea, width),
+                Direction::ToRegister => {
+                    let value = read_mem(memory, state, ea, width);
+                    state.registers.write_reg(value, accumulator);
                 }
             }
         }
@@ -497,7 +550,16 @@ fn simulate_instruction(state: &mut CpuState, memory: &mut Memory, instruction:
             },
         },
         Instruction::SegmentRegisterMove { dir, seg_reg, reg_or_mem } => match reg_or_mem {
-            RegOrMem::Mem(_) => todo!(),
+            RegOrMem::Mem(ea) => match dir {
+                Direction::FromRegister => {
+                    let value = state.registers.read_seg_reg(seg_reg);
+                    write_mem(value, memory, state, ea, OpWidth::Word);
+                }
+                Direction::ToRegister => {
+                    let value = read_mem(memory, state, ea, OpWidth::Word);
+                    state.registers.write_seg_reg(seg_reg, value);
+                }
+            },
             RegOrMem::Reg(reg_access) => match dir {
                 Direction::FromRegister => {
                     let value = state.registers.read_seg_reg(seg_reg);
@@ -514,7 +576,7 @@ fn simulate_instruction(state: &mut CpuState, memory: &mut Memory, instruction:
                 Direction::ToRegister => {
                     let one = state.registers.read_reg(reg);
                     let two = read_mem(memory, state, ea, width);
-                    let (result, flags) = evaluate_op(op, one, two);
+                    let (result, flags) = evaluate_op(op, width, one, two, state.registers.flags.contains(Flags::Carry));
                     if store_result(op) {
                         state.registers.write_reg(result, reg);
                     }
@@ -523,7 +585,7 @@ fn simulate_instruction(state: &mut CpuState, memory: &mut Memory, instruction:
                 Direction::FromRegister => {
                     let one = read_mem(memory, state, ea, width);
                     let two = state.registers.read_reg(reg);
-                    let (result, flags) = evaluate_op(op, one, two);
+                    let (result, flags) = evaluate_op(op, width, one, two, state.registers.flags.contains(Flags::Carry));
                     if store_result(op) {
                         write_mem(result, memory, state, ea, width);
                     }
@@ -534,7 +596,7 @@ fn simulate_instruction(state: &mut CpuState, memory: &mut Memory, instruction:
                 Direction::FromRegister => {
                     let one = state.registers.read_reg(reg_access);
                     let two = state.registers.read_reg(reg);
-                    let (result, flags) = evaluate_op(op, one, two);
+                    let (result, flags) = evaluate_op(op, width, one, two, state.registers.flags.contains(Flags::Carry));
                     if store_result(op) {
                         state.registers.write_reg(result, reg_access);
                     }
@@ -543,7 +605,7 @@ fn simulate_instruction(state: &mut CpuState, memory: &mut Memory, instruction:
                 Direction::ToRegister => {
                     let one = state.registers.read_reg(reg);
                     let two = state.registers.read_reg(reg_access);
-                    let (result, flags) = evaluate_op(op, one, two);
+                    let (result, flags) = evaluate_op(op, width, one, two, state.registers.flags.contains(Flags::Carry));
                     if store_result(op) {
                         state.registers.write_reg(result, reg);
                     }
@@ -554,8 +616,8 @@ fn simulate_instruction(state: &mut CpuState, memory: &mut Memory, instruction:
         Instruction::ArithmeticImmediateToRegMem { op, width, data, reg_or_mem } => match reg_or_mem {
             RegOrMem::Mem(ea) => {
                 let one = read_mem(memory, state, ea, width);
-                let two = data;
-                let (result, flags) = evaluate_op(op, one, two);
+                let two = data.value();
+                let (result, flags) = evaluate_op(op, width, one, two, state.registers.flags.contains(Flags::Carry));
                 if store_result(op) {
                     write_mem(result, memory, state, ea, width);
                 }
@@ -563,8 +625,8 @@ fn simulate_instruction(state: &mut CpuState, memory: &mut Memory, instruction:
             },
             RegOrMem::Reg(reg_access) => {
                 let one = state.registers.read_reg(reg_access);
-                let two = data;
-                let (result, flags) = evaluate_op(op, one, two);
+                let two = data.value();
+                let (result, flags) = evaluate_op(op, width, one, two, state.registers.flags.contains(Flags::Carry));
                 if store_result(op) {
                     state.registers.write_reg(result, reg_access);
                 }
@@ -573,86 +635,813 @@ fn simulate_instruction(state: &mut CpuState, memory: &mut Memory, instruction:
         },
         Instruction::JumpOnEqual(offset) => {
             if state.registers.flags.contains(Flags::Zero) {
-                state.registers.ip = state.registers.ip.overflowing_add_signed(offset as isize).0;
+                jump_relative(state, offset as i16);
             }
         },
         Instruction::JumpOnNotEqual(offset) => {
             if !state.registers.flags.contains(Flags::Zero) {
-                state.registers.ip = state.registers.ip.overflowing_add_signed(offset as isize).0;
+                jump_relative(state, offset as i16);
+            }
+        },
+        Instruction::JumpOnLess(offset) => {
+            if state.registers.flags.contains(Flags::Sign) != state.registers.flags.contains(Flags::Overflow) {
+                jump_relative(state, offset as i16);
+            }
+        },
+        Instruction::JumpOnNotLess(offset) => {
+            if state.registers.flags.contains(Flags::Sign) == state.registers.flags.contains(Flags::Overflow) {
+                jump_relative(state, offset as i16);
+            }
+        },
+        Instruction::JumpOnGreater(offset) => {
+            if !state.registers.flags.contains(Flags::Zero) && state.registers.flags.contains(Flags::Sign) == state.registers.flags.contains(Flags::Overflow) {
+                jump_relative(state, offset as i16);
+            }
+        },
+        Instruction::JumpOnNotGreater(offset) => {
+            if state.registers.flags.contains(Flags::Zero) || state.registers.flags.contains(Flags::Sign) != state.registers.flags.contains(Flags::Overflow) {
+                jump_relative(state, offset as i16);
+            }
+        },
+        Instruction::JumpOnBelow(offset) => {
+            if state.registers.flags.contains(Flags::Carry) {
+                jump_relative(state, offset as i16);
+            }
+        },
+        Instruction::JumpOnNotBelow(offset) => {
+            if !state.registers.flags.contains(Flags::Carry) {
+                jump_relative(state, offset as i16);
+            }
+        },
+        Instruction::JumpOnAbove(offset) => {
+            if !state.registers.flags.contains(Flags::Carry) && !state.registers.flags.contains(Flags::Zero) {
+                jump_relative(state, offset as i16);
+            }
+        },
+        Instruction::JumpOnNotAbove(offset) => {
+            if state.registers.flags.contains(Flags::Carry) || state.registers.flags.contains(Flags::Zero) {
+                jump_relative(state, offset as i16);
+            }
+        },
+        Instruction::JumpOnParity(offset) => {
+            if state.registers.flags.contains(Flags::Parity) {
+                jump_relative(state, offset as i16);
+            }
+        },
+        Instruction::JumpOnNoParity(offset) => {
+            if !state.registers.flags.contains(Flags::Parity) {
+                jump_relative(state, offset as i16);
+            }
+        },
+        Instruction::JumpOnOverflow(offset) => {
+            if state.registers.flags.contains(Flags::Overflow) {
+                jump_relative(state, offset as i16);
+            }
+        },
+        Instruction::JumpOnNoOverflow(offset) => {
+            if !state.registers.flags.contains(Flags::Overflow) {
+                jump_relative(state, offset as i16);
             }
         },
         Instruction::JumpOnSign(offset) => {
             if state.registers.flags.contains(Flags::Sign) {
-                state.registers.ip = state.registers.ip.overflowing_add_signed(offset as isize).0;
+                jump_relative(state, offset as i16);
             }
         },
         Instruction::JumpOnNotSign(offset) => {
             if !state.registers.flags.contains(Flags::Sign) {
-                state.registers.ip = state.registers.ip.overflowing_add_signed(offset as isize).0;
+                jump_relative(state, offset as i16);
+            }
+        },
+        Instruction::JumpOnCxZero(offset) => {
+            if state.registers.cx() == 0 {
+                jump_relative(state, offset as i16);
             }
         },
         Instruction::Loop(offset) => {
-            let cx = state.registers.cx() - 1;
+            let cx = state.registers.cx().wrapping_sub(1);
             state.registers.set_cx(cx);
             if cx != 0 {
-                let (ip, overflow) = state.registers.ip.overflowing_add_signed(offset as isize);
-                if overflow { panic!(); }
-                state.registers.ip = ip;
+                jump_relative(state, offset as i16);
             }
         },
         Instruction::LoopWhileEqual(offset) => {
-            let cx = state.registers.cx() - 1;
+            let cx = state.registers.cx().wrapping_sub(1);
             state.registers.set_cx(cx);
             if cx != 0 && state.registers.flags.contains(Flags::Zero) {
-                let (ip, overflow) = state.registers.ip.overflowing_add_signed(offset as isize);
-                if overflow { panic!(); }
-                state.registers.ip = ip;
-            };
+                jump_relative(state, offset as i16);
+            }
         },
         Instruction::LoopWhileNotEqual(offset) => {
-            let cx = state.registers.cx() - 1;
+            let cx = state.registers.cx().wrapping_sub(1);
             state.registers.set_cx(cx);
             if cx != 0 && !state.registers.flags.contains(Flags::Zero) {
-                let (ip, overflow) = state.registers.ip.overflowing_add_signed(offset as isize);
-                if overflow { panic!(); }
-                state.registers.ip = ip;
+                jump_relative(state, offset as i16);
+            }
+        },
+        Instruction::PushRegMem { reg_or_mem } => {
+            let value = match reg_or_mem {
+                RegOrMem::Mem(ea) => read_mem(memory, state, ea, OpWidth::Word),
+                RegOrMem::Reg(reg_access) => state.registers.read_reg(reg_access),
             };
+            push(value, state, memory);
+        }
+        Instruction::PushReg { reg } => {
+            let value = state.registers.read_reg(reg);
+            push(value, state, memory);
+        }
+        Instruction::PushSegmentRegister { seg_reg } => {
+            let value = state.registers.read_seg_reg(seg_reg);
+            push(value, state, memory);
+        }
+        Instruction::PopRegMem { reg_or_mem } => {
+            let value = pop(state, memory);
+            match reg_or_mem {
+                RegOrMem::Mem(ea) => write_mem(value, memory, state, ea, OpWidth::Word),
+                RegOrMem::Reg(reg_access) => state.registers.write_reg(value, reg_access),
+            }
+        }
+        Instruction::PopReg { reg } => {
+            let value = pop(state, memory);
+            state.registers.write_reg(value, reg);
+        }
+        Instruction::PopSegmentRegister { seg_reg } => {
+            let value = pop(state, memory);
+            state.registers.write_seg_reg(seg_reg, value);
+        }
+        Instruction::ExchangeRegMemWithReg { reg, reg_or_mem } => {
+            let one = state.registers.read_reg(reg);
+            match reg_or_mem {
+                RegOrMem::Mem(ea) => {
+                    let two = read_mem(memory, state, ea, reg.width);
+                    write_mem(one, memory, state, ea, reg.width);
+                    state.registers.write_reg(two, reg);
+                }
+                RegOrMem::Reg(reg_access) => {
+                    let two = state.registers.read_reg(reg_access);
+                    state.registers.write_reg(one, reg_access);
+                    state.registers.write_reg(two, reg);
+                }
+            }
+        }
+        Instruction::ExchangeRegWithAccumulator { reg } => {
+            let accumulator = RegisterAccess::new(Register::A, OpWidth::Word, 0);
+            let one = state.registers.ax();
+            let two = state.registers.read_reg(reg);
+            state.registers.write_reg(one, reg);
+            state.registers.write_reg(two, accumulator);
+        }
+        Instruction::InFixedPort { width, port } => {
+            let value = read_port(port as u16, width);
+            state.registers.write_reg(value, RegisterAccess::new(Register::A, width, 0));
+        }
+        Instruction::InVariablePort { width } => {
+            let value = read_port(state.registers.dx() as u16, width);
+            state.registers.write_reg(value, RegisterAccess::new(Register::A, width, 0));
+        }
+        Instruction::OutFixedPort { width, port } => {
+            let value = state.registers.read_reg(RegisterAccess::new(Register::A, width, 0));
+            write_port(port as u16, value, width);
+        }
+        Instruction::OutVariablePort { width } => {
+            let value = state.registers.read_reg(RegisterAccess::new(Register::A, width, 0));
+            write_port(state.registers.dx() as u16, value, width);
+        }
+        Instruction::TranslateByte { segment } => {
+            let al = RegisterAccess::new(Register::A, OpWidth::Byte, 0);
+            let offset = state.registers.bx().wrapping_add(state.registers.read_reg(al));
+            let address = physical_address(state.registers.read_seg_reg(segment.unwrap_or(SegmentRegister::Ds)), offset);
+            let value = read_mem_at(memory, address, OpWidth::Byte);
+            state.registers.write_reg(value, al);
+        }
+        Instruction::LoadEffectiveAddress { reg, mem } => {
+            let address = calculate_address(mem, state);
+            state.registers.write_reg(address, reg);
+        }
+        Instruction::LoadPointerToDs { reg, mem } | Instruction::LoadPointerToEs { reg, mem } => {
+            let (offset, segment) = read_far_pointer(memory, state, mem);
+            state.registers.write_reg(offset, reg);
+            match instruction {
+                Instruction::LoadPointerToDs { .. } => state.registers.write_seg_reg(SegmentRegister::Ds, segment),
+                _ => state.registers.write_seg_reg(SegmentRegister::Es, segment),
+            }
+        }
+        Instruction::LoadAhFromFlags => {
+            let ah = (state.registers.flags & low_flags()).bits() as i16;
+            state.registers.write_reg(ah, RegisterAccess::new(Register::A, OpWidth::Byte, 1));
+        }
+        Instruction::StoreAhIntoFlags => {
+            let ah = state.registers.read_reg(RegisterAccess::new(Register::A, OpWidth::Byte, 1));
+            let flags = (state.registers.flags - low_flags()) | (Flags::from_bits_truncate(ah as u16) & low_flags());
+            set_flags(state, flags);
+        }
+        Instruction::PushFlags => {
+            let value = state.registers.flags.bits() as i16;
+            push(value, state, memory);
+        }
+        Instruction::PopFlags => {
+            let value = pop(state, memory);
+            set_flags(state, Flags::from_bits_truncate(value as u16));
+        }
+        Instruction::ArithmeticImmediateToAccumulator { op, width, data } => {
+            let accumulator = RegisterAccess::new(Register::A, width, 0);
+            let one = state.registers.read_reg(accumulator);
+            let (result, flags) = evaluate_op(op, width, one, data.value(), state.registers.flags.contains(Flags::Carry));
+            if store_result(op) {
+                state.registers.write_reg(result, accumulator);
+            }
+            update_flags(state, (result, flags), Flags::arithmetic_flags());
+        }
+        Instruction::TestRegMemWithReg { reg, reg_or_mem } => {
+            let one = read_reg_or_mem(memory, state, reg_or_mem, reg.width);
+            let two = state.registers.read_reg(reg);
+            let result = evaluate_op(ArithmeticOp::And, reg.width, one, two, false);
+            update_flags(state, result, Flags::arithmetic_flags());
+        }
+        Instruction::TestImmediateWithRegMem { width, data, reg_or_mem } => {
+            let one = read_reg_or_mem(memory, state, reg_or_mem, width);
+            let result = evaluate_op(ArithmeticOp::And, width, one, data.value(), false);
+            update_flags(state, result, Flags::arithmetic_flags());
+        }
+        Instruction::TestImmediateWithAccumulator { width, data } => {
+            let one = state.registers.read_reg(RegisterAccess::new(Register::A, width, 0));
+            let result = evaluate_op(ArithmeticOp::And, width, one, data.value(), false);
+            update_flags(state, result, Flags::arithmetic_flags());
+        }
+        Instruction::Not { width, reg_or_mem } => {
+            let value = read_reg_or_mem(memory, state, reg_or_mem, width);
+            write_reg_or_mem(!value, memory, state, reg_or_mem, width);
+        }
+        Instruction::Shift { op, width, count, reg_or_mem } => {
+            let count = match count {
+                ShiftCount::One => 1,
+                ShiftCount::Cl => state.registers.read_reg(RegisterAccess::new(Register::C, OpWidth::Byte, 0)) as u8,
+                ShiftCount::Immediate(count) => count,
+            };
+            // the 80186 and later only use the low five bits of the count
+            let count = if state.model >= CpuModel::I80186 { count & 0b0001_1111 } else { count };
+            if count != 0 {
+                let value = read_reg_or_mem(memory, state, reg_or_mem, width);
+                let (result, flags) = evaluate_shift(op, width, value, count, state.registers.flags.contains(Flags::Carry));
+                write_reg_or_mem(result, memory, state, reg_or_mem, width);
+                update_flags(state, (result, flags), shift_flags(op));
+            }
+        }
+        Instruction::IncrementRegMem { width, reg_or_mem } | Instruction::DecrementRegMem { width, reg_or_mem } => {
+            let op = match instruction {
+                Instruction::IncrementRegMem { .. } => ArithmeticOp::Add,
+                _ => ArithmeticOp::Sub,
+            };
+            let value = read_reg_or_mem(memory, state, reg_or_mem, width);
+            let (result, flags) = evaluate_op(op, width, value, 1, false);
+            write_reg_or_mem(result, memory, state, reg_or_mem, width);
+            update_flags(state, (result, flags), Flags::arithmetic_flags() - Flags::Carry);
+        }
+        Instruction::IncrementReg { reg } | Instruction::DecrementReg { reg } => {
+            let op = match instruction {
+                Instruction::IncrementReg { .. } => ArithmeticOp::Add,
+                _ => ArithmeticOp::Sub,
+            };
+            let value = state.registers.read_reg(reg);
+            let (result, flags) = evaluate_op(op, reg.width, value, 1, false);
+            state.registers.write_reg(result, reg);
+            update_flags(state, (result, flags), Flags::arithmetic_flags() - Flags::Carry);
+        }
+        Instruction::Negate { width, reg_or_mem } => {
+            let value = read_reg_or_mem(memory, state, reg_or_mem, width);
+            let (result, flags) = evaluate_op(ArithmeticOp::Sub, width, 0, value, false);
+            write_reg_or_mem(result, memory, state, reg_or_mem, width);
+            update_flags(state, (result, flags), Flags::arithmetic_flags());
+        }
+        Instruction::MultiplyDivide { op, width, reg_or_mem } => {
+            let operand = read_reg_or_mem(memory, state, reg_or_mem, width);
+            let (low_reg, high_reg) = match width {
+                OpWidth::Byte => (RegisterAccess::new(Register::A, width, 0), RegisterAccess::new(Register::A, width, 1)),
+                OpWidth::Word => (RegisterAccess::new(Register::A, width, 0), RegisterAccess::new(Register::D, width, 0)),
+            };
+            match op {
+                MultiplyDivideOp::Mul | MultiplyDivideOp::Imul => {
+                    let (low, high, flags) = evaluate_multiply(op, width, state.registers.read_reg(low_reg), operand);
+                    state.registers.write_reg(low, low_reg);
+                    state.registers.write_reg(high, high_reg);
+                    update_flags(state, (low, flags), Flags::Carry | Flags::Overflow);
+                }
+                MultiplyDivideOp::Div | MultiplyDivideOp::Idiv => {
+                    let low = state.registers.read_reg(low_reg);
+                    let high = state.registers.read_reg(high_reg);
+                    match evaluate_divide(op, width, high, low, operand, state.model) {
+                        Some((quotient, remainder)) => {
+                            state.registers.write_reg(quotient, low_reg);
+                            state.registers.write_reg(remainder, high_reg);
+                        }
+                        None => interrupt(state, memory, 0),
+                    }
+                }
+            }
+        }
+        Instruction::StringManipulation { op, width, repeat, segment } => match repeat {
+            None => simulate_string(state, memory, op, width, segment),
+            Some(repeat) => {
+                while state.registers.cx() != 0 {
+                    simulate_string(state, memory, op, width, segment);
+                    state.registers.set_cx(state.registers.cx().wrapping_sub(1));
+
+                    if matches!(op, StringOp::Cmps | StringOp::Scas) {
+                        let zero = state.registers.flags.contains(Flags::Zero);
+                        match repeat {
+                            RepeatPrefix::Rep if !zero => break,
+                            RepeatPrefix::Repne if zero => break,
+                            _ => {}
+                        }
+                    }
+                }
+            }
         },
-        _ => todo!(),
+        Instruction::Lock(instruction) => simulate_instruction(state, memory, *instruction, length),
+        Instruction::Prefixed { prefixes, instruction } => {
+            let (instruction, _) = instruction.with_prefixes(&prefixes);
+            simulate_instruction(state, memory, instruction, length)
+        }
+        Instruction::CallDirect(offset) => {
+            push(state.registers.ip as i16, state, memory);
+            jump_relative(state, offset);
+        }
+        Instruction::CallFarDirect { offset, segment } => {
+            push(state.registers.read_seg_reg(SegmentRegister::Cs), state, memory);
+            push(state.registers.ip as i16, state, memory);
+            jump_far(state, offset as i16, segment as i16);
+        }
+        Instruction::CallIndirect { reg_or_mem } => {
+            let target = read_reg_or_mem(memory, state, reg_or_mem, OpWidth::Word);
+            push(state.registers.ip as i16, state, memory);
+            state.registers.ip = target as u16 as usize;
+        }
+        Instruction::CallFarIndirect { mem } => {
+            let (offset, segment) = read_far_pointer(memory, state, mem);
+            push(state.registers.read_seg_reg(SegmentRegister::Cs), state, memory);
+            push(state.registers.ip as i16, state, memory);
+            jump_far(state, offset, segment);
+        }
+        Instruction::JumpDirect(offset) => jump_relative(state, offset),
+        Instruction::JumpShort(offset) => jump_relative(state, offset as i16),
+        Instruction::JumpFarDirect { offset, segment } => jump_far(state, offset as i16, segment as i16),
+        Instruction::JumpIndirect { reg_or_mem } => {
+            let target = read_reg_or_mem(memory, state, reg_or_mem, OpWidth::Word);
+            state.registers.ip = target as u16 as usize;
+        }
+        Instruction::JumpFarIndirect { mem } => {
+            let (offset, segment) = read_far_pointer(memory, state, mem);
+            jump_far(state, offset, segment);
+        }
+        Instruction::Return { pop: count } => {
+            state.registers.ip = pop(state, memory) as u16 as usize;
+            if let Some(count) = count {
+                state.registers.set_sp(state.registers.sp().wrapping_add(count as i16));
+            }
+        }
+        Instruction::ReturnFar { pop: count } => {
+            let offset = pop(state, memory);
+            let segment = pop(state, memory);
+            jump_far(state, offset, segment);
+            if let Some(count) = count {
+                state.registers.set_sp(state.registers.sp().wrapping_add(count as i16));
+            }
+        }
+        Instruction::Interrupt(vector) => interrupt(state, memory, vector),
+        Instruction::Interrupt3 => interrupt(state, memory, 3),
+        Instruction::InterruptOnOverflow => {
+            if state.registers.flags.contains(Flags::Overflow) {
+                interrupt(state, memory, 4);
+            }
+        }
+        Instruction::InterruptReturn => {
+            let offset = pop(state, memory);
+            let segment = pop(state, memory);
+            jump_far(state, offset, segment);
+            let flags = pop(state, memory);
+            set_flags(state, Flags::from_bits_truncate(flags as u16));
+        }
+        Instruction::ClearCarry => set_flags(state, state.registers.flags - Flags::Carry),
+        Instruction::ComplementCarry => set_flags(state, state.registers.flags ^ Flags::Carry),
+        Instruction::SetCarry => set_flags(state, state.registers.flags | Flags::Carry),
+        Instruction::ClearDirection => set_flags(state, state.registers.flags - Flags::Direction),
+        Instruction::SetDirection => set_flags(state, state.registers.flags | Flags::Direction),
+        Instruction::ClearInterrupt => set_flags(state, state.registers.flags - Flags::Interrupt),
+        Instruction::SetInterrupt => set_flags(state, state.registers.flags | Flags::Interrupt),
+        Instruction::Halt => state.halted = true,
+        Instruction::Adjust(op) => {
+            let (ax, flags) = evaluate_adjust(op, state.registers.ax(), state.registers.flags);
+            state.registers.write_reg(ax, RegisterAccess { reg: Register::A, width: OpWidth::Word, offset: 0 });
+            let mask = match op {
+                AdjustOp::Daa | AdjustOp::Das => Flags::Zero | Flags::Sign | Flags::Parity | Flags::Carry | Flags::AuxiliaryCarry,
+                AdjustOp::Aaa | AdjustOp::Aas => Flags::Carry | Flags::AuxiliaryCarry,
+            };
+            update_flags(state, (sign_extend(ax as u16 as u32, OpWidth::Byte), flags), mask);
+        }
+        Instruction::AsciiAdjustMultiply { base } => match evaluate_ascii_adjust_multiply(state.registers.ax(), base) {
+            Some(ax) => {
+                state.registers.write_reg(ax, RegisterAccess { reg: Register::A, width: OpWidth::Word, offset: 0 });
+                update_flags(state, (sign_extend(ax as u16 as u32, OpWidth::Byte), Flags::empty()), Flags::Zero | Flags::Sign | Flags::Parity);
+            }
+            None => interrupt(state, memory, 0),
+        },
+        Instruction::AsciiAdjustDivide { base } => {
+            let ax = evaluate_ascii_adjust_divide(state.registers.ax(), base);
+            state.registers.write_reg(ax, RegisterAccess { reg: Register::A, width: OpWidth::Word, offset: 0 });
+            update_flags(state, (sign_extend(ax as u16 as u32, OpWidth::Byte), Flags::empty()), Flags::Zero | Flags::Sign | Flags::Parity);
+        }
+        Instruction::ConvertByteToWord => {
+            let ax = sign_extend(state.registers.ax() as u16 as u32, OpWidth::Byte);
+            state.registers.write_reg(ax, RegisterAccess { reg: Register::A, width: OpWidth::Word, offset: 0 });
+        }
+        Instruction::ConvertWordToDouble => {
+            let dx = if state.registers.ax() < 0 { -1 } else { 0 };
+            state.registers.write_reg(dx, RegisterAccess { reg: Register::D, width: OpWidth::Word, offset: 0 });
+        }
+        Instruction::SetAlFromCarry => {
+            let al = if state.registers.flags.contains(Flags::Carry) { -1 } else { 0 };
+            state.registers.write_reg(al, RegisterAccess::new(Register::A, OpWidth::Byte, 0));
+        }
+        Instruction::PushImmediate { data } => push(data.value(), state, memory),
+        Instruction::PushAll => {
+            let sp = state.registers.sp();
+            for reg in [Register::A, Register::C, Register::D, Register::B] {
+                push(state.registers.read_reg(RegisterAccess::new(reg, OpWidth::Word, 0)), state, memory);
+            }
+            push(sp, state, memory);
+            for value in [state.registers.bp(), state.registers.si(), state.registers.di()] {
+                push(value, state, memory);
+            }
+        }
+        Instruction::PopAll => {
+            for reg in [Register::Di, Register::Si, Register::Bp, Register::Sp, Register::B, Register::D, Register::C, Register::A] {
+                let value = pop(state, memory);
+                // the saved SP is skipped, SP ends up past the popped block
+                if !matches!(reg, Register::Sp) {
+                    state.registers.write_reg(value, RegisterAccess::new(reg, OpWidth::Word, 0));
+                }
+            }
+        }
+        Instruction::MultiplyImmediate { reg, reg_or_mem, data } => {
+            let operand = read_reg_or_mem(memory, state, reg_or_mem, OpWidth::Word);
+            let (low, _high, flags) = evaluate_multiply(MultiplyDivideOp::Imul, OpWidth::Word, operand, data.value());
+            state.registers.write_reg(low, reg);
+            update_flags(state, (low, flags), Flags::Carry | Flags::Overflow);
+        }
+        Instruction::Enter { size, level } => {
+            push(state.registers.bp(), state, memory);
+            let frame = state.registers.sp();
+            let level = level.0 & 0b0001_1111;
+            if level > 0 {
+                // copy the enclosing frame pointers from the old frame, then add the new one
+                let ss = state.registers.read_seg_reg(SegmentRegister::Ss);
+                let mut bp = state.registers.bp();
+                for _ in 1..level {
+                    bp = bp.wrapping_sub(2);
+                    push(read_mem_at(memory, physical_address(ss, bp), OpWidth::Word), state, memory);
+                }
+                push(frame, state, memory);
+            }
+            state.registers.write_reg(frame, RegisterAccess::new(Register::Bp, OpWidth::Word, 0));
+            state.registers.set_sp(state.registers.sp().wrapping_sub(size.0 as i16));
+        }
+        Instruction::Leave => {
+            state.registers.set_sp(state.registers.bp());
+            let bp = pop(state, memory);
+            state.registers.write_reg(bp, RegisterAccess::new(Register::Bp, OpWidth::Word, 0));
+        }
+        Instruction::Bound { reg, mem } => {
+            let index = state.registers.read_reg(reg);
+            let lower = read_mem(memory, state, mem, OpWidth::Word);
+            let upper = read_mem_at(memory, effective_physical_address(mem, state) + 2, OpWidth::Word);
+            // the 80186 returns to the bound instruction itself, so the check is made again
+            if index < lower || index > upper {
+                state.registers.ip -= length;
+                interrupt(state, memory, 5);
+            }
+        }
+        // there is no coprocessor to wait for or hand escapes to
+        Instruction::Wait | Instruction::Nop | Instruction::Escape { .. } => {}
     }
 }
 
-fn evaluate_op(op: ArithmeticOp, one: i16, two: i16) -> (i16, Flags) {
+/// Near jumps and calls wrap around within the current code segment
+fn jump_relative(state: &mut CpuState, offset: i16) {
+    state.registers.ip = (state.registers.ip as u16).wrapping_add(offset as u16) as usize;
+}
+
+fn jump_far(state: &mut CpuState, offset: i16, segment: i16) {
+    state.registers.write_seg_reg(SegmentRegister::Cs, segment);
+    state.registers.ip = offset as u16 as usize;
+}
+
+/// Reads an offset followed by a segment, as used by LDS, LES and the far indirect jumps
+fn read_far_pointer(memory: &Memory, state: &CpuState, mem: EffectiveAddress) -> (i16, i16) {
+    let offset = read_mem(memory, state, mem, OpWidth::Word);
+    let segment_address = EffectiveAddress { displacement: Displacement::Disp16(mem.displacement.value().wrapping_add(2)), ..mem };
+    let segment = read_mem(memory, state, segment_address, OpWidth::Word);
+    (offset, segment)
+}
+
+/// A single iteration of a string instruction, stepping SI and/or DI according to the direction flag
+fn simulate_string(state: &mut CpuState, memory: &mut Memory, op: StringOp, width: OpWidth, segment: Option<SegmentRegister>) {
+    let step: i16 = match (width, state.registers.flags.contains(Flags::Direction)) {
+        (OpWidth::Byte, false) => 1,
+        (OpWidth::Byte, true) => -1,
+        (OpWidth::Word, false) => 2,
+        (OpWidth::Word, true) => -2,
+    };
+    let accumulator = RegisterAccess::new(Register::A, width, 0);
+    let si = state.registers.si();
+    let di = state.registers.di();
+    // the source segment can be overridden, the destination is always in ES
+    let source = physical_address(state.registers.read_seg_reg(segment.unwrap_or(SegmentRegister::Ds)), si);
+    let destination = physical_address(state.registers.read_seg_reg(SegmentRegister::Es), di);
+
     match op {
-        ArithmeticOp::Add => {
-            let (result, overflow) = one.overflowing_add(two);
+        StringOp::Movs => {
+            let value = read_mem_at(memory, source, width);
+            write_mem_at(value, memory, destination, width);
+        }
+        StringOp::Cmps => {
+            let one = read_mem_at(memory, source, width);
+            let two = read_mem_at(memory, destination, width);
+            let result = evaluate_op(ArithmeticOp::Cmp, width, one, two, false);
+            update_flags(state, result, Flags::arithmetic_flags());
+        }
+        StringOp::Scas => {
+            let one = state.registers.read_reg(accumulator);
+            let two = read_mem_at(memory, destination, width);
+            let result = evaluate_op(ArithmeticOp::Cmp, width, one, two, false);
+            update_flags(state, result, Flags::arithmetic_flags());
+        }
+        StringOp::Lods => {
+            let value = read_mem_at(memory, source, width);
+            state.registers.write_reg(value, accumulator);
+        }
+        StringOp::Stos => {
+            let value = state.registers.read_reg(accumulator);
+            write_mem_at(value, memory, destination, width);
+        }
+        StringOp::Ins => {
+            let value = read_port(state.registers.dx() as u16, width);
+            write_mem_at(value, memory, destination, width);
+        }
+        StringOp::Outs => {
+            let value = read_mem_at(memory, source, width);
+            write_port(state.registers.dx() as u16, value, width);
+        }
+    }
 
-            let mut flags = Flags::empty();
-            if overflow { flags |= Flags::Overflow; }
-            if (result as u16) < (one as u16) { flags |= Flags::Carry }
+    if matches!(op, StringOp::Movs | StringOp::Cmps | StringOp::Lods | StringOp::Outs) {
+        state.registers.set_si(si.wrapping_add(step));
+    }
+    if matches!(op, StringOp::Movs | StringOp::Cmps | StringOp::Scas | StringOp::Stos | StringOp::Ins) {
+        state.registers.set_di(di.wrapping_add(step));
+    }
+}
 
-            let o = (one as u16) & 0xf;
-            let t = (two as u16) & 0xf;
-            let aux_carry = (o + t) >= 16;
-            if aux_carry {flags |= Flags::AuxiliaryCarry}
+fn interrupt(state: &mut CpuState, memory: &mut Memory, vector: u8) {
+    print!(" interrupt {vector} ");
+    push(state.registers.flags.bits() as i16, state, memory);
+    state.registers.flags -= Flags::Interrupt | Flags::Trap;
+    push(state.registers.read_seg_reg(SegmentRegister::Cs), state, memory);
+    push(state.registers.ip as i16, state, memory);
+
+    let entry = vector as usize * 4;
+    let offset = read_mem_at(memory, entry, OpWidth::Word);
+    let segment = read_mem_at(memory, entry + 2, OpWidth::Word);
+    jump_far(state, offset, segment);
+}
+
+/// Mask and sign bit for an operand of the given width
+fn width_bits(width: OpWidth) -> (u32, u32) {
+    match width {
+        OpWidth::Byte => (0xFF, 0x80),
+        OpWidth::Word => (0xFFFF, 0x8000),
+    }
+}
+
+fn sign_extend(value: u32, width: OpWidth) -> i16 {
+    match width {
+        OpWidth::Byte => value as u8 as i8 as i16,
+        OpWidth::Word => value as u16 as i16,
+    }
+}
+
+fn evaluate_op(op: ArithmeticOp, width: OpWidth, one: i16, two: i16, carry: bool) -> (i16, Flags) {
+    let (mask, sign) = width_bits(width);
+    let one = one as u16 as u32 & mask;
+    let two = two as u16 as u32 & mask;
+
+    let mut flags = Flags::empty();
+    let result = match op {
+        ArithmeticOp::Add | ArithmeticOp::Adc => {
+            let carry = matches!(op, ArithmeticOp::Adc) && carry;
+            let sum = one + two + carry as u32;
+            let result = sum & mask;
+
+            if sum > mask { flags |= Flags::Carry }
+            if (one ^ result) & (two ^ result) & sign != 0 { flags |= Flags::Overflow }
+            if (one ^ two ^ result) & 0x10 != 0 { flags |= Flags::AuxiliaryCarry }
+
+            result
+        }
+        ArithmeticOp::Sub | ArithmeticOp::Sbb | ArithmeticOp::Cmp => {
+            let borrow = matches!(op, ArithmeticOp::Sbb) && carry;
+            let result = one.wrapping_sub(two).wrapping_sub(borrow as u32) & mask;
+
+            if one < two + borrow as u32 { flags |= Flags::Carry }
+            if (one ^ two) & (one ^ result) & sign != 0 { flags |= Flags::Overflow }
+            if (one ^ two ^ result) & 0x10 != 0 { flags |= Flags::AuxiliaryCarry }
+
+            result
+        }
+        // logical operations always clear CF and OF, AF is undefined and we leave it cleared
+        ArithmeticOp::Or => one | two,
+        ArithmeticOp::And => one & two,
+        ArithmeticOp::Xor => one ^ two,
+    };
+
+    (sign_extend(result, width), flags)
+}
+
+fn evaluate_shift(op: ShiftOp, width: OpWidth, value: i16, count: u8, carry: bool) -> (i16, Flags) {
+    let (mask, sign) = width_bits(width);
+    let original = value as u16 as u32 & mask;
+
+    // the 8086 does not mask the count, it really shifts up to 255 times
+    let mut value = original;
+    let mut carry = carry;
+    for _ in 0..count {
+        match op {
+            ShiftOp::Rol => {
+                carry = value & sign != 0;
+                value = ((value << 1) | carry as u32) & mask;
+            }
+            ShiftOp::Ror => {
+                carry = value & 1 != 0;
+                value = (value >> 1) | if carry { sign } else { 0 };
+            }
+            ShiftOp::Rcl => {
+                let out = value & sign != 0;
+                value = ((value << 1) | carry as u32) & mask;
+                carry = out;
+            }
+            ShiftOp::Rcr => {
+                let out = value & 1 != 0;
+                value = (value >> 1) | if carry { sign } else { 0 };
+                carry = out;
+            }
+            ShiftOp::Shl => {
+                carry = value & sign != 0;
+                value = (value << 1) & mask;
+            }
+            ShiftOp::Shr => {
+                carry = value & 1 != 0;
+                value >>= 1;
+            }
+            ShiftOp::Sar => {
+                carry = value & 1 != 0;
+                value = (value >> 1) | (value & sign);
+            }
+        }
+    }
+
+    /* From the Intel manual:
+            OF is defined only for the single-bit forms of the
+            instructions; for multi-bit shifts and rotates it is
+            undefined. We compute it the same way regardless. */
+    let overflow = match op {
+        ShiftOp::Rol | ShiftOp::Rcl | ShiftOp::Shl => (value & sign != 0) != carry,
+        ShiftOp::Ror | ShiftOp::Rcr => (value & sign != 0) != (value & (sign >> 1) != 0),
+        ShiftOp::Shr => original & sign != 0,
+        ShiftOp::Sar => false,
+    };
+
+    let mut flags = Flags::empty();
+    if carry { flags |= Flags::Carry }
+    if overflow { flags |= Flags::Overflow }
 
-            (result, flags)
+    (sign_extend(value, width), flags)
+}
+
+/// Returns the low and high half of the product, which go to AL:AH or AX:DX
+fn evaluate_multiply(op: MultiplyDivideOp, width: OpWidth, one: i16, two: i16) -> (i16, i16, Flags) {
+    let (mask, _) = width_bits(width);
+    let bits = mask.count_ones();
+
+    let (product, fits) = match op {
+        MultiplyDivideOp::Imul => {
+            let product = sign_extend(one as u16 as u32 & mask, width) as i32 * sign_extend(two as u16 as u32 & mask, width) as i32;
+            (product as u32, product == sign_extend(product as u32 & mask, width) as i32)
         }
-        ArithmeticOp::Sub | ArithmeticOp::Cmp => {
-            let (result, overflow) = one.overflowing_sub(two);
+        _ => {
+            let product = (one as u16 as u32 & mask) * (two as u16 as u32 & mask);
+            (product, product <= mask)
+        }
+    };
+
+    let mut flags = Flags::empty();
+    if !fits { flags |= Flags::Carry | Flags::Overflow }
 
-            let mut flags = Flags::empty();
-            if overflow { flags |= Flags::Overflow }
-            if (result as u16) > (one as u16) { flags |= Flags::Carry }
+    (sign_extend(product & mask, width), sign_extend((product >> bits) & mask, width), flags)
+}
+
+/// Divides high:low by the divisor, returns None when `model` would raise a divide error
+fn evaluate_divide(op: MultiplyDivideOp, width: OpWidth, high: i16, low: i16, divisor: i16, model: CpuModel) -> Option<(i16, i16)> {
+    let (mask, _) = width_bits(width);
+    let bits = mask.count_ones();
+    let dividend = ((high as u16 as u32 & mask) << bits) | (low as u16 as u32 & mask);
+
+    match op {
+        MultiplyDivideOp::Idiv => {
+            let dividend = match width {
+                OpWidth::Byte => dividend as u16 as i16 as i32,
+                OpWidth::Word => dividend as i32,
+            };
+            let divisor = sign_extend(divisor as u16 as u32 & mask, width) as i32;
+            let quotient = dividend.checked_div(divisor)?;
+            // the 8086 can't produce the most negative quotient, it raises a divide error instead;
+            // the 80186 gives it
+            let max = (mask >> 1) as i32;
+            let min = if model == CpuModel::I8086 { -max } else { -max - 1 };
+            if quotient > max || quotient < min {
+                return None;
+            }
+            Some((quotient as i16, (dividend % divisor) as i16))
+        }
+        _ => {
+            let divisor = divisor as u16 as u32 & mask;
+            let quotient = dividend.checked_div(divisor)?;
+            if quotient > mask {
+                return None;
+            }
+            Some((sign_extend(quotient, width), sign_extend(dividend % divisor, width)))
+        }
+    }
+}
 
-            let o = (one as u16) & 0xf;
-            let t = (two as u16) & 0xf;
-            let (_, aux_carry) = o.overflowing_sub(t);
-            if aux_carry {flags |= Flags::AuxiliaryCarry}
+/// Applies a decimal adjust to AX, returns the new AX with the carry and auxiliary carry it produces
+fn evaluate_adjust(op: AdjustOp, ax: i16, flags: Flags) -> (i16, Flags) {
+    let old_al = ax as u16 & 0xFF;
+    let mut al = old_al;
+    let mut ah = (ax as u16) >> 8;
+    let mut op_flags = Flags::empty();
+    let adjust_low = (al & 0x0F) > 9 || flags.contains(Flags::AuxiliaryCarry);
 
-            (result, flags)
+    match op {
+        AdjustOp::Daa | AdjustOp::Das => {
+            let adjust_high = old_al > 0x99 || flags.contains(Flags::Carry);
+            let add = matches!(op, AdjustOp::Daa);
+            let step = |al: u16, by: u16| if add { al.wrapping_add(by) } else { al.wrapping_sub(by) };
+            if adjust_low {
+                al = step(al, 0x06) & 0xFF;
+                op_flags |= Flags::AuxiliaryCarry;
+            }
+            if adjust_high {
+                al = step(al, 0x60) & 0xFF;
+                op_flags |= Flags::Carry;
+            }
+        }
+        AdjustOp::Aaa | AdjustOp::Aas => {
+            if adjust_low {
+                // the 8086 adjusts AL and AH separately, so AL can't carry into AH
+                if matches!(op, AdjustOp::Aaa) {
+                    al = al.wrapping_add(6);
+                    ah = ah.wrapping_add(1);
+                } else {
+                    al = al.wrapping_sub(6);
+                    ah = ah.wrapping_sub(1);
+                }
+                op_flags |= Flags::Carry | Flags::AuxiliaryCarry;
+            }
+            al &= 0x0F;
         }
-        _ => todo!(),
+    }
+
+    ((((ah & 0xFF) << 8) | al) as i16, op_flags)
+}
+
+/// Splits AL into AH = AL / base and AL = AL % base, returns None for the divide error a zero base raises
+fn evaluate_ascii_adjust_multiply(ax: i16, base: u8) -> Option<i16> {
+    let al = ax as u8;
+    let ah = al.checked_div(base)?;
+    Some(((ah as u16) << 8 | (al % base) as u16) as i16)
+}
+
+/// Combines AH and AL into AL = AH * base + AL, clearing AH
+fn evaluate_ascii_adjust_divide(ax: i16, base: u8) -> i16 {
+    let al = ax as u8;
+    let ah = (ax as u16 >> 8) as u8;
+    ah.wrapping_mul(base).wrapping_add(al) as i16
+}
+
+/// Flags written by a shift or rotate with a non-zero count
+fn shift_flags(op: ShiftOp) -> Flags {
+    match op {
+        ShiftOp::Rol | ShiftOp::Ror | ShiftOp::Rcl | ShiftOp::Rcr => Flags::Carry | Flags::Overflow,
+        ShiftOp::Shl | ShiftOp::Shr | ShiftOp::Sar => Flags::Carry | Flags::Overflow | Flags::Sign | Flags::Zero | Flags::Parity,
     }
 }
 
@@ -689,21 +1478,20 @@ fn update_flags(state: &mut CpuState, result: (i16, Flags), flags: Flags) {
 }
 
 fn store_result(op: ArithmeticOp) -> bool {
-    match op {
-        ArithmeticOp::Cmp => false,
-        _ => true,
-    }
+    !matches!(op, ArithmeticOp::Cmp)
 }
 
 #[cfg(test)]
 mod test {
+    use sim8086::decoder::CpuModel;
     use sim8086::flag_registers::Flags;
-    use sim8086::ops::ArithmeticOp;
-    use crate::evaluate_op;
+    use sim8086::memory::Memory;
+    use sim8086::ops::{AdjustOp, ArithmeticOp, Displacement, EffectiveAddress, EffectiveAddressBase, Imm16, Imm8, Instruction, MultiplyDivideOp, OpWidth, RegOrMem, Register, RegisterAccess, SegmentRegister, ShiftOp};
+    use crate::{evaluate_adjust, evaluate_ascii_adjust_divide, evaluate_ascii_adjust_multiply, evaluate_divide, evaluate_multiply, evaluate_op, evaluate_shift, read_mem_at, simulate_instruction, write_mem_at, CpuState};
 
     #[test]
     fn evaluate_op_add_overflow() {
-        let (result, flags) = evaluate_op(ArithmeticOp::Add, i16::MAX, 1);
+        let (_result, flags) = evaluate_op(ArithmeticOp::Add, OpWidth::Word, i16::MAX, 1, false);
         let s = format!("{}", flags);
         print!("{}", s);
 
@@ -712,7 +1500,7 @@ mod test {
 
     #[test]
     fn evaluate_op_add_carry() {
-        let (result, flags) = evaluate_op(ArithmeticOp::Add, -1, 1);
+        let (_result, flags) = evaluate_op(ArithmeticOp::Add, OpWidth::Word, -1, 1, false);
         let s = format!("{}", flags);
         print!("{}", s);
 
@@ -721,7 +1509,7 @@ mod test {
 
     #[test]
     fn evaluate_op_add_aux_carry() {
-        let (result, flags) = evaluate_op(ArithmeticOp::Add, 10, 10);
+        let (_result, flags) = evaluate_op(ArithmeticOp::Add, OpWidth::Word, 10, 10, false);
         let s = format!("{}", flags);
         print!("{}", s);
 
@@ -730,7 +1518,7 @@ mod test {
 
     #[test]
     fn evaluate_op_sub_overflow() {
-        let (result, flags) = evaluate_op(ArithmeticOp::Sub, i16::MIN, 1);
+        let (_result, flags) = evaluate_op(ArithmeticOp::Sub, OpWidth::Word, i16::MIN, 1, false);
         let s = format!("{}", flags);
         print!("{}", s);
 
@@ -739,7 +1527,7 @@ mod test {
 
     #[test]
     fn evaluate_op_sub_carry() {
-        let (result, flags) = evaluate_op(ArithmeticOp::Sub, 0, 1);
+        let (_result, flags) = evaluate_op(ArithmeticOp::Sub, OpWidth::Word, 0, 1, false);
         let s = format!("{}", flags);
         print!("{}", s);
 
@@ -748,10 +1536,228 @@ mod test {
 
     #[test]
     fn evaluate_op_sub_aux_carry() {
-        let (result, flags) = evaluate_op(ArithmeticOp::Sub, 20, 10);
+        let (_result, flags) = evaluate_op(ArithmeticOp::Sub, OpWidth::Word, 20, 10, false);
         let s = format!("{}", flags);
         print!("{}", s);
 
         assert!(flags.contains(Flags::AuxiliaryCarry));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn evaluate_op_byte_flags() {
+        let (result, flags) = evaluate_op(ArithmeticOp::Add, OpWidth::Byte, 0x7f, 1, false);
+        assert_eq!(result, -128);
+        assert!(flags.contains(Flags::Overflow));
+        assert!(!flags.contains(Flags::Carry));
+
+        let (result, flags) = evaluate_op(ArithmeticOp::Add, OpWidth::Byte, 0xff, 1, false);
+        assert_eq!(result, 0);
+        assert!(flags.contains(Flags::Carry));
+    }
+
+    #[test]
+    fn evaluate_op_adc_sbb_use_carry() {
+        let (result, _flags) = evaluate_op(ArithmeticOp::Adc, OpWidth::Word, 1, 1, true);
+        assert_eq!(result, 3);
+
+        let (result, flags) = evaluate_op(ArithmeticOp::Sbb, OpWidth::Word, 1, 1, true);
+        assert_eq!(result, -1);
+        assert!(flags.contains(Flags::Carry));
+    }
+
+    #[test]
+    fn evaluate_op_logic_clears_carry_and_overflow() {
+        let (result, flags) = evaluate_op(ArithmeticOp::Xor, OpWidth::Word, 0x0ff0, 0x00ff, true);
+        assert_eq!(result, 0x0f0f);
+        assert!(flags.is_empty());
+    }
+
+    #[test]
+    fn evaluate_shift_carry_and_overflow() {
+        let (result, flags) = evaluate_shift(ShiftOp::Shl, OpWidth::Byte, 0x40, 1, false);
+        assert_eq!(result, -128);
+        assert!(flags.contains(Flags::Overflow));
+        assert!(!flags.contains(Flags::Carry));
+
+        let (result, flags) = evaluate_shift(ShiftOp::Sar, OpWidth::Word, -3, 1, false);
+        assert_eq!(result, -2);
+        assert!(flags.contains(Flags::Carry));
+        assert!(!flags.contains(Flags::Overflow));
+    }
+
+    #[test]
+    fn evaluate_shift_rotates_through_carry() {
+        let (result, flags) = evaluate_shift(ShiftOp::Rcl, OpWidth::Byte, 0x80u8 as i16, 1, true);
+        assert_eq!(result, 1);
+        assert!(flags.contains(Flags::Carry));
+
+        let (result, flags) = evaluate_shift(ShiftOp::Ror, OpWidth::Word, 1, 1, false);
+        assert_eq!(result, i16::MIN);
+        assert!(flags.contains(Flags::Carry | Flags::Overflow));
+    }
+
+    #[test]
+    fn evaluate_multiply_sets_carry_when_high_half_used() {
+        let (low, high, flags) = evaluate_multiply(MultiplyDivideOp::Mul, OpWidth::Byte, 0x80, 2);
+        assert_eq!((low, high), (0, 1));
+        assert!(flags.contains(Flags::Carry | Flags::Overflow));
+
+        let (low, high, flags) = evaluate_multiply(MultiplyDivideOp::Imul, OpWidth::Word, -2, 3);
+        assert_eq!((low, high), (-6, -1));
+        assert!(flags.is_empty());
+    }
+
+    #[test]
+    fn evaluate_divide_quotient_and_remainder() {
+        assert_eq!(evaluate_divide(MultiplyDivideOp::Div, OpWidth::Word, 1, 0, 3, CpuModel::I8086), Some((0x5555, 1)));
+        assert_eq!(evaluate_divide(MultiplyDivideOp::Idiv, OpWidth::Byte, -1, -7, 2, CpuModel::I8086), Some((-3, -1)));
+    }
+
+    #[test]
+    fn evaluate_divide_errors() {
+        for model in [CpuModel::I8086, CpuModel::I80186, CpuModel::I80286] {
+            assert_eq!(evaluate_divide(MultiplyDivideOp::Div, OpWidth::Byte, 0, 10, 0, model), None);
+            assert_eq!(evaluate_divide(MultiplyDivideOp::Div, OpWidth::Byte, 1, 0, 1, model), None);
+            assert_eq!(evaluate_divide(MultiplyDivideOp::Idiv, OpWidth::Byte, 1, 0, -1, model), None);
+        }
+        // 128 / -1 is the most negative quotient
+        assert_eq!(evaluate_divide(MultiplyDivideOp::Idiv, OpWidth::Byte, 0, -128, -1, CpuModel::I8086), None);
+        assert_eq!(evaluate_divide(MultiplyDivideOp::Idiv, OpWidth::Byte, 0, -128, -1, CpuModel::I80186), Some((-128, 0)));
+    }
+
+    #[test]
+    fn evaluate_adjust_after_addition() {
+        // 0x79 + 0x35 = 0xae, which adjusts to 79 + 35 = 114
+        let (ax, flags) = evaluate_adjust(AdjustOp::Daa, 0x00ae, Flags::empty());
+        assert_eq!(ax, 0x0014);
+        assert!(flags.contains(Flags::Carry | Flags::AuxiliaryCarry));
+
+        // 0x38 + 0x29 = 0x61 with a carry out of the low digit, 38 + 29 = 67
+        let (ax, flags) = evaluate_adjust(AdjustOp::Daa, 0x0061, Flags::AuxiliaryCarry);
+        assert_eq!(ax, 0x0067);
+        assert!(!flags.contains(Flags::Carry));
+
+        // ASCII '9' + '4' = 0x6d, unpacked 9 + 4 = 13
+        let (ax, flags) = evaluate_adjust(AdjustOp::Aaa, 0x006d, Flags::empty());
+        assert_eq!(ax, 0x0103);
+        assert!(flags.contains(Flags::Carry | Flags::AuxiliaryCarry));
+    }
+
+    #[test]
+    fn evaluate_adjust_after_subtraction() {
+        // 0x12 - 0x24 = 0xee with both borrows, 12 - 24 = -12 as 88 borrowing 100
+        let (ax, flags) = evaluate_adjust(AdjustOp::Das, 0x00ee, Flags::Carry | Flags::AuxiliaryCarry);
+        assert_eq!(ax, 0x0088);
+        assert!(flags.contains(Flags::Carry));
+
+        // unpacked 2 - 4 = 0xfe with a borrow from the low digit, giving 8 and borrowing from AH
+        let (ax, flags) = evaluate_adjust(AdjustOp::Aas, 0x00fe, Flags::AuxiliaryCarry);
+        assert_eq!(ax as u16, 0xff08);
+        assert!(flags.contains(Flags::Carry | Flags::AuxiliaryCarry));
+
+        let (ax, flags) = evaluate_adjust(AdjustOp::Aas, 0x0235, Flags::empty());
+        assert_eq!(ax, 0x0205);
+        assert!(flags.is_empty());
+    }
+
+    #[test]
+    fn evaluate_ascii_adjust_multiply_and_divide() {
+        // 7 * 9 = 63
+        assert_eq!(evaluate_ascii_adjust_multiply(0x003f, 10), Some(0x0603));
+        assert_eq!(evaluate_ascii_adjust_multiply(0x003f, 16), Some(0x030f));
+        assert_eq!(evaluate_ascii_adjust_multiply(0x003f, 0), None);
+
+        // unpacked 73 before dividing
+        assert_eq!(evaluate_ascii_adjust_divide(0x0703, 10), 0x0049);
+        assert_eq!(evaluate_ascii_adjust_divide(0x0703, 16), 0x0073);
+    }
+
+    fn word_register(reg: Register) -> RegisterAccess {
+        RegisterAccess::new(reg, OpWidth::Word, 0)
+    }
+
+    #[test]
+    fn enter_copies_the_enclosing_frame_pointers() {
+        let mut state = CpuState::new(CpuModel::I80186);
+        let mut memory = Memory::new();
+        state.registers.write_reg(0x100, word_register(Register::Bp));
+        state.registers.set_sp(0xf0);
+        write_mem_at(0x1111, &mut memory, 0xfe, OpWidth::Word);
+        write_mem_at(0x2222, &mut memory, 0xfc, OpWidth::Word);
+
+        simulate_instruction(&mut state, &mut memory, Instruction::Enter { size: Imm16(8), level: Imm8(3) }, 4);
+
+        // old bp, the two enclosing frame pointers, then the new frame pointer, and 8 bytes of locals below them
+        assert_eq!(state.registers.bp(), 0xee);
+        assert_eq!(state.registers.sp(), 0xe0);
+        let stack: Vec<i16> = [0xee, 0xec, 0xea, 0xe8].iter().map(|address| read_mem_at(&memory, *address, OpWidth::Word)).collect();
+        assert_eq!(stack, [0x100, 0x1111, 0x2222, 0xee]);
+    }
+
+    #[test]
+    fn bound_returns_to_itself() {
+        let mut state = CpuState::new(CpuModel::I80186);
+        let mut memory = Memory::new();
+        state.registers.set_sp(0x100);
+        state.registers.write_reg(5, word_register(Register::A));
+        write_mem_at(0, &mut memory, 0x200, OpWidth::Word);
+        write_mem_at(3, &mut memory, 0x202, OpWidth::Word);
+        // bound ax, [0x200] at 0x10, with IP already past its 4 bytes
+        state.registers.ip = 0x14;
+        let mem = EffectiveAddress { base: EffectiveAddressBase::Direct, displacement: Displacement::Disp16(0x200), segment: None };
+
+        simulate_instruction(&mut state, &mut memory, Instruction::Bound { reg: word_register(Register::A), mem }, 4);
+
+        assert_eq!(state.registers.sp(), 0xfa);
+        assert_eq!(read_mem_at(&memory, 0xfa, OpWidth::Word), 0x10);
+        assert_eq!(state.registers.ip, 0);
+    }
+
+    #[test]
+    fn translate_byte_uses_its_segment_override() {
+        let mut state = CpuState::new(CpuModel::I8086);
+        let mut memory = Memory::new();
+        state.registers.write_seg_reg(SegmentRegister::Es, 0x100);
+        state.registers.write_reg(0x20, word_register(Register::B));
+        state.registers.write_reg(3, RegisterAccess::new(Register::A, OpWidth::Byte, 0));
+        write_mem_at(0x11, &mut memory, 0x23, OpWidth::Byte);
+        write_mem_at(0x22, &mut memory, 0x1023, OpWidth::Byte);
+
+        simulate_instruction(&mut state, &mut memory, Instruction::TranslateByte { segment: None }, 1);
+        assert_eq!(state.registers.read_reg(RegisterAccess::new(Register::A, OpWidth::Byte, 0)), 0x11);
+
+        state.registers.write_reg(3, RegisterAccess::new(Register::A, OpWidth::Byte, 0));
+        simulate_instruction(&mut state, &mut memory, Instruction::TranslateByte { segment: Some(SegmentRegister::Es) }, 2);
+        assert_eq!(state.registers.read_reg(RegisterAccess::new(Register::A, OpWidth::Byte, 0)), 0x22);
+    }
+
+    #[test]
+    fn only_the_8086_faults_on_the_most_negative_quotient() {
+        let al = RegisterAccess::new(Register::A, OpWidth::Byte, 0);
+        let bl = RegisterAccess::new(Register::B, OpWidth::Byte, 0);
+        for model in [CpuModel::I8086, CpuModel::I80186, CpuModel::I80286] {
+            let mut state = CpuState::new(model);
+            let mut memory = Memory::new();
+            state.registers.set_sp(0x100);
+            // -256 / 2 in AX and BL, and -65536 / 2 in DX:AX and BX
+            state.registers.write_reg(-256, word_register(Register::A));
+            state.registers.write_reg(2, bl);
+            let idiv = |width, reg| Instruction::MultiplyDivide { op: MultiplyDivideOp::Idiv, width, reg_or_mem: RegOrMem::Reg(reg) };
+
+            simulate_instruction(&mut state, &mut memory, idiv(OpWidth::Byte, bl), 2);
+            match model {
+                CpuModel::I8086 => assert_eq!(state.registers.sp(), 0xfa, "divide error on the {model}"),
+                _ => assert_eq!(state.registers.read_reg(al), 0x80, "quotient on the {model}"),
+            }
+
+            state.registers.write_reg(0, word_register(Register::A));
+            state.registers.write_reg(-1, word_register(Register::D));
+            state.registers.write_reg(2, word_register(Register::B));
+            simulate_instruction(&mut state, &mut memory, idiv(OpWidth::Word, word_register(Register::B)), 2);
+            match model {
+                CpuModel::I8086 => assert_eq!(state.registers.sp(), 0xf4, "divide error on the {model}"),
+                _ => assert_eq!(state.registers.ax(), i16::MIN, "quotient on the {model}"),
+            }
+        }
+    }
+}
@@ -0,0 +1,400 @@
+use std::fmt::{Display, Formatter};
+
+use crate::ops::{
+    ArithmeticOp, Direction, Displacement, EffectiveAddress, EffectiveAddressBase, Instruction, MultiplyDivideOp, OpWidth, RegOrMem, ShiftCount, StringOp,
+};
+
+/// The clocks an instruction takes on the 8086, as Intel documents them. Some of it depends on what
+/// the instruction finds when it runs: whether a jump is taken, the count in CL, the repetitions in
+/// CX and the operands of a multiply or divide.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Clocks {
+    /// All of it for most instructions, and what a jump that isn't taken, a shift by no bits or a
+    /// repeat with CX zero takes
+    pub base: usize,
+    /// Added when a conditional jump or loop is taken
+    pub taken: usize,
+    /// Added for each bit a shift or rotate by CL moves
+    pub per_bit: usize,
+    /// Added for each repetition of a repeated string instruction
+    pub per_repetition: usize,
+    /// How many more clocks a multiply or divide can take, depending on its operands
+    pub spread: usize,
+}
+
+impl Clocks {
+    /// The least and the most clocks for a run that takes the jump if `taken`, shifts by `bits` and
+    /// repeats `repetitions` times; they only differ for a multiply or divide
+    pub fn run(&self, taken: bool, bits: usize, repetitions: usize) -> (usize, usize) {
+        let clocks = self.base + if taken { self.taken } else { 0 } + self.per_bit * bits + self.per_repetition * repetitions;
+        (clocks, clocks + self.spread)
+    }
+}
+
+/// In the notation of Intel's tables: `16 or 4` for a jump taken or not, `8+4/bit`, `9+17/rep` and
+/// `70-77` for a range
+impl Display for Clocks {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.taken > 0 {
+            write!(f, "{} or {}", self.base + self.taken, self.base)?;
+        } else {
+            write!(f, "{}", self.base)?;
+        }
+        if self.spread > 0 {
+            write!(f, "-{}", self.base + self.spread)?;
+        }
+        if self.per_bit > 0 {
+            write!(f, "+{}/bit", self.per_bit)?;
+        }
+        if self.per_repetition > 0 {
+            write!(f, "+{}/rep", self.per_repetition)?;
+        }
+        Ok(())
+    }
+}
+
+fn estimate_ea(effective_address: &EffectiveAddress, transfers: usize) -> usize {
+    let segment_override = if effective_address.segment.is_some() { 2 } else { 0 };
+    segment_override + estimate_ea_without_override(effective_address, transfers)
+}
+
+fn estimate_ea_without_override(effective_address: &EffectiveAddress, transfers: usize) -> usize {
+    let displacement = !matches!(effective_address.displacement, Displacement::None);
+    if !displacement {
+        match effective_address.base {
+            EffectiveAddressBase::Direct => {6} //should never happen, but let's keep it
+            EffectiveAddressBase::BxPlusSi => {7}
+            EffectiveAddressBase::BxPlusDi => {8}
+            EffectiveAddressBase::BpPlusSi => {8}
+            EffectiveAddressBase::BpPlusDi => {7}
+            EffectiveAddressBase::Si => {5}
+            EffectiveAddressBase::Di => {5}
+            EffectiveAddressBase::Bp => {5}
+            EffectiveAddressBase::Bx => {5}
+        }
+    } else {
+        let odd = effective_address.displacement.value() % 2 == 1;
+        let penalty = if odd { 4 * transfers } else  {0};
+        let ea = match effective_address.base {
+            EffectiveAddressBase::Direct => {6}
+            EffectiveAddressBase::BxPlusSi => {11}
+            EffectiveAddressBase::BxPlusDi => {12}
+            EffectiveAddressBase::BpPlusSi => {12}
+            EffectiveAddressBase::BpPlusDi => {11}
+            EffectiveAddressBase::Si => {9}
+            EffectiveAddressBase::Di => {9}
+            EffectiveAddressBase::Bp => {9}
+            EffectiveAddressBase::Bx => {9}
+        };
+        ea + penalty
+    }
+}
+
+impl Clocks {
+    /// The clocks of an instruction, including the effective address calculation
+    pub fn of(instruction: &Instruction) -> Clocks {
+        Clocks::fixed(match instruction {
+            Instruction::MovToFromRegMem { dir, reg_or_mem, .. } => {
+                match reg_or_mem {
+                    RegOrMem::Reg(_) => {2}
+                    RegOrMem::Mem(ea) => {
+                        match dir {
+                            Direction::ToRegister => {8 + estimate_ea(ea, 1) }
+                            Direction::FromRegister => { 9 + estimate_ea(ea, 1)}
+                        }
+                    }
+                }
+            }
+            Instruction::ImmediateMovRegMem { reg_or_mem, .. } => {
+                match reg_or_mem {
+                    RegOrMem::Reg(_) => {4}
+                    RegOrMem::Mem(ea) => {
+                        10 + estimate_ea(ea, 1)
+                    }
+                }
+            }
+            Instruction::ImmediateMovReg { .. } => {4}
+            Instruction::AccumulatorMove { .. } => {10}
+            Instruction::SegmentRegisterMove { dir, reg_or_mem, .. } => {
+                match reg_or_mem {
+                    RegOrMem::Reg(_) => {2}
+                    RegOrMem::Mem(ea) => {
+                        match dir {
+                            Direction::ToRegister => {8 + estimate_ea(ea, 1)}
+                            Direction::FromRegister => {9 + estimate_ea(ea, 1)}
+                        }
+                    }
+                }
+            }
+            Instruction::ArithmeticFromToRegMem { op, dir, reg_or_mem, .. } => {
+                // cmp only reads its memory operand
+                match (reg_or_mem, dir) {
+                    (RegOrMem::Reg(_), _) => {3}
+                    (RegOrMem::Mem(ea), Direction::ToRegister) => {9 + estimate_ea(ea, 1)}
+                    (RegOrMem::Mem(ea), Direction::FromRegister) if *op == ArithmeticOp::Cmp => {9 + estimate_ea(ea, 1)}
+                    (RegOrMem::Mem(ea), Direction::FromRegister) => {16 + estimate_ea(ea, 2)}
+                }
+            }
+            Instruction::ArithmeticImmediateToRegMem { op, reg_or_mem, .. } => {
+                match reg_or_mem {
+                    RegOrMem::Reg(_) => {4}
+                    RegOrMem::Mem(ea) if *op == ArithmeticOp::Cmp => {10 + estimate_ea(ea, 1)}
+                    RegOrMem::Mem(ea) => {17 + estimate_ea(ea, 2)}
+                }
+            }
+            Instruction::ArithmeticImmediateToAccumulator { .. } => {4}
+            Instruction::JumpOnEqual(_)
+            | Instruction::JumpOnLess(_)
+            | Instruction::JumpOnNotGreater(_)
+            | Instruction::JumpOnBelow(_)
+            | Instruction::JumpOnNotAbove(_)
+            | Instruction::JumpOnParity(_)
+            | Instruction::JumpOnOverflow(_)
+            | Instruction::JumpOnSign(_)
+            | Instruction::JumpOnNotEqual(_)
+            | Instruction::JumpOnNotLess(_)
+            | Instruction::JumpOnGreater(_)
+            | Instruction::JumpOnNotBelow(_)
+            | Instruction::JumpOnAbove(_)
+            | Instruction::JumpOnNoParity(_)
+            | Instruction::JumpOnNoOverflow(_)
+            | Instruction::JumpOnNotSign(_) => return Clocks { base: 4, taken: 12, ..Clocks::default() },
+            Instruction::Loop(_) => return Clocks { base: 5, taken: 12, ..Clocks::default() },
+            Instruction::LoopWhileEqual(_) => return Clocks { base: 6, taken: 12, ..Clocks::default() },
+            Instruction::LoopWhileNotEqual(_) => return Clocks { base: 5, taken: 14, ..Clocks::default() },
+            Instruction::JumpOnCxZero(_) => return Clocks { base: 6, taken: 12, ..Clocks::default() },
+            Instruction::PushRegMem { reg_or_mem } => {
+                match reg_or_mem {
+                    RegOrMem::Reg(_) => {11}
+                    RegOrMem::Mem(ea) => {16 + estimate_ea(ea, 2)}
+                }
+            }
+            Instruction::PushReg { .. } => {11}
+            Instruction::PushSegmentRegister { .. } => {10}
+            Instruction::PopRegMem { reg_or_mem } => {
+                match reg_or_mem {
+                    RegOrMem::Reg(_) => {8}
+                    RegOrMem::Mem(ea) => {17 + estimate_ea(ea, 2)}
+                }
+            }
+            Instruction::PopReg { .. } => {8}
+            Instruction::PopSegmentRegister { .. } => {8}
+            Instruction::ExchangeRegMemWithReg { reg_or_mem, .. } => {
+                match reg_or_mem {
+                    RegOrMem::Reg(_) => {4}
+                    RegOrMem::Mem(ea) => {17 + estimate_ea(ea, 2)}
+                }
+            }
+            Instruction::ExchangeRegWithAccumulator { .. } => {3}
+            Instruction::InFixedPort { .. } => {10}
+            Instruction::InVariablePort { .. } => {8}
+            Instruction::OutFixedPort { .. } => {10}
+            Instruction::OutVariablePort { .. } => {8}
+            Instruction::TranslateByte { .. } => {11}
+            Instruction::LoadEffectiveAddress { mem, .. } => {2 + estimate_ea(mem, 0)}
+            Instruction::LoadPointerToDs { mem, .. } => {16 + estimate_ea(mem, 2)}
+            Instruction::LoadPointerToEs { mem, .. } => {16 + estimate_ea(mem, 2)}
+            Instruction::LoadAhFromFlags => {4}
+            Instruction::StoreAhIntoFlags => {4}
+            Instruction::PushFlags => {10}
+            Instruction::PopFlags => {8}
+            Instruction::TestRegMemWithReg { reg_or_mem, .. } => {
+                match reg_or_mem {
+                    RegOrMem::Reg(_) => {3}
+                    RegOrMem::Mem(ea) => {9 + estimate_ea(ea, 1)}
+                }
+            }
+            Instruction::TestImmediateWithRegMem { reg_or_mem, .. } => {
+                match reg_or_mem {
+                    RegOrMem::Reg(_) => {5}
+                    RegOrMem::Mem(ea) => {11 + estimate_ea(ea, 1)}
+                }
+            }
+            Instruction::TestImmediateWithAccumulator { .. } => {4}
+            Instruction::Not { reg_or_mem, .. } => {
+                match reg_or_mem {
+                    RegOrMem::Reg(_) => {3}
+                    RegOrMem::Mem(ea) => {16 + estimate_ea(ea, 2)}
+                }
+            }
+            Instruction::Shift { count, reg_or_mem, .. } => {
+                match (count, reg_or_mem) {
+                    (ShiftCount::One, RegOrMem::Reg(_)) => {2}
+                    (ShiftCount::One, RegOrMem::Mem(ea)) => {15 + estimate_ea(ea, 2)}
+                    (ShiftCount::Cl, RegOrMem::Reg(_)) => return Clocks { base: 8, per_bit: 4, ..Clocks::default() },
+                    (ShiftCount::Cl, RegOrMem::Mem(ea)) => return Clocks { base: 20 + estimate_ea(ea, 2), per_bit: 4, ..Clocks::default() },
+                    // 80186 timings, which add a clock per bit
+                    (ShiftCount::Immediate(count), RegOrMem::Reg(_)) => {5 + *count as usize}
+                    (ShiftCount::Immediate(count), RegOrMem::Mem(ea)) => {17 + *count as usize + estimate_ea(ea, 2)}
+                }
+            }
+            Instruction::IncrementRegMem { reg_or_mem, .. } | Instruction::DecrementRegMem { reg_or_mem, .. } => {
+                match reg_or_mem {
+                    RegOrMem::Reg(_) => {3}
+                    RegOrMem::Mem(ea) => {15 + estimate_ea(ea, 2)}
+                }
+            }
+            Instruction::IncrementReg { .. } => {2}
+            Instruction::DecrementReg { .. } => {2}
+            Instruction::Negate { reg_or_mem, .. } => {
+                match reg_or_mem {
+                    RegOrMem::Reg(_) => {3}
+                    RegOrMem::Mem(ea) => {16 + estimate_ea(ea, 2)}
+                }
+            }
+            Instruction::MultiplyDivide { op, width, reg_or_mem } => {
+                // the time depends on the operands, within these ranges
+                let (reg, mem, spread) = match (op, width) {
+                    (MultiplyDivideOp::Mul, OpWidth::Byte) => (70, 76, 7),
+                    (MultiplyDivideOp::Mul, OpWidth::Word) => (118, 124, 15),
+                    (MultiplyDivideOp::Imul, OpWidth::Byte) => (80, 86, 18),
+                    (MultiplyDivideOp::Imul, OpWidth::Word) => (128, 134, 26),
+                    (MultiplyDivideOp::Div, OpWidth::Byte) => (80, 86, 10),
+                    (MultiplyDivideOp::Div, OpWidth::Word) => (144, 150, 18),
+                    (MultiplyDivideOp::Idiv, OpWidth::Byte) => (101, 107, 11),
+                    (MultiplyDivideOp::Idiv, OpWidth::Word) => (165, 171, 19),
+                };
+                let base = match reg_or_mem {
+                    RegOrMem::Reg(_) => {reg}
+                    RegOrMem::Mem(ea) => {mem + estimate_ea(ea, 1)}
+                };
+                return Clocks { base, spread, ..Clocks::default() };
+            }
+            Instruction::StringManipulation { op, repeat, .. } => {
+                // ins and outs are 80186 instructions, with its timings
+                let (single, repeated, per_repetition) = match op {
+                    StringOp::Movs => (18, 9, 17),
+                    StringOp::Cmps => (22, 9, 22),
+                    StringOp::Scas => (15, 9, 15),
+                    StringOp::Lods => (12, 9, 13),
+                    StringOp::Stos => (11, 9, 10),
+                    StringOp::Ins | StringOp::Outs => (14, 8, 8),
+                };
+                match repeat {
+                    None => {single}
+                    Some(_) => return Clocks { base: repeated, per_repetition, ..Clocks::default() },
+                }
+            }
+            Instruction::Lock(instruction) => return Clocks::of(instruction).plus(2),
+            // each prefix byte takes two clocks
+            Instruction::Prefixed { prefixes, instruction } => return Clocks::of(instruction).plus(2 * prefixes.len()),
+            Instruction::CallDirect(_) => {19}
+            Instruction::CallFarDirect { .. } => {28}
+            Instruction::CallIndirect { reg_or_mem } => {
+                match reg_or_mem {
+                    RegOrMem::Reg(_) => {16}
+                    RegOrMem::Mem(ea) => {21 + estimate_ea(ea, 2)}
+                }
+            }
+            Instruction::CallFarIndirect { mem } => {37 + estimate_ea(mem, 4)}
+            Instruction::JumpDirect(_) => {15}
+            Instruction::JumpShort(_) => {15}
+            Instruction::JumpFarDirect { .. } => {15}
+            Instruction::JumpIndirect { reg_or_mem } => {
+                match reg_or_mem {
+                    RegOrMem::Reg(_) => {11}
+                    RegOrMem::Mem(ea) => {18 + estimate_ea(ea, 1)}
+                }
+            }
+            Instruction::JumpFarIndirect { mem } => {24 + estimate_ea(mem, 2)}
+            Instruction::Return { pop: None } => {8}
+            Instruction::Return { pop: Some(_) } => {12}
+            Instruction::ReturnFar { pop: None } => {18}
+            Instruction::ReturnFar { pop: Some(_) } => {17}
+            Instruction::Interrupt(_) => {51}
+            Instruction::Interrupt3 => {52}
+            Instruction::InterruptOnOverflow => {53}
+            Instruction::InterruptReturn => {24}
+            Instruction::ClearCarry
+            | Instruction::ComplementCarry
+            | Instruction::SetCarry
+            | Instruction::ClearDirection
+            | Instruction::SetDirection
+            | Instruction::ClearInterrupt
+            | Instruction::SetInterrupt
+            | Instruction::Halt => {2}
+            Instruction::Wait => {3}
+            Instruction::Nop => {3}
+            Instruction::Adjust(_) => {4}
+            Instruction::AsciiAdjustMultiply { .. } => {83}
+            Instruction::AsciiAdjustDivide { .. } => {60}
+            Instruction::ConvertByteToWord => {2}
+            Instruction::ConvertWordToDouble => {5}
+            Instruction::Escape { reg_or_mem, .. } => {
+                match reg_or_mem {
+                    RegOrMem::Reg(_) => {2}
+                    RegOrMem::Mem(ea) => {8 + estimate_ea(ea, 1)}
+                }
+            }
+            // undocumented, so there is no published timing
+            Instruction::SetAlFromCarry => {4}
+            // the instructions below only exist from the 80186 on, so these are its timings
+            Instruction::PushImmediate { .. } => {10}
+            Instruction::PushAll => {36}
+            Instruction::PopAll => {51}
+            Instruction::MultiplyImmediate { reg_or_mem, .. } => {
+                match reg_or_mem {
+                    RegOrMem::Reg(_) => {22}
+                    RegOrMem::Mem(ea) => {29 + estimate_ea(ea, 1)}
+                }
+            }
+            Instruction::Enter { level, .. } => {
+                match level.0 {
+                    0 => {15}
+                    1 => {25}
+                    level => {22 + 16 * (level as usize - 1)}
+                }
+            }
+            Instruction::Leave => {8}
+            Instruction::Bound { mem, .. } => {33 + estimate_ea(mem, 2)}
+        })
+    }
+
+    fn fixed(base: usize) -> Clocks {
+        Clocks { base, ..Clocks::default() }
+    }
+
+    fn plus(self, clocks: usize) -> Clocks {
+        Clocks { base: self.base + clocks, ..self }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::clocks::Clocks;
+    use crate::decoder::{CpuModel, Decoder};
+
+    fn clocks(bytes: &[u8]) -> Clocks {
+        Clocks::of(&Decoder::new(CpuModel::I8086).decode_at(bytes, 0).unwrap().unwrap().instruction)
+    }
+
+    #[test]
+    fn matches_the_8086_tables() {
+        // mov bx, cx / mov ax, [0x10] / add word [bx], 5 / cmp word [bx], 5 / add [bx], ax / cmp [bx], ax
+        assert_eq!(clocks(&[0x89, 0xcb]).to_string(), "2");
+        assert_eq!(clocks(&[0xa1, 0x10, 0x00]).to_string(), "10");
+        assert_eq!(clocks(&[0x83, 0x07, 0x05]).to_string(), "22");
+        assert_eq!(clocks(&[0x83, 0x3f, 0x05]).to_string(), "15");
+        assert_eq!(clocks(&[0x01, 0x07]).to_string(), "21");
+        assert_eq!(clocks(&[0x39, 0x07]).to_string(), "14");
+    }
+
+    #[test]
+    fn counts_what_is_only_known_when_it_runs() {
+        // jne / loop
+        assert_eq!(clocks(&[0x75, 0x02]).to_string(), "16 or 4");
+        assert_eq!(clocks(&[0x75, 0x02]).run(true, 0, 0), (16, 16));
+        assert_eq!(clocks(&[0x75, 0x02]).run(false, 0, 0), (4, 4));
+        assert_eq!(clocks(&[0xe2, 0xfe]).to_string(), "17 or 5");
+
+        // shl ax, cl / rep movsb
+        assert_eq!(clocks(&[0xd3, 0xe0]).to_string(), "8+4/bit");
+        assert_eq!(clocks(&[0xd3, 0xe0]).run(false, 3, 0), (20, 20));
+        assert_eq!(clocks(&[0xf3, 0xa4]).to_string(), "9+17/rep");
+        assert_eq!(clocks(&[0xf3, 0xa4]).run(false, 0, 4), (77, 77));
+
+        // mul cl / div word [bx]
+        assert_eq!(clocks(&[0xf6, 0xe1]).to_string(), "70-77");
+        assert_eq!(clocks(&[0xf7, 0x37]).run(false, 0, 0), (155, 173));
+    }
+}
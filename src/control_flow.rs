@@ -0,0 +1,234 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::disassembly::{jump_target, Chunk, Disassembly};
+use crate::format::InstructionFormatter;
+use crate::ops::Instruction;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EdgeKind {
+    /// Execution goes on to the next instruction
+    Fallthrough,
+    /// An unconditional relative jump
+    Jump,
+    /// A conditional jump or loop that is taken
+    Branch,
+    Call,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Edge {
+    /// The start of the block the edge goes to
+    pub target: usize,
+    pub kind: EdgeKind,
+}
+
+/// Instructions that only run one after the other, entered at the first and left after the last
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub start: usize,
+    /// The address after the last instruction
+    pub end: usize,
+    /// Address, length and instruction of each instruction in the block
+    pub instructions: Vec<(usize, usize, Instruction)>,
+    pub successors: Vec<Edge>,
+    /// The starts of the blocks with an edge to this one
+    pub predecessors: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ControlFlowGraph {
+    /// The blocks by start address
+    pub blocks: BTreeMap<usize, BasicBlock>,
+}
+
+impl ControlFlowGraph {
+    /// Splits the code of a disassembly into basic blocks. Jumps into the middle of an instruction,
+    /// outside the binary or into data have no edge, nor do indirect and far jumps and calls.
+    pub fn build(disassembly: &Disassembly) -> ControlFlowGraph {
+        let code: Vec<(usize, usize, &Instruction)> = disassembly
+            .chunks
+            .iter()
+            .filter_map(|chunk| match chunk {
+                Chunk::Code { address, length, instruction } => Some((*address, *length, instruction)),
+                Chunk::Data { .. } => None,
+            })
+            .collect();
+        let starts: BTreeSet<usize> = code.iter().map(|(address, _, _)| *address).collect();
+
+        // a block starts where something jumps to, after anything that changes the flow, and after data
+        let mut leaders: BTreeSet<usize> = disassembly.jump_targets().intersection(&starts).copied().collect();
+        let mut previous_end = None;
+        for (address, length, instruction) in &code {
+            if previous_end != Some(*address) {
+                leaders.insert(*address);
+            }
+            if ends_block(instruction) {
+                leaders.insert(address + length);
+            }
+            previous_end = Some(address + length);
+        }
+
+        let mut graph = ControlFlowGraph::default();
+        let mut current: Option<BasicBlock> = None;
+        for (address, length, instruction) in code {
+            if leaders.contains(&address) {
+                graph.finish(current.take(), &starts);
+            }
+            let block = current.get_or_insert_with(|| BasicBlock { start: address, end: address, instructions: vec![], successors: vec![], predecessors: vec![] });
+            block.instructions.push((address, length, instruction.clone()));
+            block.end = address + length;
+        }
+        graph.finish(current, &starts);
+
+        let edges: Vec<(usize, usize)> =
+            graph.blocks.values().flat_map(|block| block.successors.iter().map(|edge| (block.start, edge.target))).collect();
+        for (from, to) in edges {
+            let predecessors = &mut graph.blocks.get_mut(&to).unwrap().predecessors;
+            if !predecessors.contains(&from) {
+                predecessors.push(from);
+            }
+        }
+        graph
+    }
+
+    fn finish(&mut self, block: Option<BasicBlock>, starts: &BTreeSet<usize>) {
+        let Some(mut block) = block else {
+            return;
+        };
+        let (address, length, instruction) = block.instructions.last().unwrap();
+        if let Some(disp) = instruction.relative_jump() {
+            let kind = match instruction {
+                Instruction::JumpShort(_) | Instruction::JumpDirect(_) => EdgeKind::Jump,
+                Instruction::CallDirect(_) => EdgeKind::Call,
+                _ => EdgeKind::Branch,
+            };
+            let target = jump_target(address + length, disp);
+            if starts.contains(&target) {
+                block.successors.push(Edge { target, kind });
+            }
+        }
+        if instruction.falls_through() && starts.contains(&block.end) {
+            block.successors.push(Edge { target: block.end, kind: EdgeKind::Fallthrough });
+        }
+        self.blocks.insert(block.start, block);
+    }
+
+    /// The graph in Graphviz DOT, a node per block listing its instructions. Jump targets are shown as
+    /// absolute addresses.
+    pub fn to_dot(&self, formatter: &dyn InstructionFormatter) -> String {
+        let mut dot = String::from("digraph control_flow {\n    node [shape=box, fontname=\"monospace\"];\n");
+        for block in self.blocks.values() {
+            let lines: String = block
+                .instructions
+                .iter()
+                .map(|(address, length, instruction)| {
+                    let text = instruction.format(formatter, &|disp| format!("{:#06x}", jump_target(address + length, disp)));
+                    format!("{address:04x}: {}\\l", escape(&text))
+                })
+                .collect();
+            dot += &format!("    block_{:04x} [label=\"{lines}\"];\n", block.start);
+        }
+        for block in self.blocks.values() {
+            for edge in &block.successors {
+                let style = match edge.kind {
+                    EdgeKind::Fallthrough => "",
+                    EdgeKind::Jump => " [style=bold]",
+                    EdgeKind::Branch => " [label=\"taken\"]",
+                    EdgeKind::Call => " [style=dashed, label=\"call\"]",
+                };
+                dot += &format!("    block_{:04x} -> block_{:04x}{style};\n", block.start, edge.target);
+            }
+        }
+        dot += "}\n";
+        dot
+    }
+}
+
+/// Whether the next instruction starts a new block
+fn ends_block(instruction: &Instruction) -> bool {
+    instruction.relative_jump().is_some() || !instruction.falls_through()
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod test {
+    use crate::annotations::Annotations;
+    use crate::assembler::assemble;
+    use crate::control_flow::{ControlFlowGraph, Edge, EdgeKind};
+    use crate::decoder::{CpuModel, Decoder};
+    use crate::disassembly::recursive;
+    use crate::format::Nasm;
+
+    fn graph(source: &str) -> ControlFlowGraph {
+        let bytes = assemble(source).unwrap().to_bytes();
+        ControlFlowGraph::build(&recursive(&Decoder::new(CpuModel::I8086), &bytes, 0, &[0], &Annotations::default()))
+    }
+
+    #[test]
+    fn splits_a_loop_into_blocks() {
+        let graph = graph(
+            "
+                mov cx, 5       ; 0
+            again:
+                call work       ; 3
+                dec cx          ; 6
+                jnz again       ; 7
+                hlt             ; 9
+            work:
+                inc ax          ; 10
+                ret             ; 11
+            ",
+        );
+
+        let blocks: Vec<(usize, usize)> = graph.blocks.values().map(|block| (block.start, block.end)).collect();
+        assert_eq!(blocks, [(0, 3), (3, 6), (6, 9), (9, 10), (10, 12)]);
+
+        let successors = |start: usize| graph.blocks[&start].successors.clone();
+        assert_eq!(successors(0), [Edge { target: 3, kind: EdgeKind::Fallthrough }]);
+        assert_eq!(successors(3), [Edge { target: 10, kind: EdgeKind::Call }, Edge { target: 6, kind: EdgeKind::Fallthrough }]);
+        assert_eq!(successors(6), [Edge { target: 3, kind: EdgeKind::Branch }, Edge { target: 9, kind: EdgeKind::Fallthrough }]);
+        assert_eq!(successors(9), []);
+        assert_eq!(successors(10), []);
+
+        assert_eq!(graph.blocks[&3].predecessors, [0, 6]);
+        assert_eq!(graph.blocks[&10].predecessors, [3]);
+        assert_eq!(graph.blocks[&0].predecessors, []);
+    }
+
+    #[test]
+    fn data_and_unknown_targets_have_no_edges() {
+        let graph = graph(
+            "
+                jmp short code  ; 0
+                db 1, 2         ; 2
+            code:
+                jz $ + 50       ; 4
+                jmp $ - 1       ; 6, into the middle of the jz
+            ",
+        );
+
+        assert_eq!(graph.blocks.keys().copied().collect::<Vec<_>>(), [0, 4, 6]);
+        assert_eq!(graph.blocks[&0].successors, [Edge { target: 4, kind: EdgeKind::Jump }]);
+        assert_eq!(graph.blocks[&4].successors, [Edge { target: 6, kind: EdgeKind::Fallthrough }]);
+        assert_eq!(graph.blocks[&6].successors, []);
+    }
+
+    #[test]
+    fn exports_dot() {
+        let dot = graph("top: dec cx\njnz top\nret").to_dot(&Nasm::default());
+        assert_eq!(
+            dot,
+            "digraph control_flow {
+    node [shape=box, fontname=\"monospace\"];
+    block_0000 [label=\"0000: dec cx\\l0001: jne 0x0000\\l\"];
+    block_0003 [label=\"0003: ret\\l\"];
+    block_0000 -> block_0000 [label=\"taken\"];
+    block_0000 -> block_0003;
+}
+"
+        );
+    }
+}
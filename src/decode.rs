@@ -1,7 +1,142 @@
+use crate::decoder::DecodeError;
 use crate::ops::*;
 
+/// Decodes the bytes that follow one opcode. Decoders are written once against `ByteReader` and
+/// registered in the lookup as `DecodeFrom`, which picks the reader without a check per byte.
 pub trait OpCodeDecoder {
-    fn decode(&self, op_code: u8, bytes: &mut dyn Iterator<Item = &u8>) -> Instruction;
+    fn decode(&self, op_code: u8, bytes: &mut impl ByteReader) -> Result<Instruction, DecodeError>;
+}
+
+/// `OpCodeDecoder` with one method per kind of reader, so decoders can sit behind a `dyn` in the lookup
+pub trait DecodeFrom {
+    fn decode_slice(&self, op_code: u8, bytes: &mut SliceReader) -> Result<Instruction, DecodeError>;
+    fn decode_stream(&self, op_code: u8, bytes: &mut StreamReader) -> Result<Instruction, DecodeError>;
+}
+
+impl<D: OpCodeDecoder> DecodeFrom for D {
+    fn decode_slice(&self, op_code: u8, bytes: &mut SliceReader) -> Result<Instruction, DecodeError> {
+        self.decode(op_code, bytes)
+    }
+
+    fn decode_stream(&self, op_code: u8, bytes: &mut StreamReader) -> Result<Instruction, DecodeError> {
+        self.decode(op_code, bytes)
+    }
+}
+
+/// Reads the bytes of an instruction while keeping track of how many have been consumed,
+/// so errors can point at the offending byte.
+pub trait ByteReader {
+    /// Offset of the next byte
+    fn position(&self) -> usize;
+
+    fn try_next(&mut self) -> Option<u8>;
+
+    /// Looks at the next byte without consuming it, used to select group opcodes by their ModRM byte
+    fn peek(&mut self) -> Result<u8, DecodeError>;
+
+    /// Hands the reader to `decoder` as the concrete type it decodes from
+    fn decode_with(&mut self, decoder: &dyn DecodeFrom, op_code: u8) -> Result<Instruction, DecodeError>;
+
+    #[inline]
+    fn next(&mut self) -> Result<u8, DecodeError> {
+        let offset = self.position();
+        self.try_next().ok_or(DecodeError::Truncated { offset })
+    }
+}
+
+/// A cursor over a slice; offsets in errors are indices into the slice
+pub struct SliceReader<'b> {
+    bytes: &'b [u8],
+    position: usize,
+}
+
+impl<'b> SliceReader<'b> {
+    /// Reads from `bytes` starting at `position`
+    pub fn new(bytes: &'b [u8], position: usize) -> SliceReader<'b> {
+        SliceReader { bytes, position }
+    }
+}
+
+impl ByteReader for SliceReader<'_> {
+    fn position(&self) -> usize {
+        self.position
+    }
+
+    #[inline]
+    fn try_next(&mut self) -> Option<u8> {
+        let byte = *self.bytes.get(self.position)?;
+        self.position += 1;
+        Some(byte)
+    }
+
+    #[inline]
+    fn peek(&mut self) -> Result<u8, DecodeError> {
+        self.bytes.get(self.position).copied().ok_or(DecodeError::Truncated { offset: self.position })
+    }
+
+    fn decode_with(&mut self, decoder: &dyn DecodeFrom, op_code: u8) -> Result<Instruction, DecodeError> {
+        decoder.decode_slice(op_code, self)
+    }
+}
+
+/// Reads from an iterator; offsets in errors count from its first byte
+pub struct StreamReader<'a, 'b> {
+    bytes: &'a mut dyn Iterator<Item = &'b u8>,
+    peeked: Option<u8>,
+    position: usize,
+}
+
+impl<'a, 'b> StreamReader<'a, 'b> {
+    pub fn new(bytes: &'a mut dyn Iterator<Item = &'b u8>) -> StreamReader<'a, 'b> {
+        StreamReader { bytes, peeked: None, position: 0 }
+    }
+}
+
+impl ByteReader for StreamReader<'_, '_> {
+    fn position(&self) -> usize {
+        self.position
+    }
+
+    #[inline]
+    fn try_next(&mut self) -> Option<u8> {
+        let byte = match self.peeked.take() {
+            Some(byte) => byte,
+            None => *self.bytes.next()?,
+        };
+        self.position += 1;
+        Some(byte)
+    }
+
+    fn peek(&mut self) -> Result<u8, DecodeError> {
+        if self.peeked.is_none() {
+            self.peeked = self.bytes.next().copied();
+        }
+        self.peeked.ok_or(DecodeError::Truncated { offset: self.position })
+    }
+
+    fn decode_with(&mut self, decoder: &dyn DecodeFrom, op_code: u8) -> Result<Instruction, DecodeError> {
+        decoder.decode_stream(op_code, self)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Prefix {
+    Repeat(RepeatPrefix),
+    Segment(SegmentRegister),
+    Lock,
+}
+
+pub fn decode_prefix(byte: u8) -> Option<Prefix> {
+    match byte {
+        0x26 => Some(Prefix::Segment(SegmentRegister::Es)),
+        0x2E => Some(Prefix::Segment(SegmentRegister::Cs)),
+        0x36 => Some(Prefix::Segment(SegmentRegister::Ss)),
+        0x3E => Some(Prefix::Segment(SegmentRegister::Ds)),
+        0xF0 => Some(Prefix::Lock),
+        0xF2 => Some(Prefix::Repeat(RepeatPrefix::Repne)),
+        0xF3 => Some(Prefix::Repeat(RepeatPrefix::Rep)),
+        _ => None,
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -21,17 +156,17 @@ impl MovToFromRegMemDecoder {
 }
 
 impl OpCodeDecoder for MovToFromRegMemDecoder {
-    fn decode(&self, op_code: u8, bytes: &mut dyn Iterator<Item = &u8>) -> Instruction {
+    fn decode(&self, op_code: u8, bytes: &mut impl ByteReader) -> Result<Instruction, DecodeError> {
         let dir = decode_dir(op_code, Self::DIR_MASK);
         let width = decode_width(op_code, Self::WIDTH_MASK);
 
-        let next = bytes.next().unwrap();
-        let mode = decode_mode((*next >> 6) & 0b0000_0011);
-        let reg = decode_reg((*next >> 3) & 0b0000_0111, width);
+        let next = bytes.next()?;
+        let mode = decode_mode((next >> 6) & 0b0000_0011);
+        let reg = decode_reg((next >> 3) & 0b0000_0111, width);
 
-        let reg_or_mem = decode_reg_or_mem(next & 0b0000_0111, mode, width, bytes);
+        let reg_or_mem = decode_reg_or_mem(next & 0b0000_0111, mode, width, bytes)?;
 
-        Instruction::MovToFromRegMem { dir, reg, reg_or_mem }
+        Ok(Instruction::MovToFromRegMem { dir, reg, reg_or_mem })
     }
 }
 
@@ -43,16 +178,16 @@ impl ImmediateMovToRegMemDecoder {
 }
 
 impl OpCodeDecoder for ImmediateMovToRegMemDecoder {
-    fn decode(&self, op_code: u8, bytes: &mut dyn Iterator<Item = &u8>) -> Instruction {
+    fn decode(&self, op_code: u8, bytes: &mut impl ByteReader) -> Result<Instruction, DecodeError> {
         let width = decode_width(op_code, Self::WIDTH_MASK);
 
-        let next = bytes.next().unwrap();
+        let next = bytes.next()?;
 
-        let mode = decode_mode((*next >> 6) & 0b0000_0011);
-        let reg_or_mem = decode_reg_or_mem(next & 0b0000_0111, mode, width, bytes);
-        let data = decode_immediate(bytes, width);
+        let mode = decode_mode((next >> 6) & 0b0000_0011);
+        let reg_or_mem = decode_reg_or_mem(next & 0b0000_0111, mode, width, bytes)?;
+        let data = decode_immediate(bytes, width)?;
 
-        Instruction::ImmediateMovRegMem { width, reg_or_mem, data }
+        Ok(Instruction::ImmediateMovRegMem { width, reg_or_mem, data })
     }
 }
 
@@ -64,11 +199,11 @@ impl ImmediateMovToRegDecoder {
 }
 
 impl OpCodeDecoder for ImmediateMovToRegDecoder {
-    fn decode(&self, op_code: u8, bytes: &mut dyn Iterator<Item = &u8>) -> Instruction {
+    fn decode(&self, op_code: u8, bytes: &mut impl ByteReader) -> Result<Instruction, DecodeError> {
         let width = decode_width(op_code, Self::WIDTH_MASK);
         let reg = decode_reg(op_code & 0b0000_0111, width);
-        let data = decode_immediate(bytes, width);
-        Instruction::ImmediateMovReg { reg, data }
+        let data = decode_immediate(bytes, width)?;
+        Ok(Instruction::ImmediateMovReg { reg, data })
     }
 }
 
@@ -81,11 +216,12 @@ impl MovAccumulatorDecoder {
 }
 
 impl OpCodeDecoder for MovAccumulatorDecoder {
-    fn decode(&self, op_code: u8, bytes: &mut dyn Iterator<Item = &u8>) -> Instruction {
+    fn decode(&self, op_code: u8, bytes: &mut impl ByteReader) -> Result<Instruction, DecodeError> {
         let width = decode_width(op_code, Self::WIDTH_MASK);
         let dir = decode_dir(!op_code, Self::DIR_MASK);
-        let address = decode_address(bytes, width);
-        Instruction::AccumulatorMove { dir, addr: address }
+        // the address is a full word even when moving a byte
+        let address = decode_address(bytes)?;
+        Ok(Instruction::AccumulatorMove { dir, width, addr: address, segment: None })
     }
 }
 
@@ -97,14 +233,14 @@ impl MovSegmentDecoder {
 }
 
 impl OpCodeDecoder for MovSegmentDecoder {
-    fn decode(&self, op_code: u8, bytes: &mut dyn Iterator<Item = &u8>) -> Instruction {
-        let next = *bytes.next().unwrap();
+    fn decode(&self, op_code: u8, bytes: &mut impl ByteReader) -> Result<Instruction, DecodeError> {
+        let next = bytes.next()?;
         let mode = decode_mode((next >> 6) & 0b0000_0011);
         let dir = decode_dir(op_code, Self::DIR_MASK);
         let seg_reg = decode_seg_reg(next >> 3 & 0b0000_0011);
-        let reg_or_mem = decode_reg_or_mem(next & 0b0000_0111, mode, OpWidth::Word, bytes);
+        let reg_or_mem = decode_reg_or_mem(next & 0b0000_0111, mode, OpWidth::Word, bytes)?;
 
-        Instruction::SegmentRegisterMove { dir, seg_reg, reg_or_mem }
+        Ok(Instruction::SegmentRegisterMove { dir, seg_reg, reg_or_mem })
     }
 }
 
@@ -120,9 +256,87 @@ impl JumpDecoder {
 }
 
 impl OpCodeDecoder for JumpDecoder {
-    fn decode(&self, _op_code: u8, bytes: &mut dyn Iterator<Item = &u8>) -> Instruction {
-        let disp = i8::from_le_bytes([*bytes.next().unwrap()]);
-        (self.jump_op)(disp)
+    fn decode(&self, _op_code: u8, bytes: &mut impl ByteReader) -> Result<Instruction, DecodeError> {
+        let disp = i8::from_le_bytes([bytes.next()?]);
+        Ok((self.jump_op)(disp))
+    }
+}
+
+#[derive(Clone)]
+pub struct NearJumpDecoder {
+    jump_op: fn(i16) -> Instruction,
+}
+
+impl NearJumpDecoder {
+    pub fn new(op: fn(i16) -> Instruction) -> NearJumpDecoder {
+        NearJumpDecoder { jump_op: op }
+    }
+}
+
+impl OpCodeDecoder for NearJumpDecoder {
+    fn decode(&self, _op_code: u8, bytes: &mut impl ByteReader) -> Result<Instruction, DecodeError> {
+        let disp = decode_i16(bytes)?;
+        Ok((self.jump_op)(disp))
+    }
+}
+
+#[derive(Clone)]
+pub struct FarJumpDecoder {
+    jump_op: fn(u16, u16) -> Instruction,
+}
+
+impl FarJumpDecoder {
+    pub fn new(op: fn(u16, u16) -> Instruction) -> FarJumpDecoder {
+        FarJumpDecoder { jump_op: op }
+    }
+}
+
+impl OpCodeDecoder for FarJumpDecoder {
+    fn decode(&self, _op_code: u8, bytes: &mut impl ByteReader) -> Result<Instruction, DecodeError> {
+        let offset = decode_i16(bytes)? as u16;
+        let segment = decode_i16(bytes)? as u16;
+        Ok((self.jump_op)(offset, segment))
+    }
+}
+
+#[derive(Clone)]
+pub struct ReturnDecoder {
+    return_op: fn(Option<u16>) -> Instruction,
+}
+
+impl ReturnDecoder {
+    const NO_POP_MASK: u8 = 0b0000_0001;
+
+    pub fn new(op: fn(Option<u16>) -> Instruction) -> ReturnDecoder {
+        ReturnDecoder { return_op: op }
+    }
+}
+
+impl OpCodeDecoder for ReturnDecoder {
+    fn decode(&self, op_code: u8, bytes: &mut impl ByteReader) -> Result<Instruction, DecodeError> {
+        let pop = if op_code & Self::NO_POP_MASK == 0 {
+            Some(decode_i16(bytes)? as u16)
+        } else {
+            None
+        };
+        Ok((self.return_op)(pop))
+    }
+}
+
+#[derive(Clone)]
+pub struct ByteImmediateDecoder {
+    op: fn(u8) -> Instruction,
+}
+
+impl ByteImmediateDecoder {
+    pub fn new(op: fn(u8) -> Instruction) -> ByteImmediateDecoder {
+        ByteImmediateDecoder { op }
+    }
+}
+
+impl OpCodeDecoder for ByteImmediateDecoder {
+    fn decode(&self, _op_code: u8, bytes: &mut impl ByteReader) -> Result<Instruction, DecodeError> {
+        Ok((self.op)(bytes.next()?))
     }
 }
 
@@ -135,52 +349,57 @@ impl ArithmeticFromToRegMemDecoder {
 }
 
 impl OpCodeDecoder for ArithmeticFromToRegMemDecoder {
-    fn decode(&self, op_code: u8, bytes: &mut dyn Iterator<Item = &u8>) -> Instruction {
+    fn decode(&self, op_code: u8, bytes: &mut impl ByteReader) -> Result<Instruction, DecodeError> {
         let dir = decode_dir(op_code, Self::DIR_MASK);
         let width = decode_width(op_code, Self::WIDTH_MASK);
 
         let op = decode_arithmetic_op((op_code >> 3) & 0b0000_0111);
 
-        let next = bytes.next().unwrap();
-        let mode = decode_mode((*next >> 6) & 0b0000_0011);
-        let reg = decode_reg((*next >> 3) & 0b0000_0111, width);
-        let reg_or_mem = decode_reg_or_mem(next & 0b0000_0111, mode, width, bytes);
+        let next = bytes.next()?;
+        let mode = decode_mode((next >> 6) & 0b0000_0011);
+        let reg = decode_reg((next >> 3) & 0b0000_0111, width);
+        let reg_or_mem = decode_reg_or_mem(next & 0b0000_0111, mode, width, bytes)?;
 
-        Instruction::ArithmeticFromToRegMem { op, dir, width, reg, reg_or_mem }
+        Ok(Instruction::ArithmeticFromToRegMem { op, dir, width, reg, reg_or_mem })
     }
 }
 
 #[derive(Clone)]
-pub struct ArithmeticImmediateToRegMemDecoder {}
+pub struct ArithmeticImmediateToRegMemDecoder {
+    op: ArithmeticOp,
+}
 
 impl ArithmeticImmediateToRegMemDecoder {
     const SIGN_EXTEND_MASK: u8 = 0b0000_0010;
     const WIDTH_MASK: u8 = 0b0000_0001;
+
+    pub fn new(op: ArithmeticOp) -> ArithmeticImmediateToRegMemDecoder {
+        ArithmeticImmediateToRegMemDecoder { op }
+    }
 }
 
 impl OpCodeDecoder for ArithmeticImmediateToRegMemDecoder {
-    fn decode(&self, op_code: u8, bytes: &mut dyn Iterator<Item = &u8>) -> Instruction {
+    fn decode(&self, op_code: u8, bytes: &mut impl ByteReader) -> Result<Instruction, DecodeError> {
         let sign_extend = op_code & Self::SIGN_EXTEND_MASK != 0;
         let width = decode_width(op_code, Self::WIDTH_MASK);
 
-        let next = bytes.next().unwrap();
+        let next = bytes.next()?;
 
-        let mode = decode_mode(*next >> 6 & 0b0000_0011);
-        let op = decode_arithmetic_op((*next >> 3) & 0b0000_0111);
-        let reg_or_mem = decode_reg_or_mem(*next & 0b0000_0111, mode, width, bytes);
+        let mode = decode_mode(next >> 6 & 0b0000_0011);
+        let op = self.op;
+        let reg_or_mem = decode_reg_or_mem(next & 0b0000_0111, mode, width, bytes)?;
 
-        let data = if !sign_extend {
-            decode_immediate(bytes, width)
-        } else {
-            decode_immediate(bytes, OpWidth::Byte)
+        let data = match (sign_extend, width) {
+            (true, OpWidth::Word) => Immediate::SignExtended(Imm8(bytes.next()?)),
+            _ => decode_immediate(bytes, width)?,
         };
 
-        Instruction::ArithmeticImmediateToRegMem {
+        Ok(Instruction::ArithmeticImmediateToRegMem {
             op,
             width,
             data,
             reg_or_mem,
-        }
+        })
     }
 }
 
@@ -192,23 +411,418 @@ impl ArithmeticImmediateToAccumulatorDecoder {
 }
 
 impl OpCodeDecoder for ArithmeticImmediateToAccumulatorDecoder {
-    fn decode(&self, op_code: u8, bytes: &mut dyn Iterator<Item = &u8>) -> Instruction {
+    fn decode(&self, op_code: u8, bytes: &mut impl ByteReader) -> Result<Instruction, DecodeError> {
         let op = decode_arithmetic_op((op_code >> 3) & 0b0000_0111);
         let width = decode_width(op_code, Self::WIDTH_MASK);
-        let data = decode_immediate(bytes, width);
+        let data = decode_immediate(bytes, width)?;
+
+        Ok(Instruction::ArithmeticImmediateToAccumulator { op, width, data })
+    }
+}
+
+#[derive(Clone)]
+pub struct RegisterDecoder {
+    op: fn(RegisterAccess) -> Instruction,
+}
+
+impl RegisterDecoder {
+    pub fn new(op: fn(RegisterAccess) -> Instruction) -> RegisterDecoder {
+        RegisterDecoder { op }
+    }
+}
+
+impl OpCodeDecoder for RegisterDecoder {
+    fn decode(&self, op_code: u8, _bytes: &mut impl ByteReader) -> Result<Instruction, DecodeError> {
+        let reg = decode_reg(op_code & 0b0000_0111, OpWidth::Word);
+        Ok((self.op)(reg))
+    }
+}
+
+#[derive(Clone)]
+pub struct SegmentRegisterDecoder {
+    op: fn(SegmentRegister) -> Instruction,
+}
+
+impl SegmentRegisterDecoder {
+    pub fn new(op: fn(SegmentRegister) -> Instruction) -> SegmentRegisterDecoder {
+        SegmentRegisterDecoder { op }
+    }
+}
+
+impl OpCodeDecoder for SegmentRegisterDecoder {
+    fn decode(&self, op_code: u8, _bytes: &mut impl ByteReader) -> Result<Instruction, DecodeError> {
+        let seg_reg = decode_seg_reg((op_code >> 3) & 0b0000_0011);
+        Ok((self.op)(seg_reg))
+    }
+}
+
+/// Group opcodes whose only operand is the ModRM r/m field, the operation having been selected by the reg field
+#[derive(Clone)]
+pub struct RegMemDecoder {
+    op: fn(OpWidth, RegOrMem) -> Instruction,
+}
+
+impl RegMemDecoder {
+    const WIDTH_MASK: u8 = 0b0000_0001;
+
+    pub fn new(op: fn(OpWidth, RegOrMem) -> Instruction) -> RegMemDecoder {
+        RegMemDecoder { op }
+    }
+}
+
+impl OpCodeDecoder for RegMemDecoder {
+    fn decode(&self, op_code: u8, bytes: &mut impl ByteReader) -> Result<Instruction, DecodeError> {
+        let width = decode_width(op_code, Self::WIDTH_MASK);
+
+        let next = bytes.next()?;
+        let mode = decode_mode((next >> 6) & 0b0000_0011);
+        let reg_or_mem = decode_reg_or_mem(next & 0b0000_0111, mode, width, bytes)?;
+
+        Ok((self.op)(width, reg_or_mem))
+    }
+}
+
+/// Like `RegMemDecoder`, for operations such as far calls that need a memory operand
+#[derive(Clone)]
+pub struct MemoryDecoder {
+    op: fn(EffectiveAddress) -> Instruction,
+}
+
+impl MemoryDecoder {
+    pub fn new(op: fn(EffectiveAddress) -> Instruction) -> MemoryDecoder {
+        MemoryDecoder { op }
+    }
+}
+
+impl OpCodeDecoder for MemoryDecoder {
+    fn decode(&self, _op_code: u8, bytes: &mut impl ByteReader) -> Result<Instruction, DecodeError> {
+        let modrm_offset = bytes.position();
+        let next = bytes.next()?;
+        let mode = decode_mode((next >> 6) & 0b0000_0011);
+
+        Ok(match decode_reg_or_mem(next & 0b0000_0111, mode, OpWidth::Word, bytes)? {
+            RegOrMem::Mem(mem) => (self.op)(mem),
+            RegOrMem::Reg(_) => return Err(DecodeError::UnsupportedModRm { offset: modrm_offset, modrm: next }),
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct ExchangeDecoder {}
+
+impl ExchangeDecoder {
+    const WIDTH_MASK: u8 = 0b0000_0001;
+}
+
+impl OpCodeDecoder for ExchangeDecoder {
+    fn decode(&self, op_code: u8, bytes: &mut impl ByteReader) -> Result<Instruction, DecodeError> {
+        let width = decode_width(op_code, Self::WIDTH_MASK);
 
-        Instruction::ArithmeticImmediateToAccumulator { op, width, data }
+        let next = bytes.next()?;
+        let mode = decode_mode((next >> 6) & 0b0000_0011);
+        let reg = decode_reg((next >> 3) & 0b0000_0111, width);
+        let reg_or_mem = decode_reg_or_mem(next & 0b0000_0111, mode, width, bytes)?;
+
+        Ok(Instruction::ExchangeRegMemWithReg { reg, reg_or_mem })
+    }
+}
+
+#[derive(Clone)]
+pub struct FixedPortDecoder {
+    op: fn(OpWidth, u8) -> Instruction,
+}
+
+impl FixedPortDecoder {
+    const WIDTH_MASK: u8 = 0b0000_0001;
+
+    pub fn new(op: fn(OpWidth, u8) -> Instruction) -> FixedPortDecoder {
+        FixedPortDecoder { op }
+    }
+}
+
+impl OpCodeDecoder for FixedPortDecoder {
+    fn decode(&self, op_code: u8, bytes: &mut impl ByteReader) -> Result<Instruction, DecodeError> {
+        let width = decode_width(op_code, Self::WIDTH_MASK);
+        let port = bytes.next()?;
+        Ok((self.op)(width, port))
+    }
+}
+
+#[derive(Clone)]
+pub struct VariablePortDecoder {
+    op: fn(OpWidth) -> Instruction,
+}
+
+impl VariablePortDecoder {
+    const WIDTH_MASK: u8 = 0b0000_0001;
+
+    pub fn new(op: fn(OpWidth) -> Instruction) -> VariablePortDecoder {
+        VariablePortDecoder { op }
+    }
+}
+
+impl OpCodeDecoder for VariablePortDecoder {
+    fn decode(&self, op_code: u8, _bytes: &mut impl ByteReader) -> Result<Instruction, DecodeError> {
+        let width = decode_width(op_code, Self::WIDTH_MASK);
+        Ok((self.op)(width))
+    }
+}
+
+#[derive(Clone)]
+pub struct LoadAddressDecoder {
+    op: fn(RegisterAccess, EffectiveAddress) -> Instruction,
+}
+
+impl LoadAddressDecoder {
+    pub fn new(op: fn(RegisterAccess, EffectiveAddress) -> Instruction) -> LoadAddressDecoder {
+        LoadAddressDecoder { op }
+    }
+}
+
+impl OpCodeDecoder for LoadAddressDecoder {
+    fn decode(&self, _op_code: u8, bytes: &mut impl ByteReader) -> Result<Instruction, DecodeError> {
+        let modrm_offset = bytes.position();
+        let next = bytes.next()?;
+        let mode = decode_mode((next >> 6) & 0b0000_0011);
+        let reg = decode_reg((next >> 3) & 0b0000_0111, OpWidth::Word);
+
+        Ok(match decode_reg_or_mem(next & 0b0000_0111, mode, OpWidth::Word, bytes)? {
+            RegOrMem::Mem(mem) => (self.op)(reg, mem),
+            RegOrMem::Reg(_) => return Err(DecodeError::UnsupportedModRm { offset: modrm_offset, modrm: next }),
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct SingleByteDecoder {
+    instruction: Instruction,
+}
+
+impl SingleByteDecoder {
+    pub fn new(instruction: Instruction) -> SingleByteDecoder {
+        SingleByteDecoder { instruction }
+    }
+}
+
+impl OpCodeDecoder for SingleByteDecoder {
+    fn decode(&self, _op_code: u8, _bytes: &mut impl ByteReader) -> Result<Instruction, DecodeError> {
+        Ok(self.instruction.clone())
+    }
+}
+
+#[derive(Clone)]
+pub struct TestRegMemWithRegDecoder {}
+
+impl TestRegMemWithRegDecoder {
+    const WIDTH_MASK: u8 = 0b0000_0001;
+}
+
+impl OpCodeDecoder for TestRegMemWithRegDecoder {
+    fn decode(&self, op_code: u8, bytes: &mut impl ByteReader) -> Result<Instruction, DecodeError> {
+        let width = decode_width(op_code, Self::WIDTH_MASK);
+
+        let next = bytes.next()?;
+        let mode = decode_mode((next >> 6) & 0b0000_0011);
+        let reg = decode_reg((next >> 3) & 0b0000_0111, width);
+        let reg_or_mem = decode_reg_or_mem(next & 0b0000_0111, mode, width, bytes)?;
+
+        Ok(Instruction::TestRegMemWithReg { reg, reg_or_mem })
+    }
+}
+
+#[derive(Clone)]
+pub struct TestImmediateWithAccumulatorDecoder {}
+
+impl TestImmediateWithAccumulatorDecoder {
+    const WIDTH_MASK: u8 = 0b0000_0001;
+}
+
+impl OpCodeDecoder for TestImmediateWithAccumulatorDecoder {
+    fn decode(&self, op_code: u8, bytes: &mut impl ByteReader) -> Result<Instruction, DecodeError> {
+        let width = decode_width(op_code, Self::WIDTH_MASK);
+        let data = decode_immediate(bytes, width)?;
+
+        Ok(Instruction::TestImmediateWithAccumulator { width, data })
+    }
+}
+
+#[derive(Clone)]
+pub struct TestImmediateWithRegMemDecoder {}
+
+impl TestImmediateWithRegMemDecoder {
+    const WIDTH_MASK: u8 = 0b0000_0001;
+}
+
+impl OpCodeDecoder for TestImmediateWithRegMemDecoder {
+    fn decode(&self, op_code: u8, bytes: &mut impl ByteReader) -> Result<Instruction, DecodeError> {
+        let width = decode_width(op_code, Self::WIDTH_MASK);
+
+        let next = bytes.next()?;
+        let mode = decode_mode((next >> 6) & 0b0000_0011);
+        let reg_or_mem = decode_reg_or_mem(next & 0b0000_0111, mode, width, bytes)?;
+        let data = decode_immediate(bytes, width)?;
+
+        Ok(Instruction::TestImmediateWithRegMem { width, data, reg_or_mem })
+    }
+}
+
+#[derive(Clone)]
+pub struct ShiftDecoder {
+    op: ShiftOp,
+}
+
+impl ShiftDecoder {
+    const COUNT_MASK: u8 = 0b0000_0010;
+    const WIDTH_MASK: u8 = 0b0000_0001;
+
+    pub fn new(op: ShiftOp) -> ShiftDecoder {
+        ShiftDecoder { op }
+    }
+}
+
+impl OpCodeDecoder for ShiftDecoder {
+    fn decode(&self, op_code: u8, bytes: &mut impl ByteReader) -> Result<Instruction, DecodeError> {
+        let width = decode_width(op_code, Self::WIDTH_MASK);
+        let count = if op_code & Self::COUNT_MASK != 0 {
+            ShiftCount::Cl
+        } else {
+            ShiftCount::One
+        };
+
+        let next = bytes.next()?;
+        let mode = decode_mode((next >> 6) & 0b0000_0011);
+        let reg_or_mem = decode_reg_or_mem(next & 0b0000_0111, mode, width, bytes)?;
+
+        Ok(Instruction::Shift { op: self.op, width, count, reg_or_mem })
+    }
+}
+
+#[derive(Clone)]
+pub struct EscapeDecoder {}
+
+impl OpCodeDecoder for EscapeDecoder {
+    fn decode(&self, op_code: u8, bytes: &mut impl ByteReader) -> Result<Instruction, DecodeError> {
+        let next = bytes.next()?;
+        let mode = decode_mode((next >> 6) & 0b0000_0011);
+        let opcode = ((op_code & 0b0000_0111) << 3) | ((next >> 3) & 0b0000_0111);
+        let reg_or_mem = decode_reg_or_mem(next & 0b0000_0111, mode, OpWidth::Word, bytes)?;
+
+        Ok(Instruction::Escape { opcode, reg_or_mem })
+    }
+}
+
+#[derive(Clone)]
+pub struct StringDecoder {
+    op: StringOp,
+}
+
+impl StringDecoder {
+    const WIDTH_MASK: u8 = 0b0000_0001;
+
+    pub fn new(op: StringOp) -> StringDecoder {
+        StringDecoder { op }
+    }
+}
+
+impl OpCodeDecoder for StringDecoder {
+    fn decode(&self, op_code: u8, _bytes: &mut impl ByteReader) -> Result<Instruction, DecodeError> {
+        let width = decode_width(op_code, Self::WIDTH_MASK);
+        Ok(Instruction::StringManipulation { op: self.op, width, repeat: None, segment: None })
+    }
+}
+
+#[derive(Clone)]
+pub struct ShiftImmediateDecoder {
+    op: ShiftOp,
+}
+
+impl ShiftImmediateDecoder {
+    const WIDTH_MASK: u8 = 0b0000_0001;
+
+    pub fn new(op: ShiftOp) -> ShiftImmediateDecoder {
+        ShiftImmediateDecoder { op }
+    }
+}
+
+impl OpCodeDecoder for ShiftImmediateDecoder {
+    fn decode(&self, op_code: u8, bytes: &mut impl ByteReader) -> Result<Instruction, DecodeError> {
+        let width = decode_width(op_code, Self::WIDTH_MASK);
+
+        let next = bytes.next()?;
+        let mode = decode_mode((next >> 6) & 0b0000_0011);
+        let reg_or_mem = decode_reg_or_mem(next & 0b0000_0111, mode, width, bytes)?;
+        let count = ShiftCount::Immediate(bytes.next()?);
+
+        Ok(Instruction::Shift { op: self.op, width, count, reg_or_mem })
+    }
+}
+
+#[derive(Clone)]
+pub struct PushImmediateDecoder {}
+
+impl PushImmediateDecoder {
+    const SIGN_EXTEND_MASK: u8 = 0b0000_0010;
+}
+
+impl OpCodeDecoder for PushImmediateDecoder {
+    fn decode(&self, op_code: u8, bytes: &mut impl ByteReader) -> Result<Instruction, DecodeError> {
+        let data = if op_code & Self::SIGN_EXTEND_MASK != 0 {
+            Immediate::SignExtended(Imm8(bytes.next()?))
+        } else {
+            decode_immediate(bytes, OpWidth::Word)?
+        };
+
+        Ok(Instruction::PushImmediate { data })
+    }
+}
+
+#[derive(Clone)]
+pub struct MultiplyImmediateDecoder {}
+
+impl MultiplyImmediateDecoder {
+    const SIGN_EXTEND_MASK: u8 = 0b0000_0010;
+}
+
+impl OpCodeDecoder for MultiplyImmediateDecoder {
+    fn decode(&self, op_code: u8, bytes: &mut impl ByteReader) -> Result<Instruction, DecodeError> {
+        let next = bytes.next()?;
+        let mode = decode_mode((next >> 6) & 0b0000_0011);
+        let reg = decode_reg((next >> 3) & 0b0000_0111, OpWidth::Word);
+        let reg_or_mem = decode_reg_or_mem(next & 0b0000_0111, mode, OpWidth::Word, bytes)?;
+
+        let data = if op_code & Self::SIGN_EXTEND_MASK != 0 {
+            Immediate::SignExtended(Imm8(bytes.next()?))
+        } else {
+            decode_immediate(bytes, OpWidth::Word)?
+        };
+
+        Ok(Instruction::MultiplyImmediate { reg, reg_or_mem, data })
+    }
+}
+
+#[derive(Clone)]
+pub struct EnterDecoder {}
+
+impl OpCodeDecoder for EnterDecoder {
+    fn decode(&self, _op_code: u8, bytes: &mut impl ByteReader) -> Result<Instruction, DecodeError> {
+        let size = Imm16(decode_i16(bytes)? as u16);
+        let level = Imm8(bytes.next()?);
+
+        Ok(Instruction::Enter { size, level })
     }
 }
 
 fn decode_arithmetic_op(byte: u8) -> ArithmeticOp {
     match byte {
         0 => ArithmeticOp::Add,
+        1 => ArithmeticOp::Or,
         2 => ArithmeticOp::Adc,
         3 => ArithmeticOp::Sbb,
+        4 => ArithmeticOp::And,
         5 => ArithmeticOp::Sub,
+        6 => ArithmeticOp::Xor,
         7 => ArithmeticOp::Cmp,
-        _ => todo!("not implemented yet"),
+        _ => panic!("impossible, we're only selecting 3 bits"),
     }
 }
 
@@ -226,29 +840,29 @@ fn effective_address_base2(mem: u8) -> EffectiveAddressBase {
     }
 }
 
-fn decode_i8(bytes: &mut dyn Iterator<Item = &u8>) -> i16  {
-    let lo = bytes.next().unwrap();
-    i8::from_le_bytes([*lo]) as i16
+fn decode_i8(bytes: &mut impl ByteReader) -> Result<i8, DecodeError> {
+    let lo = bytes.next()?;
+    Ok(i8::from_le_bytes([lo]))
 }
 
 // fn decode_i8(lo: &u8) -> i16 {
 //     i8::from_le_bytes([*lo]) as i16
 // }
 
-fn decode_i16(bytes: &mut dyn Iterator<Item = &u8>) -> i16 {
-    let lo = bytes.next().unwrap();
-    let hi = bytes.next().unwrap();
-    i16::from_le_bytes([*lo, *hi])
+fn decode_i16(bytes: &mut impl ByteReader) -> Result<i16, DecodeError> {
+    let lo = bytes.next()?;
+    let hi = bytes.next()?;
+    Ok(i16::from_le_bytes([lo, hi]))
 }
 
 // fn decode_i16(lo: &u8, hi: &u8) -> i16 {
 //     i16::from_le_bytes([*lo, *hi])
 // }
 
-fn decode_immediate(bytes: &mut dyn Iterator<Item = &u8>, width: OpWidth) -> i16 {
+fn decode_immediate(bytes: &mut impl ByteReader, width: OpWidth) -> Result<Immediate, DecodeError> {
     match width {
-        OpWidth::Byte => decode_i8(bytes),
-        OpWidth::Word => decode_i16(bytes),
+        OpWidth::Byte => Ok(Immediate::Byte(Imm8(bytes.next()?))),
+        OpWidth::Word => Ok(Immediate::Word(Imm16(decode_i16(bytes)? as u16))),
     }
 }
 
@@ -288,45 +902,38 @@ fn decode_seg_reg(seg_reg: u8) -> SegmentRegister {
     }
 }
 
-fn decode_address(bytes: &mut dyn Iterator<Item = &u8>, width: OpWidth) -> i16 {
-    match width {
-        OpWidth::Byte => decode_i8(bytes),
-        OpWidth::Word => decode_i16(bytes),
-    }
+fn decode_address(bytes: &mut impl ByteReader) -> Result<Addr16, DecodeError> {
+    Ok(Addr16(decode_i16(bytes)? as u16))
 }
 
-fn decode_reg_or_mem(reg_or_mem: u8, mode: Mode, width: OpWidth, bytes: &mut dyn Iterator<Item = &u8>) -> RegOrMem {
-    match mode {
+fn decode_reg_or_mem(reg_or_mem: u8, mode: Mode, width: OpWidth, bytes: &mut impl ByteReader) -> Result<RegOrMem, DecodeError> {
+    Ok(match mode {
         Mode::Register => RegOrMem::Reg(decode_reg(reg_or_mem, width)),
         Mode::MemoryNoDisplacement if reg_or_mem == 0b110 => {
-            let direct = match width {
-                OpWidth::Byte => decode_i8(bytes),
-                OpWidth::Word => decode_i16(bytes),
-            };
-            RegOrMem::Mem(EffectiveAddress {
-                base: EffectiveAddressBase::Direct,
-                displacement: direct,
-            })
+            RegOrMem::Mem(EffectiveAddress::direct(decode_address(bytes)?))
         }
         Mode::MemoryNoDisplacement => RegOrMem::Mem(EffectiveAddress {
             base: effective_address_base2(reg_or_mem),
-            displacement: 0,
+            displacement: Displacement::None,
+            segment: None,
         }),
         Mode::MemoryEightBitDisplacement => {
-            let displacement = decode_i8(bytes);
+            let displacement = Displacement::Disp8(decode_i8(bytes)?);
             RegOrMem::Mem(EffectiveAddress {
                 base: effective_address_base2(reg_or_mem),
                 displacement,
+                segment: None,
             })
         }
         Mode::MemorySixteenBitDisplacement => {
-            let displacement = decode_i16(bytes);
+            let displacement = Displacement::Disp16(decode_i16(bytes)?);
             RegOrMem::Mem(EffectiveAddress {
                 base: effective_address_base2(reg_or_mem),
                 displacement,
+                segment: None,
             })
         }
-    }
+    })
 }
 
 fn decode_reg(reg: u8, width: OpWidth) -> RegisterAccess {
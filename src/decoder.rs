@@ -1,23 +1,154 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
 use crate::decode::*;
 use crate::lookup::*;
-use crate::ops::Instruction;
+use crate::ops::{AdjustOp, ArithmeticOp, Instruction, MultiplyDivideOp, OpWidth, RegOrMem, ShiftOp, StringOp};
+
+/// Why an instruction could not be decoded. `decode_at` reports offsets into the slice it was given,
+/// `decode_next` counts from the first byte it reads; use `relocate` to turn those into memory offsets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    UnknownOpcode { offset: usize, opcode: u8 },
+    Truncated { offset: usize },
+    UnsupportedModRm { offset: usize, modrm: u8 },
+    /// An opcode the CPU executes but Intel doesn't document, rejected by `DecodeMode::Strict`
+    Undocumented { offset: usize, opcode: u8 },
+}
+
+impl DecodeError {
+    pub fn offset(&self) -> usize {
+        match *self {
+            DecodeError::UnknownOpcode { offset, .. } => offset,
+            DecodeError::Truncated { offset } => offset,
+            DecodeError::UnsupportedModRm { offset, .. } => offset,
+            DecodeError::Undocumented { offset, .. } => offset,
+        }
+    }
+
+    pub fn relocate(self, base: usize) -> DecodeError {
+        match self {
+            DecodeError::UnknownOpcode { offset, opcode } => DecodeError::UnknownOpcode { offset: base + offset, opcode },
+            DecodeError::Truncated { offset } => DecodeError::Truncated { offset: base + offset },
+            DecodeError::UnsupportedModRm { offset, modrm } => DecodeError::UnsupportedModRm { offset: base + offset, modrm },
+            DecodeError::Undocumented { offset, opcode } => DecodeError::Undocumented { offset: base + offset, opcode },
+        }
+    }
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::UnknownOpcode { offset, opcode } => write!(f, "unknown opcode {opcode:#04x} at offset {offset:#x}"),
+            DecodeError::Truncated { offset } => write!(f, "instruction truncated at offset {offset:#x}"),
+            DecodeError::UnsupportedModRm { offset, modrm } => write!(f, "unsupported ModRM byte {modrm:#04x} at offset {offset:#x}"),
+            DecodeError::Undocumented { offset, opcode } => write!(f, "undocumented opcode {opcode:#04x} at offset {offset:#x}"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// The processor whose instruction set is decoded. The 80286 is decoded in real mode only,
+/// its protected mode system instructions are not recognised.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum CpuModel {
+    #[default]
+    I8086,
+    I80186,
+    I80286,
+}
+
+impl Display for CpuModel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CpuModel::I8086 => f.write_str("8086"),
+            CpuModel::I80186 => f.write_str("80186"),
+            CpuModel::I80286 => f.write_str("80286"),
+        }
+    }
+}
+
+impl FromStr for CpuModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "8086" | "8088" => Ok(CpuModel::I8086),
+            "80186" | "186" | "80188" => Ok(CpuModel::I80186),
+            "80286" | "286" => Ok(CpuModel::I80286),
+            _ => Err(format!("unknown cpu model {s}, expected 8086, 80186 or 80286")),
+        }
+    }
+}
+
+/// Whether undocumented opcodes decode the way the silicon executes them, or are rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DecodeMode {
+    #[default]
+    Strict,
+    Permissive,
+}
+
+/// How the decoder handles a first byte, as reported by `Decoder::coverage`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpCodeCoverage {
+    Unmapped,
+    Prefix,
+    Mapped,
+    /// A group opcode; bit n of `reg_fields` is set when ModRM reg field value n decodes
+    Group { reg_fields: u8 },
+}
+
+/// An instruction together with where it was found and the bytes it was decoded from
+#[derive(Debug, Clone)]
+pub struct DecodedInstruction<'a> {
+    pub address: usize,
+    pub length: usize,
+    pub bytes: &'a [u8],
+    pub instruction: Instruction,
+}
+
+impl DecodedInstruction<'_> {
+    /// Address of the instruction that follows this one
+    pub fn next_address(&self) -> usize {
+        self.address + self.length
+    }
+}
 
 pub struct Decoder {
     lookup: OpDecoderLookup,
+    /// Undocumented opcodes, only consulted to report them in strict mode
+    undocumented: OpDecoderLookup,
+    model: CpuModel,
 }
 
 impl Decoder {
-    pub fn new() -> Decoder {
+    /// A strict decoder for the instruction set of `model`. Opcodes the model doesn't have are
+    /// reported as `DecodeError::UnknownOpcode`, the way the CPU treats them as invalid.
+    pub fn new(model: CpuModel) -> Decoder {
+        Self::with_mode(model, DecodeMode::Strict)
+    }
+
+    pub fn with_mode(model: CpuModel, mode: DecodeMode) -> Decoder {
         let mut lookup = OpDecoderLookup::new();
         lookup.insert("0b1000_10dw", MovToFromRegMemDecoder {});
-        lookup.insert("0b1100_011w", ImmediateMovToRegMemDecoder {});
+        lookup.insert("0b1100_011w 0bmm_000_rrr", ImmediateMovToRegMemDecoder {});
         lookup.insert("0b1011_wreg", ImmediateMovToRegDecoder {});
         lookup.insert("0b1010_00dw", MovAccumulatorDecoder {});
         lookup.insert("0b1000_11d0", MovSegmentDecoder {});
 
         lookup.insert("0b00xx_x0dw", ArithmeticFromToRegMemDecoder {});
-        lookup.insert("0b1000_00sw", ArithmeticImmediateToRegMemDecoder {});
-        lookup.insert("0b00xx_x1dw", ArithmeticImmediateToAccumulatorDecoder {});
+        lookup.insert("0b1000_00sw 0bmm_000_rrr", ArithmeticImmediateToRegMemDecoder::new(ArithmeticOp::Add));
+        lookup.insert("0b1000_00sw 0bmm_001_rrr", ArithmeticImmediateToRegMemDecoder::new(ArithmeticOp::Or));
+        lookup.insert("0b1000_00sw 0bmm_010_rrr", ArithmeticImmediateToRegMemDecoder::new(ArithmeticOp::Adc));
+        lookup.insert("0b1000_00sw 0bmm_011_rrr", ArithmeticImmediateToRegMemDecoder::new(ArithmeticOp::Sbb));
+        lookup.insert("0b1000_00sw 0bmm_100_rrr", ArithmeticImmediateToRegMemDecoder::new(ArithmeticOp::And));
+        lookup.insert("0b1000_00sw 0bmm_101_rrr", ArithmeticImmediateToRegMemDecoder::new(ArithmeticOp::Sub));
+        lookup.insert("0b1000_00sw 0bmm_110_rrr", ArithmeticImmediateToRegMemDecoder::new(ArithmeticOp::Xor));
+        lookup.insert("0b1000_00sw 0bmm_111_rrr", ArithmeticImmediateToRegMemDecoder::new(ArithmeticOp::Cmp));
+        lookup.insert("0b00xx_x10w", ArithmeticImmediateToAccumulatorDecoder {});
 
         lookup.insert("0b0111_0100", JumpDecoder::new(Instruction::JumpOnEqual));
         lookup.insert("0b0111_1100", JumpDecoder::new(Instruction::JumpOnLess));
@@ -40,22 +171,632 @@ impl Decoder {
         lookup.insert("0b1110_0000", JumpDecoder::new(Instruction::LoopWhileNotEqual));
         lookup.insert("0b1110_0011", JumpDecoder::new(Instruction::JumpOnCxZero));
 
-        Decoder { lookup }
+        lookup.insert("0b1110_1000", NearJumpDecoder::new(Instruction::CallDirect));
+        lookup.insert("0b1001_1010", FarJumpDecoder::new(|offset, segment| Instruction::CallFarDirect { offset, segment }));
+        lookup.insert("0b1110_1001", NearJumpDecoder::new(Instruction::JumpDirect));
+        lookup.insert("0b1110_1011", JumpDecoder::new(Instruction::JumpShort));
+        lookup.insert("0b1110_1010", FarJumpDecoder::new(|offset, segment| Instruction::JumpFarDirect { offset, segment }));
+        lookup.insert("0b1100_001x", ReturnDecoder::new(|pop| Instruction::Return { pop }));
+        lookup.insert("0b1100_101x", ReturnDecoder::new(|pop| Instruction::ReturnFar { pop }));
+        lookup.insert("0b1100_1101", ByteImmediateDecoder::new(Instruction::Interrupt));
+        lookup.insert("0b1100_1100", SingleByteDecoder::new(Instruction::Interrupt3));
+        lookup.insert("0b1100_1110", SingleByteDecoder::new(Instruction::InterruptOnOverflow));
+        lookup.insert("0b1100_1111", SingleByteDecoder::new(Instruction::InterruptReturn));
+
+
+        lookup.insert("0b1111_111w 0bmm_000_rrr", RegMemDecoder::new(|width, reg_or_mem| Instruction::IncrementRegMem { width, reg_or_mem }));
+        lookup.insert("0b1111_111w 0bmm_001_rrr", RegMemDecoder::new(|width, reg_or_mem| Instruction::DecrementRegMem { width, reg_or_mem }));
+        lookup.insert("0b1111_1111 0bmm_010_rrr", RegMemDecoder::new(|_, reg_or_mem| Instruction::CallIndirect { reg_or_mem }));
+        lookup.insert("0b1111_1111 0bmm_011_rrr", MemoryDecoder::new(|mem| Instruction::CallFarIndirect { mem }));
+        lookup.insert("0b1111_1111 0bmm_100_rrr", RegMemDecoder::new(|_, reg_or_mem| Instruction::JumpIndirect { reg_or_mem }));
+        lookup.insert("0b1111_1111 0bmm_101_rrr", MemoryDecoder::new(|mem| Instruction::JumpFarIndirect { mem }));
+        lookup.insert("0b1111_1111 0bmm_110_rrr", RegMemDecoder::new(|_, reg_or_mem| Instruction::PushRegMem { reg_or_mem }));
+        lookup.insert("0b0101_0reg", RegisterDecoder::new(|reg| Instruction::PushReg { reg }));
+        lookup.insert("0b000s_s110", SegmentRegisterDecoder::new(|seg_reg| Instruction::PushSegmentRegister { seg_reg }));
+        lookup.insert("0b1000_1111 0bmm_000_rrr", RegMemDecoder::new(|_, reg_or_mem| Instruction::PopRegMem { reg_or_mem }));
+        lookup.insert("0b0101_1reg", RegisterDecoder::new(|reg| Instruction::PopReg { reg }));
+        lookup.insert("0b0000_0111", SegmentRegisterDecoder::new(|seg_reg| Instruction::PopSegmentRegister { seg_reg }));
+        lookup.insert("0b0001_0111", SegmentRegisterDecoder::new(|seg_reg| Instruction::PopSegmentRegister { seg_reg }));
+        lookup.insert("0b0001_1111", SegmentRegisterDecoder::new(|seg_reg| Instruction::PopSegmentRegister { seg_reg }));
+        lookup.insert("0b1000_011w", ExchangeDecoder {});
+        lookup.insert("0b1001_0reg", RegisterDecoder::new(|reg| Instruction::ExchangeRegWithAccumulator { reg }));
+        lookup.insert("0b1110_010w", FixedPortDecoder::new(|width, port| Instruction::InFixedPort { width, port }));
+        lookup.insert("0b1110_110w", VariablePortDecoder::new(|width| Instruction::InVariablePort { width }));
+        lookup.insert("0b1110_011w", FixedPortDecoder::new(|width, port| Instruction::OutFixedPort { width, port }));
+        lookup.insert("0b1110_111w", VariablePortDecoder::new(|width| Instruction::OutVariablePort { width }));
+        lookup.insert("0b1101_0111", SingleByteDecoder::new(Instruction::TranslateByte { segment: None }));
+        lookup.insert("0b1000_1101", LoadAddressDecoder::new(|reg, mem| Instruction::LoadEffectiveAddress { reg, mem }));
+        lookup.insert("0b1100_0101", LoadAddressDecoder::new(|reg, mem| Instruction::LoadPointerToDs { reg, mem }));
+        lookup.insert("0b1100_0100", LoadAddressDecoder::new(|reg, mem| Instruction::LoadPointerToEs { reg, mem }));
+        lookup.insert("0b1001_1111", SingleByteDecoder::new(Instruction::LoadAhFromFlags));
+        lookup.insert("0b1001_1110", SingleByteDecoder::new(Instruction::StoreAhIntoFlags));
+        lookup.insert("0b1001_1100", SingleByteDecoder::new(Instruction::PushFlags));
+        lookup.insert("0b1001_1101", SingleByteDecoder::new(Instruction::PopFlags));
+
+        lookup.insert("0b1000_010w", TestRegMemWithRegDecoder {});
+        lookup.insert("0b1010_100w", TestImmediateWithAccumulatorDecoder {});
+        lookup.insert("0b1111_011w 0bmm_000_rrr", TestImmediateWithRegMemDecoder {});
+        lookup.insert("0b1111_011w 0bmm_010_rrr", RegMemDecoder::new(|width, reg_or_mem| Instruction::Not { width, reg_or_mem }));
+        lookup.insert("0b1111_011w 0bmm_011_rrr", RegMemDecoder::new(|width, reg_or_mem| Instruction::Negate { width, reg_or_mem }));
+        lookup.insert("0b1111_011w 0bmm_100_rrr", RegMemDecoder::new(|width, reg_or_mem| mul_div(MultiplyDivideOp::Mul, width, reg_or_mem)));
+        lookup.insert("0b1111_011w 0bmm_101_rrr", RegMemDecoder::new(|width, reg_or_mem| mul_div(MultiplyDivideOp::Imul, width, reg_or_mem)));
+        lookup.insert("0b1111_011w 0bmm_110_rrr", RegMemDecoder::new(|width, reg_or_mem| mul_div(MultiplyDivideOp::Div, width, reg_or_mem)));
+        lookup.insert("0b1111_011w 0bmm_111_rrr", RegMemDecoder::new(|width, reg_or_mem| mul_div(MultiplyDivideOp::Idiv, width, reg_or_mem)));
+
+        lookup.insert("0b1101_00vw 0bmm_000_rrr", ShiftDecoder::new(ShiftOp::Rol));
+        lookup.insert("0b1101_00vw 0bmm_001_rrr", ShiftDecoder::new(ShiftOp::Ror));
+        lookup.insert("0b1101_00vw 0bmm_010_rrr", ShiftDecoder::new(ShiftOp::Rcl));
+        lookup.insert("0b1101_00vw 0bmm_011_rrr", ShiftDecoder::new(ShiftOp::Rcr));
+        lookup.insert("0b1101_00vw 0bmm_100_rrr", ShiftDecoder::new(ShiftOp::Shl));
+        lookup.insert("0b1101_00vw 0bmm_101_rrr", ShiftDecoder::new(ShiftOp::Shr));
+        lookup.insert("0b1101_00vw 0bmm_111_rrr", ShiftDecoder::new(ShiftOp::Sar));
+
+        lookup.insert("0b0100_0reg", RegisterDecoder::new(|reg| Instruction::IncrementReg { reg }));
+        lookup.insert("0b0100_1reg", RegisterDecoder::new(|reg| Instruction::DecrementReg { reg }));
+
+        lookup.insert("0b1010_010w", StringDecoder::new(StringOp::Movs));
+        lookup.insert("0b1010_011w", StringDecoder::new(StringOp::Cmps));
+        lookup.insert("0b1010_111w", StringDecoder::new(StringOp::Scas));
+        lookup.insert("0b1010_110w", StringDecoder::new(StringOp::Lods));
+        lookup.insert("0b1010_101w", StringDecoder::new(StringOp::Stos));
+
+        lookup.insert("0b1111_1000", SingleByteDecoder::new(Instruction::ClearCarry));
+        lookup.insert("0b1111_0101", SingleByteDecoder::new(Instruction::ComplementCarry));
+        lookup.insert("0b1111_1001", SingleByteDecoder::new(Instruction::SetCarry));
+        lookup.insert("0b1111_1100", SingleByteDecoder::new(Instruction::ClearDirection));
+        lookup.insert("0b1111_1101", SingleByteDecoder::new(Instruction::SetDirection));
+        lookup.insert("0b1111_1010", SingleByteDecoder::new(Instruction::ClearInterrupt));
+        lookup.insert("0b1111_1011", SingleByteDecoder::new(Instruction::SetInterrupt));
+        lookup.insert("0b1111_0100", SingleByteDecoder::new(Instruction::Halt));
+        lookup.insert("0b1001_1011", SingleByteDecoder::new(Instruction::Wait));
+        lookup.insert("0b1101_1xxx", EscapeDecoder {});
+        lookup.insert("0b0010_0111", SingleByteDecoder::new(Instruction::Adjust(AdjustOp::Daa)));
+        lookup.insert("0b0010_1111", SingleByteDecoder::new(Instruction::Adjust(AdjustOp::Das)));
+        lookup.insert("0b0011_0111", SingleByteDecoder::new(Instruction::Adjust(AdjustOp::Aaa)));
+        lookup.insert("0b0011_1111", SingleByteDecoder::new(Instruction::Adjust(AdjustOp::Aas)));
+        lookup.insert("0b1101_0100", ByteImmediateDecoder::new(|base| Instruction::AsciiAdjustMultiply { base }));
+        lookup.insert("0b1101_0101", ByteImmediateDecoder::new(|base| Instruction::AsciiAdjustDivide { base }));
+        lookup.insert("0b1001_1000", SingleByteDecoder::new(Instruction::ConvertByteToWord));
+        lookup.insert("0b1001_1001", SingleByteDecoder::new(Instruction::ConvertWordToDouble));
+
+        // xchg ax, ax does nothing and is documented as nop
+        lookup.replace("0b1001_0000", SingleByteDecoder::new(Instruction::Nop));
+
+        if model >= CpuModel::I80186 {
+            Self::insert_80186(&mut lookup);
+        }
+
+        let mut undocumented = OpDecoderLookup::new();
+        match mode {
+            DecodeMode::Strict => Self::insert_undocumented(&mut undocumented, model),
+            DecodeMode::Permissive => Self::insert_undocumented(&mut lookup, model),
+        }
+
+        Decoder { lookup, undocumented, model }
+    }
+
+    /// Opcodes that aren't in Intel's documentation but that the hardware executes anyway.
+    ///
+    /// Two group encodings are left out on purpose, because on the 8086 they are not aliases of a
+    /// documented instruction. FE /2 to /7 run the FF operations (call, jmp, push) on a byte operand.
+    /// D0 to D3 /6 run as SETMO and SETMOC, which set the operand to all ones instead of shifting it.
+    /// Both stay `UnsupportedModRm` in every mode.
+    fn insert_undocumented(lookup: &mut OpDecoderLookup, model: CpuModel) {
+        lookup.insert("0b1101_0110", SingleByteDecoder::new(Instruction::SetAlFromCarry));
+
+        if model != CpuModel::I8086 {
+            return;
+        }
+
+        // the 8086 ignores bit 4 of the opcode for these, the 80186 reused them for new instructions
+        lookup.insert("0b0110_0100", JumpDecoder::new(Instruction::JumpOnEqual));
+        lookup.insert("0b0110_1100", JumpDecoder::new(Instruction::JumpOnLess));
+        lookup.insert("0b0110_1110", JumpDecoder::new(Instruction::JumpOnNotGreater));
+        lookup.insert("0b0110_0010", JumpDecoder::new(Instruction::JumpOnBelow));
+        lookup.insert("0b0110_0110", JumpDecoder::new(Instruction::JumpOnNotAbove));
+        lookup.insert("0b0110_1010", JumpDecoder::new(Instruction::JumpOnParity));
+        lookup.insert("0b0110_0000", JumpDecoder::new(Instruction::JumpOnOverflow));
+        lookup.insert("0b0110_1000", JumpDecoder::new(Instruction::JumpOnSign));
+        lookup.insert("0b0110_0101", JumpDecoder::new(Instruction::JumpOnNotEqual));
+        lookup.insert("0b0110_1101", JumpDecoder::new(Instruction::JumpOnNotLess));
+        lookup.insert("0b0110_1111", JumpDecoder::new(Instruction::JumpOnGreater));
+        lookup.insert("0b0110_0011", JumpDecoder::new(Instruction::JumpOnNotBelow));
+        lookup.insert("0b0110_0111", JumpDecoder::new(Instruction::JumpOnAbove));
+        lookup.insert("0b0110_1011", JumpDecoder::new(Instruction::JumpOnNoParity));
+        lookup.insert("0b0110_0001", JumpDecoder::new(Instruction::JumpOnNoOverflow));
+        lookup.insert("0b0110_1001", JumpDecoder::new(Instruction::JumpOnNotSign));
+
+        // ... and bit 1 for the returns
+        lookup.insert("0b1100_000x", ReturnDecoder::new(|pop| Instruction::Return { pop }));
+        lookup.insert("0b1100_100x", ReturnDecoder::new(|pop| Instruction::ReturnFar { pop }));
+
+        lookup.insert("0b0000_1111", SegmentRegisterDecoder::new(|seg_reg| Instruction::PopSegmentRegister { seg_reg }));
+
+        // groups with a single operation execute it whatever the reg field holds
+        for reg in ["0bmm_001_rrr", "0bmm_01x_rrr", "0bmm_1xx_rrr"] {
+            lookup.insert(&format!("0b1000_1111 {reg}"), RegMemDecoder::new(|_, reg_or_mem| Instruction::PopRegMem { reg_or_mem }));
+            lookup.insert(&format!("0b1100_011w {reg}"), ImmediateMovToRegMemDecoder {});
+        }
+        lookup.insert("0b1111_011w 0bmm_001_rrr", TestImmediateWithRegMemDecoder {});
+    }
+
+    /// Instructions added by the 80186, which the 80286 keeps in real mode
+    fn insert_80186(lookup: &mut OpDecoderLookup) {
+        lookup.insert("0b0110_10s0", PushImmediateDecoder {});
+        lookup.insert("0b0110_10s1", MultiplyImmediateDecoder {});
+        lookup.insert("0b0110_0000", SingleByteDecoder::new(Instruction::PushAll));
+        lookup.insert("0b0110_0001", SingleByteDecoder::new(Instruction::PopAll));
+        lookup.insert("0b0110_0010", LoadAddressDecoder::new(|reg, mem| Instruction::Bound { reg, mem }));
+        lookup.insert("0b1100_1000", EnterDecoder {});
+        lookup.insert("0b1100_1001", SingleByteDecoder::new(Instruction::Leave));
+        lookup.insert("0b0110_110w", StringDecoder::new(StringOp::Ins));
+        lookup.insert("0b0110_111w", StringDecoder::new(StringOp::Outs));
+
+        lookup.insert("0b1100_000w 0bmm_000_rrr", ShiftImmediateDecoder::new(ShiftOp::Rol));
+        lookup.insert("0b1100_000w 0bmm_001_rrr", ShiftImmediateDecoder::new(ShiftOp::Ror));
+        lookup.insert("0b1100_000w 0bmm_010_rrr", ShiftImmediateDecoder::new(ShiftOp::Rcl));
+        lookup.insert("0b1100_000w 0bmm_011_rrr", ShiftImmediateDecoder::new(ShiftOp::Rcr));
+        lookup.insert("0b1100_000w 0bmm_100_rrr", ShiftImmediateDecoder::new(ShiftOp::Shl));
+        lookup.insert("0b1100_000w 0bmm_101_rrr", ShiftImmediateDecoder::new(ShiftOp::Shr));
+        lookup.insert("0b1100_000w 0bmm_111_rrr", ShiftImmediateDecoder::new(ShiftOp::Sar));
+    }
+
+    pub fn model(&self) -> CpuModel {
+        self.model
+    }
+
+    /// Reports for each of the 256 opcodes whether it is a prefix, decodes, or is unmapped
+    pub fn coverage(&self) -> [OpCodeCoverage; 256] {
+        let mut coverage = [OpCodeCoverage::Unmapped; 256];
+        for (byte, entry) in coverage.iter_mut().enumerate() {
+            let byte = byte as u8;
+            *entry = match (decode_prefix(byte), self.lookup.get(&byte)) {
+                (Some(_), _) => OpCodeCoverage::Prefix,
+                (None, Some(OpCodeEntry::Decoder(_))) => OpCodeCoverage::Mapped,
+                (None, Some(OpCodeEntry::Group(group))) => OpCodeCoverage::Group { reg_fields: group.reg_fields() },
+                (None, None) => OpCodeCoverage::Unmapped,
+            };
+        }
+        coverage
+    }
+
+    /// Decodes the instruction starting at `address`, returning `None` if it lies past the end of `bytes`
+    #[inline]
+    pub fn decode_at<'a>(&self, bytes: &'a [u8], address: usize) -> Option<Result<DecodedInstruction<'a>, DecodeError>> {
+        let mut reader = SliceReader::new(bytes, address);
+        let byte = reader.try_next()?;
+        let instruction = match self.decode_from(&self.lookup, byte, &mut reader) {
+            Ok(instruction) => instruction,
+            Err(e) => return Some(Err(e)),
+        };
+
+        let length = reader.position() - address;
+        Some(Ok(DecodedInstruction {
+            address,
+            length,
+            bytes: &bytes[address..address + length],
+            instruction,
+        }))
+    }
+
+    /// Decodes the next instruction, returning `None` once the input is exhausted
+    pub fn decode_next(&self, iter: &mut dyn Iterator<Item = &u8>) -> Option<Result<Instruction, DecodeError>> {
+        let mut bytes = StreamReader::new(iter);
+        let byte = bytes.try_next()?;
+        Some(self.decode_from(&self.lookup, byte, &mut bytes))
+    }
+
+    /// Decodes like `decode_next`, but through `lookup` instead of the decoder's own table
+    #[doc(hidden)]
+    pub fn decode_next_with(
+        &self,
+        lookup: &HashMap<u8, &OpCodeEntry>,
+        iter: &mut dyn Iterator<Item = &u8>,
+    ) -> Option<Result<Instruction, DecodeError>> {
+        let mut bytes = StreamReader::new(iter);
+        let byte = bytes.try_next()?;
+        Some(self.decode_from(lookup, byte, &mut bytes))
+    }
+
+    /// The opcode lookup as the HashMap it was before it became a dispatch table, only there so
+    /// `decode_benchmark` can measure against it with `decode_next_with`
+    #[doc(hidden)]
+    pub fn hash_map_lookup(&self) -> HashMap<u8, &OpCodeEntry> {
+        self.lookup.to_hash_map()
     }
 
-    pub fn decode_next(&self, iter: &mut dyn Iterator<Item = &u8>) -> Option<Instruction> {
-        let byte = iter.next()?;
+    // inlined into decode_at and decode_next so the instruction isn't passed back through memory,
+    // which cost decode_at more than a third of its speed
+    #[inline(always)]
+    fn decode_from(&self, lookup: &impl OpCodeTable, mut byte: u8, bytes: &mut impl ByteReader) -> Result<Instruction, DecodeError> {
+        let mut prefixes = Vec::new();
+        while decode_prefix(byte).is_some() {
+            prefixes.push(byte);
+            byte = bytes.next()?;
+        }
+
+        let offset = bytes.position() - 1;
+        let decoder = match Self::find(lookup.get(&byte), bytes)? {
+            Some(decoder) => decoder,
+            None if Self::find(self.undocumented.get(&byte), bytes)?.is_some() => {
+                return Err(DecodeError::Undocumented { offset, opcode: byte });
+            }
+            None if lookup.get(&byte).is_some() => {
+                return Err(DecodeError::UnsupportedModRm { offset: bytes.position(), modrm: bytes.peek()? });
+            }
+            None => return Err(DecodeError::UnknownOpcode { offset, opcode: byte }),
+        };
 
-        let decoder = self.lookup.get(byte).unwrap_or_else(|| panic!("no decoder found for {byte:#b}"));
+        let code = bytes.decode_with(decoder, byte)?;
+        if prefixes.is_empty() {
+            return Ok(code);
+        }
 
-        let code = decoder.decode(*byte, iter);
+        // prefixes the 8086 ignores are kept, so the instruction still encodes to the bytes it came from
+        Ok(match code.clone().with_prefixes(&prefixes) {
+            (code, true) => code,
+            (_, false) => Instruction::Prefixed { prefixes, instruction: Box::new(code) },
+        })
+    }
 
-        Some(code)
+    /// The decoder in `entry`, peeking at the ModRM byte of group opcodes
+    #[inline]
+    fn find<'l>(entry: Option<&'l OpCodeEntry>, bytes: &mut impl ByteReader) -> Result<Option<&'l dyn DecodeFrom>, DecodeError> {
+        Ok(match entry {
+            Some(OpCodeEntry::Decoder(decoder)) => Some(decoder.as_ref()),
+            Some(OpCodeEntry::Group(group)) => group.get(bytes.peek()?),
+            None => None,
+        })
     }
 }
 
+fn mul_div(op: MultiplyDivideOp, width: OpWidth, reg_or_mem: RegOrMem) -> Instruction {
+    Instruction::MultiplyDivide { op, width, reg_or_mem }
+}
+
 impl Default for Decoder {
     fn default() -> Self {
-        Self::new()
+        Self::new(CpuModel::default())
+    }
+}
+#[cfg(test)]
+mod test {
+    use crate::assembler::assemble;
+    use crate::decoder::{CpuModel, DecodeError, DecodeMode, Decoder, OpCodeCoverage};
+    use crate::ops::Instruction;
+
+    fn disassemble(bytes: &[u8]) -> Vec<String> {
+        disassemble_with(&Decoder::new(CpuModel::I8086), bytes)
+    }
+
+    fn disassemble_with(decoder: &Decoder, bytes: &[u8]) -> Vec<String> {
+        let mut iter = bytes.iter();
+        let mut lines = vec![];
+        while let Some(instruction) = decoder.decode_next(&mut iter) {
+            let instruction = instruction.unwrap();
+            lines.push(instruction.encode(|disp| format!("{disp}")));
+        }
+        lines
+    }
+
+    #[test]
+    fn push_pop() {
+        let bytes = [0xff, 0x32, 0x51, 0x0e, 0x8f, 0x46, 0x04, 0x5b, 0x1f, 0xff, 0xf0];
+        assert_eq!(
+            disassemble(&bytes),
+            ["push word [bp + si]", "push cx", "push cs", "pop word [bp + 4]", "pop bx", "pop ds", "push ax"]
+        );
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn exchange_and_ports() {
+        let bytes = [0x86, 0x60, 0x21, 0x93, 0xe4, 0xc8, 0xed, 0xe7, 0x2c, 0xee];
+        assert_eq!(
+            disassemble(&bytes),
+            ["xchg ah, [bx + si + 33]", "xchg ax, bx", "in al, 200", "in ax, dx", "out 44, ax", "out dx, al"]
+        );
+    }
+
+    #[test]
+    fn address_loads_and_flags() {
+        let bytes = [0xd7, 0x8d, 0x81, 0x8c, 0x05, 0xc5, 0x1b, 0xc4, 0xbd, 0x67, 0x01, 0x9f, 0x9e, 0x9c, 0x9d];
+        assert_eq!(
+            disassemble(&bytes),
+            ["xlat", "lea ax, [bx + di + 1420]", "lds bx, [bp + di]", "les di, [di + 359]", "lahf", "sahf", "pushf", "popf"]
+        );
+    }
+
+    #[test]
+    fn logic_test_and_shifts() {
+        let bytes = [
+            0x21, 0xd8, 0x0a, 0x2f, 0x34, 0x0f, 0x81, 0x4e, 0x02, 0x00, 0x10, 0x85, 0xcb, 0xa8, 0x7f, 0xf6, 0x07, 0x01, 0xf7, 0xd1,
+            0xd0, 0xe0, 0xd3, 0x3f, 0xd2, 0xca,
+        ];
+        assert_eq!(
+            disassemble(&bytes),
+            [
+                "and ax, bx",
+                "or ch, [bx]",
+                "xor al, 15",
+                "or [bp + 2], word 4096",
+                "test bx, cx",
+                "test al, 127",
+                "test [bx], byte 1",
+                "not cx",
+                "shl al, 1",
+                "sar word [bx], cl",
+                "ror dl, cl",
+            ]
+        );
+    }
+
+    #[test]
+    fn unary_group() {
+        let bytes = [
+            0xfe, 0xc0, 0xff, 0x4f, 0x02, 0x41, 0x4e, 0xf6, 0xdb, 0xf7, 0xe1, 0xf6, 0x2f, 0xf7, 0x76, 0x00, 0xf6, 0xf9,
+        ];
+        assert_eq!(
+            disassemble(&bytes),
+            ["inc al", "dec word [bx + 2]", "inc cx", "dec si", "neg bl", "mul cx", "imul byte [bx]", "div word [bp]", "idiv cl"]
+        );
+    }
+
+    #[test]
+    fn string_instructions_with_prefixes() {
+        let bytes = [0xa4, 0xf3, 0xa5, 0xf3, 0xa6, 0xf2, 0xae, 0xac, 0xf3, 0xab];
+        assert_eq!(disassemble(&bytes), ["movsb", "rep movsw", "repe cmpsb", "repne scasb", "lodsb", "rep stosw"]);
+    }
+
+    #[test]
+    fn keeps_prefixes_the_8086_ignores() {
+        let bytes = [0xf3, 0x90, 0x26, 0x2e, 0x8b, 0x07, 0x26, 0x40, 0xf2, 0xf3, 0xa4];
+        let source = disassemble(&bytes);
+        assert_eq!(source, ["db 243\nnop", "db 38, 46\nmov ax, [bx]", "db 38\ninc ax", "db 242, 243\nmovsb"]);
+        assert_eq!(assemble(&source.join("\n")).unwrap().to_bytes(), bytes);
+
+        let decoder = Decoder::new(CpuModel::I8086);
+        let decoded = decoder.decode_at(&bytes, 2).unwrap().unwrap();
+        assert_eq!(decoded.length, 4);
+        assert_eq!(decoded.instruction.to_bytes(), &bytes[2..6]);
+        let Instruction::Prefixed { prefixes, instruction } = decoded.instruction else { panic!("prefixes were dropped") };
+        assert_eq!(instruction.with_prefixes(&prefixes).0.encode(|disp| disp.to_string()), "mov ax, cs:[bx]");
+
+        // both prefixes take effect, but the encoder would write them the other way round
+        let bytes = [0x26, 0xf3, 0xa4];
+        let decoded = decoder.decode_at(&bytes, 0).unwrap().unwrap();
+        assert!(matches!(decoded.instruction, Instruction::Prefixed { .. }));
+        assert_eq!(decoded.instruction.to_bytes(), bytes);
+        let bytes = [0xf3, 0x26, 0xa4];
+        let decoded = decoder.decode_at(&bytes, 0).unwrap().unwrap();
+        assert!(matches!(decoded.instruction, Instruction::StringManipulation { .. }));
+        assert_eq!(decoded.instruction.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn segment_override_and_lock() {
+        let bytes = [
+            0x2e, 0x8a, 0x00, 0x26, 0x8b, 0x56, 0x00, 0x3e, 0xff, 0x74, 0x04, 0x36, 0xa1, 0x10, 0x00, 0xf0, 0xf6, 0x96, 0xb1, 0x26,
+            0xf0, 0x87, 0x06, 0x64, 0x00, 0x26, 0xf3, 0xa4,
+        ];
+        assert_eq!(
+            disassemble(&bytes),
+            [
+                "mov al, cs:[bx + si]",
+                "mov dx, es:[bp]",
+                "push word ds:[si + 4]",
+                "mov ax, ss:[16]",
+                "lock not byte [bp + 9905]",
+                "lock xchg ax, [100]",
+                "db 38, 243\nmovsb",
+            ]
+        );
+    }
+
+    #[test]
+    fn control_transfer() {
+        let bytes = [
+            0xe8, 0xfd, 0xff, 0x9a, 0x34, 0x12, 0x00, 0xf0, 0xff, 0xd3, 0xff, 0x5f, 0x02, 0xe9, 0x00, 0x01, 0xeb, 0xfe, 0xea, 0x00,
+            0x00, 0xff, 0xff, 0xff, 0x27, 0xff, 0x2e, 0x10, 0x00, 0xc3, 0xc2, 0x04, 0x00, 0xcb, 0xca, 0x08, 0x00, 0xcd, 0x21, 0xcc,
+            0xce, 0xcf,
+        ];
+        assert_eq!(
+            disassemble(&bytes),
+            [
+                "call -3",
+                "call 61440:4660",
+                "call bx",
+                "call far [bx + 2]",
+                "jmp near 256",
+                "jmp short -2",
+                "jmp 65535:0",
+                "jmp word [bx]",
+                "jmp far [16]",
+                "ret",
+                "ret 4",
+                "retf",
+                "retf 8",
+                "int 33",
+                "int3",
+                "into",
+                "iret",
+            ]
+        );
+    }
+
+    #[test]
+    fn operands_keep_their_encoding() {
+        let bytes = [
+            0xa0, 0xe8, 0x03, 0xa3, 0x40, 0x9c, 0x8a, 0x0e, 0xe8, 0x03, 0xc6, 0x06, 0x10, 0x00, 0xc8, 0x83, 0xc3, 0xfd, 0x81, 0xc3,
+            0x05, 0x00, 0x81, 0xc3, 0xe8, 0x03, 0x05, 0x05, 0x00, 0x80, 0x07, 0xff, 0x8b, 0x87, 0x04, 0x00, 0x8b, 0x40, 0x00, 0x8b,
+            0x46, 0x00, 0x8b, 0x42, 0xfc, 0x8b, 0x81, 0x00, 0x80,
+        ];
+        assert_eq!(
+            disassemble(&bytes),
+            [
+                "mov al, [1000]",
+                "mov [40000], ax",
+                "mov cl, [1000]",
+                "mov [16], byte 200",
+                "add bx, word -3",
+                "add bx, strict word 5",
+                "add bx, word 1000",
+                "add ax, strict word 5",
+                "add [bx], byte 255",
+                "mov ax, [word bx + 4]",
+                "mov ax, [byte bx + si + 0]",
+                "mov ax, [bp]",
+                "mov ax, [bp + si - 4]",
+                "mov ax, [bx + di - 32768]",
+            ]
+        );
+    }
+
+    #[test]
+    fn processor_control() {
+        let bytes = [0xf8, 0xf5, 0xf9, 0xfc, 0xfd, 0xfa, 0xfb, 0xf4, 0x9b, 0x90, 0x91, 0xd9, 0x07, 0xde, 0xc1, 0xf0, 0x87, 0x07];
+        assert_eq!(
+            disassemble(&bytes),
+            [
+                "clc",
+                "cmc",
+                "stc",
+                "cld",
+                "std",
+                "cli",
+                "sti",
+                "hlt",
+                "wait",
+                "nop",
+                "xchg ax, cx",
+                "db 217, 7 ; esc 8, [bx]",
+                "db 222, 193 ; esc 48, cx",
+                "lock xchg ax, [bx]",
+            ]
+        );
+    }
+
+    #[test]
+    fn decimal_adjust_and_conversions() {
+        let bytes = [0x27, 0x2f, 0x37, 0x3f, 0xd4, 0x0a, 0xd4, 0x10, 0xd5, 0x0a, 0xd5, 0x08, 0x98, 0x99];
+        assert_eq!(disassemble(&bytes), ["daa", "das", "aaa", "aas", "aam", "aam 16", "aad", "aad 8", "cbw", "cwd"]);
+    }
+
+    #[test]
+    fn instructions_added_by_the_80186() {
+        let bytes = [
+            0x68, 0x34, 0x12, 0x6a, 0xff, 0x68, 0x05, 0x00, 0x69, 0xc3, 0xe8, 0x03, 0x6b, 0x47, 0x02, 0x0a, 0xc1, 0xe0, 0x03, 0xc0, 0x2f,
+            0x02, 0xc8, 0x10, 0x00, 0x00, 0xc9, 0x60, 0x61, 0x62, 0x17, 0xf3, 0x6c, 0x6f,
+        ];
+        let expected = [
+            "push word 4660",
+            "push word -1",
+            "push strict word 5",
+            "imul ax, bx, 1000",
+            "imul ax, [bx + 2], 10",
+            "shl ax, 3",
+            "shr byte [bx], 2",
+            "enter 16, 0",
+            "leave",
+            "pusha",
+            "popa",
+            "bound dx, [bx]",
+            "rep insb",
+            "outsw",
+        ];
+        assert_eq!(disassemble_with(&Decoder::new(CpuModel::I80186), &bytes), expected);
+        assert_eq!(disassemble_with(&Decoder::new(CpuModel::I80286), &bytes), expected);
+    }
+
+    #[test]
+    fn the_8086_rejects_80186_opcodes() {
+        // on the 8086 these are aliases of other instructions, which strict mode rejects
+        for opcode in [0x60, 0x61, 0x62, 0x68, 0x69, 0x6a, 0x6b, 0x6c, 0x6f, 0xc0, 0xc1, 0xc8, 0xc9] {
+            assert_eq!(decode_error(&[opcode, 0, 0, 0]), DecodeError::Undocumented { offset: 0, opcode });
+        }
+        assert_eq!("80186".parse(), Ok(CpuModel::I80186));
+        assert!("80386".parse::<CpuModel>().is_err());
+    }
+
+    #[test]
+    fn permissive_mode_decodes_undocumented_opcodes() {
+        let decoder = Decoder::with_mode(CpuModel::I8086, DecodeMode::Permissive);
+        let bytes = [
+            0x60, 0x02, 0x6f, 0xfe, 0xc1, 0xc0, 0x04, 0x00, 0xc9, 0xc8, 0x02, 0x00, 0x0f, 0xd6, 0x8f, 0x0f, 0xc6, 0xc8, 0x07, 0xf6, 0xc8,
+            0x01,
+        ];
+        assert_eq!(
+            disassemble_with(&decoder, &bytes),
+            ["jo 2", "jg -2", "ret", "ret 4", "retf", "retf 2", "pop cs", "salc", "pop word [bx]", "mov al, byte 7", "test al, byte 1"]
+        );
+
+        // the 80186 gave most of these opcodes a meaning of its own
+        let decoder = Decoder::with_mode(CpuModel::I80186, DecodeMode::Permissive);
+        assert_eq!(disassemble_with(&decoder, &[0x60, 0xd6, 0xc9]), ["pusha", "salc", "leave"]);
+        assert!(matches!(decoder.decode_next(&mut [0x0f].iter()), Some(Err(DecodeError::UnknownOpcode { .. }))));
+
+        // group encodings that aren't aliases stay unsupported
+        let decoder = Decoder::with_mode(CpuModel::I8086, DecodeMode::Permissive);
+        for (opcode, reg) in [(0xfe, 2..8), (0xd0, 6..7), (0xd1, 6..7), (0xd2, 6..7), (0xd3, 6..7)] {
+            for reg in reg {
+                let modrm = 0b11_000_000 | reg << 3;
+                let error = decoder.decode_next(&mut [opcode, modrm].iter()).unwrap().unwrap_err();
+                assert_eq!(error, DecodeError::UnsupportedModRm { offset: 1, modrm });
+            }
+        }
+    }
+
+    #[test]
+    fn strict_mode_rejects_undocumented_opcodes() {
+        assert_eq!(decode_error(&[0x60, 0x02]), DecodeError::Undocumented { offset: 0, opcode: 0x60 });
+        assert_eq!(decode_error(&[0xc1]), DecodeError::Undocumented { offset: 0, opcode: 0xc1 });
+        assert_eq!(decode_error(&[0x2e, 0xd6]), DecodeError::Undocumented { offset: 1, opcode: 0xd6 });
+        assert_eq!(decode_error(&[0x8f, 0x0f]), DecodeError::Undocumented { offset: 0, opcode: 0x8f });
+        assert_eq!(decode_error(&[0xc7, 0xc8, 0x07, 0x00]), DecodeError::Undocumented { offset: 0, opcode: 0xc7 });
+        assert_eq!(decode_error(&[0xf7, 0xc8, 0x01, 0x00]), DecodeError::Undocumented { offset: 0, opcode: 0xf7 });
+    }
+
+    fn decode_error(bytes: &[u8]) -> DecodeError {
+        let decoder = Decoder::new(CpuModel::I8086);
+        decoder.decode_next(&mut bytes.iter()).unwrap().unwrap_err()
+    }
+
+    #[test]
+    fn decode_errors() {
+        assert_eq!(decode_error(&[0xf1]), DecodeError::UnknownOpcode { offset: 0, opcode: 0xf1 });
+        assert_eq!(decode_error(&[0x2e, 0xf1]), DecodeError::UnknownOpcode { offset: 1, opcode: 0xf1 });
+        assert_eq!(decode_error(&[0xb8, 0x01]), DecodeError::Truncated { offset: 2 });
+        assert_eq!(decode_error(&[0xf3]), DecodeError::Truncated { offset: 1 });
+        assert_eq!(decode_error(&[0x8d, 0xc0]), DecodeError::UnsupportedModRm { offset: 1, modrm: 0xc0 });
+        assert_eq!(decode_error(&[0xff, 0xff]), DecodeError::UnsupportedModRm { offset: 1, modrm: 0xff });
+        assert_eq!(decode_error(&[0xd0, 0x30]), DecodeError::UnsupportedModRm { offset: 1, modrm: 0x30 });
+        assert_eq!(DecodeError::Truncated { offset: 2 }.relocate(0x100).offset(), 0x102);
+    }
+
+    #[test]
+    fn decode_at_reports_address_and_bytes() {
+        let bytes = [0x90, 0x2e, 0x8b, 0x47, 0x02, 0xb8];
+        let decoder = Decoder::new(CpuModel::I8086);
+
+        let decoded = decoder.decode_at(&bytes, 1).unwrap().unwrap();
+        assert_eq!(decoded.address, 1);
+        assert_eq!(decoded.length, 4);
+        assert_eq!(decoded.bytes, [0x2e, 0x8b, 0x47, 0x02]);
+        assert_eq!(decoded.next_address(), 5);
+        assert_eq!(decoded.instruction.encode(|disp| format!("{disp}")), "mov ax, cs:[bx + 2]");
+
+        assert_eq!(decoder.decode_at(&bytes, 5).unwrap().unwrap_err(), DecodeError::Truncated { offset: 6 });
+        assert!(decoder.decode_at(&bytes, 6).is_none());
+    }
+
+    #[test]
+    fn coverage() {
+        let coverage = Decoder::new(CpuModel::I8086).coverage();
+        assert_eq!(coverage[0x00], OpCodeCoverage::Mapped);
+        assert_eq!(coverage[0x0f], OpCodeCoverage::Unmapped);
+        assert_eq!(coverage[0x26], OpCodeCoverage::Prefix);
+        assert_eq!(coverage[0xf3], OpCodeCoverage::Prefix);
+        assert_eq!(coverage[0x80], OpCodeCoverage::Group { reg_fields: 0xff });
+        assert_eq!(coverage[0xd0], OpCodeCoverage::Group { reg_fields: 0b1011_1111 });
+        assert_eq!(coverage[0xfe], OpCodeCoverage::Group { reg_fields: 0b0000_0011 });
+    }
+}
@@ -0,0 +1,373 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::annotations::Annotations;
+use crate::decoder::{DecodeError, DecodedInstruction, Decoder};
+use crate::ops::Instruction;
+
+/// A run of the binary, either decoded as an instruction or kept as data bytes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Chunk {
+    Code { address: usize, length: usize, instruction: Instruction },
+    Data { address: usize, length: usize },
+}
+
+impl Chunk {
+    pub fn address(&self) -> usize {
+        match *self {
+            Chunk::Code { address, .. } | Chunk::Data { address, .. } => address,
+        }
+    }
+
+    pub fn length(&self) -> usize {
+        match *self {
+            Chunk::Code { length, .. } | Chunk::Data { length, .. } => length,
+        }
+    }
+
+    pub fn next_address(&self) -> usize {
+        self.address() + self.length()
+    }
+}
+
+/// The chunks cover the whole binary in address order, without gaps or overlaps
+#[derive(Debug, Clone, Default)]
+pub struct Disassembly {
+    pub chunks: Vec<Chunk>,
+    pub errors: Vec<DecodeError>,
+}
+
+impl Disassembly {
+    /// Where the relative jumps and calls of the decoded instructions go, including targets outside the binary
+    pub fn jump_targets(&self) -> BTreeSet<usize> {
+        self.chunks
+            .iter()
+            .filter_map(|chunk| match chunk {
+                Chunk::Code { instruction, .. } => instruction.relative_jump().map(|disp| jump_target(chunk.next_address(), disp)),
+                Chunk::Data { .. } => None,
+            })
+            .collect()
+    }
+}
+
+/// Decodes from `origin` to the end of `bytes`, keeping each byte that doesn't decode as a data byte.
+/// The data ranges of `annotations` are skipped, and so is a byte whose instruction would run into one.
+/// Prefixes the 8086 would ignore are data too, and so are prefixes in another order than the one
+/// instructions encode them in, so that a listing reassembles to the same bytes.
+/// Addresses are offsets into `bytes`, so the bytes before `origin` only put the program where it runs.
+pub fn linear(decoder: &Decoder, bytes: &[u8], origin: usize, annotations: &Annotations) -> Disassembly {
+    let mut disassembly = Disassembly::default();
+    let mut address = origin;
+    while address < bytes.len() {
+        if let Some((start, range)) = annotations.data_at(address) {
+            let end = (start + range.length).min(bytes.len());
+            disassembly.chunks.push(Chunk::Data { address, length: end - address });
+            address = end;
+            continue;
+        }
+        let Some(decoded) = decoder.decode_at(bytes, address) else {
+            break;
+        };
+        match decoded {
+            Ok(decoded) if annotations.overlaps_data(address, decoded.length) => {
+                disassembly.chunks.push(Chunk::Data { address, length: 1 });
+                address += 1;
+            }
+            Ok(DecodedInstruction { instruction: Instruction::Prefixed { prefixes, .. }, .. }) => {
+                disassembly.chunks.push(Chunk::Data { address, length: prefixes.len() });
+                address += prefixes.len();
+            }
+            Ok(decoded) => {
+                let length = decoded.length;
+                disassembly.chunks.push(Chunk::Code { address, length, instruction: decoded.instruction });
+                address += length;
+            }
+            Err(e) => {
+                disassembly.errors.push(e);
+                disassembly.chunks.push(Chunk::Data { address, length: 1 });
+                address += 1;
+            }
+        }
+    }
+    disassembly
+}
+
+/// Decodes only what can be reached from `entry_points` by following fallthrough, relative jumps and
+/// calls; the bytes in between are data. An instruction that would overlap code found earlier ends
+/// that path, so a jump into the middle of an instruction leaves it as decoded first. So does one
+/// that would overlap a data range of `annotations`, and each range is kept as a single chunk. As
+/// with `linear`, nothing before `origin` is decoded and ignored prefixes are data.
+pub fn recursive(decoder: &Decoder, bytes: &[u8], origin: usize, entry_points: &[usize], annotations: &Annotations) -> Disassembly {
+    let mut disassembly = Disassembly::default();
+    let mut code: BTreeMap<usize, (usize, Instruction)> = BTreeMap::new();
+    let mut covered = vec![false; bytes.len()];
+
+    let mut pending: Vec<usize> = entry_points.iter().rev().copied().collect();
+    while let Some(mut address) = pending.pop() {
+        while (origin..bytes.len()).contains(&address) && !covered[address] {
+            let decoded = match decoder.decode_at(bytes, address) {
+                Some(Ok(decoded)) => decoded,
+                Some(Err(e)) => {
+                    disassembly.errors.push(e);
+                    break;
+                }
+                None => break,
+            };
+            if covered[address..decoded.next_address()].iter().any(|covered| *covered) || annotations.overlaps_data(address, decoded.length) {
+                break;
+            }
+            if let Instruction::Prefixed { prefixes, .. } = &decoded.instruction {
+                covered[address..address + prefixes.len()].fill(true);
+                address += prefixes.len();
+                continue;
+            }
+            covered[address..decoded.next_address()].fill(true);
+
+            if let Some(disp) = decoded.instruction.relative_jump() {
+                pending.push(jump_target(decoded.next_address(), disp));
+            }
+            let falls_through = decoded.instruction.falls_through();
+            code.insert(address, (decoded.length, decoded.instruction));
+            if !falls_through {
+                break;
+            }
+            address += decoded.length;
+        }
+    }
+
+    // data is split where something jumps into it, so the target can get a label
+    let targets: BTreeSet<usize> =
+        code.iter().filter_map(|(address, (length, instruction))| instruction.relative_jump().map(|disp| jump_target(address + length, disp))).collect();
+    let mut address = origin;
+    while address < bytes.len() {
+        if let Some((length, instruction)) = code.remove(&address) {
+            disassembly.chunks.push(Chunk::Code { address, length, instruction });
+            address += length;
+            continue;
+        }
+        let end = match annotations.data_at(address) {
+            Some((start, range)) => (start + range.length).min(bytes.len()),
+            None => (address + 1..bytes.len())
+                .find(|end| covered[*end] || targets.contains(end) || annotations.data.contains_key(end))
+                .unwrap_or(bytes.len()),
+        };
+        disassembly.chunks.push(Chunk::Data { address, length: end - address });
+        address = end;
+    }
+    disassembly
+}
+
+/// The address a relative jump goes to. Near jumps wrap around within the 64K code segment.
+pub fn jump_target(next_address: usize, disp: i16) -> usize {
+    (next_address as u16).wrapping_add(disp as u16) as usize
+}
+
+impl Instruction {
+    /// The displacement of a relative jump or call
+    pub fn relative_jump(&self) -> Option<i16> {
+        match *self {
+            Instruction::CallDirect(disp) | Instruction::JumpDirect(disp) => Some(disp),
+            Instruction::JumpShort(disp)
+            | Instruction::JumpOnEqual(disp)
+            | Instruction::JumpOnLess(disp)
+            | Instruction::JumpOnNotGreater(disp)
+            | Instruction::JumpOnBelow(disp)
+            | Instruction::JumpOnNotAbove(disp)
+            | Instruction::JumpOnParity(disp)
+            | Instruction::JumpOnOverflow(disp)
+            | Instruction::JumpOnSign(disp)
+            | Instruction::JumpOnNotEqual(disp)
+            | Instruction::JumpOnNotLess(disp)
+            | Instruction::JumpOnGreater(disp)
+            | Instruction::JumpOnNotBelow(disp)
+            | Instruction::JumpOnAbove(disp)
+            | Instruction::JumpOnNoParity(disp)
+            | Instruction::JumpOnNoOverflow(disp)
+            | Instruction::JumpOnNotSign(disp)
+            | Instruction::Loop(disp)
+            | Instruction::LoopWhileEqual(disp)
+            | Instruction::LoopWhileNotEqual(disp)
+            | Instruction::JumpOnCxZero(disp) => Some(disp as i16),
+            _ => None,
+        }
+    }
+
+    /// Whether execution can go on to the next instruction. Calls are assumed to return, and `hlt`
+    /// is taken to end the program as the simulator does.
+    pub fn falls_through(&self) -> bool {
+        !matches!(
+            self,
+            Instruction::JumpDirect(_)
+                | Instruction::JumpShort(_)
+                | Instruction::JumpFarDirect { .. }
+                | Instruction::JumpIndirect { .. }
+                | Instruction::JumpFarIndirect { .. }
+                | Instruction::Return { .. }
+                | Instruction::ReturnFar { .. }
+                | Instruction::InterruptReturn
+                | Instruction::Halt
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::annotations::Annotations;
+    use crate::assembler::assemble;
+    use crate::decoder::{CpuModel, Decoder};
+    use crate::disassembly::{linear, recursive, Chunk};
+
+    fn layout(chunks: &[Chunk]) -> Vec<(char, usize, usize)> {
+        chunks
+            .iter()
+            .map(|chunk| match chunk {
+                Chunk::Code { address, length, .. } => ('c', *address, *length),
+                Chunk::Data { address, length } => ('d', *address, *length),
+            })
+            .collect()
+    }
+
+    /// The chunks written out the way the printer does, instructions in NASM syntax and data as db lines
+    fn source(chunks: &[Chunk], bytes: &[u8]) -> String {
+        chunks
+            .iter()
+            .map(|chunk| match chunk {
+                Chunk::Code { instruction, .. } => instruction.encode(|disp| disp.to_string()) + "\n",
+                Chunk::Data { address, length } => {
+                    let values: Vec<String> = bytes[*address..address + length].iter().map(u8::to_string).collect();
+                    format!("db {}\n", values.join(", "))
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn recursive_leaves_unreached_bytes_as_data() {
+        let bytes = assemble(
+            "
+                call print      ; 0
+                jmp done        ; 3
+            message: db 'hi$'   ; 6
+            print:
+                mov ah, 9       ; 9
+                int 0x21        ; 11
+                ret             ; 13
+            done:
+                hlt             ; 14
+                db 0xff, 0xff   ; 15
+            ",
+        )
+        .unwrap()
+        .to_bytes();
+        let decoder = Decoder::new(CpuModel::I8086);
+
+        let disassembly = recursive(&decoder, &bytes, 0, &[0], &Annotations::default());
+        assert_eq!(layout(&disassembly.chunks), [('c', 0, 3), ('c', 3, 3), ('d', 6, 3), ('c', 9, 2), ('c', 11, 2), ('c', 13, 1), ('c', 14, 1), ('d', 15, 2)]);
+        assert!(disassembly.errors.is_empty());
+        assert_eq!(disassembly.jump_targets().into_iter().collect::<Vec<_>>(), [9, 14]);
+
+        // decoding linearly runs into the string, which starts with 80186 opcodes, and the bytes
+        // after hlt; each byte that doesn't decode becomes data
+        let disassembly = linear(&decoder, &bytes, 0, &Annotations::default());
+        assert_eq!(disassembly.errors.len(), 4);
+        assert_eq!(disassembly.chunks.last(), Some(&Chunk::Data { address: 16, length: 1 }));
+    }
+
+    #[test]
+    fn recursive_keeps_the_first_decoding_of_overlapping_code() {
+        // the jump goes into the middle of the mov, whose immediate is a nop
+        let bytes = [0xb8, 0x90, 0x90, 0xeb, 0xfc, 0xc3];
+        let disassembly = recursive(&Decoder::new(CpuModel::I8086), &bytes, 0, &[0], &Annotations::default());
+        assert_eq!(layout(&disassembly.chunks), [('c', 0, 3), ('c', 3, 2), ('d', 5, 1)]);
+
+        // targets outside the binary are not followed
+        let bytes = [0x74, 0x10, 0xe9, 0x00, 0x80];
+        let disassembly = recursive(&Decoder::new(CpuModel::I8086), &bytes, 0, &[0], &Annotations::default());
+        assert_eq!(layout(&disassembly.chunks), [('c', 0, 2), ('c', 2, 3)]);
+    }
+
+    #[test]
+    fn data_is_split_at_jump_targets() {
+        // the conditional jump goes to an opcode that doesn't decode, so it stays data but starts a chunk
+        let bytes = [0x74, 0x03, 0xc3, 0x00, 0x00, 0xf1, 0x00];
+        let disassembly = recursive(&Decoder::new(CpuModel::I8086), &bytes, 0, &[0], &Annotations::default());
+        assert_eq!(layout(&disassembly.chunks), [('c', 0, 2), ('c', 2, 1), ('d', 3, 2), ('d', 5, 2)]);
+        assert_eq!(disassembly.errors.len(), 1);
+    }
+
+    #[test]
+    fn data_ranges_are_not_decoded() {
+        // mov ax, 0x1234 / nop / nop / ret
+        let bytes = [0xb8, 0x34, 0x12, 0x90, 0x90, 0xc3];
+        let decoder = Decoder::new(CpuModel::I8086);
+        let annotations = Annotations::parse("1 word 1\n4 byte 8").unwrap();
+
+        let disassembly = linear(&decoder, &bytes, 0, &annotations);
+        assert_eq!(layout(&disassembly.chunks), [('d', 0, 1), ('d', 1, 2), ('c', 3, 1), ('d', 4, 2)]);
+        assert!(disassembly.errors.is_empty());
+
+        let disassembly = recursive(&decoder, &bytes, 0, &[0, 3], &annotations);
+        assert_eq!(layout(&disassembly.chunks), [('d', 0, 1), ('d', 1, 2), ('c', 3, 1), ('d', 4, 2)]);
+    }
+
+    #[test]
+    fn addresses_count_from_the_start_of_the_segment() {
+        // as a .COM file sees it: jmp 0x103 / ret / jmp 0x0000, into the PSP
+        let mut bytes = vec![0xcd, 0x20];
+        bytes.resize(0x100, 0);
+        bytes.extend([0xeb, 0x01, 0xc3, 0xe9, 0xfa, 0xfe]);
+        let decoder = Decoder::new(CpuModel::I8086);
+
+        let disassembly = recursive(&decoder, &bytes, 0x100, &[0x100], &Annotations::default());
+        assert_eq!(layout(&disassembly.chunks), [('c', 0x100, 2), ('d', 0x102, 1), ('c', 0x103, 3)]);
+        assert_eq!(disassembly.jump_targets().into_iter().collect::<Vec<_>>(), [0, 0x103]);
+
+        let disassembly = linear(&decoder, &bytes, 0x100, &Annotations::default());
+        assert_eq!(layout(&disassembly.chunks), [('c', 0x100, 2), ('c', 0x102, 1), ('c', 0x103, 3)]);
+    }
+
+    #[test]
+    fn ignored_prefixes_are_kept_as_data() {
+        // rep nop / es cs mov ax, [bx] / es nop / rep es movsb / es rep movsb
+        let bytes = [0xf3, 0x90, 0x26, 0x2e, 0x8b, 0x07, 0x26, 0x90, 0xf3, 0x26, 0xa4, 0x26, 0xf3, 0xa4];
+        let decoder = Decoder::new(CpuModel::I8086);
+
+        let disassembly = linear(&decoder, &bytes, 0, &Annotations::default());
+        assert_eq!(
+            layout(&disassembly.chunks),
+            [('d', 0, 1), ('c', 1, 1), ('d', 2, 2), ('c', 4, 2), ('d', 6, 1), ('c', 7, 1), ('c', 8, 3), ('d', 11, 2), ('c', 13, 1)]
+        );
+
+        let text = source(&disassembly.chunks, &bytes);
+        assert_eq!(assemble(&text).unwrap().to_bytes(), bytes, "{text}");
+
+        let disassembly = recursive(&decoder, &bytes, 0, &[0], &Annotations::default());
+        assert_eq!(
+            layout(&disassembly.chunks),
+            [('d', 0, 1), ('c', 1, 1), ('d', 2, 1), ('d', 3, 1), ('c', 4, 2), ('d', 6, 1), ('c', 7, 1), ('c', 8, 3), ('d', 11, 1), ('d', 12, 1), ('c', 13, 1)]
+        );
+    }
+
+    #[test]
+    fn prefixes_in_any_order_reassemble() {
+        let bytes = [
+            0xf0, 0x26, 0x87, 0x06, 0x64, 0x00, // lock es xchg ax, [100]
+            0x26, 0xf0, 0x87, 0x06, 0x64, 0x00, // es lock xchg ax, [100]
+            0xf3, 0x2e, 0xa5, // rep cs movsw
+            0x2e, 0xf3, 0xa5, // cs rep movsw
+            0xf0, 0xf2, 0x36, 0xa6, // lock repne ss cmpsb
+            0x36, 0xf2, 0xf0, 0xa6, // ss repne lock cmpsb
+            0xf2, 0xf0, 0x3e, 0xac, // repne lock ds lodsb
+            0x3e, 0xd7, // ds xlat
+        ];
+        let decoder = Decoder::new(CpuModel::I8086);
+
+        let disassembly = linear(&decoder, &bytes, 0, &Annotations::default());
+        assert!(disassembly.errors.is_empty());
+        let text = source(&disassembly.chunks, &bytes);
+        assert_eq!(assemble(&text).unwrap().to_bytes(), bytes, "{text}");
+
+        let disassembly = recursive(&decoder, &bytes, 0, &[0], &Annotations::default());
+        let text = source(&disassembly.chunks, &bytes);
+        assert_eq!(assemble(&text).unwrap().to_bytes(), bytes, "{text}");
+    }
+}
@@ -0,0 +1,531 @@
+use crate::ops::*;
+
+impl Instruction {
+    /// Encodes the instruction as machine code, the inverse of decoding it.
+    ///
+    /// Where the 8086 has more than one encoding this picks the one the instruction records: the
+    /// direction bit, immediate sizes and displacements are kept as they were decoded. Prefixes come
+    /// first, in the order lock, repeat, segment override. `[bp]` can only be encoded with a
+    /// displacement, so it gets a zero byte displacement when it has none.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut encoder = Encoder::default();
+        encoder.instruction(self);
+        encoder.finish()
+    }
+}
+
+#[derive(Default)]
+struct Encoder {
+    lock: bool,
+    repeat: Option<RepeatPrefix>,
+    segment: Option<SegmentRegister>,
+    bytes: Vec<u8>,
+}
+
+impl Encoder {
+    /// The prefixes go first in a fixed order, lock, repeat, then segment override, whatever order
+    /// they were decoded in. The decoder keeps prefixes in any other order as `Instruction::Prefixed`,
+    /// which writes its prefix bytes as they were.
+    fn finish(self) -> Vec<u8> {
+        let mut bytes = vec![];
+        if self.lock {
+            bytes.push(0xF0);
+        }
+        match self.repeat {
+            Some(RepeatPrefix::Repne) => bytes.push(0xF2),
+            Some(RepeatPrefix::Rep) => bytes.push(0xF3),
+            None => {}
+        }
+        if let Some(segment) = self.segment {
+            bytes.push(0x26 | seg_reg_code(segment) << 3);
+        }
+        bytes.extend(self.bytes);
+        bytes
+    }
+
+    fn instruction(&mut self, instruction: &Instruction) {
+        match *instruction {
+            Instruction::MovToFromRegMem { dir, reg, ref reg_or_mem } => {
+                self.byte(0x88 | dir_bit(dir) | width_bit(reg.width));
+                self.modrm(reg_code(reg), reg_or_mem);
+            }
+            Instruction::ImmediateMovRegMem { width, ref reg_or_mem, data } => {
+                self.byte(0xC6 | width_bit(width));
+                self.modrm(0, reg_or_mem);
+                self.immediate(data);
+            }
+            Instruction::ImmediateMovReg { reg, data } => {
+                self.byte(0xB0 | width_bit(reg.width) << 3 | reg_code(reg));
+                self.immediate(data);
+            }
+            Instruction::AccumulatorMove { dir, width, addr, segment } => {
+                // unlike the other moves the bit is set when storing the accumulator
+                let dir = match dir {
+                    Direction::FromRegister => 0b0000_0010,
+                    Direction::ToRegister => 0,
+                };
+                self.segment = segment;
+                self.byte(0xA0 | dir | width_bit(width));
+                self.word(addr.0);
+            }
+            Instruction::SegmentRegisterMove { dir, seg_reg, ref reg_or_mem } => {
+                self.byte(0x8C | dir_bit(dir));
+                self.modrm(seg_reg_code(seg_reg), reg_or_mem);
+            }
+            Instruction::ArithmeticFromToRegMem { op, dir, width, reg, ref reg_or_mem } => {
+                self.byte(arithmetic_code(op) << 3 | dir_bit(dir) | width_bit(width));
+                self.modrm(reg_code(reg), reg_or_mem);
+            }
+            Instruction::ArithmeticImmediateToRegMem { op, width, data, ref reg_or_mem } => {
+                let sign_extend = match data {
+                    Immediate::SignExtended(_) => 0b0000_0010,
+                    _ => 0,
+                };
+                self.byte(0x80 | sign_extend | width_bit(width));
+                self.modrm(arithmetic_code(op), reg_or_mem);
+                self.immediate(data);
+            }
+            Instruction::ArithmeticImmediateToAccumulator { op, width, data } => {
+                self.byte(arithmetic_code(op) << 3 | 0b0000_0100 | width_bit(width));
+                self.immediate(data);
+            }
+            Instruction::JumpOnOverflow(disp) => self.short_jump(0x70, disp),
+            Instruction::JumpOnNoOverflow(disp) => self.short_jump(0x71, disp),
+            Instruction::JumpOnBelow(disp) => self.short_jump(0x72, disp),
+            Instruction::JumpOnNotBelow(disp) => self.short_jump(0x73, disp),
+            Instruction::JumpOnEqual(disp) => self.short_jump(0x74, disp),
+            Instruction::JumpOnNotEqual(disp) => self.short_jump(0x75, disp),
+            Instruction::JumpOnNotAbove(disp) => self.short_jump(0x76, disp),
+            Instruction::JumpOnAbove(disp) => self.short_jump(0x77, disp),
+            Instruction::JumpOnSign(disp) => self.short_jump(0x78, disp),
+            Instruction::JumpOnNotSign(disp) => self.short_jump(0x79, disp),
+            Instruction::JumpOnParity(disp) => self.short_jump(0x7A, disp),
+            Instruction::JumpOnNoParity(disp) => self.short_jump(0x7B, disp),
+            Instruction::JumpOnLess(disp) => self.short_jump(0x7C, disp),
+            Instruction::JumpOnNotLess(disp) => self.short_jump(0x7D, disp),
+            Instruction::JumpOnNotGreater(disp) => self.short_jump(0x7E, disp),
+            Instruction::JumpOnGreater(disp) => self.short_jump(0x7F, disp),
+            Instruction::LoopWhileNotEqual(disp) => self.short_jump(0xE0, disp),
+            Instruction::LoopWhileEqual(disp) => self.short_jump(0xE1, disp),
+            Instruction::Loop(disp) => self.short_jump(0xE2, disp),
+            Instruction::JumpOnCxZero(disp) => self.short_jump(0xE3, disp),
+            Instruction::PushRegMem { ref reg_or_mem } => {
+                self.byte(0xFF);
+                self.modrm(6, reg_or_mem);
+            }
+            Instruction::PushReg { reg } => self.byte(0x50 | reg_code(reg)),
+            Instruction::PushSegmentRegister { seg_reg } => self.byte(0x06 | seg_reg_code(seg_reg) << 3),
+            Instruction::PopRegMem { ref reg_or_mem } => {
+                self.byte(0x8F);
+                self.modrm(0, reg_or_mem);
+            }
+            Instruction::PopReg { reg } => self.byte(0x58 | reg_code(reg)),
+            Instruction::PopSegmentRegister { seg_reg } => self.byte(0x07 | seg_reg_code(seg_reg) << 3),
+            Instruction::ExchangeRegMemWithReg { reg, ref reg_or_mem } => {
+                self.byte(0x86 | width_bit(reg.width));
+                self.modrm(reg_code(reg), reg_or_mem);
+            }
+            Instruction::ExchangeRegWithAccumulator { reg } => self.byte(0x90 | reg_code(reg)),
+            Instruction::InFixedPort { width, port } => {
+                self.byte(0xE4 | width_bit(width));
+                self.byte(port);
+            }
+            Instruction::InVariablePort { width } => self.byte(0xEC | width_bit(width)),
+            Instruction::OutFixedPort { width, port } => {
+                self.byte(0xE6 | width_bit(width));
+                self.byte(port);
+            }
+            Instruction::OutVariablePort { width } => self.byte(0xEE | width_bit(width)),
+            Instruction::TranslateByte { segment } => {
+                self.segment = segment;
+                self.byte(0xD7);
+            }
+            Instruction::LoadEffectiveAddress { reg, mem } => self.memory_operand(0x8D, reg_code(reg), mem),
+            Instruction::LoadPointerToDs { reg, mem } => self.memory_operand(0xC5, reg_code(reg), mem),
+            Instruction::LoadPointerToEs { reg, mem } => self.memory_operand(0xC4, reg_code(reg), mem),
+            Instruction::LoadAhFromFlags => self.byte(0x9F),
+            Instruction::StoreAhIntoFlags => self.byte(0x9E),
+            Instruction::PushFlags => self.byte(0x9C),
+            Instruction::PopFlags => self.byte(0x9D),
+            Instruction::TestRegMemWithReg { reg, ref reg_or_mem } => {
+                self.byte(0x84 | width_bit(reg.width));
+                self.modrm(reg_code(reg), reg_or_mem);
+            }
+            Instruction::TestImmediateWithRegMem { width, data, ref reg_or_mem } => {
+                self.byte(0xF6 | width_bit(width));
+                self.modrm(0, reg_or_mem);
+                self.immediate(data);
+            }
+            Instruction::TestImmediateWithAccumulator { width, data } => {
+                self.byte(0xA8 | width_bit(width));
+                self.immediate(data);
+            }
+            Instruction::Not { width, ref reg_or_mem } => self.group(0xF6 | width_bit(width), 2, reg_or_mem),
+            Instruction::Negate { width, ref reg_or_mem } => self.group(0xF6 | width_bit(width), 3, reg_or_mem),
+            Instruction::MultiplyDivide { op, width, ref reg_or_mem } => {
+                self.group(0xF6 | width_bit(width), multiply_divide_code(op), reg_or_mem)
+            }
+            Instruction::Shift { op, width, count, ref reg_or_mem } => {
+                let op_code = match count {
+                    ShiftCount::One => 0xD0,
+                    ShiftCount::Cl => 0xD2,
+                    ShiftCount::Immediate(_) => 0xC0,
+                };
+                self.group(op_code | width_bit(width), shift_code(op), reg_or_mem);
+                if let ShiftCount::Immediate(count) = count {
+                    self.byte(count);
+                }
+            }
+            Instruction::IncrementRegMem { width, ref reg_or_mem } => self.group(0xFE | width_bit(width), 0, reg_or_mem),
+            Instruction::IncrementReg { reg } => self.byte(0x40 | reg_code(reg)),
+            Instruction::DecrementRegMem { width, ref reg_or_mem } => self.group(0xFE | width_bit(width), 1, reg_or_mem),
+            Instruction::DecrementReg { reg } => self.byte(0x48 | reg_code(reg)),
+            Instruction::StringManipulation { op, width, repeat, segment } => {
+                let op_code = match op {
+                    StringOp::Movs => 0xA4,
+                    StringOp::Cmps => 0xA6,
+                    StringOp::Stos => 0xAA,
+                    StringOp::Lods => 0xAC,
+                    StringOp::Scas => 0xAE,
+                    StringOp::Ins => 0x6C,
+                    StringOp::Outs => 0x6E,
+                };
+                self.repeat = repeat;
+                self.segment = segment;
+                self.byte(op_code | width_bit(width));
+            }
+            Instruction::Lock(ref instruction) => {
+                self.lock = true;
+                self.instruction(instruction);
+            }
+            Instruction::Prefixed { ref prefixes, ref instruction } => {
+                self.bytes.extend(prefixes);
+                self.instruction(instruction);
+            }
+            Instruction::CallDirect(disp) => {
+                self.byte(0xE8);
+                self.word(disp as u16);
+            }
+            Instruction::CallFarDirect { offset, segment } => {
+                self.byte(0x9A);
+                self.word(offset);
+                self.word(segment);
+            }
+            Instruction::CallIndirect { ref reg_or_mem } => self.group(0xFF, 2, reg_or_mem),
+            Instruction::CallFarIndirect { mem } => self.memory_operand(0xFF, 3, mem),
+            Instruction::JumpDirect(disp) => {
+                self.byte(0xE9);
+                self.word(disp as u16);
+            }
+            Instruction::JumpShort(disp) => self.short_jump(0xEB, disp),
+            Instruction::JumpFarDirect { offset, segment } => {
+                self.byte(0xEA);
+                self.word(offset);
+                self.word(segment);
+            }
+            Instruction::JumpIndirect { ref reg_or_mem } => self.group(0xFF, 4, reg_or_mem),
+            Instruction::JumpFarIndirect { mem } => self.memory_operand(0xFF, 5, mem),
+            Instruction::Return { pop } => self.ret(0xC2, pop),
+            Instruction::ReturnFar { pop } => self.ret(0xCA, pop),
+            Instruction::Interrupt(vector) => {
+                self.byte(0xCD);
+                self.byte(vector);
+            }
+            Instruction::Interrupt3 => self.byte(0xCC),
+            Instruction::InterruptOnOverflow => self.byte(0xCE),
+            Instruction::InterruptReturn => self.byte(0xCF),
+            Instruction::ClearCarry => self.byte(0xF8),
+            Instruction::ComplementCarry => self.byte(0xF5),
+            Instruction::SetCarry => self.byte(0xF9),
+            Instruction::ClearDirection => self.byte(0xFC),
+            Instruction::SetDirection => self.byte(0xFD),
+            Instruction::ClearInterrupt => self.byte(0xFA),
+            Instruction::SetInterrupt => self.byte(0xFB),
+            Instruction::Halt => self.byte(0xF4),
+            Instruction::Wait => self.byte(0x9B),
+            Instruction::Nop => self.byte(0x90),
+            Instruction::Adjust(op) => self.byte(match op {
+                AdjustOp::Daa => 0x27,
+                AdjustOp::Das => 0x2F,
+                AdjustOp::Aaa => 0x37,
+                AdjustOp::Aas => 0x3F,
+            }),
+            Instruction::AsciiAdjustMultiply { base } => {
+                self.byte(0xD4);
+                self.byte(base);
+            }
+            Instruction::AsciiAdjustDivide { base } => {
+                self.byte(0xD5);
+                self.byte(base);
+            }
+            Instruction::ConvertByteToWord => self.byte(0x98),
+            Instruction::ConvertWordToDouble => self.byte(0x99),
+            Instruction::SetAlFromCarry => self.byte(0xD6),
+            Instruction::PushImmediate { data } => {
+                self.byte(match data {
+                    Immediate::Word(_) => 0x68,
+                    Immediate::Byte(_) | Immediate::SignExtended(_) => 0x6A,
+                });
+                self.immediate(data);
+            }
+            Instruction::PushAll => self.byte(0x60),
+            Instruction::PopAll => self.byte(0x61),
+            Instruction::MultiplyImmediate { reg, ref reg_or_mem, data } => {
+                self.byte(match data {
+                    Immediate::Word(_) => 0x69,
+                    Immediate::Byte(_) | Immediate::SignExtended(_) => 0x6B,
+                });
+                self.modrm(reg_code(reg), reg_or_mem);
+                self.immediate(data);
+            }
+            Instruction::Enter { size, level } => {
+                self.byte(0xC8);
+                self.word(size.0);
+                self.byte(level.0);
+            }
+            Instruction::Leave => self.byte(0xC9),
+            Instruction::Bound { reg, mem } => self.memory_operand(0x62, reg_code(reg), mem),
+            Instruction::Escape { opcode, ref reg_or_mem } => {
+                self.byte(0xD8 | (opcode >> 3) & 0b0000_0111);
+                self.modrm(opcode & 0b0000_0111, reg_or_mem);
+            }
+        }
+    }
+
+    fn byte(&mut self, byte: u8) {
+        self.bytes.push(byte);
+    }
+
+    fn word(&mut self, word: u16) {
+        self.bytes.extend(word.to_le_bytes());
+    }
+
+    fn immediate(&mut self, data: Immediate) {
+        match data {
+            Immediate::Byte(Imm8(data)) | Immediate::SignExtended(Imm8(data)) => self.byte(data),
+            Immediate::Word(Imm16(data)) => self.word(data),
+        }
+    }
+
+    fn short_jump(&mut self, op_code: u8, disp: i8) {
+        self.byte(op_code);
+        self.byte(disp as u8);
+    }
+
+    /// `op_code` is the form that pops a count, the one after it returns without popping
+    fn ret(&mut self, op_code: u8, pop: Option<u16>) {
+        match pop {
+            Some(pop) => {
+                self.byte(op_code);
+                self.word(pop);
+            }
+            None => self.byte(op_code + 1),
+        }
+    }
+
+    /// A group opcode, whose ModRM reg field selects the operation
+    fn group(&mut self, op_code: u8, reg: u8, reg_or_mem: &RegOrMem) {
+        self.byte(op_code);
+        self.modrm(reg, reg_or_mem);
+    }
+
+    fn memory_operand(&mut self, op_code: u8, reg: u8, mem: EffectiveAddress) {
+        self.group(op_code, reg, &RegOrMem::Mem(mem));
+    }
+
+    fn modrm(&mut self, reg: u8, reg_or_mem: &RegOrMem) {
+        let mem = match *reg_or_mem {
+            RegOrMem::Reg(rm) => return self.byte(0b1100_0000 | reg << 3 | reg_code(rm)),
+            RegOrMem::Mem(mem) => mem,
+        };
+
+        self.segment = mem.segment;
+        let rm = match mem.base {
+            EffectiveAddressBase::Direct => {
+                self.byte(reg << 3 | 0b0000_0110);
+                self.word(mem.displacement.value() as u16);
+                return;
+            }
+            EffectiveAddressBase::BxPlusSi => 0,
+            EffectiveAddressBase::BxPlusDi => 1,
+            EffectiveAddressBase::BpPlusSi => 2,
+            EffectiveAddressBase::BpPlusDi => 3,
+            EffectiveAddressBase::Si => 4,
+            EffectiveAddressBase::Di => 5,
+            EffectiveAddressBase::Bp => 6,
+            EffectiveAddressBase::Bx => 7,
+        };
+
+        match mem.displacement {
+            // mod 00 with r/m 110 is the direct address, so [bp] needs an explicit displacement
+            Displacement::None if matches!(mem.base, EffectiveAddressBase::Bp) => {
+                self.byte(0b0100_0000 | reg << 3 | rm);
+                self.byte(0);
+            }
+            Displacement::None => self.byte(reg << 3 | rm),
+            Displacement::Disp8(disp) => {
+                self.byte(0b0100_0000 | reg << 3 | rm);
+                self.byte(disp as u8);
+            }
+            Displacement::Disp16(disp) => {
+                self.byte(0b1000_0000 | reg << 3 | rm);
+                self.word(disp as u16);
+            }
+        }
+    }
+}
+
+fn width_bit(width: OpWidth) -> u8 {
+    match width {
+        OpWidth::Byte => 0,
+        OpWidth::Word => 1,
+    }
+}
+
+fn dir_bit(dir: Direction) -> u8 {
+    match dir {
+        Direction::FromRegister => 0,
+        Direction::ToRegister => 0b0000_0010,
+    }
+}
+
+fn reg_code(reg: RegisterAccess) -> u8 {
+    let code = match reg.reg {
+        Register::A => 0,
+        Register::C => 1,
+        Register::D => 2,
+        Register::B => 3,
+        Register::Sp => 4,
+        Register::Bp => 5,
+        Register::Si => 6,
+        Register::Di => 7,
+    };
+    // ah, ch, dh and bh take the codes of sp, bp, si and di
+    match (reg.width, reg.offset) {
+        (OpWidth::Byte, 1) => code + 4,
+        _ => code,
+    }
+}
+
+fn seg_reg_code(seg_reg: SegmentRegister) -> u8 {
+    match seg_reg {
+        SegmentRegister::Es => 0,
+        SegmentRegister::Cs => 1,
+        SegmentRegister::Ss => 2,
+        SegmentRegister::Ds => 3,
+    }
+}
+
+fn arithmetic_code(op: ArithmeticOp) -> u8 {
+    match op {
+        ArithmeticOp::Add => 0,
+        ArithmeticOp::Or => 1,
+        ArithmeticOp::Adc => 2,
+        ArithmeticOp::Sbb => 3,
+        ArithmeticOp::And => 4,
+        ArithmeticOp::Sub => 5,
+        ArithmeticOp::Xor => 6,
+        ArithmeticOp::Cmp => 7,
+    }
+}
+
+fn shift_code(op: ShiftOp) -> u8 {
+    match op {
+        ShiftOp::Rol => 0,
+        ShiftOp::Ror => 1,
+        ShiftOp::Rcl => 2,
+        ShiftOp::Rcr => 3,
+        ShiftOp::Shl => 4,
+        ShiftOp::Shr => 5,
+        ShiftOp::Sar => 7,
+    }
+}
+
+fn multiply_divide_code(op: MultiplyDivideOp) -> u8 {
+    match op {
+        MultiplyDivideOp::Mul => 4,
+        MultiplyDivideOp::Imul => 5,
+        MultiplyDivideOp::Div => 6,
+        MultiplyDivideOp::Idiv => 7,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use crate::decoder::{CpuModel, DecodeMode, Decoder};
+
+    #[test]
+    fn decoding_an_encoded_instruction_gives_it_back() {
+        let decoders = [
+            Decoder::new(CpuModel::I8086),
+            Decoder::with_mode(CpuModel::I8086, DecodeMode::Permissive),
+            Decoder::with_mode(CpuModel::I80186, DecodeMode::Permissive),
+        ];
+        let mut rng = StdRng::seed_from_u64(8086);
+
+        for decoder in decoders.iter() {
+            for _ in 0..100_000 {
+                let bytes: [u8; 8] = rng.gen();
+                let Some(Ok(decoded)) = decoder.decode_at(&bytes, 0) else { continue };
+
+                let encoded = decoded.instruction.to_bytes();
+                let redecoded = decoder.decode_at(&encoded, 0).unwrap().unwrap();
+                assert_eq!(redecoded.instruction, decoded.instruction, "{:02x?} was encoded as {encoded:02x?}", decoded.bytes);
+                assert_eq!(redecoded.length, encoded.len());
+            }
+        }
+    }
+
+    #[test]
+    fn encoding_keeps_the_bytes_it_was_decoded_from() {
+        const PREFIXES: [u8; 7] = [0x26, 0x2e, 0x36, 0x3e, 0xf0, 0xf2, 0xf3];
+        let decoder = Decoder::new(CpuModel::I8086);
+        let mut rng = StdRng::seed_from_u64(8088);
+
+        for _ in 0..100_000 {
+            let mut bytes: Vec<u8> = (0..rng.gen_range(0..4)).map(|_| PREFIXES[rng.gen_range(0..PREFIXES.len())]).collect();
+            bytes.extend(rng.gen::<[u8; 6]>());
+            let Some(Ok(decoded)) = decoder.decode_at(&bytes, 0) else { continue };
+            let op_code = bytes.iter().position(|byte| !PREFIXES.contains(byte)).unwrap();
+
+            // aliases the decoder folds into the documented encoding: 0x82 runs as 0x80, and the
+            // segment register moves ignore the top bit of the reg field
+            let (code, modrm) = (bytes[op_code], bytes[op_code + 1]);
+            if code == 0x82 || (code == 0x8c || code == 0x8e) && modrm & 0b0010_0000 != 0 {
+                continue;
+            }
+            assert_eq!(decoded.instruction.to_bytes(), decoded.bytes, "{}", decoded.instruction.encode(|disp| format!("{disp}")));
+        }
+    }
+
+    #[test]
+    fn documented_encodings_are_reproduced() {
+        let decoder = Decoder::new(CpuModel::I80186);
+        let encodings: [&[u8]; 16] = [
+            &[0x89, 0xd8],
+            &[0x8b, 0x46, 0x00],
+            &[0x26, 0x8b, 0x87, 0x34, 0x12],
+            &[0xc6, 0x06, 0x10, 0x00, 0x7f],
+            &[0x81, 0xc3, 0x05, 0x00],
+            &[0x83, 0xeb, 0xfb],
+            &[0xa3, 0x34, 0x12],
+            &[0xf0, 0x2e, 0x86, 0x07],
+            &[0xf3, 0x26, 0xa5],
+            &[0x74, 0xfe],
+            &[0xe8, 0x00, 0x01],
+            &[0xca, 0x04, 0x00],
+            &[0xd3, 0xf8],
+            &[0xc1, 0xe0, 0x03],
+            &[0xdd, 0x1e, 0x00, 0x02],
+            &[0x6b, 0xc3, 0xf6],
+        ];
+
+        for bytes in encodings {
+            let decoded = decoder.decode_at(bytes, 0).unwrap().unwrap();
+            assert_eq!(decoded.instruction.to_bytes(), bytes, "{}", decoded.instruction.encode(|disp| format!("{disp}")));
+        }
+    }
+}
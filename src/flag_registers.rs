@@ -10,6 +10,9 @@ bitflags! {
         const AuxiliaryCarry = 0b0000_0001_0000;
         const Zero = 0b0000_0100_0000;
         const Sign = 0b0000_1000_0000;
+        const Trap = 0b0001_0000_0000;
+        const Interrupt = 0b0010_0000_0000;
+        const Direction = 0b0100_0000_0000;
         const Overflow = 0b1000_0000_0000;
     }
 }
@@ -28,6 +31,9 @@ impl Display for Flags {
         if self.contains(Flags::AuxiliaryCarry) { f.write_str("A")?; }
         if self.contains(Flags::Zero) { f.write_str("Z")?; }
         if self.contains(Flags::Sign) { f.write_str("S")?; }
+        if self.contains(Flags::Trap) { f.write_str("T")?; }
+        if self.contains(Flags::Interrupt) { f.write_str("I")?; }
+        if self.contains(Flags::Direction) { f.write_str("D")?; }
         if self.contains(Flags::Overflow) { f.write_str("O")?; }
 
         Ok(())
@@ -0,0 +1,700 @@
+use crate::decode::{decode_prefix, Prefix};
+use crate::ops::*;
+
+/// An instruction operand, with what a syntax needs to know to spell it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    Register(RegisterAccess),
+    SegmentRegister(SegmentRegister),
+    /// `size` is set when no other operand implies the size of the access
+    Memory { mem: EffectiveAddress, size: Option<MemorySize> },
+    /// `size` is set when no register operand implies it. `strict` marks a word small enough that an
+    /// assembler would encode it as a sign-extended byte instead.
+    Immediate { value: i16, size: Option<OpWidth>, strict: bool },
+    /// A port, interrupt vector, count or similar, always unsigned
+    Number(u16),
+    /// A relative jump, with the distance when the mnemonic alone doesn't give the encoding
+    Jump { disp: i16, distance: Option<JumpDistance> },
+    FarPointer { segment: u16, offset: u16 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemorySize {
+    Byte,
+    Word,
+    /// A 32-bit segment:offset pointer, for far calls and jumps
+    Far,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JumpDistance {
+    Short,
+    Near,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Case {
+    #[default]
+    Lower,
+    Upper,
+}
+
+impl Case {
+    pub fn apply(&self, text: &str) -> String {
+        match self {
+            Case::Lower => text.to_owned(),
+            Case::Upper => text.to_uppercase(),
+        }
+    }
+}
+
+/// What jump targets and memory addresses are called. Any `Fn(i16) -> String` names jump targets
+/// from their displacement and leaves addresses as numbers.
+pub trait Names {
+    fn jump(&self, disp: i16) -> String;
+
+    /// A name for a direct memory address, used instead of the number
+    fn address(&self, _address: u16) -> Option<String> {
+        None
+    }
+}
+
+impl<F: Fn(i16) -> String> Names for F {
+    fn jump(&self, disp: i16) -> String {
+        self(disp)
+    }
+}
+
+/// Spells out instructions in the syntax of a particular assembler
+pub trait InstructionFormatter {
+    /// The mnemonic as this syntax spells it, given as NASM spells it, along with any prefixes
+    fn mnemonic(&self, mnemonic: &str) -> String;
+
+    /// Jump targets and names from `names` are used as they are
+    fn operand(&self, operand: &Operand, names: &dyn Names) -> String;
+
+    fn instruction(&self, mnemonic: &str, operands: &[Operand], names: &dyn Names) -> String {
+        let mnemonic = self.mnemonic(mnemonic);
+        if operands.is_empty() {
+            return mnemonic;
+        }
+        let operands: Vec<String> = operands.iter().map(|operand| self.operand(operand, names)).collect();
+        format!("{mnemonic} {}", operands.join(", "))
+    }
+
+    /// A comment to put after an instruction
+    fn comment(&self, text: &str) -> String {
+        format!("; {text}")
+    }
+}
+
+/// The syntax `printer` emits by default, which NASM and the built-in assembler turn back into the
+/// same bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Nasm {
+    pub case: Case,
+}
+
+impl InstructionFormatter for Nasm {
+    fn mnemonic(&self, mnemonic: &str) -> String {
+        self.case.apply(mnemonic)
+    }
+
+    fn operand(&self, operand: &Operand, names: &dyn Names) -> String {
+        let text = match *operand {
+            Operand::Memory { mem, size } => {
+                let size = match size {
+                    None => "",
+                    Some(MemorySize::Byte) => "byte ",
+                    Some(MemorySize::Word) => "word ",
+                    Some(MemorySize::Far) => "far ",
+                };
+                match named(&mem, names, self.case, None) {
+                    Some(named) => return format!("{}{named}", self.case.apply(size)),
+                    None => format!("{size}{}", nasm_memory(&mem)),
+                }
+            }
+            Operand::Immediate { value, size: Some(size), strict: true } => format!("strict {size} {value}"),
+            Operand::Immediate { value, size: Some(size), strict: false } => format!("{size} {value}"),
+            Operand::Jump { disp, distance } => {
+                let distance = match distance {
+                    None => "",
+                    Some(JumpDistance::Short) => "short ",
+                    Some(JumpDistance::Near) => "near ",
+                };
+                return format!("{}{}", self.case.apply(distance), names.jump(disp));
+            }
+            _ => plain_operand(operand),
+        };
+        self.case.apply(&text)
+    }
+}
+
+/// The syntax of MASM and TASM listings: `word ptr` sizes, `ds:` on direct addresses and the
+/// `jz`/`jnz` family of condition aliases
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Masm {
+    pub case: Case,
+}
+
+impl InstructionFormatter for Masm {
+    fn mnemonic(&self, mnemonic: &str) -> String {
+        let words: Vec<&str> = mnemonic
+            .split(' ')
+            .map(|word| match word {
+                "je" => "jz",
+                "jne" => "jnz",
+                "jnb" => "jae",
+                "jnbe" => "ja",
+                "jnl" => "jge",
+                "jp" => "jpe",
+                "jnp" => "jpo",
+                "loope" => "loopz",
+                "loopne" => "loopnz",
+                "int3" => "int 3",
+                word => word,
+            })
+            .collect();
+        self.case.apply(&words.join(" "))
+    }
+
+    fn operand(&self, operand: &Operand, names: &dyn Names) -> String {
+        let text = match *operand {
+            Operand::Memory { mem, size } => {
+                let size = match size {
+                    None => "",
+                    Some(MemorySize::Byte) => "byte ptr ",
+                    Some(MemorySize::Word) => "word ptr ",
+                    Some(MemorySize::Far) => "dword ptr ",
+                };
+                match named(&mem, names, self.case, Some(SegmentRegister::Ds)) {
+                    Some(named) => return format!("{}{named}", self.case.apply(size)),
+                    None => format!("{size}{}", masm_memory(&mem)),
+                }
+            }
+            Operand::Immediate { value, .. } => format!("{value}"),
+            Operand::Jump { disp, distance } => {
+                let distance = match distance {
+                    None => "",
+                    Some(JumpDistance::Short) => "short ",
+                    Some(JumpDistance::Near) => "near ptr ",
+                };
+                return format!("{}{}", self.case.apply(distance), names.jump(disp));
+            }
+            _ => plain_operand(operand),
+        };
+        self.case.apply(&text)
+    }
+
+    /// MASM puts the size on the memory operand rather than the immediate
+    fn instruction(&self, mnemonic: &str, operands: &[Operand], names: &dyn Names) -> String {
+        let immediate_size = operands.iter().find_map(|operand| match operand {
+            Operand::Immediate { size: Some(OpWidth::Byte), .. } => Some(MemorySize::Byte),
+            Operand::Immediate { size: Some(OpWidth::Word), .. } => Some(MemorySize::Word),
+            _ => None,
+        });
+        let operands: Vec<String> = operands
+            .iter()
+            .map(|operand| match *operand {
+                Operand::Memory { mem, size: None } => Operand::Memory { mem, size: immediate_size },
+                operand => operand,
+            })
+            .map(|operand| self.operand(&operand, names))
+            .collect();
+
+        let mnemonic = self.mnemonic(mnemonic);
+        if operands.is_empty() {
+            return mnemonic;
+        }
+        format!("{mnemonic} {}", operands.join(", "))
+    }
+}
+
+/// The AT&T syntax of GNU as in `.code16`: `%` on registers, `$` on immediates, sources before
+/// destinations and the size as a suffix on the mnemonic. It is for reading; as picks its own
+/// encodings, so only the NASM syntax reassembles to the same bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Att {
+    pub case: Case,
+}
+
+impl InstructionFormatter for Att {
+    fn mnemonic(&self, mnemonic: &str) -> String {
+        let words: Vec<&str> = mnemonic
+            .split(' ')
+            .map(|word| match word {
+                "db" => ".byte",
+                "dw" => ".word",
+                "cbw" => "cbtw",
+                "cwd" => "cwtd",
+                "retf" => "lret",
+                word => word,
+            })
+            .collect();
+        self.case.apply(&words.join(" "))
+    }
+
+    fn operand(&self, operand: &Operand, names: &dyn Names) -> String {
+        let text = match *operand {
+            Operand::Register(reg) => format!("%{reg}"),
+            Operand::SegmentRegister(seg_reg) => format!("%{seg_reg}"),
+            Operand::Memory { mem, .. } => {
+                let segment = mem.segment.map_or(String::new(), |segment| format!("%{segment}:"));
+                match names.address(mem.displacement.value() as u16) {
+                    Some(name) if mem.base == EffectiveAddressBase::Direct => return format!("{}{name}", self.case.apply(&segment)),
+                    _ => format!("{segment}{}", att_memory(&mem)),
+                }
+            }
+            Operand::Immediate { value, .. } => format!("${value}"),
+            Operand::Number(number) => format!("${number}"),
+            Operand::FarPointer { segment, offset } => format!("${segment}, ${offset}"),
+            Operand::Jump { disp, .. } => return names.jump(disp),
+        };
+        self.case.apply(&text)
+    }
+
+    /// Far jumps, calls and returns get an `l`, the targets of indirect ones a `*`, and the others a
+    /// `b` or `w` where no register gives the size
+    fn instruction(&self, mnemonic: &str, operands: &[Operand], names: &dyn Names) -> String {
+        if let "db" | "dw" = mnemonic {
+            let values: Vec<String> = operands.iter().map(plain_operand).collect();
+            return format!("{} {}", self.mnemonic(mnemonic), values.join(", "));
+        }
+
+        let branch = matches!(mnemonic, "jmp" | "call");
+        let far = operands.iter().any(|operand| matches!(operand, Operand::FarPointer { .. } | Operand::Memory { size: Some(MemorySize::Far), .. }));
+        let size = operands.iter().find_map(|operand| match operand {
+            Operand::Memory { size: Some(MemorySize::Byte), .. } | Operand::Immediate { size: Some(OpWidth::Byte), .. } => Some("b"),
+            Operand::Memory { size: Some(MemorySize::Word), .. } | Operand::Immediate { size: Some(OpWidth::Word), .. } => Some("w"),
+            _ => None,
+        });
+        let mnemonic = match (far, size) {
+            (true, _) => format!("l{mnemonic}"),
+            (false, Some(size)) if !branch => format!("{mnemonic}{size}"),
+            _ => mnemonic.to_owned(),
+        };
+
+        let mnemonic = self.mnemonic(&mnemonic);
+        if operands.is_empty() {
+            return mnemonic;
+        }
+        let operands: Vec<String> = operands
+            .iter()
+            .rev()
+            .map(|operand| match operand {
+                Operand::Register(_) | Operand::Memory { .. } if branch => format!("*{}", self.operand(operand, names)),
+                _ => self.operand(operand, names),
+            })
+            .collect();
+        format!("{mnemonic} {}", operands.join(", "))
+    }
+
+    fn comment(&self, text: &str) -> String {
+        format!("# {text}")
+    }
+}
+
+/// Operands that are spelled the same in every syntax
+fn plain_operand(operand: &Operand) -> String {
+    match *operand {
+        Operand::Register(reg) => format!("{reg}"),
+        Operand::SegmentRegister(seg_reg) => format!("{seg_reg}"),
+        Operand::Immediate { value, .. } => format!("{value}"),
+        Operand::Number(number) => format!("{number}"),
+        Operand::FarPointer { segment, offset } => format!("{segment}:{offset}"),
+        Operand::Memory { .. } | Operand::Jump { .. } => unreachable!("{operand:?} depends on the syntax"),
+    }
+}
+
+/// A direct address that has a name, with the name left in its own case. `segment` is spelled out
+/// when there's no override, as MASM needs one to tell memory from a constant.
+fn named(mem: &EffectiveAddress, names: &dyn Names, case: Case, segment: Option<SegmentRegister>) -> Option<String> {
+    if mem.base != EffectiveAddressBase::Direct {
+        return None;
+    }
+    let name = names.address(mem.displacement.value() as u16)?;
+    Some(match mem.segment.or(segment) {
+        Some(segment) => format!("{}:[{name}]", case.apply(&segment.to_string())),
+        None => format!("[{name}]"),
+    })
+}
+
+/// Keeps the original displacement size, so NASM reproduces the encoding
+pub(crate) fn nasm_memory(mem: &EffectiveAddress) -> String {
+    let segment = mem.segment.map_or(String::new(), |segment| format!("{segment}:"));
+    let (base, displacement) = (mem.base, mem.displacement);
+    if base == EffectiveAddressBase::Direct {
+        return format!("{segment}[{}]", displacement.value() as u16);
+    }
+
+    // [bp] can only be encoded with a displacement, so an assembler adds the zero byte itself
+    let size = match (base, displacement) {
+        (EffectiveAddressBase::Bp, Displacement::Disp8(0)) => "",
+        (_, Displacement::Disp8(_)) if displacement.value() == 0 => "byte ",
+        (_, Displacement::Disp16(disp)) if (-128..=127).contains(&disp) => "word ",
+        _ => "",
+    };
+    let disp = displacement.value();
+    if disp == 0 && size.is_empty() {
+        format!("{segment}[{base}]")
+    } else if disp >= 0 {
+        format!("{segment}[{size}{base} + {disp}]")
+    } else {
+        format!("{segment}[{size}{base} - {}]", disp.unsigned_abs())
+    }
+}
+
+/// `disp(%base,%index)`, with a direct address as a bare number
+fn att_memory(mem: &EffectiveAddress) -> String {
+    let (base, displacement) = (mem.base, mem.displacement);
+    if base == EffectiveAddressBase::Direct {
+        return format!("{}", displacement.value() as u16);
+    }
+    let registers: Vec<String> = base.to_string().split(" + ").map(|register| format!("%{register}")).collect();
+    match (base, displacement) {
+        (EffectiveAddressBase::Bp, Displacement::Disp8(0)) | (_, Displacement::None) => format!("({})", registers.join(",")),
+        _ => format!("{}({})", displacement.value(), registers.join(",")),
+    }
+}
+
+/// MASM reads a bare `[1234]` as a constant, so direct addresses always get a segment
+fn masm_memory(mem: &EffectiveAddress) -> String {
+    let segment = mem.segment.map_or(String::new(), |segment| format!("{segment}:"));
+    let (base, displacement) = (mem.base, mem.displacement);
+    match (base, displacement) {
+        (EffectiveAddressBase::Direct, _) if segment.is_empty() => format!("ds:[{}]", displacement.value() as u16),
+        (EffectiveAddressBase::Direct, _) => format!("{segment}[{}]", displacement.value() as u16),
+        (EffectiveAddressBase::Bp, Displacement::Disp8(0)) | (_, Displacement::None) => format!("{segment}[{base}]"),
+        _ if displacement.value() >= 0 => format!("{segment}[{base} + {}]", displacement.value()),
+        _ => format!("{segment}[{base} - {}]", displacement.value().unsigned_abs()),
+    }
+}
+
+impl Instruction {
+    /// Spells the instruction with `formatter`, using `names` for jump targets and named addresses
+    pub fn format(&self, formatter: &dyn InstructionFormatter, names: &dyn Names) -> String {
+        let (mnemonic, operands) = self.operands();
+        let text = formatter.instruction(&mnemonic, &operands, names);
+        match self {
+            // assemblers only know these as the coprocessor instructions they start, so the bytes are
+            // given with the esc form as a comment
+            Instruction::Escape { .. } => {
+                let bytes: Vec<Operand> = self.to_bytes().into_iter().map(|byte| Operand::Number(byte as u16)).collect();
+                format!("{} {}", formatter.instruction("db", &bytes, names), formatter.comment(&text))
+            }
+            // assemblers reject prefixes an instruction has no use for, so those are given as data
+            Instruction::Prefixed { prefixes, instruction } => {
+                let bytes: Vec<Operand> = prefixes.iter().map(|byte| Operand::Number(*byte as u16)).collect();
+                format!("{}\n{}", formatter.instruction("db", &bytes, names), instruction.format(formatter, names))
+            }
+            _ => text,
+        }
+    }
+
+    /// The mnemonic as NASM spells it, with any prefixes, and the operands in Intel order
+    pub fn operands(&self) -> (String, Vec<Operand>) {
+        use Operand::{Immediate as Imm, Jump, Number, Register as Reg, SegmentRegister as SegReg};
+
+        let (mnemonic, operands) = match *self {
+            Instruction::MovToFromRegMem { dir, reg, reg_or_mem } => ("mov", ordered(dir, Reg(reg), unsized_operand(reg_or_mem))),
+            Instruction::ImmediateMovRegMem { width, reg_or_mem, data } => {
+                ("mov", vec![unsized_operand(reg_or_mem), Imm { value: data.value(), size: Some(width), strict: false }])
+            }
+            Instruction::ImmediateMovReg { reg, data } => ("mov", vec![Reg(reg), immediate(data)]),
+            Instruction::AccumulatorMove { dir, width, addr, segment } => {
+                let mem = Operand::Memory { mem: EffectiveAddress { segment, ..EffectiveAddress::direct(addr) }, size: None };
+                ("mov", ordered(dir, accumulator(width), mem))
+            }
+            Instruction::SegmentRegisterMove { dir, seg_reg, reg_or_mem } => ("mov", ordered(dir, SegReg(seg_reg), unsized_operand(reg_or_mem))),
+            Instruction::ArithmeticFromToRegMem { op, dir, reg, reg_or_mem, .. } => return (op.to_string(), ordered(dir, Reg(reg), unsized_operand(reg_or_mem))),
+            Instruction::ArithmeticImmediateToRegMem { op, width, data, reg_or_mem } => {
+                let data = if data.fits_in_byte() { strict_word(data) } else { Imm { value: data.value(), size: Some(width), strict: false } };
+                return (op.to_string(), vec![unsized_operand(reg_or_mem), data]);
+            }
+            Instruction::ArithmeticImmediateToAccumulator { op, width, data } => {
+                let data = if data.fits_in_byte() { strict_word(data) } else { immediate(data) };
+                return (op.to_string(), vec![accumulator(width), data]);
+            }
+            Instruction::JumpOnEqual(disp) => ("je", vec![short(disp)]),
+            Instruction::JumpOnLess(disp) => ("jl", vec![short(disp)]),
+            Instruction::JumpOnNotGreater(disp) => ("jle", vec![short(disp)]),
+            Instruction::JumpOnBelow(disp) => ("jb", vec![short(disp)]),
+            Instruction::JumpOnNotAbove(disp) => ("jbe", vec![short(disp)]),
+            Instruction::JumpOnParity(disp) => ("jp", vec![short(disp)]),
+            Instruction::JumpOnOverflow(disp) => ("jo", vec![short(disp)]),
+            Instruction::JumpOnSign(disp) => ("js", vec![short(disp)]),
+            Instruction::JumpOnNotEqual(disp) => ("jne", vec![short(disp)]),
+            Instruction::JumpOnNotLess(disp) => ("jnl", vec![short(disp)]),
+            Instruction::JumpOnGreater(disp) => ("jg", vec![short(disp)]),
+            Instruction::JumpOnNotBelow(disp) => ("jnb", vec![short(disp)]),
+            Instruction::JumpOnAbove(disp) => ("jnbe", vec![short(disp)]),
+            Instruction::JumpOnNoParity(disp) => ("jnp", vec![short(disp)]),
+            Instruction::JumpOnNoOverflow(disp) => ("jno", vec![short(disp)]),
+            Instruction::JumpOnNotSign(disp) => ("jns", vec![short(disp)]),
+            Instruction::Loop(disp) => ("loop", vec![short(disp)]),
+            Instruction::LoopWhileEqual(disp) => ("loope", vec![short(disp)]),
+            Instruction::LoopWhileNotEqual(disp) => ("loopne", vec![short(disp)]),
+            Instruction::JumpOnCxZero(disp) => ("jcxz", vec![short(disp)]),
+            Instruction::PushRegMem { reg_or_mem } => ("push", vec![sized_operand(reg_or_mem, OpWidth::Word)]),
+            Instruction::PushReg { reg } => ("push", vec![Reg(reg)]),
+            Instruction::PushSegmentRegister { seg_reg } => ("push", vec![SegReg(seg_reg)]),
+            Instruction::PopRegMem { reg_or_mem } => ("pop", vec![sized_operand(reg_or_mem, OpWidth::Word)]),
+            Instruction::PopReg { reg } => ("pop", vec![Reg(reg)]),
+            Instruction::PopSegmentRegister { seg_reg } => ("pop", vec![SegReg(seg_reg)]),
+            Instruction::ExchangeRegMemWithReg { reg, reg_or_mem } => ("xchg", vec![Reg(reg), unsized_operand(reg_or_mem)]),
+            Instruction::ExchangeRegWithAccumulator { reg } => ("xchg", vec![accumulator(OpWidth::Word), Reg(reg)]),
+            Instruction::InFixedPort { width, port } => ("in", vec![accumulator(width), Number(port as u16)]),
+            Instruction::InVariablePort { width } => ("in", vec![accumulator(width), dx()]),
+            Instruction::OutFixedPort { width, port } => ("out", vec![Number(port as u16), accumulator(width)]),
+            Instruction::OutVariablePort { width } => ("out", vec![dx(), accumulator(width)]),
+            Instruction::TranslateByte { segment: Some(segment) } => return (format!("{segment} xlat"), vec![]),
+            Instruction::TranslateByte { segment: None } => ("xlat", vec![]),
+            Instruction::LoadEffectiveAddress { reg, mem } => ("lea", vec![Reg(reg), Operand::Memory { mem, size: None }]),
+            Instruction::LoadPointerToDs { reg, mem } => ("lds", vec![Reg(reg), Operand::Memory { mem, size: None }]),
+            Instruction::LoadPointerToEs { reg, mem } => ("les", vec![Reg(reg), Operand::Memory { mem, size: None }]),
+            Instruction::LoadAhFromFlags => ("lahf", vec![]),
+            Instruction::StoreAhIntoFlags => ("sahf", vec![]),
+            Instruction::PushFlags => ("pushf", vec![]),
+            Instruction::PopFlags => ("popf", vec![]),
+            Instruction::TestRegMemWithReg { reg, reg_or_mem } => ("test", vec![unsized_operand(reg_or_mem), Reg(reg)]),
+            Instruction::TestImmediateWithRegMem { width, data, reg_or_mem } => {
+                ("test", vec![unsized_operand(reg_or_mem), Imm { value: data.value(), size: Some(width), strict: false }])
+            }
+            Instruction::TestImmediateWithAccumulator { width, data } => ("test", vec![accumulator(width), immediate(data)]),
+            Instruction::Not { width, reg_or_mem } => ("not", vec![sized_operand(reg_or_mem, width)]),
+            Instruction::Shift { op, width, count, reg_or_mem } => {
+                let count = match count {
+                    ShiftCount::One => Number(1),
+                    ShiftCount::Cl => Reg(RegisterAccess::new(Register::C, OpWidth::Byte, 0)),
+                    ShiftCount::Immediate(count) => Number(count as u16),
+                };
+                return (op.to_string(), vec![sized_operand(reg_or_mem, width), count]);
+            }
+            Instruction::IncrementRegMem { width, reg_or_mem } => ("inc", vec![sized_operand(reg_or_mem, width)]),
+            Instruction::IncrementReg { reg } => ("inc", vec![Reg(reg)]),
+            Instruction::DecrementRegMem { width, reg_or_mem } => ("dec", vec![sized_operand(reg_or_mem, width)]),
+            Instruction::DecrementReg { reg } => ("dec", vec![Reg(reg)]),
+            Instruction::Negate { width, reg_or_mem } => ("neg", vec![sized_operand(reg_or_mem, width)]),
+            Instruction::MultiplyDivide { op, width, reg_or_mem } => return (op.to_string(), vec![sized_operand(reg_or_mem, width)]),
+            Instruction::StringManipulation { op, width, repeat, segment } => {
+                let prefix = match (repeat, op) {
+                    (None, _) => "",
+                    (Some(RepeatPrefix::Rep), StringOp::Cmps | StringOp::Scas) => "repe ",
+                    (Some(RepeatPrefix::Rep), _) => "rep ",
+                    (Some(RepeatPrefix::Repne), _) => "repne ",
+                };
+                let suffix = match width {
+                    OpWidth::Byte => "b",
+                    OpWidth::Word => "w",
+                };
+                let mnemonic = match segment {
+                    Some(segment) => format!("{segment} {prefix}{op}{suffix}"),
+                    None => format!("{prefix}{op}{suffix}"),
+                };
+                return (mnemonic, vec![]);
+            }
+            Instruction::Lock(ref instruction) => {
+                let (mnemonic, operands) = instruction.operands();
+                return (format!("lock {mnemonic}"), operands);
+            }
+            Instruction::Prefixed { ref prefixes, ref instruction } => {
+                let (mnemonic, operands) = instruction.operands();
+                let prefixes: Vec<String> = prefixes.iter().filter_map(|byte| decode_prefix(*byte)).map(prefix_name).collect();
+                return (format!("{} {mnemonic}", prefixes.join(" ")), operands);
+            }
+            Instruction::CallDirect(disp) => ("call", vec![Jump { disp, distance: None }]),
+            Instruction::CallFarDirect { offset, segment } => ("call", vec![Operand::FarPointer { segment, offset }]),
+            Instruction::CallIndirect { reg_or_mem } => ("call", vec![sized_operand(reg_or_mem, OpWidth::Word)]),
+            Instruction::CallFarIndirect { mem } => ("call", vec![Operand::Memory { mem, size: Some(MemorySize::Far) }]),
+            Instruction::JumpDirect(disp) => ("jmp", vec![Jump { disp, distance: Some(JumpDistance::Near) }]),
+            Instruction::JumpShort(disp) => ("jmp", vec![Jump { disp: disp as i16, distance: Some(JumpDistance::Short) }]),
+            Instruction::JumpFarDirect { offset, segment } => ("jmp", vec![Operand::FarPointer { segment, offset }]),
+            Instruction::JumpIndirect { reg_or_mem } => ("jmp", vec![sized_operand(reg_or_mem, OpWidth::Word)]),
+            Instruction::JumpFarIndirect { mem } => ("jmp", vec![Operand::Memory { mem, size: Some(MemorySize::Far) }]),
+            Instruction::Return { pop } => ("ret", pop.map(Number).into_iter().collect()),
+            Instruction::ReturnFar { pop } => ("retf", pop.map(Number).into_iter().collect()),
+            Instruction::Interrupt(vector) => ("int", vec![Number(vector as u16)]),
+            Instruction::Interrupt3 => ("int3", vec![]),
+            Instruction::InterruptOnOverflow => ("into", vec![]),
+            Instruction::InterruptReturn => ("iret", vec![]),
+            Instruction::ClearCarry => ("clc", vec![]),
+            Instruction::ComplementCarry => ("cmc", vec![]),
+            Instruction::SetCarry => ("stc", vec![]),
+            Instruction::ClearDirection => ("cld", vec![]),
+            Instruction::SetDirection => ("std", vec![]),
+            Instruction::ClearInterrupt => ("cli", vec![]),
+            Instruction::SetInterrupt => ("sti", vec![]),
+            Instruction::Halt => ("hlt", vec![]),
+            Instruction::Wait => ("wait", vec![]),
+            Instruction::Nop => ("nop", vec![]),
+            Instruction::Adjust(op) => return (op.to_string(), vec![]),
+            Instruction::AsciiAdjustMultiply { base: 10 } => ("aam", vec![]),
+            Instruction::AsciiAdjustMultiply { base } => ("aam", vec![Number(base as u16)]),
+            Instruction::AsciiAdjustDivide { base: 10 } => ("aad", vec![]),
+            Instruction::AsciiAdjustDivide { base } => ("aad", vec![Number(base as u16)]),
+            Instruction::ConvertByteToWord => ("cbw", vec![]),
+            Instruction::ConvertWordToDouble => ("cwd", vec![]),
+            Instruction::SetAlFromCarry => ("salc", vec![]),
+            Instruction::PushImmediate { data } => ("push", vec![Imm { value: data.value(), size: Some(OpWidth::Word), strict: data.fits_in_byte() }]),
+            Instruction::PushAll => ("pusha", vec![]),
+            Instruction::PopAll => ("popa", vec![]),
+            Instruction::MultiplyImmediate { reg, reg_or_mem, data } => {
+                let data = if data.fits_in_byte() { strict_word(data) } else { immediate(data) };
+                ("imul", vec![Reg(reg), unsized_operand(reg_or_mem), data])
+            }
+            Instruction::Enter { size, level } => ("enter", vec![Number(size.0), Number(level.0 as u16)]),
+            Instruction::Leave => ("leave", vec![]),
+            Instruction::Bound { reg, mem } => ("bound", vec![Reg(reg), Operand::Memory { mem, size: None }]),
+            Instruction::Escape { opcode, reg_or_mem } => ("esc", vec![Number(opcode as u16), unsized_operand(reg_or_mem)]),
+        };
+        (mnemonic.to_owned(), operands)
+    }
+}
+
+/// Puts the register first when it is the destination
+fn ordered(dir: Direction, reg: Operand, reg_or_mem: Operand) -> Vec<Operand> {
+    match dir {
+        Direction::FromRegister => vec![reg_or_mem, reg],
+        Direction::ToRegister => vec![reg, reg_or_mem],
+    }
+}
+
+fn unsized_operand(reg_or_mem: RegOrMem) -> Operand {
+    match reg_or_mem {
+        RegOrMem::Reg(reg) => Operand::Register(reg),
+        RegOrMem::Mem(mem) => Operand::Memory { mem, size: None },
+    }
+}
+
+/// Memory operands need an explicit size when the other operand doesn't imply one
+fn sized_operand(reg_or_mem: RegOrMem, width: OpWidth) -> Operand {
+    let size = match width {
+        OpWidth::Byte => MemorySize::Byte,
+        OpWidth::Word => MemorySize::Word,
+    };
+    match reg_or_mem {
+        RegOrMem::Reg(reg) => Operand::Register(reg),
+        RegOrMem::Mem(mem) => Operand::Memory { mem, size: Some(size) },
+    }
+}
+
+fn immediate(data: Immediate) -> Operand {
+    Operand::Immediate { value: data.value(), size: None, strict: false }
+}
+
+fn strict_word(data: Immediate) -> Operand {
+    Operand::Immediate { value: data.value(), size: Some(OpWidth::Word), strict: true }
+}
+
+fn short(disp: i8) -> Operand {
+    Operand::Jump { disp: disp as i16, distance: None }
+}
+
+fn accumulator(width: OpWidth) -> Operand {
+    Operand::Register(RegisterAccess::new(Register::A, width, 0))
+}
+
+fn prefix_name(prefix: Prefix) -> String {
+    match prefix {
+        Prefix::Repeat(RepeatPrefix::Rep) => "rep".to_owned(),
+        Prefix::Repeat(RepeatPrefix::Repne) => "repne".to_owned(),
+        Prefix::Segment(segment) => segment.to_string(),
+        Prefix::Lock => "lock".to_owned(),
+    }
+}
+
+fn dx() -> Operand {
+    Operand::Register(RegisterAccess::new(Register::D, OpWidth::Word, 0))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::decoder::{CpuModel, Decoder};
+    use crate::format::{Att, Case, InstructionFormatter, Masm, Names, Nasm};
+
+    fn format(formatter: &dyn InstructionFormatter, bytes: &[u8]) -> String {
+        let decoded = Decoder::new(CpuModel::I8086).decode_at(bytes, 0).unwrap().unwrap();
+        decoded.instruction.format(formatter, &|disp| format!("label_{disp}"))
+    }
+
+    #[test]
+    fn masm_syntax() {
+        let masm = Masm::default();
+        assert_eq!(format(&masm, &[0xc7, 0x07, 0x34, 0x12]), "mov word ptr [bx], 4660");
+        assert_eq!(format(&masm, &[0x80, 0x7f, 0x02, 0x05]), "cmp byte ptr [bx + 2], 5");
+        assert_eq!(format(&masm, &[0x83, 0xc3, 0x04]), "add bx, 4");
+        assert_eq!(format(&masm, &[0xfe, 0x00]), "inc byte ptr [bx + si]");
+        assert_eq!(format(&masm, &[0xa1, 0x10, 0x00]), "mov ax, ds:[16]");
+        assert_eq!(format(&masm, &[0x26, 0x8b, 0x47, 0x00]), "mov ax, es:[bx + 0]");
+        assert_eq!(format(&masm, &[0x75, 0x02]), "jnz label_2");
+        assert_eq!(format(&masm, &[0x7d, 0x02]), "jge label_2");
+        assert_eq!(format(&masm, &[0xe9, 0x00, 0x01]), "jmp near ptr label_256");
+        assert_eq!(format(&masm, &[0xff, 0x2f]), "jmp dword ptr [bx]");
+        assert_eq!(format(&masm, &[0xcc]), "int 3");
+        assert_eq!(format(&masm, &[0x3c, 0x80]), "cmp al, 128");
+    }
+
+    #[test]
+    fn nasm_syntax() {
+        let nasm = Nasm::default();
+        assert_eq!(format(&nasm, &[0xc7, 0x07, 0x34, 0x12]), "mov [bx], word 4660");
+        assert_eq!(format(&nasm, &[0x75, 0x02]), "jne label_2");
+        assert_eq!(format(&nasm, &[0xff, 0x2f]), "jmp far [bx]");
+        assert_eq!(format(&nasm, &[0x81, 0xc3, 0x04, 0x00]), "add bx, strict word 4");
+        assert_eq!(format(&nasm, &[0xb0, 0xff]), "mov al, 255");
+        assert_eq!(format(&nasm, &[0x80, 0x07, 0x80]), "add [bx], byte 128");
+        assert_eq!(format(&nasm, &[0x83, 0xc0, 0xff]), "add ax, word -1");
+        assert_eq!(format(&nasm, &[0x26, 0xdd, 0x47, 0x02]), "db 38, 221, 71, 2 ; esc 40, es:[bx + 2]");
+    }
+
+    #[test]
+    fn att_syntax() {
+        let att = Att::default();
+        assert_eq!(format(&att, &[0xc7, 0x07, 0x34, 0x12]), "movw $4660, (%bx)");
+        assert_eq!(format(&att, &[0x8b, 0x42, 0xfe]), "mov -2(%bp,%si), %ax");
+        assert_eq!(format(&att, &[0x26, 0xa1, 0x10, 0x00]), "mov %es:16, %ax");
+        assert_eq!(format(&att, &[0xd3, 0xe0]), "shl %cl, %ax");
+        assert_eq!(format(&att, &[0xe4, 0x10]), "in $16, %al");
+        assert_eq!(format(&att, &[0xff, 0xe3]), "jmp *%bx");
+        assert_eq!(format(&att, &[0xff, 0x2f]), "ljmp *(%bx)");
+        assert_eq!(format(&att, &[0xea, 0x00, 0x01, 0x00, 0xf0]), "ljmp $61440, $256");
+        assert_eq!(format(&att, &[0x75, 0x02]), "jne label_2");
+        assert_eq!(format(&att, &[0x98]), "cbtw");
+        assert_eq!(format(&att, &[0xd9, 0x07]), ".byte 217, 7 # esc (%bx), $8");
+    }
+
+    #[test]
+    fn uppercase_leaves_jump_targets_alone() {
+        assert_eq!(format(&Nasm { case: Case::Upper }, &[0xc6, 0x46, 0x02, 0x07]), "MOV [BP + 2], BYTE 7");
+        assert_eq!(format(&Nasm { case: Case::Upper }, &[0xeb, 0x02]), "JMP SHORT label_2");
+        assert_eq!(format(&Masm { case: Case::Upper }, &[0x26, 0xa4]), "ES MOVSB");
+        assert_eq!(format(&Masm { case: Case::Upper }, &[0x26, 0xd7]), "ES XLAT");
+        assert_eq!(format(&Masm { case: Case::Upper }, &[0xe2, 0xfe]), "LOOP label_-2");
+    }
+
+    struct Counter;
+
+    impl Names for Counter {
+        fn jump(&self, disp: i16) -> String {
+            format!("label_{disp}")
+        }
+
+        fn address(&self, address: u16) -> Option<String> {
+            (address == 0x10).then(|| "Counter".to_owned())
+        }
+    }
+
+    #[test]
+    fn direct_addresses_use_their_names() {
+        let named = |formatter: &dyn InstructionFormatter, bytes: &[u8]| {
+            let decoded = Decoder::new(CpuModel::I8086).decode_at(bytes, 0).unwrap().unwrap();
+            decoded.instruction.format(formatter, &Counter)
+        };
+        assert_eq!(named(&Nasm::default(), &[0xa1, 0x10, 0x00]), "mov ax, [Counter]");
+        assert_eq!(named(&Nasm::default(), &[0xc7, 0x06, 0x10, 0x00, 0x01, 0x00]), "mov [Counter], word 1");
+        assert_eq!(named(&Nasm { case: Case::Upper }, &[0x26, 0xfe, 0x06, 0x10, 0x00]), "INC BYTE ES:[Counter]");
+        assert_eq!(named(&Nasm::default(), &[0xa1, 0x11, 0x00]), "mov ax, [17]");
+        assert_eq!(named(&Masm::default(), &[0xa1, 0x10, 0x00]), "mov ax, ds:[Counter]");
+        assert_eq!(named(&Masm { case: Case::Upper }, &[0xfe, 0x06, 0x10, 0x00]), "INC BYTE PTR DS:[Counter]");
+    }
+}
@@ -0,0 +1,266 @@
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+
+use crate::memory::Memory;
+
+/// The segment DOS programs get their PSP at; the program follows it
+pub const LOAD_SEGMENT: u16 = 0x1000;
+
+/// The first paragraph past conventional memory, which the PSP gives as the top of memory
+const MEMORY_TOP: u16 = 0xa000;
+const PSP_SIZE: usize = 0x100;
+const PAGE_SIZE: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Bytes loaded at address 0, run from 0000:0000 with every register zero
+    Raw,
+    /// A DOS .COM file, loaded after its PSP and run from CS:0100 with all segments on the PSP
+    Com,
+    /// A DOS executable with an MZ header
+    Exe,
+}
+
+impl Format {
+    /// An MZ header makes an executable whatever the file is called, as it does for DOS; otherwise
+    /// a `.com` extension makes a .COM file and anything else is raw
+    pub fn detect(path: &Path, bytes: &[u8]) -> Format {
+        if bytes.starts_with(b"MZ") || bytes.starts_with(b"ZM") {
+            return Format::Exe;
+        }
+        match path.extension() {
+            Some(extension) if extension.eq_ignore_ascii_case("com") => Format::Com,
+            _ => Format::Raw,
+        }
+    }
+}
+
+/// Why a file could not be loaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    /// The program and the memory it needs don't fit in memory, below the top of conventional memory
+    /// for DOS programs or in its segment for a .COM file
+    TooLarge { size: usize },
+    /// The MZ header is cut short or says the file is longer than it is
+    Truncated { needed: usize, length: usize },
+    /// A relocation or the entry point is outside the load module
+    OutsideImage { offset: usize },
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::TooLarge { size } => write!(f, "program of {size:#x} bytes doesn't fit in memory"),
+            LoadError::Truncated { needed, length } => write!(f, "file is {length:#x} bytes, the header needs {needed:#x}"),
+            LoadError::OutsideImage { offset } => write!(f, "offset {offset:#x} is outside the load module"),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// A program in memory and the registers to start it with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Image {
+    pub format: Format,
+    pub cs: u16,
+    pub ip: u16,
+    pub ss: u16,
+    pub sp: u16,
+    pub ds: u16,
+    pub es: u16,
+    /// The physical address of the first byte of the program, after any PSP
+    pub start: usize,
+    /// The physical address after the last byte of the program
+    pub end: usize,
+}
+
+impl Image {
+    pub fn load(format: Format, bytes: &[u8], memory: &mut Memory) -> Result<Image, LoadError> {
+        match format {
+            Format::Raw => {
+                if bytes.len() > 0x10_0000 {
+                    return Err(LoadError::TooLarge { size: bytes.len() });
+                }
+                memory.copy_from_slice(bytes, 0);
+                Ok(Image { format, cs: 0, ip: 0, ss: 0, sp: 0, ds: 0, es: 0, start: 0, end: bytes.len() })
+            }
+            Format::Com => load_com(bytes, memory),
+            Format::Exe => load_exe(bytes, memory),
+        }
+    }
+
+    /// The code segment from CS:0000 up to the end of the program, at most 64K, and the offset in it
+    /// of the first byte of the program
+    pub fn code<'a>(&self, memory: &'a Memory) -> (&'a [u8], usize) {
+        let base = self.cs as usize * 16;
+        let end = self.end.clamp(base, base + 0x10000);
+        (memory.slice(base, end), self.start.saturating_sub(base))
+    }
+}
+
+/// The segment is 64K, less the PSP and the word of stack that the return address to the PSP takes
+fn load_com(bytes: &[u8], memory: &mut Memory) -> Result<Image, LoadError> {
+    if bytes.len() > 0x10000 - PSP_SIZE - 2 {
+        return Err(LoadError::TooLarge { size: bytes.len() });
+    }
+    write_psp(memory, LOAD_SEGMENT);
+    let start = LOAD_SEGMENT as usize * 16 + PSP_SIZE;
+    memory.copy_from_slice(bytes, start);
+
+    // the stack starts with a zero word, so a near ret goes to the int 0x20 at the start of the PSP
+    let segment = LOAD_SEGMENT;
+    Ok(Image { format: Format::Com, cs: segment, ip: PSP_SIZE as u16, ss: segment, sp: 0xfffe, ds: segment, es: segment, start, end: start + bytes.len() })
+}
+
+/// The load module goes in the paragraph after the PSP, and the segment of each relocation has
+/// that paragraph added to it, as do the CS and SS in the header
+fn load_exe(bytes: &[u8], memory: &mut Memory) -> Result<Image, LoadError> {
+    const HEADER_SIZE: usize = 0x1c;
+    let word = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]) as usize;
+    if bytes.len() < HEADER_SIZE {
+        return Err(LoadError::Truncated { needed: HEADER_SIZE, length: bytes.len() });
+    }
+    let (last_page, pages, relocations, header_paragraphs) = (word(0x02), word(0x04), word(0x06), word(0x08));
+    let (min_extra_paragraphs, ss, sp, ip, cs, relocation_table) = (word(0x0a), word(0x0e), word(0x10), word(0x14), word(0x16), word(0x18));
+
+    // the last page is only partly used when the header gives the bytes in it
+    let file_size = match last_page {
+        0 => pages * PAGE_SIZE,
+        last_page => pages.saturating_sub(1) * PAGE_SIZE + last_page,
+    };
+    let header_size = header_paragraphs * 16;
+    let needed = file_size.max(header_size).max(relocation_table + relocations * 4);
+    if bytes.len() < needed {
+        return Err(LoadError::Truncated { needed, length: bytes.len() });
+    }
+    let module = &bytes[header_size..file_size.max(header_size)];
+
+    let start_segment = LOAD_SEGMENT + (PSP_SIZE / 16) as u16;
+    let start = start_segment as usize * 16;
+    let end = start + module.len();
+    if end + min_extra_paragraphs * 16 > MEMORY_TOP as usize * 16 {
+        return Err(LoadError::TooLarge { size: module.len() + min_extra_paragraphs * 16 });
+    }
+    write_psp(memory, LOAD_SEGMENT);
+    memory.copy_from_slice(module, start);
+
+    for i in 0..relocations {
+        let entry = relocation_table + i * 4;
+        let offset = word(entry + 2) * 16 + word(entry);
+        if offset + 2 > module.len() {
+            return Err(LoadError::OutsideImage { offset });
+        }
+        let fixed = word_at(memory, start + offset).wrapping_add(start_segment);
+        memory.copy_from_slice(&fixed.to_le_bytes(), start + offset);
+    }
+
+    let entry = cs * 16 + ip;
+    if entry >= module.len() {
+        return Err(LoadError::OutsideImage { offset: entry });
+    }
+    Ok(Image {
+        format: Format::Exe,
+        cs: start_segment.wrapping_add(cs as u16),
+        ip: ip as u16,
+        ss: start_segment.wrapping_add(ss as u16),
+        sp: sp as u16,
+        ds: LOAD_SEGMENT,
+        es: LOAD_SEGMENT,
+        start,
+        end,
+    })
+}
+
+fn word_at(memory: &Memory, address: usize) -> u16 {
+    u16::from_le_bytes([*memory.get(address).unwrap(), *memory.get(address + 1).unwrap()])
+}
+
+/// Just enough of a program segment prefix for programs that exit through it or look at their
+/// command line: `int 0x20` at the start, the top of memory, the `int 0x21`/`retf` dispatcher and
+/// an empty command tail
+fn write_psp(memory: &mut Memory, segment: u16) {
+    let base = segment as usize * 16;
+    memory.copy_from_slice(&[0; PSP_SIZE], base);
+    memory.copy_from_slice(&[0xcd, 0x20], base);
+    memory.copy_from_slice(&MEMORY_TOP.to_le_bytes(), base + 0x02);
+    memory.copy_from_slice(&[0xcd, 0x21, 0xcb], base + 0x50);
+    memory.copy_from_slice(&[0x00, 0x0d], base + 0x80);
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use crate::image::{Format, Image, LoadError, LOAD_SEGMENT};
+    use crate::memory::Memory;
+
+    #[test]
+    fn loads_com_files_after_the_psp() {
+        let mut memory = Memory::new();
+        let bytes = [0xb4, 0x4c, 0xcd, 0x21];
+        assert_eq!(Format::detect(Path::new("HELLO.COM"), &bytes), Format::Com);
+        assert_eq!(Format::detect(Path::new("listing_0037"), &bytes), Format::Raw);
+
+        let image = Image::load(Format::Com, &bytes, &mut memory).unwrap();
+        assert_eq!((image.cs, image.ip, image.ss, image.sp, image.ds), (LOAD_SEGMENT, 0x100, LOAD_SEGMENT, 0xfffe, LOAD_SEGMENT));
+        let psp = LOAD_SEGMENT as usize * 16;
+        assert_eq!(memory.slice(psp, psp + 4), [0xcd, 0x20, 0x00, 0xa0]);
+        assert_eq!(memory.slice(image.start, image.end), bytes);
+
+        let (code, origin) = image.code(&memory);
+        assert_eq!((code.len(), origin), (0x104, 0x100));
+        assert_eq!(code[0x100..], bytes);
+
+        assert_eq!(Image::load(Format::Com, &vec![0; 0xff00], &mut memory), Err(LoadError::TooLarge { size: 0xff00 }));
+    }
+
+    /// A header of two paragraphs with one relocation, then a module of 0x24 bytes whose code
+    /// segment starts at paragraph 1 and stack at paragraph 2
+    fn exe() -> Vec<u8> {
+        let mut bytes = vec![0; 0x20 + 0x24];
+        let header: [u16; 14] = [0x5a4d, 0x44, 1, 1, 2, 0x10, 0xffff, 2, 0x100, 0, 4, 1, 0x1c, 0];
+        for (i, word) in header.iter().enumerate() {
+            bytes[i * 2..i * 2 + 2].copy_from_slice(&word.to_le_bytes());
+        }
+        // the relocation is for the immediate of `mov ax, 0x0002` at 0001:0005
+        bytes[0x1c..0x20].copy_from_slice(&[0x05, 0x00, 0x01, 0x00]);
+        bytes[0x30..0x38].copy_from_slice(&[0x90, 0x90, 0x90, 0x90, 0xb8, 0x02, 0x00, 0xf4]);
+        bytes
+    }
+
+    #[test]
+    fn loads_exe_files_with_relocations() {
+        let mut memory = Memory::new();
+        let bytes = exe();
+        assert_eq!(Format::detect(Path::new("game.bin"), &bytes), Format::Exe);
+
+        let image = Image::load(Format::Exe, &bytes, &mut memory).unwrap();
+        let start_segment = LOAD_SEGMENT + 0x10;
+        assert_eq!((image.cs, image.ip), (start_segment + 1, 4));
+        assert_eq!((image.ss, image.sp), (start_segment + 2, 0x100));
+        assert_eq!((image.ds, image.es), (LOAD_SEGMENT, LOAD_SEGMENT));
+        assert_eq!((image.start, image.end), (start_segment as usize * 16, start_segment as usize * 16 + 0x24));
+
+        let (code, origin) = image.code(&memory);
+        assert_eq!(origin, 0);
+        assert_eq!(code[4..8], [0xb8, (start_segment + 2) as u8, ((start_segment + 2) >> 8) as u8, 0xf4]);
+    }
+
+    #[test]
+    fn rejects_broken_exe_files() {
+        let mut memory = Memory::new();
+        let load = |bytes: &[u8], memory: &mut Memory| Image::load(Format::Exe, bytes, memory);
+
+        assert_eq!(load(&exe()[..0x40], &mut memory), Err(LoadError::Truncated { needed: 0x44, length: 0x40 }));
+        assert_eq!(load(b"MZ", &mut memory), Err(LoadError::Truncated { needed: 0x1c, length: 2 }));
+
+        let mut bytes = exe();
+        bytes[0x1c] = 0x30;
+        assert_eq!(load(&bytes, &mut memory), Err(LoadError::OutsideImage { offset: 0x40 }));
+
+        let mut bytes = exe();
+        bytes[0x0a..0x0c].copy_from_slice(&0xffffu16.to_le_bytes());
+        assert_eq!(load(&bytes, &mut memory), Err(LoadError::TooLarge { size: 0x24 + 0xffff0 }));
+    }
+}
@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
 use std::iter::Peekable;
 use std::str::Chars;
 
@@ -12,6 +13,65 @@ pub enum JsonValue {
     Object(HashMap<String, JsonValue>),
 }
 
+impl JsonValue {
+    /// An object with the given fields, a later field replacing an earlier one with the same key
+    pub fn object<'a>(fields: impl IntoIterator<Item = (&'a str, JsonValue)>) -> JsonValue {
+        JsonValue::Object(fields.into_iter().map(|(key, value)| (key.to_owned(), value)).collect())
+    }
+}
+
+/// Writes compact JSON. The keys of an object are written in order, so the same value always gives
+/// the same text, and numbers that JSON can't represent are written as null.
+impl Display for JsonValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JsonValue::String(string) => write_string(f, string),
+            JsonValue::Number(number) if number.is_finite() => write!(f, "{number}"),
+            JsonValue::Number(_) | JsonValue::Null => f.write_str("null"),
+            JsonValue::Boolean(boolean) => write!(f, "{boolean}"),
+            JsonValue::Array(values) => {
+                f.write_str("[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{value}")?;
+                }
+                f.write_str("]")
+            }
+            JsonValue::Object(object) => {
+                let mut keys: Vec<&String> = object.keys().collect();
+                keys.sort();
+                f.write_str("{")?;
+                for (i, key) in keys.into_iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", object[key])?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut Formatter<'_>, string: &str) -> std::fmt::Result {
+    f.write_str("\"")?;
+    for c in string.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    f.write_str("\"")
+}
+
 pub fn parse_json_from_iter(json_iter: Chars)
 {
     parse_value(&mut json_iter.peekable());
@@ -189,7 +249,7 @@ fn parse_number(chars: &mut Peekable<Chars>) -> Option<JsonValue> {
     let mut number_str = String::new();
 
     while let Some(&c) = chars.peek() {
-        if c.is_digit(10) || c == '.' || c == 'e' || c == 'E' || (number_str.is_empty() && (c == '-' || c == '+')) {
+        if c.is_ascii_digit() || c == '.' || c == 'e' || c == 'E' || (number_str.is_empty() && (c == '-' || c == '+')) {
             number_str.push(c);
             chars.next();
         } else {
@@ -210,21 +270,12 @@ fn parse_bool(chars: &mut Peekable<Chars>) -> Option<JsonValue> {
             // true
             let result: String = chars.take(4).collect();
             if result == "true" {
-                chars.next();
-                chars.next();
-                chars.next();
-                chars.next();
                 return Some(JsonValue::Boolean(true));
             }
         } else if *c == 'f' {
             // false
             let result: String = chars.take(5).collect();
             if result == "false" {
-                chars.next();
-                chars.next();
-                chars.next();
-                chars.next();
-                chars.next();
                 return Some(JsonValue::Boolean(false));
             }
         }
@@ -236,10 +287,6 @@ fn parse_bool(chars: &mut Peekable<Chars>) -> Option<JsonValue> {
 fn parse_null(chars: &mut Peekable<Chars>) -> Option<JsonValue> {
     let result: String = chars.take(4).collect();
     if result == "null" {
-        chars.next();
-        chars.next();
-        chars.next();
-        chars.next();
         Some(JsonValue::Null)
     } else {
         None
@@ -288,9 +335,10 @@ fn parse_value(chars: &mut Peekable<Chars>) -> Option<JsonValue> {
     None
 }
 
+#[cfg(test)]
 mod test {
-    use json::parse_json_from_str as parse;
     use crate::json;
+    use crate::json::parse_json_from_str as parse;
 
     #[test]
     fn main() {
@@ -309,7 +357,7 @@ mod test {
     "#;
 
         let result = parse(json_str);
-        assert!(matches!(result, Some(_)));
+        assert!(result.is_some());
         println!("{result:?}")
     }
 
@@ -319,4 +367,18 @@ mod test {
         let result = parse(json_str);
         assert!(matches!(result, Some(json::JsonValue::String(_))));
     }
+
+    #[test]
+    fn writes_compact_json() {
+        let value = json::JsonValue::object([
+            ("name", json::JsonValue::String("say \"hi\"\n".to_owned())),
+            ("age", json::JsonValue::Number(30.0)),
+            ("ratio", json::JsonValue::Number(0.5)),
+            ("nothing", json::JsonValue::Number(f64::NAN)),
+            ("grades", json::JsonValue::Array(vec![json::JsonValue::Boolean(true), json::JsonValue::Null])),
+        ]);
+        let text = value.to_string();
+        assert_eq!(text, r#"{"age":30,"grades":[true,null],"name":"say \"hi\"\n","nothing":null,"ratio":0.5}"#);
+        assert_eq!(parse(&text).map(|value| value.to_string()), Some(text));
+    }
 }
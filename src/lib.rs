@@ -1,8 +1,16 @@
 mod decode;
+mod encode;
+pub mod format;
 pub mod decoder;
+pub mod disassembly;
+pub mod control_flow;
+pub mod assembler;
+pub mod annotations;
 mod lookup;
 pub mod ops;
 pub mod memory;
+pub mod image;
+pub mod clocks;
 
 pub mod flag_registers;
 
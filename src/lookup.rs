@@ -1,32 +1,155 @@
-use crate::decode::*;
 use std::collections::HashMap;
 
+use crate::decode::*;
+
+/// What a first byte maps to: either a decoder for the whole opcode, or one decoder per value of
+/// the ModRM reg field for group opcodes such as 0x80 or 0xFF.
+pub enum OpCodeEntry {
+    Decoder(Box<dyn DecodeFrom>),
+    Group(ModRmGroup),
+}
+
+#[derive(Default)]
+pub struct ModRmGroup {
+    decoders: [Option<Box<dyn DecodeFrom>>; 8],
+}
+
+impl ModRmGroup {
+    pub fn get(&self, modrm: u8) -> Option<&dyn DecodeFrom> {
+        self.decoders[Self::reg_field(modrm) as usize].as_deref()
+    }
+
+    /// Bit n is set when reg field value n has a decoder
+    pub fn reg_fields(&self) -> u8 {
+        self.decoders.iter().enumerate().filter(|(_, d)| d.is_some()).fold(0, |mask, (reg, _)| mask | 1 << reg)
+    }
+
+    fn reg_field(modrm: u8) -> u8 {
+        (modrm >> 3) & 0b0000_0111
+    }
+}
+
+/// Where the decoder finds what a first byte maps to
+pub trait OpCodeTable {
+    fn get(&self, opcode: &u8) -> Option<&OpCodeEntry>;
+}
+
+impl OpCodeTable for OpDecoderLookup {
+    #[inline]
+    fn get(&self, opcode: &u8) -> Option<&OpCodeEntry> {
+        OpDecoderLookup::get(self, opcode)
+    }
+}
+
+/// The HashMap the decoder used before `OpDecoderLookup` became an array, see `OpDecoderLookup::to_hash_map`
+impl OpCodeTable for HashMap<u8, &OpCodeEntry> {
+    fn get(&self, opcode: &u8) -> Option<&OpCodeEntry> {
+        HashMap::get(self, opcode).copied()
+    }
+}
+
+/// Dispatch table indexed directly by the first byte of an instruction
 pub struct OpDecoderLookup {
-    map: HashMap<u8, Box<dyn OpCodeDecoder>>,
+    table: [Option<OpCodeEntry>; 256],
 }
 
 impl OpDecoderLookup {
     pub fn new() -> OpDecoderLookup {
-        OpDecoderLookup { map: HashMap::new() }
+        OpDecoderLookup { table: std::array::from_fn(|_| None) }
     }
 
+    /// Registers `decoder` for every byte matching `pattern`. Letters in the pattern match either bit value.
+    ///
+    /// A second pattern for the ModRM byte selects group opcodes by their reg field, written as
+    /// `mod reg r/m`, e.g. `"0b1111_111w 0bmm_000_rrr"`. Only the reg bits may be fixed.
+    ///
+    /// Panics if the pattern overlaps an earlier registration, use `replace` where that is intended.
     pub fn insert<D: OpCodeDecoder + Clone + 'static>(&mut self, pattern: &str, decoder: D) {
-        let pattern = pattern.strip_prefix("0b").unwrap_or(pattern);
-        let pattern = pattern.replace('_', "");
+        let conflicts = self.conflicts(pattern);
+        if !conflicts.is_empty() {
+            let conflicts: Vec<String> = conflicts.iter().map(|b| format!("{b:#04x}")).collect();
+            panic!("{pattern} overlaps existing opcodes {}", conflicts.join(", "));
+        }
+        self.replace(pattern, decoder);
+    }
 
-        let mut v = vec![];
-        Self::expand(&pattern, &mut v);
+    /// Registers `decoder` like `insert`, overwriting whatever was registered for the same opcodes
+    pub fn replace<D: OpCodeDecoder + Clone + 'static>(&mut self, pattern: &str, decoder: D) {
+        let (op_codes, regs) = Self::parse_pattern(pattern);
 
-        for b in v {
-            self.map.insert(b, Box::new(decoder.clone()));
+        match regs {
+            None => {
+                for b in op_codes {
+                    self.table[b as usize] = Some(OpCodeEntry::Decoder(Box::new(decoder.clone())));
+                }
+            }
+            Some(regs) => {
+                for b in op_codes {
+                    let entry = self.table[b as usize].get_or_insert_with(|| OpCodeEntry::Group(ModRmGroup::default()));
+                    if let OpCodeEntry::Decoder(_) = entry {
+                        *entry = OpCodeEntry::Group(ModRmGroup::default());
+                    }
+                    if let OpCodeEntry::Group(group) = entry {
+                        for reg in regs.iter() {
+                            group.decoders[*reg as usize] = Some(Box::new(decoder.clone()));
+                        }
+                    }
+                }
+            }
         }
     }
 
-    pub fn get(&self, opcode: &u8) -> Option<&dyn OpCodeDecoder> {
-        match self.map.get(opcode) {
-            Some(decoder) => Some(decoder.as_ref()),
-            None => None
-        }
+    /// The opcodes for which `pattern` would overwrite an existing registration
+    pub fn conflicts(&self, pattern: &str) -> Vec<u8> {
+        let (op_codes, regs) = Self::parse_pattern(pattern);
+
+        op_codes
+            .into_iter()
+            .filter(|b| match (self.get(b), &regs) {
+                (None, _) => false,
+                (Some(OpCodeEntry::Group(group)), Some(regs)) => regs.iter().any(|reg| group.reg_fields() & 1 << reg != 0),
+                (Some(_), _) => true,
+            })
+            .collect()
+    }
+
+    #[inline]
+    pub fn get(&self, opcode: &u8) -> Option<&OpCodeEntry> {
+        self.table[*opcode as usize].as_ref()
+    }
+
+    /// The same entries keyed by a HashMap, kept so the benchmark can compare against the old lookup
+    pub fn to_hash_map(&self) -> HashMap<u8, &OpCodeEntry> {
+        (0..=255).filter_map(|opcode| Some((opcode, self.get(&opcode)?))).collect()
+    }
+
+    fn parse_pattern(pattern: &str) -> (Vec<u8>, Option<Vec<u8>>) {
+        let mut patterns = pattern.split_whitespace();
+        let op_codes = Self::parse(patterns.next().unwrap_or_else(|| panic!("empty pattern")));
+
+        let regs = patterns.next().map(|modrm| {
+            let modrm = Self::normalize(modrm);
+            if modrm.len() != 8 || !modrm[..2].chars().chain(modrm[5..].chars()).all(char::is_alphabetic) {
+                panic!("only the reg field of a ModRM pattern may be fixed: {pattern}");
+            }
+
+            let mut regs = vec![];
+            Self::expand(&modrm[2..5], &mut regs);
+            regs
+        });
+
+        (op_codes, regs)
+    }
+
+    fn parse(pattern: &str) -> Vec<u8> {
+        let mut v = vec![];
+        Self::expand(&Self::normalize(pattern), &mut v);
+        v
+    }
+
+    fn normalize(pattern: &str) -> String {
+        let pattern = pattern.strip_prefix("0b").unwrap_or(pattern);
+        pattern.replace('_', "")
     }
 
     fn expand(i: &str, v: &mut Vec<u8>) {
@@ -39,3 +162,65 @@ impl OpDecoderLookup {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::decode::{DecodeFrom, SingleByteDecoder, SliceReader};
+    use crate::lookup::{OpCodeEntry, OpDecoderLookup};
+    use crate::ops::Instruction;
+
+    fn decode(entry: &OpCodeEntry, modrm: u8) -> Option<String> {
+        let decoder: &dyn DecodeFrom = match entry {
+            OpCodeEntry::Decoder(decoder) => decoder.as_ref(),
+            OpCodeEntry::Group(group) => group.get(modrm)?,
+        };
+        let instruction = decoder.decode_slice(0, &mut SliceReader::new(&[], 0)).unwrap();
+        Some(instruction.encode(|disp| format!("{disp}")))
+    }
+
+    #[test]
+    fn group_patterns_select_on_reg_field() {
+        let mut lookup = OpDecoderLookup::new();
+        lookup.insert("0b1111_111w 0bmm_00x_rrr", SingleByteDecoder::new(Instruction::PushFlags));
+        lookup.insert("0b1111_1111 0bmm_110_rrr", SingleByteDecoder::new(Instruction::PopFlags));
+
+        let fe = lookup.get(&0xfe).unwrap();
+        assert_eq!(decode(fe, 0b11_000_001).as_deref(), Some("pushf"));
+        assert_eq!(decode(fe, 0b00_001_110).as_deref(), Some("pushf"));
+        assert_eq!(decode(fe, 0b01_110_000), None);
+
+        let ff = lookup.get(&0xff).unwrap();
+        assert_eq!(decode(ff, 0b01_110_000).as_deref(), Some("popf"));
+        assert_eq!(decode(ff, 0b11_111_000), None);
+    }
+
+    #[test]
+    fn overlapping_patterns_are_conflicts() {
+        let mut lookup = OpDecoderLookup::new();
+        lookup.insert("0b1111_111w 0bmm_00x_rrr", SingleByteDecoder::new(Instruction::PushFlags));
+        lookup.insert("0b1001_110x", SingleByteDecoder::new(Instruction::PushFlags));
+
+        assert_eq!(lookup.conflicts("0b1111_1111 0bmm_001_rrr"), [0xff]);
+        assert_eq!(lookup.conflicts("0b1111_1111 0bmm_010_rrr"), []);
+        assert_eq!(lookup.conflicts("0b1111_11x1"), [0xff]);
+        assert_eq!(lookup.conflicts("0b1001_11xx"), [0x9c, 0x9d]);
+        assert_eq!(lookup.conflicts("0b1001_11xx 0bmm_000_rrr"), [0x9c, 0x9d]);
+
+        lookup.replace("0b1001_1100", SingleByteDecoder::new(Instruction::PopFlags));
+        assert_eq!(decode(lookup.get(&0x9c).unwrap(), 0).as_deref(), Some("popf"));
+    }
+
+    #[test]
+    #[should_panic(expected = "overlaps existing opcodes 0xfe")]
+    fn insert_rejects_overlaps() {
+        let mut lookup = OpDecoderLookup::new();
+        lookup.insert("0b1111_111w", SingleByteDecoder::new(Instruction::PushFlags));
+        lookup.insert("0b1111_1110 0bmm_000_rrr", SingleByteDecoder::new(Instruction::PopFlags));
+    }
+
+    #[test]
+    #[should_panic]
+    fn group_patterns_cannot_fix_mod_bits() {
+        OpDecoderLookup::new().insert("0b1111_1111 0b11_000_rrr", SingleByteDecoder::new(Instruction::PushFlags));
+    }
+}
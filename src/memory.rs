@@ -19,6 +19,10 @@ impl Memory {
       self.data[offset..limit].iter()
     }
 
+    pub fn slice(&self, offset: usize, limit: usize) -> &[u8] {
+        &self.data[offset..limit]
+    }
+
     pub fn get(&self, offset: usize) -> Option<&u8> {
         if offset < self.data.len() {
             Some(&self.data[offset])
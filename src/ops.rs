@@ -1,6 +1,9 @@
 use std::fmt::Display;
 
-#[derive(Debug, Clone, Copy)]
+use crate::decode::{decode_prefix, Prefix};
+use crate::format::{nasm_memory, Nasm};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Register {
     A,
     C,
@@ -18,7 +21,7 @@ impl Display for Register {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SegmentRegister {
     Es,
     Cs,
@@ -32,7 +35,7 @@ impl Display for SegmentRegister {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct RegisterAccess {
     pub reg: Register,
     pub width: OpWidth,
@@ -72,7 +75,7 @@ impl Display for RegisterAccess {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum EffectiveAddressBase {
     Direct,
     BxPlusSi,
@@ -101,13 +104,103 @@ impl Display for EffectiveAddressBase {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+/// The displacement of a memory operand as it was encoded, so the same encoding can be reproduced.
+/// For the `Direct` base this holds the 16-bit address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Displacement {
+    None,
+    Disp8(i8),
+    Disp16(i16),
+}
+
+impl Displacement {
+    pub fn value(&self) -> i16 {
+        match *self {
+            Displacement::None => 0,
+            Displacement::Disp8(disp) => disp as i16,
+            Displacement::Disp16(disp) => disp,
+        }
+    }
+
+    /// The smallest encoding for `disp`, as an assembler would choose it
+    pub fn shortest(disp: i16) -> Displacement {
+        match disp {
+            0 => Displacement::None,
+            -128..=127 => Displacement::Disp8(disp as i8),
+            _ => Displacement::Disp16(disp),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct EffectiveAddress {
     pub base: EffectiveAddressBase,
-    pub displacement: i16,
+    pub displacement: Displacement,
+    pub segment: Option<SegmentRegister>,
+}
+
+impl EffectiveAddress {
+    pub fn direct(address: Addr16) -> EffectiveAddress {
+        EffectiveAddress {
+            base: EffectiveAddressBase::Direct,
+            displacement: Displacement::Disp16(address.0 as i16),
+            segment: None,
+        }
+    }
+}
+
+/// An 8-bit immediate exactly as it appeared in the instruction stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Imm8(pub u8);
+
+/// A 16-bit immediate exactly as it appeared in the instruction stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Imm16(pub u16);
+
+/// A 16-bit offset into a segment, always unsigned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Addr16(pub u16);
+
+impl Display for Addr16 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Immediate operand of an instruction. `SignExtended` is a byte in the stream that the
+/// CPU widens to a word, as in the 0x83 arithmetic opcode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Immediate {
+    Byte(Imm8),
+    SignExtended(Imm8),
+    Word(Imm16),
+}
+
+impl Immediate {
+    /// The operand value as a word: only a `SignExtended` byte is sign-extended, a plain byte is
+    /// unsigned
+    pub fn value(&self) -> i16 {
+        match *self {
+            Immediate::Byte(Imm8(data)) => data as i16,
+            Immediate::SignExtended(Imm8(data)) => data as i8 as i16,
+            Immediate::Word(Imm16(data)) => data as i16,
+        }
+    }
+
+    /// A word immediate small enough that an assembler would encode it as a sign-extended byte,
+    /// so printing it needs `strict word` to keep the original encoding
+    pub(crate) fn fits_in_byte(&self) -> bool {
+        matches!(self, Immediate::Word(_)) && (-128..=127).contains(&self.value())
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
+impl Display for Immediate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.value().fmt(f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RegOrMem {
     Reg(RegisterAccess),
     Mem(EffectiveAddress),
@@ -117,32 +210,34 @@ impl Display for RegOrMem {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             RegOrMem::Reg(ra) => ra.fmt(f),
+            RegOrMem::Mem(ea) => f.write_str(&nasm_memory(ea)),
+        }
+    }
+}
 
-            RegOrMem::Mem(EffectiveAddress { base, displacement }) if matches!(base, &EffectiveAddressBase::Direct) => {
-                f.write_fmt(format_args!("[{displacement}]"))
-            }
-            RegOrMem::Mem(EffectiveAddress { base, displacement }) => {
-                if displacement == &0 {
-                    f.write_fmt(format_args!("[{base}]"))
-                } else if displacement > &0 {
-                    f.write_fmt(format_args!("[{base} + {displacement}]"))
-                } else if displacement == &(-256) {
-                    f.write_fmt(format_args!("[{base} - 256]"))
-                } else {
-                    f.write_fmt(format_args!("[{base} - {}]", -(*displacement)))
-                }
-            }
+impl RegOrMem {
+    pub fn as_mem(&self) -> Option<&EffectiveAddress> {
+        match self {
+            RegOrMem::Mem(ea) => Some(ea),
+            RegOrMem::Reg(_) => None,
+        }
+    }
+
+    pub fn as_mem_mut(&mut self) -> Option<&mut EffectiveAddress> {
+        match self {
+            RegOrMem::Mem(ea) => Some(ea),
+            RegOrMem::Reg(_) => None,
         }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Direction {
     ToRegister,
     FromRegister,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum OpWidth {
     Byte,
     Word,
@@ -157,12 +252,15 @@ impl std::fmt::Display for OpWidth {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ArithmeticOp {
     Add,
+    Or,
     Adc,
-    Sub,
     Sbb,
+    And,
+    Sub,
+    Xor,
     Cmp,
 }
 
@@ -170,15 +268,132 @@ impl Display for ArithmeticOp {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match *self {
             ArithmeticOp::Add => f.write_str("add"),
+            ArithmeticOp::Or => f.write_str("or"),
             ArithmeticOp::Adc => f.write_str("adc"),
-            ArithmeticOp::Sub => f.write_str("sub"),
             ArithmeticOp::Sbb => f.write_str("sbb"),
+            ArithmeticOp::And => f.write_str("and"),
+            ArithmeticOp::Sub => f.write_str("sub"),
+            ArithmeticOp::Xor => f.write_str("xor"),
             ArithmeticOp::Cmp => f.write_str("cmp"),
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShiftOp {
+    Rol,
+    Ror,
+    Rcl,
+    Rcr,
+    Shl,
+    Shr,
+    Sar,
+}
+
+impl Display for ShiftOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            ShiftOp::Rol => f.write_str("rol"),
+            ShiftOp::Ror => f.write_str("ror"),
+            ShiftOp::Rcl => f.write_str("rcl"),
+            ShiftOp::Rcr => f.write_str("rcr"),
+            ShiftOp::Shl => f.write_str("shl"),
+            ShiftOp::Shr => f.write_str("shr"),
+            ShiftOp::Sar => f.write_str("sar"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MultiplyDivideOp {
+    Mul,
+    Imul,
+    Div,
+    Idiv,
+}
+
+impl Display for MultiplyDivideOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            MultiplyDivideOp::Mul => f.write_str("mul"),
+            MultiplyDivideOp::Imul => f.write_str("imul"),
+            MultiplyDivideOp::Div => f.write_str("div"),
+            MultiplyDivideOp::Idiv => f.write_str("idiv"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StringOp {
+    Movs,
+    Cmps,
+    Scas,
+    Lods,
+    Stos,
+    Ins,
+    Outs,
+}
+
+impl Display for StringOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            StringOp::Movs => f.write_str("movs"),
+            StringOp::Cmps => f.write_str("cmps"),
+            StringOp::Scas => f.write_str("scas"),
+            StringOp::Lods => f.write_str("lods"),
+            StringOp::Stos => f.write_str("stos"),
+            StringOp::Ins => f.write_str("ins"),
+            StringOp::Outs => f.write_str("outs"),
+        }
+    }
+}
+
+/// Decimal (packed BCD) and ASCII (unpacked BCD) corrections applied to AL after arithmetic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdjustOp {
+    Daa,
+    Das,
+    Aaa,
+    Aas,
+}
+
+impl Display for AdjustOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            AdjustOp::Daa => f.write_str("daa"),
+            AdjustOp::Das => f.write_str("das"),
+            AdjustOp::Aaa => f.write_str("aaa"),
+            AdjustOp::Aas => f.write_str("aas"),
+        }
+    }
+}
+
+/// F3 repeats while CX is non-zero (and ZF is set for CMPS/SCAS), F2 while ZF is clear
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepeatPrefix {
+    Rep,
+    Repne,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShiftCount {
+    One,
+    Cl,
+    /// Shift by an immediate byte, 80186 and later
+    Immediate(u8),
+}
+
+impl Display for ShiftCount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            ShiftCount::One => f.write_str("1"),
+            ShiftCount::Cl => f.write_str("cl"),
+            ShiftCount::Immediate(count) => count.fmt(f),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Instruction {
     MovToFromRegMem {
         dir: Direction,
@@ -188,15 +403,17 @@ pub enum Instruction {
     ImmediateMovRegMem {
         width: OpWidth,
         reg_or_mem: RegOrMem,
-        data: i16,
+        data: Immediate,
     },
     ImmediateMovReg {
         reg: RegisterAccess,
-        data: i16,
+        data: Immediate,
     },
     AccumulatorMove {
         dir: Direction,
-        addr: i16,
+        width: OpWidth,
+        addr: Addr16,
+        segment: Option<SegmentRegister>,
     },
     SegmentRegisterMove {
         dir: Direction,
@@ -213,13 +430,13 @@ pub enum Instruction {
     ArithmeticImmediateToRegMem {
         op: ArithmeticOp,
         width: OpWidth,
-        data: i16,
+        data: Immediate,
         reg_or_mem: RegOrMem,
     },
     ArithmeticImmediateToAccumulator {
         op: ArithmeticOp,
         width: OpWidth,
-        data: i16,
+        data: Immediate,
     },
     JumpOnEqual(i8),
     JumpOnLess(i8),
@@ -241,83 +458,306 @@ pub enum Instruction {
     LoopWhileEqual(i8),
     LoopWhileNotEqual(i8),
     JumpOnCxZero(i8),
+    PushRegMem {
+        reg_or_mem: RegOrMem,
+    },
+    PushReg {
+        reg: RegisterAccess,
+    },
+    PushSegmentRegister {
+        seg_reg: SegmentRegister,
+    },
+    PopRegMem {
+        reg_or_mem: RegOrMem,
+    },
+    PopReg {
+        reg: RegisterAccess,
+    },
+    PopSegmentRegister {
+        seg_reg: SegmentRegister,
+    },
+    ExchangeRegMemWithReg {
+        reg: RegisterAccess,
+        reg_or_mem: RegOrMem,
+    },
+    ExchangeRegWithAccumulator {
+        reg: RegisterAccess,
+    },
+    InFixedPort {
+        width: OpWidth,
+        port: u8,
+    },
+    InVariablePort {
+        width: OpWidth,
+    },
+    OutFixedPort {
+        width: OpWidth,
+        port: u8,
+    },
+    OutVariablePort {
+        width: OpWidth,
+    },
+    TranslateByte {
+        segment: Option<SegmentRegister>,
+    },
+    LoadEffectiveAddress {
+        reg: RegisterAccess,
+        mem: EffectiveAddress,
+    },
+    LoadPointerToDs {
+        reg: RegisterAccess,
+        mem: EffectiveAddress,
+    },
+    LoadPointerToEs {
+        reg: RegisterAccess,
+        mem: EffectiveAddress,
+    },
+    LoadAhFromFlags,
+    StoreAhIntoFlags,
+    PushFlags,
+    PopFlags,
+    TestRegMemWithReg {
+        reg: RegisterAccess,
+        reg_or_mem: RegOrMem,
+    },
+    TestImmediateWithRegMem {
+        width: OpWidth,
+        data: Immediate,
+        reg_or_mem: RegOrMem,
+    },
+    TestImmediateWithAccumulator {
+        width: OpWidth,
+        data: Immediate,
+    },
+    Not {
+        width: OpWidth,
+        reg_or_mem: RegOrMem,
+    },
+    Shift {
+        op: ShiftOp,
+        width: OpWidth,
+        count: ShiftCount,
+        reg_or_mem: RegOrMem,
+    },
+    IncrementRegMem {
+        width: OpWidth,
+        reg_or_mem: RegOrMem,
+    },
+    IncrementReg {
+        reg: RegisterAccess,
+    },
+    DecrementRegMem {
+        width: OpWidth,
+        reg_or_mem: RegOrMem,
+    },
+    DecrementReg {
+        reg: RegisterAccess,
+    },
+    Negate {
+        width: OpWidth,
+        reg_or_mem: RegOrMem,
+    },
+    MultiplyDivide {
+        op: MultiplyDivideOp,
+        width: OpWidth,
+        reg_or_mem: RegOrMem,
+    },
+    StringManipulation {
+        op: StringOp,
+        width: OpWidth,
+        repeat: Option<RepeatPrefix>,
+        segment: Option<SegmentRegister>,
+    },
+    Lock(Box<Instruction>),
+    /// Prefixes the 8086 ignores at least one of, such as a repeat prefix on an instruction that isn't a
+    /// string instruction or a segment prefix followed by another. `prefixes` are all of them in order,
+    /// and `instruction` is decoded without them; `with_prefixes` gives what runs.
+    Prefixed {
+        prefixes: Vec<u8>,
+        instruction: Box<Instruction>,
+    },
+    CallDirect(i16),
+    CallFarDirect {
+        offset: u16,
+        segment: u16,
+    },
+    CallIndirect {
+        reg_or_mem: RegOrMem,
+    },
+    CallFarIndirect {
+        mem: EffectiveAddress,
+    },
+    JumpDirect(i16),
+    JumpShort(i8),
+    JumpFarDirect {
+        offset: u16,
+        segment: u16,
+    },
+    JumpIndirect {
+        reg_or_mem: RegOrMem,
+    },
+    JumpFarIndirect {
+        mem: EffectiveAddress,
+    },
+    Return {
+        pop: Option<u16>,
+    },
+    ReturnFar {
+        pop: Option<u16>,
+    },
+    Interrupt(u8),
+    Interrupt3,
+    InterruptOnOverflow,
+    InterruptReturn,
+    ClearCarry,
+    ComplementCarry,
+    SetCarry,
+    ClearDirection,
+    SetDirection,
+    ClearInterrupt,
+    SetInterrupt,
+    Halt,
+    Wait,
+    Nop,
+    Adjust(AdjustOp),
+    /// AAM, the base is the immediate byte which is 10 in the documented encoding
+    AsciiAdjustMultiply {
+        base: u8,
+    },
+    AsciiAdjustDivide {
+        base: u8,
+    },
+    ConvertByteToWord,
+    ConvertWordToDouble,
+    /// SALC, undocumented: sets AL to 0xFF if the carry flag is set, 0 otherwise
+    SetAlFromCarry,
+    /// PUSH imm, 80186 and later. `data` is `SignExtended` for the short encoding
+    PushImmediate {
+        data: Immediate,
+    },
+    PushAll,
+    PopAll,
+    /// IMUL reg, r/m, imm, 80186 and later. Only the low word of the product is kept
+    MultiplyImmediate {
+        reg: RegisterAccess,
+        reg_or_mem: RegOrMem,
+        data: Immediate,
+    },
+    /// ENTER, 80186 and later. Allocates `size` bytes of locals and copies `level` frame pointers
+    Enter {
+        size: Imm16,
+        level: Imm8,
+    },
+    Leave,
+    /// BOUND, 80186 and later. Raises interrupt 5 unless `mem` <= reg <= `mem + 2`, signed
+    Bound {
+        reg: RegisterAccess,
+        mem: EffectiveAddress,
+    },
+    /// Hands an opcode and operand to a coprocessor; `opcode` joins the low bits of the
+    /// first byte with the ModRM reg field
+    Escape {
+        opcode: u8,
+        reg_or_mem: RegOrMem,
+    },
 }
 
 impl Instruction {
+    /// Spells the instruction in NASM syntax, see `format` for the others
     pub fn encode<F>(&self, format_jump: F) -> String
     where
-        F: Fn(i8) -> String,
+        F: Fn(i16) -> String,
     {
-        match *self {
-            Instruction::MovToFromRegMem { dir, reg, ref reg_or_mem } => match dir {
-                Direction::FromRegister => format!("mov {reg_or_mem}, {reg}"),
-                Direction::ToRegister => format!("mov {reg}, {reg_or_mem}"),
-            },
-            Instruction::ImmediateMovRegMem {
-                width,
-                ref reg_or_mem,
-                data,
-            } => {
-                format!("mov {reg_or_mem}, {width} {data}")
-            }
-            Instruction::ImmediateMovReg { reg, data } => {
-                format!("mov {reg}, {data}")
-            }
-            Instruction::AccumulatorMove { dir, addr } => match dir {
-                Direction::FromRegister => format!("mov [{addr}], ax"),
-                Direction::ToRegister => format!("mov ax, [{addr}]"),
-            },
-            Instruction::SegmentRegisterMove { dir, seg_reg, reg_or_mem } => match dir {
-                Direction::FromRegister => format!("mov {reg_or_mem}, {seg_reg}"),
-                Direction::ToRegister => format!("mov {seg_reg}, {reg_or_mem}"),
-            },
-            Instruction::ArithmeticFromToRegMem {
-                op,
-                dir,
-                width: _,
-                reg,
-                ref reg_or_mem,
-            } => match dir {
-                Direction::FromRegister => format!("{op} {reg_or_mem}, {reg}"),
-                Direction::ToRegister => format!("{op} {reg}, {reg_or_mem}"),
+        self.format(&Nasm::default(), &format_jump)
+    }
+
+    /// The memory operand of the instruction, if it has one encoded in a ModRM byte
+    pub fn memory_operand_mut(&mut self) -> Option<&mut EffectiveAddress> {
+        match self {
+            Instruction::MovToFromRegMem { reg_or_mem, .. }
+            | Instruction::ImmediateMovRegMem { reg_or_mem, .. }
+            | Instruction::SegmentRegisterMove { reg_or_mem, .. }
+            | Instruction::ArithmeticFromToRegMem { reg_or_mem, .. }
+            | Instruction::ArithmeticImmediateToRegMem { reg_or_mem, .. }
+            | Instruction::PushRegMem { reg_or_mem }
+            | Instruction::PopRegMem { reg_or_mem }
+            | Instruction::ExchangeRegMemWithReg { reg_or_mem, .. }
+            | Instruction::TestRegMemWithReg { reg_or_mem, .. }
+            | Instruction::TestImmediateWithRegMem { reg_or_mem, .. }
+            | Instruction::Not { reg_or_mem, .. }
+            | Instruction::Shift { reg_or_mem, .. }
+            | Instruction::IncrementRegMem { reg_or_mem, .. }
+            | Instruction::DecrementRegMem { reg_or_mem, .. }
+            | Instruction::Negate { reg_or_mem, .. }
+            | Instruction::MultiplyDivide { reg_or_mem, .. }
+            | Instruction::CallIndirect { reg_or_mem }
+            | Instruction::JumpIndirect { reg_or_mem }
+            | Instruction::MultiplyImmediate { reg_or_mem, .. }
+            | Instruction::Escape { reg_or_mem, .. } => reg_or_mem.as_mem_mut(),
+            Instruction::LoadEffectiveAddress { mem, .. }
+            | Instruction::Bound { mem, .. }
+            | Instruction::LoadPointerToDs { mem, .. }
+            | Instruction::LoadPointerToEs { mem, .. }
+            | Instruction::CallFarIndirect { mem }
+            | Instruction::JumpFarIndirect { mem } => Some(mem),
+            Instruction::Lock(instruction) => instruction.memory_operand_mut(),
+            _ => None,
+        }
+    }
+
+    /// Applies a segment override prefix to whichever memory operand the instruction has, returning
+    /// `false` if it doesn't access memory through a segment the prefix can replace
+    pub fn set_segment_override(&mut self, segment_override: SegmentRegister) -> bool {
+        match self {
+            Instruction::AccumulatorMove { segment, .. }
+            | Instruction::StringManipulation { segment, .. }
+            | Instruction::TranslateByte { segment } => *segment = Some(segment_override),
+            Instruction::Lock(instruction) => return instruction.set_segment_override(segment_override),
+            _ => match self.memory_operand_mut() {
+                Some(ea) => ea.segment = Some(segment_override),
+                None => return false,
             },
-            Instruction::ArithmeticImmediateToRegMem {
-                op,
-                width,
-                data,
-                ref reg_or_mem,
-            } => {
-                format!("{op} {reg_or_mem}, {width} {data}")
+        }
+        true
+    }
+
+    /// The instruction as the 8086 runs it after the prefix bytes `prefixes`: the last segment and repeat
+    /// prefixes win, and a prefix the instruction has no use for is ignored. Also returns whether every
+    /// prefix took effect in the order the encoder writes them (lock, repeat, segment), that is whether
+    /// the instruction encodes back to `prefixes` followed by its own bytes.
+    pub fn with_prefixes(mut self, prefixes: &[u8]) -> (Instruction, bool) {
+        let mut used = true;
+        let mut repeat = None;
+        let mut segment = None;
+        let mut lock = false;
+        let mut rank = 0;
+        for prefix in prefixes.iter().filter_map(|byte| decode_prefix(*byte)) {
+            let prefix_rank = match prefix {
+                Prefix::Lock => 0,
+                Prefix::Repeat(_) => 1,
+                Prefix::Segment(_) => 2,
+            };
+            used &= prefix_rank >= rank;
+            rank = prefix_rank;
+            match prefix {
+                Prefix::Repeat(prefix) => used &= repeat.replace(prefix).is_none(),
+                Prefix::Segment(prefix) => used &= segment.replace(prefix).is_none(),
+                Prefix::Lock => used &= !std::mem::replace(&mut lock, true),
             }
-            Instruction::ArithmeticImmediateToAccumulator { op, width, data } => {
-                format!(
-                    "{op} {}, {data}",
-                    match width {
-                        OpWidth::Byte => "al",
-                        OpWidth::Word => "ax",
-                    }
-                )
+        }
+
+        if let Some(prefix) = repeat {
+            match &mut self {
+                Instruction::StringManipulation { repeat, .. } => *repeat = Some(prefix),
+                _ => used = false,
             }
-            Instruction::JumpOnEqual(disp) => format!("je {}", format_jump(disp)),
-            Instruction::JumpOnLess(disp) => format!("jl {}", format_jump(disp)),
-            Instruction::JumpOnNotGreater(disp) => format!("jle {}", format_jump(disp)),
-            Instruction::JumpOnBelow(disp) => format!("jb {}", format_jump(disp)),
-            Instruction::JumpOnNotAbove(disp) => format!("jbe {}", format_jump(disp)),
-            Instruction::JumpOnParity(disp) => format!("jp {}", format_jump(disp)),
-            Instruction::JumpOnOverflow(disp) => format!("jo {}", format_jump(disp)),
-            Instruction::JumpOnSign(disp) => format!("js {}", format_jump(disp)),
-            Instruction::JumpOnNotEqual(disp) => format!("jne {}", format_jump(disp)),
-            Instruction::JumpOnNotLess(disp) => format!("jnl {}", format_jump(disp)),
-            Instruction::JumpOnGreater(disp) => format!("jg {}", format_jump(disp)),
-            Instruction::JumpOnNotBelow(disp) => format!("jnb {}", format_jump(disp)),
-            Instruction::JumpOnAbove(disp) => format!("jnbe {}", format_jump(disp)),
-            Instruction::JumpOnNoParity(disp) => format!("jnp {}", format_jump(disp)),
-            Instruction::JumpOnNoOverflow(disp) => format!("jno {}", format_jump(disp)),
-            Instruction::JumpOnNotSign(disp) => format!("jns {}", format_jump(disp)),
-            Instruction::Loop(disp) => format!("loop {}", format_jump(disp)),
-            Instruction::LoopWhileEqual(disp) => format!("loope {}", format_jump(disp)),
-            Instruction::LoopWhileNotEqual(disp) => format!("loopne {}", format_jump(disp)),
-            Instruction::JumpOnCxZero(disp) => format!("jcxz {}", format_jump(disp)),
         }
+        if let Some(segment) = segment {
+            used &= self.set_segment_override(segment);
+        }
+        if lock {
+            self = Instruction::Lock(Box::new(self));
+        }
+        (self, used)
     }
 }